@@ -0,0 +1,167 @@
+//! Running an operation on all the instances at the same time (`--all`), with a summary of
+//! the results at the end
+
+use anyhow::{anyhow, Result};
+use console::style;
+use rayon::prelude::*;
+use std::io::Write;
+
+use crate::{common::create_spinner, info};
+
+/// Default number of the instances operated on at the same time
+pub const DEFAULT_BULK_JOBS: usize = 4;
+
+/// Result of the operation on an instance
+pub enum Outcome {
+    Done,
+    /// Nothing is done, with the reason (e.g. the instance is already stopped)
+    Skipped(String),
+    Failed(anyhow::Error),
+}
+
+/// An operation run on many instances, at most `jobs` of them at the same time
+pub struct BulkAction {
+    /// Shown in the summary for the instances the operation is done on, e.g. `stopped`
+    pub done: &'static str,
+    pub jobs: usize,
+}
+
+impl BulkAction {
+    fn run_each<T, F>(
+        &self,
+        progress: &str,
+        items: Vec<(String, T)>,
+        func: F,
+    ) -> Result<Vec<(String, Outcome)>>
+    where
+        T: Send,
+        F: Fn(&str, T) -> Outcome + Sync,
+    {
+        let multi = indicatif::MultiProgress::new();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.jobs.max(1))
+            .build()?;
+        let outcomes = pool.install(|| {
+            items
+                .into_par_iter()
+                .map(|(instance, item)| {
+                    let spinner = multi.add(create_spinner("", 200));
+                    spinner.set_message(format!("{}: {}...", instance, progress));
+                    let outcome = func(&instance, item);
+                    spinner.finish_and_clear();
+                    (instance, outcome)
+                })
+                .collect()
+        });
+
+        Ok(outcomes)
+    }
+
+    /// Run the operation on the instances, an error only fails the instance it comes from
+    /// and the others carry on. `progress` is shown while it is running, e.g. `stopping`
+    pub fn run<F>(
+        &self,
+        progress: &str,
+        instances: &[String],
+        func: F,
+    ) -> Result<Vec<(String, Outcome)>>
+    where
+        F: Fn(&str) -> Result<Outcome> + Sync,
+    {
+        let items = instances.iter().map(|x| (x.clone(), ())).collect();
+
+        self.run_each(progress, items, |instance, ()| {
+            func(instance).unwrap_or_else(Outcome::Failed)
+        })
+    }
+
+    /// Run the next step of the operation on the instances the previous steps did not fail on,
+    /// given the outcome of the previous steps
+    pub fn then<F>(
+        &self,
+        progress: &str,
+        outcomes: Vec<(String, Outcome)>,
+        func: F,
+    ) -> Result<Vec<(String, Outcome)>>
+    where
+        F: Fn(&str, Outcome) -> Result<Outcome> + Sync,
+    {
+        self.run_each(progress, outcomes, |instance, outcome| match outcome {
+            Outcome::Failed(e) => Outcome::Failed(e),
+            outcome => func(instance, outcome).unwrap_or_else(Outcome::Failed),
+        })
+    }
+
+    /// Print the outcome of each instance in a table and the totals,
+    /// returns an error if the operation failed on any of them
+    pub fn summarize(&self, outcomes: &[(String, Outcome)]) -> Result<()> {
+        use tabwriter::TabWriter;
+
+        if outcomes.is_empty() {
+            info!("No instances.");
+            return Ok(());
+        }
+        let mut formatter = TabWriter::new(std::io::stderr());
+        writeln!(&mut formatter, "INSTANCE\tRESULT")?;
+        let (mut done, mut skipped, mut failed) = (0, 0, 0);
+        for (instance, outcome) in outcomes {
+            let result = match outcome {
+                Outcome::Done => {
+                    done += 1;
+                    style(self.done).green().to_string()
+                }
+                Outcome::Skipped(reason) => {
+                    skipped += 1;
+                    style(format!("skipped: {}", reason)).dim().to_string()
+                }
+                Outcome::Failed(e) => {
+                    failed += 1;
+                    // the table is kept one line per instance
+                    let e = format!("{:#}", e).replace('\n', " ");
+                    style(format!("failed: {}", e)).red().to_string()
+                }
+            };
+            writeln!(&mut formatter, "{}\t{}", instance, result)?;
+        }
+        formatter.flush()?;
+        info!(
+            "{} {}, {} skipped, {} failed.",
+            done, self.done, skipped, failed
+        );
+        if failed > 0 {
+            return Err(anyhow!("Failed on {} of the instances.", failed));
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_bulk_action() {
+    let action = BulkAction {
+        done: "stopped",
+        jobs: 2,
+    };
+    let instances = ["a", "b", "c"].map(|x| x.to_owned());
+    let outcomes = action
+        .run("stopping", &instances, |instance| match instance {
+            "a" => Ok(Outcome::Done),
+            "b" => Ok(Outcome::Skipped("already stopped".to_owned())),
+            _ => Err(anyhow!("unable to stop")),
+        })
+        .unwrap();
+    // a failure does not stop the next steps on the other instances
+    let outcomes = action
+        .then("un-mounting", outcomes, |instance, outcome| {
+            assert_ne!(instance, "c");
+            Ok(outcome)
+        })
+        .unwrap();
+    let names = outcomes.iter().map(|x| x.0.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["a", "b", "c"]);
+    assert!(matches!(outcomes[0].1, Outcome::Done));
+    assert!(matches!(outcomes[1].1, Outcome::Skipped(_)));
+    assert!(matches!(&outcomes[2].1, Outcome::Failed(e) if e.to_string() == "unable to stop"));
+    assert!(action.summarize(&outcomes).is_err());
+    assert!(action.summarize(&outcomes[..2]).is_ok());
+}
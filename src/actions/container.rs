@@ -1,31 +1,116 @@
 use anyhow::{anyhow, Result};
 use console::{style, user_attended};
-use dialoguer::{theme::ColorfulTheme, Confirm, Input};
+use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
 use git2::Repository;
 use nix::unistd::sync;
 use rand::random;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::{
     ffi::OsStr,
     fs,
+    io::{BufRead, BufReader, Read},
     path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
+use walkdir::WalkDir;
 
 use crate::{
     actions::ensure_host_sanity,
+    cache, command_log,
     common::*,
-    config, error, info,
-    machine::{self, get_container_ns_name, inspect_instance, spawn_container},
-    network::download_file_progress,
-    overlayfs, warn,
+    config::{self, MountSpec},
+    dpkg, error, info,
+    machine::{
+        self, get_container_ns_name, inspect_instance, spawn_container, CommandOptions, StopLevel,
+        StopOptions,
+    },
+    metadata::{self, InstanceMetadata},
+    network::{
+        benchmark_mirrors, download_file_progress, download_stream, ensure_branch_fetched,
+        ensure_reachable, fetch_recipe, fetch_tarball_checksum, get_arch_name, git_switch_branch,
+        select_tarball, url_host, DownloadOptions, MirrorBenchmark, Recipe, Tarball,
+        DEFAULT_MIRROR, DEFAULT_VARIANT, MIRROR_SAMPLE_BYTES, RELEASE_MIRRORS,
+    },
+    overlayfs, warn, workspace,
 };
 
-use super::{for_each_instance, UPDATE_SCRIPT};
+use super::{
+    bulk::{BulkAction, Outcome},
+    for_each_instance,
+    hooks::{run_post_hook, run_pre_hook},
+    package_manager_error, trash, update_script, UpdateOptions, UpdateSummary,
+};
+
+/// Where a cloned instance is copied before it is moved into the instances
+const CLONE_STAGING_DIR: &str = ".ciel/container/clone.incomplete";
+/// Marker (in the instance directory) of the configuration to be applied on the next mount
+const PENDING_CONFIG_MARKER: &str = "config.pending";
+/// The unit of systemd-networkd in the instances
+const NETWORKD_UNIT: &str = "/usr/lib/systemd/system/systemd-networkd.service";
+/// Where systemd-networkd is enabled in the instances (in the veth network mode)
+const NETWORKD_WANTS_LINK: &str =
+    "etc/systemd/system/multi-user.target.wants/systemd-networkd.service";
+/// The first version of Linux with the idmapped mounts of overlayfs (5.12 only has them for
+/// ext4, xfs and btrfs), otherwise systemd-nspawn shifts the ownership of the files in the
+/// instances with user namespacing (`private-users`)
+const IDMAPPED_MOUNTS_KERNEL: (u32, u32) = (5, 19);
+/// Benchmark results of the release mirrors
+const MIRROR_RANKING_FILE: &str = ".ciel/data/mirror-ranking.json";
+/// How long the benchmark results of the mirrors are reused, in seconds
+const MIRROR_RANKING_TTL: u64 = 24 * 60 * 60;
+
+/// Which tarball to pick from the mirror, the latest BuildKit for the host is picked by default
+#[derive(Debug, Default)]
+pub struct TarballSelection {
+    pub variant: Option<String>,
+    pub arch: Option<String>,
+    /// Release date of the tarball (e.g. `20230101`)
+    pub date: Option<String>,
+    /// Let the user choose the tarball if nothing is specified,
+    /// or enter the URL if the tarballs could not be listed
+    pub interactive: bool,
+}
+
+/// Options of loading the OS
+#[derive(Debug, Clone, Copy)]
+pub struct LoadOsOptions {
+    /// Allow network access even if `isolate-network` is enabled
+    pub online: bool,
+    /// Verify the checksum of the tarball
+    pub verify: bool,
+    /// Save the tarball to disk before extracting it, so that the download can be resumed
+    pub keep_tarball: bool,
+    /// Use the tarball cache shared by the workspaces
+    pub use_cache: bool,
+    /// Download from the fastest release mirror (also enabled by `fastest-mirror`)
+    pub fastest_mirror: bool,
+}
+
+impl Default for LoadOsOptions {
+    fn default() -> Self {
+        LoadOsOptions {
+            online: false,
+            verify: true,
+            keep_tarball: false,
+            use_cache: true,
+            fastest_mirror: false,
+        }
+    }
+}
 
-/// Get the branch name of the workspace TREE repository
+/// Get the branch name of the workspace TREE repository, the branch chosen when cloning or
+/// switching is used if no branch is checked out (e.g. a tag in a shallow clone)
 #[inline]
 fn get_branch_name() -> Result<String> {
     let repo = Repository::open("TREE")?;
     let head = repo.head()?;
+    if !head.is_branch() {
+        if let Some(branch) = workspace::recorded_branch() {
+            return Ok(branch);
+        }
+    }
 
     Ok(head
         .shorthand()
@@ -33,117 +118,639 @@ fn get_branch_name() -> Result<String> {
         .to_owned())
 }
 
-/// Determine the output directory name
+/// Switch the tree to the branch. Refuses if the tree has uncommitted changes unless `force`
+/// is set, the changes are carried over then. The running instances are stopped if the output
+/// directory changes with the branch, so that the new one is mounted when they start again
+pub fn switch_tree(branch: &str, force: bool) -> Result<()> {
+    let mut repo = Repository::open("TREE")?;
+    if repo.state() != git2::RepositoryState::Clean {
+        return Err(anyhow!(
+            "The tree has an operation in progress (e.g. a rebase), please finish it first."
+        ));
+    }
+    let mut status_options = git2::StatusOptions::new();
+    status_options
+        .include_untracked(true)
+        .include_ignored(false);
+    let changes = repo.statuses(Some(&mut status_options))?.len();
+    if changes > 0 && !force {
+        return Err(anyhow!(
+            "The tree has {} uncommitted changes, please commit or stash them first, or use `--force` to carry them over.",
+            changes
+        ));
+    }
+    ensure_branch_fetched(&repo, branch)?;
+    let config = config::read_config()?;
+    let old_output = get_output_directory(&config, config.sep_mount);
+    let stashed = git_switch_branch(&mut repo, branch, None)?;
+    workspace::record_branch(branch)?;
+    info!("Switched the tree to {}.", branch);
+    if stashed {
+        info!("The uncommitted changes have been carried over.");
+    }
+    if get_output_directory(&config, config.sep_mount) == old_output {
+        return Ok(());
+    }
+    for instance in machine::list_instances_simple()? {
+        if inspect_instance(&instance, &get_instance_ns_name(&instance)?)?.started {
+            stop_container(&instance)?;
+            info!(
+                "{}: the output directory of {} will be mounted when it starts again.",
+                instance, branch
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Determine the output directory, branch-exclusive if `sep_mount` is set
 #[inline]
-pub fn get_output_directory(sep_mount: bool) -> String {
-    if sep_mount {
-        format!(
-            "OUTPUT-{}",
-            get_branch_name().unwrap_or_else(|_| "HEAD".to_string())
-        )
+pub fn get_output_directory(config: &config::CielConfig, sep_mount: bool) -> PathBuf {
+    let branch = if sep_mount {
+        Some(get_branch_name().unwrap_or_else(|_| "HEAD".to_string()))
     } else {
-        "OUTPUT".to_string()
+        None
+    };
+
+    workspace::output_dir(config, branch.as_deref())
+}
+
+/// Determine the output directory used before the output-dir-pattern option was introduced
+#[inline]
+pub fn get_legacy_output_directory(config: &config::CielConfig) -> PathBuf {
+    let branch = get_branch_name().unwrap_or_else(|_| "HEAD".to_string());
+
+    workspace::output_dir_with_pattern(config, Some(&branch), config::DEFAULT_OUTPUT_DIR_PATTERN)
+}
+
+/// Shows the progress of committing in a progress bar of the bytes merged
+struct CommitProgressBar {
+    bar: indicatif::ProgressBar,
+    files: u64,
+    total_files: u64,
+}
+
+impl CommitProgressBar {
+    fn new() -> Self {
+        let bar = indicatif::ProgressBar::new(0);
+        bar.set_style(
+            indicatif::ProgressStyle::default_bar()
+                .template("{spinner} [{bar:25.cyan/blue}] {bytes}/{total_bytes} {wide_msg}")
+                .unwrap(),
+        );
+        bar.set_draw_target(indicatif::ProgressDrawTarget::stderr_with_hz(5));
+        bar.set_message("Scanning upper layer...");
+        bar.enable_steady_tick(std::time::Duration::from_millis(200));
+
+        CommitProgressBar {
+            bar,
+            files: 0,
+            total_files: 0,
+        }
+    }
+}
+
+impl overlayfs::CommitProgress for CommitProgressBar {
+    fn scanned(&mut self, files: u64, bytes: u64) {
+        self.total_files = files;
+        self.bar.set_length(bytes);
+    }
+
+    fn merged(&mut self, path: &Path, files: u64, bytes: u64) {
+        self.files += files;
+        self.bar.inc(bytes);
+        self.bar.set_message(format!(
+            "({}/{} files) {}",
+            self.files,
+            self.total_files,
+            path.display()
+        ));
     }
 }
 
-fn commit(instance: &str) -> Result<()> {
+fn commit(instance: &str, progress: bool) -> Result<()> {
     get_instance_ns_name(instance)?;
     info!("Un-mounting all the instances...");
     // Un-mount all the instances
     for_each_instance(&container_down)?;
     info!("{}: committing instance...", instance);
-    let spinner = create_spinner("Committing upper layer...", 200);
+    let started = Instant::now();
     let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
-    man.commit()?;
+    // scanning the upper layer for the progress takes time, so only a spinner without it
+    let summary = if progress {
+        let mut progress = CommitProgressBar::new();
+        let summary = man.commit_with_progress(Some(&mut progress));
+        progress.bar.finish_and_clear();
+        summary?
+    } else {
+        let spinner = create_spinner("Committing upper layer...", 200);
+        let summary = man.commit_with_progress(None);
+        spinner.finish_and_clear();
+        summary?
+    };
+    reset_machine_id(man)?;
     sync();
-    spinner.finish_and_clear();
+    info!(
+        "{}: merged {} files, applied {} whiteouts in {}.",
+        instance,
+        summary.merged,
+        summary.whiteouts,
+        format_duration(started.elapsed())
+    );
 
     Ok(())
 }
 
-/// Rollback the container (by removing the upper layer)
-fn rollback(instance: &str) -> Result<()> {
+/// Rollback the container (by removing the upper layer, which is moved into the trash instead
+/// unless `purge` is set)
+fn rollback(instance: &str, purge: bool) -> Result<()> {
     get_instance_ns_name(instance)?;
     info!("{}: rolling back instance...", instance);
-    let spinner = create_spinner("Removing upper layer...", 200);
+    let spinner = create_spinner("Discarding upper layer...", 200);
     let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
-    man.rollback()?;
+    let saved = trash::discard_upper_layer(instance, man, purge)?;
+    reset_machine_id(man)?;
     sync();
     spinner.finish_and_clear();
+    if let Some(id) = saved {
+        info!(
+            "{}: the changes are moved into the trash, use `ciel trash restore {}` to bring them back.",
+            instance,
+            id
+        );
+        trash::prune_trash(Some(&id))?;
+    }
 
     Ok(())
 }
 
-/// Remove everything in the current workspace
-pub fn farewell(path: &Path) -> Result<()> {
-    if !user_attended() {
-        eprintln!("DELETE THIS CIEL WORKSPACE?");
-        info!("Not controlled by an user. Automatically confirmed.");
-        // Un-mount all the instances
-        for_each_instance(&container_down)?;
-        fs::remove_dir_all(path.join(".ciel"))?;
-        return Ok(());
-    }
-    let theme = ColorfulTheme::default();
-    let delete = Confirm::with_theme(&theme)
-        .with_prompt("DELETE THIS CIEL WORKSPACE?")
-        .default(false)
-        .interact()?;
-    if !delete {
-        info!("Not confirmed.");
-        return Ok(());
-    }
-    info!(
-        "If you are absolutely sure, please type the following:\n{}",
-        style("Do as I say!").bold()
-    );
-    if Input::<String>::with_theme(&theme)
-        .with_prompt("Your turn")
-        .interact()?
-        != "Do as I say!"
-    {
-        info!("Prompt answered incorrectly. Not confirmed.");
-        return Ok(());
+/// Truncate the machine-id in the upper layer if `clear-machine-id` is enabled
+fn reset_machine_id(man: &mut dyn overlayfs::LayerManager) -> Result<()> {
+    if config::read_config().map_or(false, |c| c.clear_machine_id) {
+        man.clear_machine_id()?;
     }
 
-    info!("... as you wish. Commencing destruction ...");
-    info!("Un-mounting all the instances...");
-    // Un-mount all the instances
-    for_each_instance(&container_down)?;
-    fs::remove_dir_all(path.join(".ciel"))?;
-
     Ok(())
 }
 
-/// Download the OS tarball and then extract it for use as the base layer
-pub fn load_os(url: &str, sha256: Option<String>) -> Result<()> {
-    info!("Downloading base OS tarball...");
+/// Download the OS tarball and extract it at the same time, the tarball is not saved.
+/// The base system is only replaced after the checksum is verified
+fn load_os_streamed(url: &str, sha256: Option<&str>) -> Result<()> {
+    info!("Downloading and extracting base OS tarball...");
+    let (resp, total) = download_stream(url)?;
+    let rate_limit = resp.rate_limit();
+    let mut reader = Sha256Reader::new(resp);
+    stage_system_tarball(&mut reader, total, rate_limit)?;
+    let checksum = reader.finish()?;
+    if let Some(sha256) = sha256 {
+        if !checksum.eq_ignore_ascii_case(sha256.trim()) {
+            return Err(anyhow!(
+                "Checksum mismatch: expected {} but got {}",
+                sha256,
+                checksum
+            ));
+        }
+        info!("Checksum verified.");
+    }
+
+    install_staged_system()
+}
+
+/// Download the OS tarball and then extract it for use as the base layer.
+/// A verified tarball is taken from (or downloaded into) the tarball cache if enabled.
+/// Otherwise the tarball is downloaded to the current directory first (so that the download can
+/// be resumed) if `keep_tarball` is set or a previously downloaded tarball exists,
+/// or it is extracted while downloading
+pub fn load_os(url: &str, sha256: Option<String>, options: &LoadOsOptions) -> Result<()> {
     let path = Path::new(url)
         .file_name()
         .ok_or_else(|| anyhow!("Unable to convert path to string"))?
         .to_str()
         .ok_or_else(|| anyhow!("Unable to decode path string"))?;
+    let download = DownloadOptions::from_config(&config::read_config().unwrap_or_default());
+    if let (true, Some(sha256)) = (options.use_cache && !Path::new(path).is_file(), &sha256) {
+        if let Some(tarball) = cache::fetch_cached_tarball(url, sha256, &download)? {
+            info!("Loading base OS tarball from the cache...");
+            return extract_system_tarball(&tarball, tarball.metadata()?.len());
+        }
+    }
+    if !options.keep_tarball && !Path::new(path).is_file() {
+        let result = load_os_streamed(url, sha256.as_deref());
+        if result.is_err() {
+            fs::remove_dir_all(CIEL_DIST_STAGING_DIR).ok();
+        }
+        return result;
+    }
+    info!("Downloading base OS tarball...");
     let total = if !Path::new(path).is_file() {
-        download_file_progress(url, path)?
+        // the checksum is verified before the downloaded file is renamed into place
+        download_file_progress(url, path, sha256.as_deref(), &download)?
     } else {
+        if let Some(sha256) = sha256 {
+            verify_tarball(Path::new(path), &sha256)?;
+        }
         let tarball = fs::File::open(path)?;
         tarball.metadata()?.len()
     };
-    if let Some(sha256) = sha256 {
-        info!("Verifying tarball checksum...");
-        let tarball = fs::File::open(path)?;
-        let checksum = sha256sum(tarball)?;
-        if sha256 == checksum {
-            info!("Checksum verified.");
+    extract_system_tarball(&PathBuf::from(path), total)?;
+
+    Ok(())
+}
+
+/// Check the SHA-256 checksum of the tarball, the expected checksum is in hex
+fn verify_tarball(tarball: &Path, sha256: &str) -> Result<()> {
+    let sha256 = sha256.trim().to_ascii_lowercase();
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("Invalid SHA-256 checksum: {}", sha256));
+    }
+    info!("Verifying tarball checksum...");
+    let checksum = sha256sum_file(tarball)?;
+    if sha256 != checksum {
+        return Err(anyhow!(
+            "Checksum mismatch: expected {} but got {}",
+            sha256,
+            checksum
+        ));
+    }
+    info!("Checksum verified.");
+
+    Ok(())
+}
+
+/// Look for the checksum of the tarball in the sibling checksum file (`<tarball>.sha256sum`)
+fn find_tarball_checksum(tarball: &Path) -> Result<Option<String>> {
+    let file_name = tarball
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid tarball path: {}", tarball.display()))?
+        .to_string_lossy();
+    for ext in ["sha256sum", "sha256"] {
+        let checksum_file = tarball.with_file_name(format!("{}.{}", file_name, ext));
+        if !checksum_file.is_file() {
+            continue;
+        }
+        let content = fs::read_to_string(&checksum_file)?;
+        return match parse_checksum_file(&content, &file_name) {
+            Some(checksum) => {
+                info!("Using the checksum in {}", checksum_file.display());
+                Ok(Some(checksum))
+            }
+            None => Err(anyhow!(
+                "No checksum of {} is found in {}",
+                file_name,
+                checksum_file.display()
+            )),
+        };
+    }
+
+    Ok(None)
+}
+
+/// Load the OS from a URL, a `file://` URL or a local path. If not specified, the checksum is
+/// read from the checksum file published along with the tarball (or the sibling checksum file
+/// of a local tarball). Nothing is verified if `verify` is not set
+pub fn load_os_from(source: &str, sha256: Option<String>, options: &LoadOsOptions) -> Result<()> {
+    let verify = options.verify;
+    ensure_no_instance_mounted()?;
+    if Path::new(CIEL_DIST_STAGING_DIR).exists() {
+        warn!("Removing the incomplete base system left by an interrupted load-os...");
+        fs::remove_dir_all(CIEL_DIST_STAGING_DIR)?;
+    }
+    if !verify {
+        warn!(
+            "{}",
+            style("Verification is disabled, the OS tarball will be used without checking its integrity!")
+                .bold()
+        );
+    }
+    let sha256 = sha256.filter(|_| verify);
+    if source.starts_with("https://") || source.starts_with("http://") {
+        ensure_network_allowed(options.online)?;
+        ensure_reachable(source, "Loading the OS")?;
+        let sha256 = match sha256 {
+            Some(sha256) => Some(sha256),
+            None if verify => {
+                let keyring = config::read_config().ok().and_then(|c| c.rootfs_keyring);
+                let sha256 = fetch_tarball_checksum(source, keyring.as_deref()).map_err(|e| {
+                    anyhow!(
+                        "{:#}. Please specify the checksum with --sha256, or skip the verification with --no-verify.",
+                        e
+                    )
+                })?;
+                Some(sha256)
+            }
+            None => None,
+        };
+        load_os(source, sha256, options)?;
+        return record_loaded_os(source);
+    }
+    let tarball = Path::new(source.strip_prefix("file://").unwrap_or(source));
+    if tarball.is_dir() {
+        let candidates = fs::read_dir(tarball)?
+            .flatten()
+            .map(|x| x.file_name().to_string_lossy().into_owned())
+            .filter(|x| {
+                [".tar.xz", ".tar.zst", ".tar.gz"]
+                    .iter()
+                    .any(|ext| x.ends_with(ext))
+            })
+            .collect::<Vec<_>>();
+        return Err(match candidates.first() {
+            Some(candidate) => anyhow!(
+                "{} is a directory, please specify the path to the tarball (e.g. {})",
+                tarball.display(),
+                tarball.join(candidate).display()
+            ),
+            None => anyhow!(
+                "{} is a directory, please specify the path to the tarball",
+                tarball.display()
+            ),
+        });
+    }
+    if !tarball.is_file() {
+        return Err(anyhow!("{} is not a file", tarball.display()));
+    }
+    let sha256 = match sha256 {
+        Some(sha256) => Some(sha256),
+        None if verify => find_tarball_checksum(tarball)?,
+        None => None,
+    };
+    match sha256 {
+        Some(sha256) => verify_tarball(tarball, &sha256)?,
+        None if verify => warn!("No checksum is available, the tarball will not be verified."),
+        None => (),
+    }
+    info!("Loading base OS tarball from {} ...", tarball.display());
+    extract_system_tarball(tarball, tarball.metadata()?.len())?;
+
+    record_loaded_os(source)
+}
+
+/// Guess the architecture from the file name of the tarball
+/// (e.g. `aosc-os_buildkit_20230101_amd64.tar.xz`)
+fn tarball_arch(source: &str) -> Option<&str> {
+    let name = source.rsplit('/').next()?;
+    let (stem, _) = name.split_once(".tar")?;
+    let (_, arch) = stem.rsplit_once('_')?;
+    let valid = !arch.is_empty() && arch.chars().all(|c| c.is_ascii_alphanumeric());
+
+    valid.then(|| arch)
+}
+
+/// Record the architecture of the loaded OS in the workspace, and warn if it is not the
+/// architecture of the host. The newly loaded OS has not been updated yet
+fn record_loaded_os(source: &str) -> Result<()> {
+    workspace::forget_update()?;
+    let arch = match tarball_arch(source) {
+        Some(arch) => arch,
+        None => return Ok(()),
+    };
+    workspace::record_arch(arch)?;
+    if get_arch_name().map_or(false, |x| x != arch) {
+        warn!(
+            "The OS is for {}, which is not the architecture of the host ({}). Make sure binfmt_misc with qemu-user-static is set up.",
+            arch,
+            get_arch_name().unwrap_or("unknown")
+        );
+    }
+
+    Ok(())
+}
+
+/// Let the user choose the variant, the architecture and the release date of the tarball
+fn choose_tarball(recipe: &Recipe, default_arch: Option<&str>) -> Result<Tarball> {
+    let theme = ColorfulTheme::default();
+    let variants = recipe
+        .variants
+        .iter()
+        .filter(|x| !x.tarballs.is_empty())
+        .collect::<Vec<_>>();
+    if variants.is_empty() {
+        return Err(anyhow!("No tarball is available on the mirror"));
+    }
+    let names = variants.iter().map(|x| x.name.as_str()).collect::<Vec<_>>();
+    let variant = variants[Select::with_theme(&theme)
+        .with_prompt("Variant")
+        .items(&names)
+        .default(
+            names
+                .iter()
+                .position(|x| *x == DEFAULT_VARIANT)
+                .unwrap_or(0),
+        )
+        .interact()?];
+    let mut archs = variant
+        .tarballs
+        .iter()
+        .map(|x| x.arch.as_str())
+        .collect::<Vec<_>>();
+    archs.sort_unstable();
+    archs.dedup();
+    let default_arch = default_arch.or_else(get_arch_name);
+    let arch = archs[Select::with_theme(&theme)
+        .with_prompt("Architecture")
+        .items(&archs)
+        .default(
+            archs
+                .iter()
+                .position(|x| Some(*x) == default_arch)
+                .unwrap_or(0),
+        )
+        .interact()?];
+    let mut tarballs = variant
+        .tarballs
+        .iter()
+        .filter(|x| x.arch == arch)
+        .collect::<Vec<_>>();
+    // the latest one first
+    tarballs.sort_unstable_by(|a, b| b.date.cmp(&a.date));
+    let dates = tarballs.iter().map(|x| x.date.as_str()).collect::<Vec<_>>();
+    let tarball = tarballs[Select::with_theme(&theme)
+        .with_prompt("Release date")
+        .items(&dates)
+        .default(0)
+        .interact()?];
+
+    Ok(tarball.clone())
+}
+
+/// Determine the URL (and the checksum) of the OS tarball according to the selection and the
+/// configuration (`rootfs-url` and `rootfs-arch`), the default mirror is used if not configured
+/// Returns whether the URL points to a mirror providing the release manifest (ends with `/`)
+fn is_mirror_url(url: &str) -> bool {
+    url.ends_with('/') && url.contains("://") && !url.starts_with("file://")
+}
+
+pub fn resolve_rootfs_source(
+    config: &config::CielConfig,
+    selection: &TarballSelection,
+) -> Result<(String, Option<String>)> {
+    let mirror = match &config.rootfs_url {
+        Some(url) if is_mirror_url(url) => url.as_str(),
+        Some(url) => return Ok((url.clone(), None)),
+        None => DEFAULT_MIRROR,
+    };
+    let keyring = config.rootfs_keyring.as_deref();
+    ensure_reachable(mirror, "Loading the OS")?;
+    let recipe = fetch_recipe(mirror, keyring).map_err(|e| {
+        if config.rootfs_url.is_some() {
+            anyhow!(
+                "The configured mirror {} (rootfs-url) is unreachable: {:#}",
+                mirror,
+                e
+            )
         } else {
-            return Err(anyhow!(
-                "Checksum mismatch: expected {} but got {}",
-                sha256,
-                checksum
-            ));
+            anyhow!("The default mirror {} is unreachable: {:#}", mirror, e)
+        }
+    });
+    let recipe = match recipe {
+        Ok(recipe) => recipe,
+        Err(e) if selection.interactive => {
+            warn!("{}", e);
+            let url = Input::<String>::with_theme(&ColorfulTheme::default())
+                .with_prompt("Unable to list the tarballs, please enter the tarball URL")
+                .interact_text()?;
+            return Ok((url, None));
+        }
+        Err(e) => return Err(e),
+    };
+    let arch = selection.arch.as_deref().or(config.rootfs_arch.as_deref());
+    let nothing_selected =
+        selection.variant.is_none() && selection.arch.is_none() && selection.date.is_none();
+    let tarball = if selection.interactive && nothing_selected {
+        choose_tarball(&recipe, arch)?
+    } else {
+        let variant = selection.variant.as_deref().unwrap_or(DEFAULT_VARIANT);
+        select_tarball(&recipe, variant, arch, selection.date.as_deref())?
+    };
+    info!(
+        "Ciel has picked the tarball for {}, released on {}",
+        tarball.arch, tarball.date
+    );
+
+    Ok((
+        format!("{}{}", mirror, tarball.path),
+        Some(tarball.sha256sum),
+    ))
+}
+
+#[derive(Serialize, Deserialize)]
+struct MirrorRanking {
+    candidates: Vec<String>,
+    /// When the mirrors were benchmarked, in seconds since the Unix epoch
+    time: u64,
+    mirrors: Vec<MirrorBenchmark>,
+}
+
+/// Returns the reachable mirrors ranked from the fastest to the slowest,
+/// the benchmark results are saved in the workspace and reused for a day
+fn ranked_mirrors(candidates: Vec<String>) -> Result<Vec<String>> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let cached = fs::read(MIRROR_RANKING_FILE)
+        .ok()
+        .and_then(|x| serde_json::from_slice::<MirrorRanking>(&x).ok())
+        .filter(|x| x.candidates == candidates && now.saturating_sub(x.time) < MIRROR_RANKING_TTL);
+    let mirrors = match cached {
+        Some(ranking) => ranking.mirrors,
+        None => {
+            info!("Looking for the fastest mirror...");
+            let mirrors = benchmark_mirrors(&candidates, MIRROR_SAMPLE_BYTES);
+            if !mirrors.is_empty() {
+                let ranking = MirrorRanking {
+                    candidates,
+                    time: now,
+                    mirrors,
+                };
+                fs::write(MIRROR_RANKING_FILE, serde_json::to_vec(&ranking)?)?;
+                ranking.mirrors
+            } else {
+                mirrors
+            }
         }
+    };
+    let fastest = mirrors
+        .first()
+        .ok_or_else(|| anyhow!("None of the mirrors is reachable"))?;
+    info!(
+        "Using the fastest mirror {} ({}/s, {} ms)",
+        fastest.url,
+        indicatif::HumanBytes(fastest.throughput as u64),
+        fastest.latency.as_millis()
+    );
+
+    Ok(mirrors.into_iter().map(|x| x.url).collect())
+}
+
+/// Load the OS using the source in the configuration or the selected tarball
+/// (the latest buildkit by default) from the default mirror
+pub fn load_os_auto(options: &LoadOsOptions, selection: &TarballSelection) -> Result<()> {
+    let mut config = config::read_config().unwrap_or_default();
+    let remote = config.rootfs_url.as_deref().map_or(true, |x| {
+        x.starts_with("https://") || x.starts_with("http://")
+    });
+    if remote {
+        ensure_network_allowed(options.online)?;
     }
-    extract_system_tarball(&PathBuf::from(path), total)?;
+    let configured = config.rootfs_url.clone();
+    let mirrors = match configured.as_deref() {
+        _ if !options.fastest_mirror && !config.fastest_mirror => Vec::new(),
+        Some(url) if !is_mirror_url(url) => {
+            warn!("rootfs-url is not a mirror, the fastest mirror will not be used.");
+            Vec::new()
+        }
+        configured => {
+            let mut candidates = configured.into_iter().map(String::from).collect::<Vec<_>>();
+            for mirror in RELEASE_MIRRORS {
+                if !candidates.iter().any(|x| x == mirror) {
+                    candidates.push(mirror.to_string());
+                }
+            }
+            ranked_mirrors(candidates)?
+        }
+    };
+    if let Some(fastest) = mirrors.first() {
+        config.rootfs_url = Some(fastest.clone());
+    }
+    let (url, sha256) = resolve_rootfs_source(&config, selection)?;
+    ensure_no_instance_mounted()?;
+    let mut result = load_os_from(&url, sha256.clone(), options);
+    // fail over to the other mirrors in the order of the ranking
+    if let Some(path) = mirrors.first().and_then(|x| url.strip_prefix(x.as_str())) {
+        for (failed, mirror) in mirrors.iter().zip(mirrors.iter().skip(1)) {
+            match &result {
+                Ok(_) => break,
+                Err(e) => warn!(
+                    "Unable to load the OS from {}: {:#}. Trying the next mirror {} ...",
+                    failed, e, mirror
+                ),
+            }
+            result = load_os_from(&format!("{}{}", mirror, path), sha256.clone(), options);
+        }
+    }
+    result.map_err(|e| match &configured {
+        Some(configured) => anyhow!(
+            "Unable to load the OS from {} (rootfs-url = {}): {:#}",
+            url,
+            configured,
+            e
+        ),
+        None => anyhow!("Unable to load the OS from the default mirror: {:#}", e),
+    })
+}
+
+/// Check if the network access is allowed by the configuration (`isolate-network`).
+/// If `online` is set, the network access is allowed temporarily for the containers
+/// started by this process (see [GlobalOptions::online])
+pub fn ensure_network_allowed(online: bool) -> Result<()> {
+    if !config::read_config().map_or(false, |c| c.isolate_network) {
+        return Ok(());
+    }
+    if !online {
+        return Err(anyhow!(
+            "Network isolation is enabled (isolate-network). Use `--online` to allow network access for this command."
+        ));
+    }
+    warn!("Network isolation is enabled, but network access is allowed for this command.");
 
     Ok(())
 }
@@ -152,8 +759,9 @@ pub fn load_os(url: &str, sha256: Option<String>) -> Result<()> {
 pub fn config_os(instance: Option<&str>) -> Result<()> {
     let config;
     let mut prev_volatile = None;
-    if let Ok(c) = config::read_config() {
-        prev_volatile = Some(c.volatile_mount);
+    if let Ok(c) = config::read_config_raw() {
+        prev_volatile =
+            Some(instance.map_or(c.volatile_mount, |i| c.for_instance(i).volatile_mount));
         config = config::ask_for_config(Some(c));
     } else {
         config = config::ask_for_config(None);
@@ -166,36 +774,34 @@ pub fn config_os(instance: Option<&str>) -> Result<()> {
         path = PathBuf::from(CIEL_DIST_DIR);
     }
     if let Ok(c) = config {
-        info!("Shutting down instance(s) before applying config...");
-        if let Some(instance) = instance {
-            container_down(instance)?;
-        } else {
-            for_each_instance(&container_down)?;
-        }
-        config::apply_config(path, &c)?;
-        fs::create_dir_all(CIEL_DATA_DIR)?;
-        fs::write(
-            Path::new(CIEL_DATA_DIR).join("config.toml"),
-            c.save_config()?,
-        )?;
-        info!("Configurations applied.");
         let volatile_changed = if let Some(prev_voltile) = prev_volatile {
-            prev_voltile != c.volatile_mount
+            prev_voltile != instance.map_or(c.volatile_mount, |i| c.for_instance(i).volatile_mount)
         } else {
             false
         };
+        // the base system must not change underneath the mounted instances
+        let downed = match instance {
+            Some(_) => DownedInstances::default(),
+            None => take_down_instances("Applying the configuration", false)?,
+        };
+        // the volatile mode only takes effect after re-mounting
+        if let (true, Some(instance)) = (volatile_changed, instance) {
+            info!("Shutting down instance(s) before applying config...");
+            container_down(instance)?;
+        }
+        config::apply_config(path, &c, instance)?;
+        config::write_config(&c)?;
+        info!("Configurations applied.");
         if volatile_changed {
             warn!("You have changed the volatile mount option, please save your work and\x1b[1m\x1b[93m rollback \x1b[4mall the instances\x1b[0m.");
-            return Ok(());
+        } else {
+            let instances = match instance {
+                Some(instance) => vec![instance.to_owned()],
+                None => machine::list_instances_simple()?,
+            };
+            apply_config_to_instances(&c, &instances)?;
         }
-        warn!(
-            "Please rollback {} for the new config to take effect!",
-            if let Some(inst) = instance {
-                inst
-            } else {
-                "all your instances"
-            }
-        );
+        restore_instances(&downed, false)?;
     } else {
         return Err(anyhow!("Could not recognize the configuration."));
     }
@@ -203,135 +809,1335 @@ pub fn config_os(instance: Option<&str>) -> Result<()> {
     Ok(())
 }
 
-/// Mount the filesystem of the instance
-pub fn mount_fs(instance: &str) -> Result<()> {
-    let config = config::read_config()?;
-    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
-    man.set_volatile(config.volatile_mount)?;
-    machine::mount_layers(man, instance)?;
-    info!("{}: filesystem mounted.", instance);
+/// Apply the workspace configuration to the base system or the instance without asking,
+/// the changes are only printed if `dry_run` is set
+pub fn apply_config_os(instance: Option<&str>, dry_run: bool) -> Result<()> {
+    let c = config::read_config_raw()?;
+    let path = match instance {
+        Some(instance) => overlayfs::get_overlayfs_manager(instance)?.get_config_layer()?,
+        None => PathBuf::from(CIEL_DIST_DIR),
+    };
+    if dry_run {
+        let changes = match instance {
+            Some(instance) => config::apply_instance_config_plan(&path, &c, instance)?,
+            None => config::apply_config_plan(&path, &c)?,
+        };
+        config::print_config_plan(&path, &changes);
+        return Ok(());
+    }
+    info!("Shutting down instance(s) before applying config...");
+    if let Some(instance) = instance {
+        container_down(instance)?;
+    } else {
+        for_each_instance(&container_down)?;
+    }
+    config::apply_config(&path, &c, instance)?;
+    info!("Configurations applied.");
+    warn!(
+        "Please rollback {} for the new config to take effect!",
+        instance.unwrap_or("all your instances")
+    );
 
     Ok(())
 }
 
-/// Un-mount the filesystem of the container
-pub fn unmount_fs(instance: &str) -> Result<()> {
-    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
-    let target = std::env::current_dir()?.join(instance);
-    let mut retry = 0usize;
-    while man.is_mounted(&target)? {
-        retry += 1;
-        if retry > 10 {
-            return Err(anyhow!("Unable to unmount filesystem after 10 attempts."));
-        }
-        man.unmount(&target)?;
+/// Ask for the configuration values introduced by newer versions of ciel,
+/// then apply the upgraded configuration to the base system after the confirmation
+pub fn upgrade_config_os() -> Result<()> {
+    let old = config::read_config_raw()?;
+    if old.defaulted_keys().is_empty() {
+        info!("The configuration is up to date, nothing to upgrade.");
+        return Ok(());
     }
-    info!("{}: filesystem un-mounted.", instance);
+    let c = config::upgrade_interactive(old)?;
+    config::write_config(&c)?;
+    info!("Configuration file has been upgraded.");
+    if !user_attended() {
+        info!("Run `ciel config apply` to apply the configuration to the base system.");
+        return Ok(());
+    }
+    let apply = Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Apply the configuration to the base system now?")
+        .default(true)
+        .interact()?;
+    if !apply {
+        return Ok(());
+    }
+    info!("Shutting down instance(s) before applying config...");
+    for_each_instance(&container_down)?;
+    config::apply_config(CIEL_DIST_DIR, &c, None)?;
+    info!("Configurations applied.");
+    warn!("Please rollback all your instances for the new config to take effect!");
 
     Ok(())
 }
 
-/// Remove the mount point (usually a directory) of the container overlay filesystem
-pub fn remove_mount(instance: &str) -> Result<()> {
-    let target = std::env::current_dir()?.join(instance);
-    if !target.exists() {
-        return Ok(());
-    } else if !target.is_dir() {
-        warn!("{}: mount point is not a directory.", instance);
-        return Ok(());
-    }
-    match fs::read_dir(&target) {
-        Ok(mut entry) => {
-            if entry.any(|_| true) {
-                warn!(
-                    "Mount point {:?} still contains files, so it will not be removed.",
-                    target
-                );
-                return Ok(());
-            }
-        }
-        Err(e) => {
-            error!("Error when querying {:?}: {}", target, e);
+/// Export the configuration and the metadata of the workspace to the file (or stdout)
+pub fn export_config(file: Option<&str>, include_secrets: bool) -> Result<()> {
+    let c = config::read_config_raw()?;
+    let repo = Repository::open("TREE").ok();
+    let tree_url = repo
+        .as_ref()
+        .and_then(|repo| repo.find_remote("origin").ok())
+        .and_then(|remote| remote.url().map(|x| x.to_owned()));
+    let metadata = config::WorkspaceMetadata {
+        ciel_version: env!("CARGO_PKG_VERSION").to_owned(),
+        tree_url,
+        tree_branch: get_branch_name().ok(),
+        instances: machine::list_instances_simple().unwrap_or_default(),
+    };
+    let data = config::export_config(&c, metadata, include_secrets)?;
+    match file {
+        Some(file) => {
+            fs::write(file, data)?;
+            info!("Configuration exported to {}.", file);
         }
+        None => print!("{}", data),
     }
-    fs::remove_dir(target)?;
-    info!("{}: mount point removed.", instance);
 
     Ok(())
 }
 
-fn get_instance_ns_name(instance: &str) -> Result<String> {
-    if !is_instance_exists(instance) {
-        error!("Instance `{}` does not exist.", instance);
+/// Import the configuration exported by [export_config] into the current workspace and
+/// apply it to the base system, the changes are shown before importing.
+/// Running instances are stopped only if `force` is set
+pub fn import_config(file: &str, force: bool) -> Result<()> {
+    let data = fs::read_to_string(file).map_err(|e| anyhow!("Unable to read {}: {}", file, e))?;
+    let (c, metadata) = config::import_config(&data)?;
+    info!("Configuration exported by ciel {}.", metadata.ciel_version);
+    if let Some(tree_url) = &metadata.tree_url {
         info!(
-            "You can add a new instance like this: `ciel add {}`",
-            instance
+            "Tree: {} ({})",
+            tree_url,
+            metadata.tree_branch.as_deref().unwrap_or("unknown branch")
         );
-        return Err(anyhow!("Unable to acquire container information."));
     }
-    let legacy = is_legacy_workspace()?;
+    if !metadata.instances.is_empty() {
+        info!("Instances: {}", metadata.instances.join(", "));
+    }
+    let mut running = Vec::new();
+    for instance in machine::list_instances_simple()? {
+        let ns_name = get_instance_ns_name(&instance)?;
+        if inspect_instance(&instance, &ns_name)?.started {
+            running.push(instance);
+        }
+    }
+    if !running.is_empty() && !force {
+        return Err(anyhow!(
+            "Instance(s) {} are running, importing the configuration would rewrite the files under them. Use --force to stop them and import anyway.",
+            running.join(", ")
+        ));
+    }
+    let file_change = config::config_file_plan(&c)?;
+    let changes = config::apply_config_plan(CIEL_DIST_DIR, &c)?;
+    if file_change.is_none() && changes.is_empty() {
+        info!("The configuration is identical to the current one, nothing to import.");
+        return Ok(());
+    }
+    if let Some(change) = &file_change {
+        change.print(Path::new("/"));
+    }
+    for change in changes.iter() {
+        change.print(Path::new(CIEL_DIST_DIR));
+    }
+    if user_attended()
+        && !Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Import the configuration?")
+            .default(true)
+            .interact()?
+    {
+        return Ok(());
+    }
+    info!("Shutting down instance(s) before applying config...");
+    for_each_instance(&container_down)?;
+    config::write_config(&c)?;
+    config::apply_config(CIEL_DIST_DIR, &c, None)?;
+    info!("Configuration imported.");
+    warn!("Please rollback all your instances for the new config to take effect!");
+
+    Ok(())
+}
+
+/// Returns the path to the marker requesting the configuration to be applied
+/// when the instance is mounted next time
+fn pending_config_marker(instance: &str) -> PathBuf {
+    Path::new(CIEL_INST_DIR)
+        .join(instance)
+        .join(PENDING_CONFIG_MARKER)
+}
+
+/// Apply the configuration files to the instance, returns `false` if the instance is not mounted
+/// (the configuration is then applied when the instance is mounted next time)
+fn apply_config_to_instance(config: &config::CielConfig, instance: &str) -> Result<bool> {
+    let inst = inspect_instance(instance, &get_instance_ns_name(instance)?)?;
+    if !inst.mounted {
+        fs::write(pending_config_marker(instance), b"")?;
+        return Ok(false);
+    }
+    // the files are written into the upper layer of the instance
+    config::apply_config(instance, config, Some(instance))?;
+    fs::remove_file(pending_config_marker(instance)).ok();
+
+    Ok(true)
+}
+
+/// Apply the configuration files (e.g. APT sources and ab3cfg.sh) to the instances without
+/// rolling them back, the other changes in the instances are preserved.
+/// Volatile instances are skipped since the changes are going to be discarded anyway
+pub fn apply_config_to_instances(config: &config::CielConfig, instances: &[String]) -> Result<()> {
+    let mut failed = Vec::new();
+    for instance in instances {
+        if config.for_instance(instance).volatile_mount {
+            fs::remove_file(pending_config_marker(instance)).ok();
+            info!(
+                "{}: skipped, the changes to a volatile instance would be discarded anyway.",
+                instance
+            );
+            continue;
+        }
+        match apply_config_to_instance(config, instance) {
+            Ok(true) => info!("{}: configuration applied.", instance),
+            Ok(false) => info!(
+                "{}: configuration will be applied when the instance is mounted.",
+                instance
+            ),
+            Err(e) => {
+                error!("{}: failed to apply the configuration: {}", instance, e);
+                failed.push(instance.as_str());
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(anyhow!(
+            "Failed to apply the configuration to: {}",
+            failed.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Write the generated files into the base system, unless it is not loaded yet
+/// (`ciel load-os` applies the configuration then)
+pub(crate) fn apply_config_to_base(dist: &Path, config: &config::CielConfig) -> Result<()> {
+    if fs::read_dir(dist).map_or(true, |mut x| x.next().is_none()) {
+        return Ok(());
+    }
+
+    config::apply_config(dist, config, None)
+}
+
+/// Apply the configuration just changed (e.g. by `ciel config set`) to the instances affected
+/// by the key, without rolling them back. The global keys are applied to the base system as
+/// well, otherwise rolling the instances back would bring the old values back
+pub fn propagate_config_value(key: &str) -> Result<()> {
+    match config::generated_files_scope(key) {
+        None => Ok(()),
+        Some(Some(instance)) if is_instance_exists(&instance) => {
+            apply_config_to_instances(&config::read_config_raw()?, &[instance])
+        }
+        Some(Some(_)) => Ok(()),
+        Some(None) => {
+            let config = config::read_config_raw()?;
+            // the base system must not change underneath the mounted instances
+            let downed = match take_down_instances("Applying the configuration", false) {
+                Ok(downed) => {
+                    apply_config_to_base(Path::new(CIEL_DIST_DIR), &config)?;
+                    Some(downed)
+                }
+                Err(e) => {
+                    warn!("{}", e);
+                    warn!("The base system still has the previous value, which comes back when the instances are rolled back. Run `ciel config apply` to apply it to the base system.");
+                    None
+                }
+            };
+            let applied = apply_config_to_instances(&config, &machine::list_instances_simple()?);
+            if let Some(downed) = downed {
+                restore_instances(&downed, false)?;
+            }
+
+            applied
+        }
+    }
+}
+
+/// Returns if the instance is to be mounted in the read-only mode
+fn wants_read_only(instance: &str) -> bool {
+    global_options().read_only
+        || config::read_config().map_or(false, |c| c.for_instance(instance).read_only)
+}
+
+/// Mount the filesystem of the instance
+pub fn mount_fs(instance: &str) -> Result<()> {
+    let config = config::read_config()?;
+    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
+    let read_only = wants_read_only(instance);
+    man.set_volatile(config.for_instance(instance).volatile_mount)?;
+    man.set_read_only(read_only)?;
+    machine::mount_layers(man, instance)?;
+    workspace::record_root()?;
+    if read_only {
+        info!(
+            "{}: filesystem mounted in the read-only mode, the changes are discarded when the instance stops.",
+            instance
+        );
+        // the configuration would be discarded along with the other changes
+        return Ok(());
+    }
+    info!("{}: filesystem mounted.", instance);
+    if pending_config_marker(instance).exists() {
+        let result = config::read_config_raw()
+            .and_then(|c| apply_config_to_instances(&c, &[instance.to_owned()]));
+        if let Err(e) = result {
+            warn!(
+                "{}: unable to apply the pending configuration: {}",
+                instance, e
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Clean up after the workspace is moved from `old_root`: stop the instances registered with
+/// the old location, un-mount their filesystems and update the paths stored in the configuration
+pub fn relocate_workspace(old_root: &Path) -> Result<()> {
+    let new_root = std::env::current_dir()?;
+    // the workspace is copied instead of moved if the old one still exists, leave it alone
+    if old_root.join(".ciel").is_dir() {
+        // the copy gets its own machine names
+        workspace::reset_id()?;
+        for instance in machine::list_instances_simple()? {
+            metadata::clear_machine_name(&instance)?;
+        }
+    } else {
+        let legacy = is_legacy_workspace()?;
+        for instance in machine::list_instances_simple()? {
+            let ns_name = match machine::get_container_ns_name_at(old_root, &instance, legacy) {
+                Ok(ns_name) => ns_name,
+                Err(e) => {
+                    warn!(
+                        "{}: unable to find the instance started at the old location: {}",
+                        instance, e
+                    );
+                    continue;
+                }
+            };
+            let inst = inspect_instance(&instance, &ns_name)?;
+            if inst.started {
+                info!(
+                    "{}: stopping the instance started at the old location...",
+                    instance
+                );
+                machine::terminate_container_by_name(&ns_name)?;
+                machine::clean_child_process();
+            }
+            if inst.mounted {
+                unmount_fs(&instance)?;
+            }
+        }
+    }
+    let mut c = config::read_config_raw()?;
+    if c.relocate_paths(old_root, &new_root) {
+        config::write_config(&c)?;
+        info!("Paths in the configuration have been updated.");
+    }
+    workspace::record_root()?;
+    info!(
+        "Workspace has been relocated from {} to {}.",
+        old_root.display(),
+        new_root.display()
+    );
+
+    Ok(())
+}
+
+/// Set the per-instance volatile mode, the instance must not be mounted
+pub fn set_instance_volatile(instance: &str, volatile: bool) -> Result<()> {
+    let ns_name = get_instance_ns_name(instance)?;
+    let inst = inspect_instance(instance, &ns_name)?;
+    if inst.mounted {
+        return Err(anyhow!(
+            "{}: instance is mounted, please run `ciel down -i {}` before changing the volatile mode.",
+            instance,
+            instance
+        ));
+    }
+    let mut c = config::read_config_raw()?;
+    c.instances
+        .entry(instance.to_owned())
+        .or_default()
+        .volatile_mount = Some(volatile);
+    config::write_config(&c)?;
+    info!(
+        "{}: volatile mode {}.",
+        instance,
+        if volatile { "enabled" } else { "disabled" }
+    );
+
+    Ok(())
+}
+
+/// Un-mount the filesystem of the container
+pub fn unmount_fs(instance: &str) -> Result<()> {
+    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
+    let target = std::env::current_dir()?.join(instance);
+    unmount_layers(man, &target)?;
+    info!("{}: filesystem un-mounted.", instance);
+
+    Ok(())
+}
+
+fn unmount_layers(man: &mut dyn overlayfs::LayerManager, target: &Path) -> Result<()> {
+    let mut retry = 0usize;
+    while man.is_mounted(target)? {
+        retry += 1;
+        if retry > 10 {
+            return Err(anyhow!("Unable to unmount filesystem after 10 attempts."));
+        }
+        man.unmount(target)?;
+    }
+
+    Ok(())
+}
+
+/// Remove the mount point (usually a directory) of the container overlay filesystem
+pub fn remove_mount(instance: &str) -> Result<()> {
+    let target = std::env::current_dir()?.join(instance);
+    if !target.exists() {
+        return Ok(());
+    } else if !target.is_dir() {
+        warn!("{}: mount point is not a directory.", instance);
+        return Ok(());
+    }
+    match fs::read_dir(&target) {
+        Ok(mut entry) => {
+            if entry.any(|_| true) {
+                warn!(
+                    "Mount point {:?} still contains files, so it will not be removed.",
+                    target
+                );
+                return Ok(());
+            }
+        }
+        Err(e) => {
+            error!("Error when querying {:?}: {}", target, e);
+        }
+    }
+    fs::remove_dir(target)?;
+    info!("{}: mount point removed.", instance);
+
+    Ok(())
+}
+
+pub(super) fn get_instance_ns_name(instance: &str) -> Result<String> {
+    if !is_instance_exists(instance) {
+        error!("Instance `{}` does not exist.", instance);
+        info!(
+            "You can add a new instance like this: `ciel add {}`",
+            instance
+        );
+        return Err(anyhow!("Unable to acquire container information."));
+    }
+    let legacy = is_legacy_workspace()?;
 
     get_container_ns_name(instance, legacy)
 }
 
+/// Returns an error if the filesystem of any instance in the workspace is mounted
+fn ensure_no_instance_mounted() -> Result<()> {
+    for instance in machine::list_instances_simple()? {
+        if inspect_instance(&instance, &get_instance_ns_name(&instance)?)?.mounted {
+            return Err(anyhow!(
+                "Some instances are mounted, please run `ciel down` before loading the OS."
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// The instances taken down before modifying the base system, see [take_down_instances]
+#[derive(Debug, Default)]
+pub struct DownedInstances {
+    /// The instances which were mounted or booted
+    instances: Vec<String>,
+    /// The booted ones among them
+    started: Vec<String>,
+}
+
+/// Guard of the operations modifying the base system, which must not change underneath the
+/// mounted instances (whether or not their containers are booted). Refuses if any instance is
+/// in use, unless the user agrees to stop and un-mount them (without asking if `stop_all` is
+/// set). Returns the instances taken down, see [restore_instances]
+pub fn take_down_instances(operation: &str, stop_all: bool) -> Result<DownedInstances> {
+    let mut downed = DownedInstances::default();
+    for instance in machine::list_instances_simple()? {
+        let state = inspect_instance(&instance, &get_instance_ns_name(&instance)?)?;
+        if state.started {
+            downed.started.push(instance.clone());
+        }
+        if state.mounted || state.started {
+            downed.instances.push(instance);
+        }
+    }
+    if downed.instances.is_empty() {
+        return Ok(downed);
+    }
+    warn!(
+        "{} would modify the base system underneath the instances in use: {}",
+        operation,
+        downed.instances.join(", ")
+    );
+    let confirmed = stop_all
+        || (user_attended()
+            && Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt("Stop and un-mount them now?")
+                .default(false)
+                .interact()?);
+    if !confirmed {
+        return Err(anyhow!(
+            "Please stop and un-mount the instances (`ciel down`) first, or use `--stop-all` to do it automatically."
+        ));
+    }
+    for instance in &downed.instances {
+        container_down(instance)?;
+    }
+
+    Ok(downed)
+}
+
+/// Bring the instances taken down by [take_down_instances] back after the base system is
+/// modified: they are rolled back (their upper layers were made against the previous base
+/// system, so they are moved into the trash), mounted, and booted if they were. The user is
+/// asked unless `restore` is set
+pub fn restore_instances(downed: &DownedInstances, restore: bool) -> Result<()> {
+    if downed.instances.is_empty() {
+        return Ok(());
+    }
+    let restore = restore
+        || (user_attended()
+            && Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt(format!(
+                    "Roll back and mount the instances stopped before ({}) again? The uncommitted changes are moved into the trash",
+                    downed.instances.join(", ")
+                ))
+                .default(false)
+                .interact()?);
+    if !restore {
+        info!(
+            "The instances stopped before are left un-mounted: {}",
+            downed.instances.join(", ")
+        );
+        return Ok(());
+    }
+    for instance in &downed.instances {
+        rollback(instance, false)?;
+        mount_fs(instance)?;
+        if downed.started.contains(instance) {
+            start_container(instance)?;
+        }
+        info!("{}: instance restored.", instance);
+    }
+
+    Ok(())
+}
+
+/// Pre-flight check of the package manager state in the root filesystem, returns whether it has
+/// to be repaired with [dpkg::REPAIR_COMMAND] before the operation. The user is asked for the
+/// repair unless `auto_repair` is set, and the stale lock files are removed if it is repaired
+pub(crate) fn check_dpkg_state(root: &Path, auto_repair: bool) -> Result<bool> {
+    let state = dpkg::inspect(root)?;
+    if !state.held_locks.is_empty() {
+        return Err(anyhow!(
+            "The package manager in {} is being used by another process (holding {}), please wait for it to finish.",
+            root.display(),
+            state
+                .held_locks
+                .iter()
+                .map(|x| x.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    if !state.needs_repair() {
+        return Ok(false);
+    }
+    let repair = auto_repair
+        || {
+            warn!(
+            "The package manager in {} was interrupted, it needs to be repaired with `dpkg --configure -a`.",
+            root.display()
+        );
+            user_attended()
+                && Confirm::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Repair it now?")
+                    .default(true)
+                    .interact()?
+        };
+    if !repair {
+        return Err(dpkg::RepairNeeded {
+            root: root.to_owned(),
+            state,
+        }
+        .into());
+    }
+    info!("Repairing the package manager in {}...", root.display());
+    dpkg::clear_stale_locks(&state)?;
+
+    Ok(true)
+}
+
+/// Returns whether any instance in the workspace is running
+fn is_any_instance_started() -> Result<bool> {
+    for instance in machine::list_instances_simple()? {
+        if inspect_instance(&instance, &get_instance_ns_name(&instance)?)?.started {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Remove the partial files left by the interrupted downloads in the shared APT cache
+fn prune_apt_cache(cache: &Path) -> Result<()> {
+    for entry in fs::read_dir(cache.join("partial"))? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove all the packages in the shared APT cache
+pub fn clean_apt_cache() -> Result<()> {
+    let cache = Path::new(CIEL_APT_CACHE_DIR);
+    if !cache.is_dir() {
+        info!("The shared APT cache is empty.");
+        return Ok(());
+    }
+    if is_any_instance_started()? {
+        return Err(anyhow!(
+            "Some instances are running and may be using the shared APT cache, please stop them first."
+        ));
+    }
+    fs::remove_dir_all(cache)?;
+    info!("The shared APT cache has been emptied.");
+
+    Ok(())
+}
+
 /// Start the container/instance, also mounting the container filesystem prior to the action
 pub fn start_container(instance: &str) -> Result<String> {
+    boot_container(instance, true)
+}
+
+/// Start the container/instance like [start_container],
+/// but return as soon as nspawn is spawned if `wait` is false
+pub fn boot_container(instance: &str, wait: bool) -> Result<String> {
+    boot_container_with(instance, wait, &[])
+}
+
+/// Start the container/instance with the ad-hoc bind mounts, which are added to the running
+/// instance if it is already started
+fn boot_container_with(instance: &str, wait: bool, adhoc_mounts: &[MountSpec]) -> Result<String> {
+    for mount in adhoc_mounts {
+        mount.validate()?;
+    }
     let ns_name = get_instance_ns_name(instance)?;
-    let inst = inspect_instance(instance, &ns_name)?;
-    let (mut extra_options, mounts) = ensure_host_sanity()?;
-    if std::env::var("CIEL_OFFLINE").is_ok() {
+    let mut inst = inspect_instance(instance, &ns_name)?;
+    // another operation (e.g. a quick run, see [run_quick]) may be using the instance
+    let _lock = if inst.started {
+        None
+    } else {
+        let lock = metadata::lock_instance(instance, "boot")?;
+        // it may have been booted while waiting for the lock
+        inst = inspect_instance(instance, &ns_name)?;
+        Some(lock)
+    };
+    // recorded, so that the instance is found under the name it is started as even if the
+    // workspace ID changes while it is running
+    let ns_name = if inst.started || is_legacy_workspace()? {
+        ns_name
+    } else {
+        let ns_name = machine::workspace_machine_name(instance)?;
+        metadata::record_machine_name(instance, &ns_name)?;
+        ns_name
+    };
+    let NspawnOptions {
+        options: mut extra_options,
+        mounts,
+        inst_config,
+        hostname,
+    } = nspawn_options(instance, &inst)?;
+    // the one-shot limits (e.g. `ciel build --memory`) take precedence
+    let limits = inst_config
+        .as_ref()
+        .map(|c| c.limits.clone())
+        .unwrap_or_default()
+        .merge(&config::ResourceLimits::one_shot())
+        .properties()
+        .map_err(|e| anyhow!("{}: {}", instance, e))?;
+    if inst.mounted && wants_read_only(instance) && !overlayfs::is_read_only(instance)? {
+        if inst.started {
+            return Err(anyhow!(
+                "{}: instance is running, stop it (`ciel stop`) to boot it in the read-only mode.",
+                instance
+            ));
+        }
+        unmount_fs(instance)?;
+        inst.mounted = false;
+    }
+    if !inst.mounted {
+        mount_fs(instance)?;
+    }
+    if !inst.started {
+        // left over if the instance was not stopped by ciel
+        metadata::clear_adhoc_mounts(instance, Path::new(instance))?;
+        if let Some(hostname) = &hostname {
+            write_hostname(instance, hostname)?;
+        }
+    }
+    for mount in adhoc_mounts {
+        record_adhoc_mount(instance, mount)?;
+        if !inst.started {
+            extra_options.push(mount.to_nspawn_option());
+            continue;
+        }
+        machine::bind_mount(&ns_name, mount).map_err(|e| {
+            anyhow!(
+                "{}: unable to mount {} into the running instance: {}\nStop the instance (`ciel stop`) to mount it when the instance boots instead.",
+                instance,
+                mount,
+                e
+            )
+        })?;
+        info!("{}: mounted {}.", instance, mount);
+    }
+    if !inst.started {
+        if inst_config.map(|x| x.network) == Some(config::NetworkMode::Veth) {
+            enable_networkd(instance);
+        }
+        extra_options.extend(limits.iter().map(|x| format!("--property={}", x)));
+        spawn_container(&ns_name, instance, &extra_options, &mounts, wait)?;
+    } else if let Err(e) = machine::set_machine_properties(&ns_name, &limits) {
+        warn!("{}: unable to apply the resource limits: {:#}", instance, e);
+    }
+
+    Ok(ns_name)
+}
+
+/// Put the configured resource limits back onto the running instance, after the one-shot
+/// limits (e.g. `ciel build --memory`) are applied to it
+pub fn restore_resource_limits(instance: &str) -> Result<()> {
+    let ns_name = get_instance_ns_name(instance)?;
+    if !inspect_instance(instance, &ns_name)?.started {
+        // the limits are set again when the instance boots
+        return Ok(());
+    }
+    let limits = config::read_config()
+        .map(|c| c.for_instance(instance).limits)
+        .unwrap_or_default()
+        .properties()?;
+
+    machine::set_machine_properties(&ns_name, &limits)
+}
+
+/// Record the ad-hoc mount with the mount points it is going to create in the instance
+fn record_adhoc_mount(instance: &str, mount: &MountSpec) -> Result<()> {
+    metadata::record_adhoc_mount(
+        instance,
+        metadata::AdhocMount {
+            spec: mount.to_string(),
+            created: metadata::missing_paths(Path::new(instance), &mount.container),
+        },
+    )
+}
+
+/// How the instance is started, see [nspawn_options]
+struct NspawnOptions {
+    /// systemd-nspawn options, including the extra mounts and the network
+    options: Vec<String>,
+    /// Bind mounts (host and container paths) set up once the instance boots
+    mounts: Vec<(String, String)>,
+    inst_config: Option<config::InstanceConfig>,
+    /// Hostname of the instance, `None` if it is not valid
+    hostname: Option<String>,
+}
+
+fn nspawn_options(instance: &str, inst: &machine::CielInstance) -> Result<NspawnOptions> {
+    let (mut extra_options, mounts) = ensure_host_sanity(instance)?;
+    config::validate_nspawn_options(&extra_options).map_err(|e| {
+        anyhow!(
+            "{}: invalid nspawn-extra-options:\n  {}",
+            instance,
+            e.join("\n  ")
+        )
+    })?;
+    if let Ok(c) = config::read_config() {
+        for mount in c.extra_mounts.iter() {
+            mount.validate()?;
+            extra_options.push(mount.to_nspawn_option());
+        }
+        if let Some(mut mount) = c.ccache_mount() {
+            fs::create_dir_all(&mount.host)?;
+            mount.host = mount.host.canonicalize()?;
+            extra_options.push(mount.to_nspawn_option());
+        }
+        if let Some(mut mount) = c.apt_cache_mount() {
+            fs::create_dir_all(mount.host.join("partial"))?;
+            if !inst.started && !is_any_instance_started()? {
+                prune_apt_cache(&mount.host)?;
+            }
+            mount.host = mount.host.canonicalize()?;
+            extra_options.push(mount.to_nspawn_option());
+        }
+        if let Some(mut mount) = c.home_mount(instance) {
+            if !mount.host.is_dir() {
+                create_home(&mount.host)?;
+            }
+            mount.host = mount.host.canonicalize()?;
+            extra_options.push(mount.to_nspawn_option());
+        }
+    }
+    let isolate_network =
+        config::read_config().map_or(false, |c| c.isolate_network) && !global_options().online;
+    let inst_config = config::read_config().map(|c| c.for_instance(instance)).ok();
+    if isolate_network || std::env::var("CIEL_OFFLINE").is_ok() {
         // FIXME: does not work with current version of systemd
         // add the offline option (private-network means don't share the host network)
         extra_options.push("--private-network".to_string());
         info!("{}: network disconnected.", instance);
+    } else if let Some(inst_config) = &inst_config {
+        let options = inst_config
+            .network_options()
+            .map_err(|e| anyhow!("{}: {}", instance, e))?;
+        extra_options.extend(options);
+    }
+    if let Some(inst_config) = &inst_config {
+        let options = inst_config.private_users_options();
+        if !options.is_empty() {
+            check_private_users(instance)?;
+        }
+        // the ones in nspawn-extra-options take precedence
+        extra_options.splice(0..0, options);
+    }
+    let hostname =
+        inst_config
+            .as_ref()
+            .and_then(|c| match config::validate_hostname(&c.hostname) {
+                Ok(()) => Some(c.hostname.clone()),
+                Err(e) => {
+                    warn!(
+                        "{}: {:#}, set another one with `ciel config set instance.{}.hostname`.",
+                        instance, e, instance
+                    );
+                    None
+                }
+            });
+    if let Some(hostname) = &hostname {
+        // the one in nspawn-extra-options takes precedence
+        extra_options.insert(0, format!("--hostname={}", hostname));
+    }
+
+    Ok(NspawnOptions {
+        options: extra_options,
+        mounts,
+        inst_config,
+        hostname,
+    })
+}
+
+/// Parse the major and the minor version of Linux from its release, e.g. `6.1.0-aosc-main`
+fn parse_kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.split(|x: char| !x.is_ascii_digit());
+
+    Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
+/// Returns an error if the user namespaces (`private-users`) cannot be created on the host,
+/// and warns if the files in the instance are going to be shifted instead of mapped
+fn check_private_users(instance: &str) -> Result<()> {
+    let max_namespaces = fs::read_to_string("/proc/sys/user/max_user_namespaces")
+        .ok()
+        .and_then(|x| x.trim().parse::<u64>().ok());
+    if max_namespaces.unwrap_or(0) == 0 {
+        return Err(anyhow!(
+            "{}: private-users is set, but the user namespaces are not available on the host (the kernel is built without them, or user.max_user_namespaces is 0). Enable them or set private-users to off.",
+            instance
+        ));
+    }
+    let release = nix::sys::utsname::uname()?
+        .release()
+        .to_string_lossy()
+        .into_owned();
+    if parse_kernel_version(&release).map_or(false, |x| x < IDMAPPED_MOUNTS_KERNEL) {
+        warn!(
+            "{}: Linux {} has no idmapped mounts of overlayfs, the files in the instance are shifted to the users of the container instead (copying the base system into the instance) until it is committed.",
+            instance, release
+        );
+    }
+
+    Ok(())
+}
+
+/// Write the hostname into etc/hostname of the mounted instance, for the tools reading the file
+/// instead of asking the kernel
+fn write_hostname(instance: &str, hostname: &str) -> Result<()> {
+    let path = Path::new(instance).join("etc/hostname");
+    let content = format!("{}\n", hostname);
+    // a symbolic link would be followed on the host
+    if fs::symlink_metadata(&path).map_or(false, |x| x.file_type().is_symlink()) {
+        fs::remove_file(&path)?;
+    } else if fs::read_to_string(&path).ok().as_deref() == Some(content.as_str()) {
+        return Ok(());
+    }
+    fs::write(&path, content)?;
+
+    Ok(())
+}
+
+/// Create the persisted home directory of the instance (`persist-home`) with the content of
+/// `/root` in the base system, so that the shell starts with the usual dotfiles
+fn create_home(path: &Path) -> Result<()> {
+    let staging = path.with_extension("incomplete");
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    let base = Path::new(CIEL_DIST_DIR).join("root");
+    if base.is_dir() {
+        let output = Command::new("cp")
+            .args(["-a", "--"])
+            .arg(&base)
+            .arg(&staging)
+            .output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Unable to copy {} into the home directory: {}",
+                base.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+    } else {
+        fs::create_dir(&staging)?;
+    }
+    fs::rename(&staging, path)?;
+
+    Ok(())
+}
+
+/// Execute the command without booting the instance: systemd-nspawn runs it as PID 2 on the
+/// filesystem of the instance, which is un-mounted afterwards if it was not mounted before.
+/// The command is run as usual if the instance is already started
+fn run_quick<S: AsRef<OsStr>>(instance: &str, args: &[S], options: &CommandOptions) -> Result<i32> {
+    for mount in &options.mounts {
+        mount.validate()?;
+    }
+    let ns_name = get_instance_ns_name(instance)?;
+    // only one quick run (or boot) at a time, they would share the mount
+    let lock = metadata::lock_instance(instance, "quick run")?;
+    let inst = inspect_instance(instance, &ns_name)?;
+    if inst.started {
+        drop(lock);
+        let options = CommandOptions {
+            quick: false,
+            ..options.clone()
+        };
+        return run_in_container_with(instance, args, &options);
+    }
+    let NspawnOptions {
+        options: mut nspawn_options,
+        mounts,
+        hostname,
+        ..
+    } = nspawn_options(instance, &inst)?;
+    for (host, container) in mounts {
+        let host = fs::canonicalize(host)?;
+        nspawn_options.push(format!("--bind={}:{}", host.display(), container));
     }
     if !inst.mounted {
         mount_fs(instance)?;
     }
-    if !inst.started {
-        spawn_container(&ns_name, instance, &extra_options, &mounts)?;
+    if let Some(hostname) = &hostname {
+        write_hostname(instance, hostname)?;
+    }
+    let result = run_quick_mounted(instance, args, options, nspawn_options);
+    if let Err(e) = metadata::clear_adhoc_mounts(instance, Path::new(instance)) {
+        warn!(
+            "{}: unable to remove the ad-hoc mount points: {:#}",
+            instance, e
+        );
+    }
+    if !inst.mounted {
+        unmount_fs(instance)?;
+        remove_mount(instance)?;
     }
 
-    Ok(ns_name)
+    result
+}
+
+fn run_quick_mounted<S: AsRef<OsStr>>(
+    instance: &str,
+    args: &[S],
+    options: &CommandOptions,
+    mut nspawn_options: Vec<String>,
+) -> Result<i32> {
+    let root = std::env::current_dir()?.join(instance);
+    if let Some(workdir) = &options.workdir {
+        if !root.join(workdir.trim_start_matches('/')).is_dir() {
+            return Err(anyhow!(
+                "{}: working directory {} does not exist in the instance.",
+                instance,
+                workdir
+            ));
+        }
+    }
+    metadata::clear_adhoc_mounts(instance, Path::new(instance))?;
+    for mount in &options.mounts {
+        record_adhoc_mount(instance, mount)?;
+        nspawn_options.push(mount.to_nspawn_option());
+    }
+    let _activity = metadata::begin_activity(instance)?;
+    let env = config::read_config()
+        .map(|c| c.container_environment())
+        .unwrap_or_default();
+    let mut options = options.clone();
+    options.log = options.log.or_else(|| command_log::log_path(instance));
+    let result = machine::execute_quick_command(&root, &nspawn_options, args, &env, &options);
+    let (status, _) = check_command_log(instance, options.log.as_deref(), result.map(|x| (x, ())))?;
+
+    Ok(status)
+}
+
+/// Enable systemd-networkd in the instance, which configures the veth link to the host
+/// (`host0`) with the default network file shipped with systemd
+fn enable_networkd(instance: &str) {
+    let root = Path::new(instance);
+    if !root.join(NETWORKD_UNIT.trim_start_matches('/')).exists() {
+        warn!(
+            "{}: systemd-networkd is not installed, the veth link will not be configured.",
+            instance
+        );
+        return;
+    }
+    let link = root.join(NETWORKD_WANTS_LINK);
+    if link.symlink_metadata().is_ok() {
+        return;
+    }
+    let result = link
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| std::os::unix::fs::symlink(NETWORKD_UNIT, &link));
+    if let Err(e) = result {
+        warn!("{}: unable to enable systemd-networkd: {}", instance, e);
+    }
+}
+
+/// Returns the host to be resolved in the instance before accessing the repositories, `None`
+/// if the wait for the network is skipped: the network is isolated, the instance has no network,
+/// no repository is remote, or the network of the host is shared and already online
+fn network_probe_host<F: Fn(&str) -> bool>(
+    isolated: bool,
+    network: config::NetworkMode,
+    uris: &[String],
+    online_on_host: F,
+) -> Option<String> {
+    if isolated || network == config::NetworkMode::None {
+        return None;
+    }
+    let host = uris.iter().find_map(|x| url_host(x))?;
+    if network == config::NetworkMode::Host && online_on_host(host) {
+        return None;
+    }
+
+    Some(host.to_owned())
+}
+
+/// Returns if the host name can be resolved on the host
+fn resolves_on_host(host: &str) -> bool {
+    use std::net::ToSocketAddrs;
+
+    (host, 0)
+        .to_socket_addrs()
+        .map_or(false, |mut x| x.next().is_some())
+}
+
+/// Wait for the network in the booted instance before running the commands accessing the
+/// repositories (e.g. apt and acbs), up to `network-timeout`
+pub(crate) fn wait_for_network(instance: &str) -> Result<()> {
+    let timeout = machine::network_timeout();
+    if timeout.is_zero() {
+        return Ok(());
+    }
+    let conf = config::read_config().unwrap_or_default();
+    let isolated =
+        (conf.isolate_network && !global_options().online) || std::env::var("CIEL_OFFLINE").is_ok();
+    let inst_config = conf.for_instance(instance);
+    let host = match network_probe_host(
+        isolated,
+        inst_config.network,
+        &inst_config.apt_source_uris(),
+        resolves_on_host,
+    ) {
+        Some(host) => host,
+        None => return Ok(()),
+    };
+    let ns_name = get_instance_ns_name(instance)?;
+
+    machine::wait_for_network(&ns_name, &host, timeout)
+        .map_err(|e| anyhow!("{}: {:#}", instance, e))
 }
 
 /// Execute the specified command in the container
 pub fn run_in_container<S: AsRef<OsStr>>(instance: &str, args: &[S]) -> Result<i32> {
-    let ns_name = start_container(instance)?;
-    let status = machine::execute_container_command(&ns_name, args)?;
+    run_in_container_with(instance, args, &CommandOptions::default())
+}
+
+/// Execute the specified command in the container with the options
+pub fn run_in_container_with<S: AsRef<OsStr>>(
+    instance: &str,
+    args: &[S],
+    options: &CommandOptions,
+) -> Result<i32> {
+    if options.quick {
+        return run_quick(instance, args, options);
+    }
+    let ns_name = boot_container_with(instance, true, &options.mounts)?;
+    if let Some(workdir) = &options.workdir {
+        if !machine::is_dir_in_container(&ns_name, workdir)? {
+            return Err(anyhow!(
+                "{}: working directory {} does not exist in the instance.",
+                instance,
+                workdir
+            ));
+        }
+    }
+    let _activity = metadata::begin_activity(instance)?;
+    let env = config::read_config()
+        .map(|c| c.container_environment())
+        .unwrap_or_default();
+    let mut options = options.clone();
+    options.log = options.log.or_else(|| command_log::log_path(instance));
+    let result = machine::execute_container_command(&ns_name, args, &env, &options);
+    let (status, _) = check_command_log(instance, options.log.as_deref(), result.map(|x| (x, ())))?;
 
     Ok(status)
 }
 
+/// Mention where the output of the command is saved if it fails
+fn check_command_log<T>(
+    instance: &str,
+    log: Option<&Path>,
+    result: Result<(i32, T)>,
+) -> Result<(i32, T)> {
+    let log = match log {
+        Some(log) => log,
+        None => return result,
+    };
+    match result {
+        Ok((status, output)) => {
+            if status != 0 {
+                warn!(
+                    "{}: the command exited with status {}, see {} for the output.",
+                    instance,
+                    status,
+                    log.display()
+                );
+            }
+            Ok((status, output))
+        }
+        Err(e) => Err(anyhow!("{:#} (see {} for the output)", e, log.display())),
+    }
+}
+
+/// Run the command in the container like [run_in_container], the output is also captured
+/// and returned along with the exit code
+pub fn run_in_container_captured<S: AsRef<OsStr>>(
+    instance: &str,
+    args: &[S],
+) -> Result<(i32, String)> {
+    let ns_name = start_container(instance)?;
+    let _activity = metadata::begin_activity(instance)?;
+    let env = config::read_config()
+        .map(|c| c.container_environment())
+        .unwrap_or_default();
+    let options = CommandOptions {
+        log: command_log::log_path(instance),
+        ..Default::default()
+    };
+    let result = machine::execute_container_command_captured(&ns_name, args, &env, &options);
+
+    check_command_log(instance, options.log.as_deref(), result)
+}
+
+/// Attach to the running instance: an interactive shell, or following its console output if
+/// `console` is set. Unlike `ciel shell`, the instance is neither started nor locked, so this
+/// works alongside a running build. Returns the exit code of the shell
+pub fn attach_container(instance: &str, console: bool) -> Result<i32> {
+    ensure_instance_exists(instance)?;
+    let ns_name = get_instance_ns_name(instance)?;
+    let inst = inspect_instance(instance, &ns_name)?;
+    // the console output is also interesting while the container is still booting
+    if !inst.started || !(console || inst.running) {
+        return Err(anyhow!(
+            "{}: instance is not booted, start it with `ciel boot -i {}` first.",
+            instance,
+            instance
+        ));
+    }
+    if console {
+        machine::follow_console(instance, &ns_name)?;
+        return Ok(0);
+    }
+
+    machine::attach_shell(&ns_name)
+}
+
 /// Stop the container/instance (without un-mounting the filesystem)
 pub fn stop_container(instance: &str) -> Result<()> {
+    stop_container_with(instance, &StopOptions::default())
+}
+
+/// Stop the container/instance, escalating to killing it if it does not power off in time
+pub fn stop_container_with(instance: &str, options: &StopOptions) -> Result<()> {
     let ns_name = get_instance_ns_name(instance)?;
+    let _lock = metadata::lock_instance(instance, "stop")?;
     let inst = inspect_instance(instance, &ns_name)?;
     if !inst.started {
         info!("{}: instance is not running!", instance);
         return Ok(());
     }
     info!("{}: stopping...", instance);
-    machine::terminate_container_by_name(&ns_name)?;
-    machine::clean_child_process();
-    info!("{}: instance stopped.", instance);
+    let level = stop_started(instance, &ns_name, options)?;
+    if level > StopLevel::Poweroff && !options.force {
+        warn!(
+            "{}: instance did not power off cleanly, it was {}.",
+            instance, level
+        );
+    } else {
+        info!("{}: instance stopped ({}).", instance, level);
+    }
 
     Ok(())
 }
 
+/// Stop the machine of the started instance and forget about it, the caller holds the lock
+fn stop_started(instance: &str, ns_name: &str, options: &StopOptions) -> Result<StopLevel> {
+    let level = machine::stop_machine(ns_name, options)?;
+    machine::clean_child_process();
+    if let Err(e) = metadata::clear_machine_name(instance) {
+        warn!("{}: unable to forget the machine name: {}", instance, e);
+    }
+    // the mount points of the ad-hoc mounts are not meant to be kept (or committed)
+    if let Err(e) = metadata::clear_adhoc_mounts(instance, Path::new(instance)) {
+        warn!(
+            "{}: unable to remove the ad-hoc mount points: {:#}",
+            instance, e
+        );
+    }
+    // the changes made in the read-only mode are discarded along with the machine
+    if overlayfs::is_read_only(instance)? {
+        let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
+        unmount_layers(man, &std::env::current_dir()?.join(instance))?;
+        remove_mount(instance)?;
+    }
+
+    Ok(level)
+}
+
+/// Stop the instance like [stop_container_with] for [BulkAction], without any output
+fn stop_quietly(instance: &str, options: &StopOptions) -> Result<Outcome> {
+    let ns_name = get_instance_ns_name(instance)?;
+    let _lock = metadata::lock_instance(instance, "stop")?;
+    if !inspect_instance(instance, &ns_name)?.started {
+        return Ok(Outcome::Skipped("already stopped".to_owned()));
+    }
+    stop_started(instance, &ns_name, options)?;
+
+    Ok(Outcome::Done)
+}
+
+/// Un-mount the filesystem of the stopped instance and remove the mount point, without any
+/// output. Returns if the filesystem was mounted
+fn unmount_quietly(instance: &str) -> Result<bool> {
+    let _lock = metadata::lock_instance(instance, "down")?;
+    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
+    let target = std::env::current_dir()?.join(instance);
+    let mounted = man.is_mounted(&target)?;
+    unmount_layers(man, &target)?;
+    remove_mount(instance)?;
+
+    Ok(mounted)
+}
+
+/// Stop all the instances, at most `jobs` of them at the same time.
+/// A summary is printed at the end, returns an error if any of them failed
+pub fn stop_all(options: &StopOptions, jobs: usize) -> Result<()> {
+    let action = BulkAction {
+        done: "stopped",
+        jobs,
+    };
+    let instances = machine::list_instances_simple()?;
+    let outcomes = action.run("stopping", &instances, |instance| {
+        stop_quietly(instance, options)
+    })?;
+
+    action.summarize(&outcomes)
+}
+
+/// Stop and un-mount all the instances, at most `jobs` of them at the same time. The instances
+/// are powered off first, and then un-mounted once the machines are gone.
+/// A summary is printed at the end, returns an error if any of them failed
+pub fn down_all(jobs: usize) -> Result<()> {
+    let action = BulkAction { done: "down", jobs };
+    let instances = machine::list_instances_simple()?;
+    let outcomes = action.run("stopping", &instances, |instance| {
+        stop_quietly(instance, &StopOptions::default())
+    })?;
+    let outcomes = action.then("un-mounting", outcomes, |instance, stopped| {
+        let mounted = unmount_quietly(instance)?;
+        match stopped {
+            Outcome::Skipped(_) if !mounted => Ok(Outcome::Skipped("already down".to_owned())),
+            _ => Ok(Outcome::Done),
+        }
+    })?;
+
+    action.summarize(&outcomes)
+}
+
 /// Stop and un-mount the container and its filesystem
 pub fn container_down(instance: &str) -> Result<()> {
+    let _lock = metadata::lock_instance(instance, "down")?;
+    // the filesystem is only un-mounted once the machine is gone
     stop_container(instance)?;
-    unmount_fs(instance)?;
+    let target = std::env::current_dir()?.join(instance);
+    unmount_fs(instance).map_err(|e| {
+        anyhow!(
+            "{}: instance is stopped, but its filesystem could not be un-mounted: {:#}\nYou may need to run `umount {}` manually.",
+            instance,
+            e,
+            target.display()
+        )
+    })?;
     remove_mount(instance)?;
 
     Ok(())
 }
 
-/// Commit the container/instance upper layer changes to the base layer of the filesystem
-pub fn commit_container(instance: &str) -> Result<()> {
+/// Commit the container/instance upper layer changes to the base layer of the filesystem,
+/// showing the progress if `progress` is set
+pub fn commit_container(instance: &str, progress: bool) -> Result<()> {
+    let _lock = metadata::lock_instance(instance, "commit")?;
+    if overlayfs::is_read_only(instance)? {
+        return Err(anyhow!(
+            "{}: instance is in the read-only mode, stop it (`ciel stop`) before committing.",
+            instance
+        ));
+    }
     container_down(instance)?;
-    commit(instance)?;
+    commit(instance, progress)?;
     info!("{}: instance has been committed.", instance);
 
     Ok(())
@@ -339,24 +2145,290 @@ pub fn commit_container(instance: &str) -> Result<()> {
 
 /// Clear the upper layer of the container/instance filesystem
 pub fn rollback_container(instance: &str) -> Result<()> {
+    rollback_container_with(instance, true)
+}
+
+/// Clear the upper layer of the container/instance filesystem, it is moved into the trash
+/// (`ciel trash`) unless `purge` is set
+pub fn rollback_container_with(instance: &str, purge: bool) -> Result<()> {
+    let _lock = metadata::lock_instance(instance, "rollback")?;
     container_down(instance)?;
-    rollback(instance)?;
+    rollback(instance, purge)?;
     info!("{}: instance has been rolled back.", instance);
 
     Ok(())
 }
 
+/// Un-mount and roll back the instance, without any output
+fn rollback_unmounted(instance: &str, purge: bool) -> Result<()> {
+    let _lock = metadata::lock_instance(instance, "rollback")?;
+    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
+    let target = std::env::current_dir()?.join(instance);
+    unmount_layers(man, &target)?;
+    // like `remove_mount`, the mount point is only removed if it is empty
+    fs::remove_dir(&target).ok();
+    trash::discard_upper_layer(instance, man, purge)?;
+    reset_machine_id(man)?;
+
+    Ok(())
+}
+
+/// Roll back all the instances, at most `jobs` of them at the same time. The booted instances
+/// are stopped first if `stop` is set, otherwise they are skipped. The upper layers are moved
+/// into the trash unless `purge` is set.
+/// A summary is printed at the end, returns an error if any of them failed
+pub fn rollback_all(stop: bool, purge: bool, jobs: usize) -> Result<()> {
+    let action = BulkAction {
+        done: "rolled back",
+        jobs,
+    };
+    let instances = machine::list_instances_simple()?;
+    let outcomes = action.run("rolling back", &instances, |instance| {
+        if inspect_instance(instance, &get_instance_ns_name(instance)?)?.started {
+            if !stop {
+                return Ok(Outcome::Skipped(
+                    "instance is running (use `--stop` to stop it first)".to_owned(),
+                ));
+            }
+            stop_quietly(instance, &StopOptions::default())?;
+        }
+        rollback_unmounted(instance, purge)?;
+
+        Ok(Outcome::Done)
+    })?;
+    sync();
+    if !purge {
+        trash::prune_trash(None)?;
+    }
+
+    action.summarize(&outcomes)
+}
+
 /// Create a new instance
 #[inline]
-pub fn add_instance(instance: &str) -> Result<()> {
+pub fn add_instance(instance: &str, metadata: &InstanceMetadata) -> Result<()> {
+    validate_instance_name(instance)?;
+    if is_instance_exists(instance) {
+        return Err(anyhow!("Instance `{}` already exists.", instance));
+    }
     overlayfs::create_new_instance_fs(CIEL_INST_DIR, instance)?;
+    metadata::write_metadata(instance, metadata)?;
+    reset_machine_id(&mut *overlayfs::get_overlayfs_manager(instance)?)?;
     info!("{}: instance created.", instance);
 
     Ok(())
 }
 
+/// Returns an error listing the available instances if the instance does not exist
+pub(super) fn ensure_instance_exists(instance: &str) -> Result<()> {
+    if is_instance_exists(instance) {
+        return Ok(());
+    }
+    let mut instances = machine::list_instances_simple()?;
+    instances.sort();
+    if instances.is_empty() {
+        return Err(anyhow!(
+            "Instance `{}` does not exist, there are no instances in this workspace.",
+            instance
+        ));
+    }
+
+    Err(anyhow!(
+        "Instance `{}` does not exist, available instances: {}",
+        instance,
+        instances.join(", ")
+    ))
+}
+
+/// Change the description and the labels of the instance,
+/// or print them if nothing is to be changed
+pub fn describe_instance(
+    instance: &str,
+    description: Option<&str>,
+    labels: &[(String, String)],
+    unlabel: &[String],
+) -> Result<()> {
+    ensure_instance_exists(instance)?;
+    let mut metadata = metadata::read_metadata(instance)?;
+    if description.is_none() && labels.is_empty() && unlabel.is_empty() {
+        let created_at = metadata
+            .created_at
+            .map(format_time)
+            .unwrap_or_else(|| "unknown".to_owned());
+        println!("Name: {}", instance);
+        println!("Created at: {}", created_at);
+        println!(
+            "Description: {}",
+            metadata.description.as_deref().unwrap_or("")
+        );
+        println!("Labels: {}", metadata.labels_string());
+        return Ok(());
+    }
+    if let Some(description) = description {
+        metadata.description = Some(description.to_owned()).filter(|x| !x.is_empty());
+    }
+    for key in unlabel {
+        if metadata.labels.remove(key).is_none() {
+            warn!("{}: instance has no label `{}`.", instance, key);
+        }
+    }
+    for (key, value) in labels {
+        metadata.labels.insert(key.clone(), value.clone());
+    }
+    metadata::write_metadata(instance, &metadata)?;
+    info!("{}: metadata updated.", instance);
+
+    Ok(())
+}
+
+/// Rename the instance, including its layers and its per-instance configuration.
+/// The instance must be stopped and un-mounted
+pub fn rename_instance(instance: &str, new_name: &str) -> Result<()> {
+    ensure_instance_exists(instance)?;
+    validate_instance_name(new_name)?;
+    if is_instance_exists(new_name) {
+        return Err(anyhow!("Instance `{}` already exists.", new_name));
+    }
+    // the new instance is going to be mounted there
+    let mount_point = Path::new(new_name);
+    if mount_point.exists()
+        && !(mount_point.is_dir() && fs::read_dir(mount_point)?.next().is_none())
+    {
+        return Err(anyhow!(
+            "`{}` already exists in the workspace, please choose another name.",
+            new_name
+        ));
+    }
+    let _lock = metadata::lock_instance(instance, "rename")?;
+    let inst = inspect_instance(instance, &get_instance_ns_name(instance)?)?;
+    if inst.started || inst.mounted {
+        return Err(anyhow!(
+            "{}: instance is in use, please run `ciel down -i {}` before renaming it.",
+            instance,
+            instance
+        ));
+    }
+    // the per-instance options (e.g. branch-exclusive-output) follow the instance
+    let mut config = config::read_config_raw()?;
+    let overrides = config.instances.remove(instance);
+    let inst_dir = Path::new(CIEL_INST_DIR);
+    fs::rename(inst_dir.join(instance), inst_dir.join(new_name))?;
+    // left over if the instance was not stopped by ciel
+    metadata::clear_machine_name(new_name)?;
+    // the empty mount point left by the previous mounts
+    fs::remove_dir(instance).ok();
+    if let Some(overrides) = overrides {
+        config.instances.insert(new_name.to_owned(), overrides);
+        if let Err(e) = config::write_config(&config) {
+            // keep the configuration with the layers
+            fs::rename(inst_dir.join(new_name), inst_dir.join(instance)).ok();
+            return Err(e);
+        }
+    }
+    info!("{}: instance renamed to {}.", instance, new_name);
+
+    Ok(())
+}
+
+/// Copy the directory with `cp`, which preserves the whiteouts, the extended attributes and
+/// the hard links in the layers, and uses reflinks where the filesystem supports them
+fn copy_instance_dir(from: &Path, to: &Path) -> Result<()> {
+    let total = WalkDir::new(from).into_iter().count() as u64;
+    let progress_bar = indicatif::ProgressBar::new(total);
+    progress_bar.set_style(
+        indicatif::ProgressStyle::default_bar()
+            .template("{spinner} [{bar:25.cyan/blue}] Copying the instance... ({pos}/{len} files, eta {eta})")
+            .unwrap(),
+    );
+    progress_bar.set_draw_target(indicatif::ProgressDrawTarget::stderr_with_hz(5));
+    let mut child = Command::new("cp")
+        .args(["-a", "-v", "--reflink=auto", "--"])
+        .arg(from)
+        .arg(to)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("Unable to run cp: {}", e))?;
+    let mut stderr = child.stderr.take().unwrap();
+    let errors = std::thread::spawn(move || {
+        let mut errors = String::new();
+        stderr.read_to_string(&mut errors).ok();
+        errors
+    });
+    // cp prints a line for each file copied
+    for _ in BufReader::new(child.stdout.take().unwrap()).lines() {
+        progress_bar.inc(1);
+    }
+    let status = child.wait()?;
+    progress_bar.finish_and_clear();
+    let errors = errors.join().unwrap_or_default();
+    if !status.success() {
+        return Err(anyhow!(
+            "Unable to copy the instance: {}",
+            errors.lines().last().unwrap_or("cp failed")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Create a new instance with a copy of the layers of the instance (including the uncommitted
+/// changes) and its per-instance configuration. The instance must not be running
+pub fn clone_instance(instance: &str, new_name: &str) -> Result<()> {
+    ensure_instance_exists(instance)?;
+    validate_instance_name(new_name)?;
+    if is_instance_exists(new_name) {
+        return Err(anyhow!("Instance `{}` already exists.", new_name));
+    }
+    let _lock = metadata::lock_instance(instance, "clone")?;
+    let inst = inspect_instance(instance, &get_instance_ns_name(instance)?)?;
+    if inst.started {
+        return Err(anyhow!(
+            "{}: instance is running, please run `ciel stop -i {}` before cloning it.",
+            instance,
+            instance
+        ));
+    }
+    info!("{}: cloning into {}...", instance, new_name);
+    let staging = Path::new(CLONE_STAGING_DIR).join(new_name);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(CLONE_STAGING_DIR)?;
+    // the partial copy stays out of the instances if anything fails
+    let result =
+        copy_instance_dir(&Path::new(CIEL_INST_DIR).join(instance), &staging).and_then(|_| {
+            // the work directory is only meaningful to the mounted overlay
+            match fs::remove_dir_all(staging.join("layers/diff.tmp")) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+            fs::rename(&staging, Path::new(CIEL_INST_DIR).join(new_name))?;
+            Ok(())
+        });
+    if let Err(e) = result {
+        fs::remove_dir_all(&staging).ok();
+        return Err(e);
+    }
+    fs::remove_dir(CLONE_STAGING_DIR).ok();
+    // the clone is started as a machine of its own
+    metadata::clear_machine_name(new_name)?;
+    let mut metadata = metadata::read_metadata(new_name)?;
+    metadata.created_at = InstanceMetadata::new()?.created_at;
+    metadata::write_metadata(new_name, &metadata)?;
+    let mut config = config::read_config_raw()?;
+    if let Some(overrides) = config.instances.get(instance).cloned() {
+        config.instances.insert(new_name.to_owned(), overrides);
+        config::write_config(&config)?;
+    }
+    info!("{}: instance cloned from {}.", new_name, instance);
+
+    Ok(())
+}
+
 /// Remove the container/instance and its filesystem from the host filesystem
 pub fn remove_instance(instance: &str) -> Result<()> {
+    let _lock = metadata::lock_instance(instance, "remove")?;
     container_down(instance)?;
     info!("{}: removing instance...", instance);
     let spinner = create_spinner("Removing the instance...", 200);
@@ -368,17 +2440,206 @@ pub fn remove_instance(instance: &str) -> Result<()> {
     Ok(())
 }
 
+/// Remove all the instances, at most `jobs` of them at the same time. Asks for confirmation
+/// unless `yes` is set. A summary is printed at the end, returns an error if any of them failed
+pub fn remove_all(yes: bool, jobs: usize) -> Result<()> {
+    let instances = machine::list_instances_simple()?;
+    if instances.is_empty() {
+        info!("No instances.");
+        return Ok(());
+    }
+    let confirmed = yes
+        || (user_attended()
+            && Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt(format!(
+                    "Remove all the instances ({})?",
+                    instances.join(", ")
+                ))
+                .default(false)
+                .interact()?);
+    if !confirmed {
+        return Err(anyhow!(
+            "Not removing the instances, use `--yes` to remove them without asking."
+        ));
+    }
+    let action = BulkAction {
+        done: "removed",
+        jobs,
+    };
+    let outcomes = action.run("removing", &instances, |instance| {
+        let _lock = metadata::lock_instance(instance, "remove")?;
+        stop_quietly(instance, &StopOptions::default())?;
+        unmount_quietly(instance)?;
+        overlayfs::get_overlayfs_manager(instance)?.destroy()?;
+
+        Ok(Outcome::Done)
+    })?;
+
+    action.summarize(&outcomes)
+}
+
 /// Update AOSC OS in the container/instance
-pub fn update_os() -> Result<()> {
+pub fn update_os(options: &UpdateOptions) -> Result<Option<UpdateSummary>> {
+    let conf = config::read_config().unwrap_or_default();
+    conf.hooks.validate()?;
+    let manager = options.resolve_package_manager()?;
+    let repair = check_dpkg_state(Path::new(CIEL_DIST_DIR), options.auto_repair)?;
+    if is_any_instance_started()? {
+        warn!("Some instances are running, the base system would be modified underneath their mounted filesystems.");
+        if !options.force {
+            return Err(anyhow!(
+                "Please stop the instances (`ciel stop`) first, or use `--force` to update the OS anyway."
+            ));
+        }
+    }
+    let isolated = conf.isolate_network && !global_options().online;
+    if !isolated {
+        for uri in conf.apt_source_uris() {
+            ensure_reachable(&uri, "Updating the OS")?;
+        }
+    }
     info!("Updating base OS...");
     let instance = format!("update-{:x}", random::<u32>());
-    add_instance(&instance)?;
-    let status = run_in_container(&instance, &["/bin/bash", "-ec", UPDATE_SCRIPT])?;
+    let mut hook_env = vec![("CIEL_INSTANCE", instance.clone())];
+    run_pre_hook(
+        "pre-update-os",
+        conf.hooks.pre_update_os.as_deref(),
+        &hook_env,
+    )?;
+    let mut metadata = InstanceMetadata::new()?;
+    metadata.description = Some("Temporary instance of update-os".to_owned());
+    if let Err(e) = add_instance(&instance, &metadata) {
+        remove_instance(&instance).ok();
+        return Err(e);
+    }
+    let packages: &[&str] = if conf.use_ccache { &["ccache"] } else { &[] };
+    let mut script = update_script(options, manager, packages);
+    if repair {
+        script = format!("{};{}", dpkg::REPAIR_COMMAND, script);
+    }
+    // the upgrade runs in the booted temporary instance, so that the services needed by the
+    // maintainer scripts are available. The base system is only modified by the commit
+    let result = start_container(&instance)
+        .and_then(|_| wait_for_network(&instance))
+        .and_then(|_| run_in_container_captured(&instance, &["/bin/bash", "-ec", &script]))
+        .and_then(|(status, output)| {
+            if status == 0 {
+                commit_container(&instance, true)?;
+            }
+            Ok((status, output))
+        });
+    // the temporary instance is removed whether the update succeeded or not
+    if let Err(e) = remove_instance(&instance) {
+        warn!(
+            "Unable to remove the temporary instance {}: {:#}",
+            instance, e
+        );
+    }
+    let (status, output) = result?;
+    if status == 0 {
+        if let Err(e) = workspace::record_update() {
+            warn!("Unable to record the time of the update: {}", e);
+        }
+    }
+    hook_env.push(("CIEL_EXIT_CODE", status.to_string()));
+    run_post_hook(
+        "post-update-os",
+        conf.hooks.post_update_os.as_deref(),
+        &hook_env,
+    );
+    if status != 0 {
+        return Err(match package_manager_error(&output) {
+            Some(error) => anyhow!("Failed to update OS ({}): {}", status, error),
+            None => anyhow!("Failed to update OS: {}", status),
+        });
+    }
+
+    Ok(UpdateSummary::parse(&output))
+}
+
+/// Print the ccache statistics of the specified instance
+pub fn ccache_stats(instance: &str) -> Result<()> {
+    let config = config::read_config()?;
+    if !config.use_ccache {
+        return Err(anyhow!(
+            "ccache is not enabled, run `ciel config set use-ccache true` to enable it."
+        ));
+    }
+    let status = run_in_container(
+        instance,
+        &[
+            "/usr/bin/env",
+            &format!("CCACHE_DIR={}", config::CCACHE_CONTAINER_DIR),
+            "ccache",
+            "-s",
+        ],
+    )?;
     if status != 0 {
-        return Err(anyhow!("Failed to update OS: {}", status));
+        return Err(anyhow!("Failed to get ccache statistics: {}", status));
     }
-    commit_container(&instance)?;
-    remove_instance(&instance)?;
 
     Ok(())
 }
+
+#[test]
+fn test_network_probe_host() {
+    use config::NetworkMode;
+
+    let uris = vec![
+        "file:///debs".to_owned(),
+        "https://repo.aosc.io/debs/".to_owned(),
+    ];
+    let online = |_: &str| true;
+    let offline = |_: &str| false;
+    assert_eq!(
+        network_probe_host(false, NetworkMode::Veth, &uris, online),
+        Some("repo.aosc.io".to_owned())
+    );
+    assert_eq!(
+        network_probe_host(false, NetworkMode::Host, &uris, offline),
+        Some("repo.aosc.io".to_owned())
+    );
+    assert_eq!(
+        network_probe_host(false, NetworkMode::Host, &uris, online),
+        None
+    );
+    assert_eq!(
+        network_probe_host(true, NetworkMode::Veth, &uris, online),
+        None
+    );
+    assert_eq!(
+        network_probe_host(false, NetworkMode::None, &uris, offline),
+        None
+    );
+    assert_eq!(
+        network_probe_host(false, NetworkMode::Veth, &uris[..1], offline),
+        None
+    );
+}
+
+#[test]
+fn test_parse_kernel_version() {
+    assert_eq!(parse_kernel_version("6.1.0-aosc-main"), Some((6, 1)));
+    assert_eq!(parse_kernel_version("5.10.102"), Some((5, 10)));
+    assert_eq!(parse_kernel_version("4.19"), Some((4, 19)));
+    assert_eq!(parse_kernel_version("linux"), None);
+    assert!(parse_kernel_version("5.4.0").unwrap() < IDMAPPED_MOUNTS_KERNEL);
+    // idmapped mounts of ext4, but not of overlayfs
+    assert!(parse_kernel_version("5.15.0").unwrap() < IDMAPPED_MOUNTS_KERNEL);
+    assert!(parse_kernel_version("6.1.0").unwrap() >= IDMAPPED_MOUNTS_KERNEL);
+}
+
+#[test]
+fn test_tarball_arch() {
+    assert_eq!(
+        tarball_arch(
+            "https://releases.aosc.io/os-amd64/buildkit/aosc-os_buildkit_20230101_amd64.tar.xz"
+        ),
+        Some("amd64")
+    );
+    assert_eq!(
+        tarball_arch("/mnt/nfs/aosc-os_buildkit_20230101_loongarch64.tar.xz"),
+        Some("loongarch64")
+    );
+    assert_eq!(tarball_arch("/mnt/nfs/rootfs.tar.xz"), None);
+}
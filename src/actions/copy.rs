@@ -0,0 +1,310 @@
+//! Copying files between the host and the instances (`ciel cp`)
+
+use anyhow::{anyhow, Result};
+use console::style;
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    fs,
+    path::{Component, Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    info,
+    machine::{self, inspect_instance},
+    metadata, overlayfs, warn,
+};
+
+use super::{
+    container::{ensure_instance_exists, get_instance_ns_name},
+    mount_fs, remove_mount, unmount_fs,
+};
+
+/// Maximum number of the symbolic links followed when resolving a path in an instance
+const MAX_SYMLINKS: usize = 40;
+
+/// One side of `ciel cp`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CopyLocation {
+    Host(PathBuf),
+    /// An absolute path in the instance
+    Instance(String, PathBuf),
+}
+
+/// Parse `<instance>:<path>` as a path in the instance (relative to its root), anything else
+/// is a path on the host. The host paths containing `:` can be given as `./a:b`
+pub fn parse_copy_location(value: &str) -> CopyLocation {
+    match value.split_once(':') {
+        Some((instance, path)) if !instance.is_empty() && !instance.contains('/') => {
+            CopyLocation::Instance(instance.to_owned(), Path::new("/").join(path))
+        }
+        _ => CopyLocation::Host(PathBuf::from(value)),
+    }
+}
+
+/// Resolve the path in the instance as if `root` were `/`, following the symbolic links within
+/// the root. Returns the path on the host, or an error if the path leads outside of the root
+fn resolve_in_root(root: &Path, path: &Path) -> Result<PathBuf> {
+    let escaped = || {
+        anyhow!(
+            "{} leads outside of the instance, which is not allowed.",
+            path.display()
+        )
+    };
+    let mut resolved = PathBuf::new();
+    let mut pending = path
+        .components()
+        .map(|x| x.as_os_str().to_owned())
+        .collect::<VecDeque<OsString>>();
+    let mut symlinks = 0;
+    while let Some(component) = pending.pop_front() {
+        match Path::new(&component).components().next() {
+            Some(Component::RootDir) => resolved = PathBuf::new(),
+            Some(Component::ParentDir) => {
+                if !resolved.pop() {
+                    return Err(escaped());
+                }
+            }
+            Some(Component::Normal(name)) => {
+                let candidate = resolved.join(name);
+                let target = match fs::symlink_metadata(root.join(&candidate)) {
+                    Ok(x) if x.file_type().is_symlink() => fs::read_link(root.join(&candidate))?,
+                    _ => {
+                        resolved = candidate;
+                        continue;
+                    }
+                };
+                symlinks += 1;
+                if symlinks > MAX_SYMLINKS {
+                    return Err(anyhow!(
+                        "Too many levels of symbolic links in {}.",
+                        path.display()
+                    ));
+                }
+                // the absolute targets start over from the root of the instance
+                for component in target.components().rev() {
+                    pending.push_front(component.as_os_str().to_owned());
+                }
+            }
+            _ => (),
+        }
+    }
+
+    Ok(root.join(resolved))
+}
+
+/// Returns where the source is copied to: into the destination if it is an existing directory
+/// (like `cp`), otherwise the destination itself
+fn copy_target(source: &Path, destination: PathBuf) -> Result<PathBuf> {
+    if !destination.is_dir() {
+        return Ok(destination);
+    }
+    let name = source
+        .file_name()
+        .ok_or_else(|| anyhow!("Unable to tell the name of {}.", source.display()))?;
+
+    Ok(destination.join(name))
+}
+
+/// Make way for the copy, the existing file or directory is only removed if `force` is set
+fn clear_target(target: &Path, shown: &Path, force: bool) -> Result<()> {
+    let metadata = match fs::symlink_metadata(target) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(()),
+    };
+    if !force {
+        return Err(anyhow!(
+            "{} already exists, use `--force` to overwrite it.",
+            shown.display()
+        ));
+    }
+    if metadata.is_dir() {
+        fs::remove_dir_all(target)?;
+    } else {
+        fs::remove_file(target)?;
+    }
+
+    Ok(())
+}
+
+/// Copy recursively with `cp`, preserving the permissions, the owners and the timestamps
+fn copy_tree(source: &Path, target: &Path) -> Result<()> {
+    let output = Command::new("cp")
+        .args(["-a", "-T", "--"])
+        .arg(source)
+        .arg(target)
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Unable to copy {} to {}: {}",
+            source.display(),
+            target.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the path in the instance of the path resolved by [resolve_in_root]
+fn path_in_instance(root: &Path, resolved: &Path) -> Result<PathBuf> {
+    Ok(Path::new("/").join(resolved.strip_prefix(root)?))
+}
+
+/// Copy between the host and the mounted instance. The running machine (if any) copies the
+/// files itself, so that they also land in the mounts of the container
+fn copy_mounted(
+    root: &Path,
+    machine: Option<&str>,
+    source: &CopyLocation,
+    destination: &CopyLocation,
+    force: bool,
+) -> Result<()> {
+    match (source, destination) {
+        (CopyLocation::Host(source), CopyLocation::Instance(instance, path)) => {
+            fs::symlink_metadata(source)
+                .map_err(|e| anyhow!("Unable to read {}: {}", source.display(), e))?;
+            let target = copy_target(source, resolve_in_root(root, path)?)?;
+            // the last component may be a new symbolic link
+            let target = resolve_in_root(root, &path_in_instance(root, &target)?)?;
+            let container_path = path_in_instance(root, &target)?;
+            let shown = PathBuf::from(format!("{}:{}", instance, container_path.display()));
+            clear_target(&target, &shown, force)?;
+            match machine {
+                Some(ns_name) => {
+                    machine::copy_to_machine(ns_name, &fs::canonicalize(source)?, &container_path)
+                }
+                None => copy_tree(source, &target),
+            }
+        }
+        (CopyLocation::Instance(instance, path), CopyLocation::Host(destination)) => {
+            let source = resolve_in_root(root, path)?;
+            fs::symlink_metadata(&source)
+                .map_err(|e| anyhow!("Unable to read {}:{}: {}", instance, path.display(), e))?;
+            let target = copy_target(&source, destination.clone())?;
+            clear_target(&target, &target, force)?;
+            match machine {
+                Some(ns_name) => {
+                    machine::copy_from_machine(ns_name, &path_in_instance(root, &source)?, &target)
+                }
+                None => copy_tree(&source, &target),
+            }
+        }
+        _ => Err(anyhow!(
+            "Only one of the source and the destination can be in the instance."
+        )),
+    }
+}
+
+/// Copy a file or a directory (recursively) between the host and an instance, the existing
+/// files are only overwritten if `force` is set. The instance is mounted temporarily if it is
+/// not mounted, the host paths must be absolute
+pub fn copy_files(source: &CopyLocation, destination: &CopyLocation, force: bool) -> Result<()> {
+    let instance = match (source, destination) {
+        (CopyLocation::Host(_), CopyLocation::Instance(instance, _))
+        | (CopyLocation::Instance(instance, _), CopyLocation::Host(_)) => instance,
+        (CopyLocation::Host(_), CopyLocation::Host(_)) => {
+            return Err(anyhow!(
+                "Either the source or the destination must be in an instance (`<instance>:<path>`)."
+            ))
+        }
+        _ => {
+            return Err(anyhow!(
+                "Copying between the instances is not supported, please copy to the host first."
+            ))
+        }
+    };
+    ensure_instance_exists(instance)?;
+    let ns_name = get_instance_ns_name(instance)?;
+    let inst = inspect_instance(instance, &ns_name)?;
+    let _lock = if inst.mounted {
+        None
+    } else {
+        let lock = metadata::lock_instance(instance, "copy")?;
+        info!("{}: mounting the instance temporarily...", instance);
+        mount_fs(instance)?;
+        Some(lock)
+    };
+    if matches!(destination, CopyLocation::Instance(..)) && overlayfs::is_read_only(instance)? {
+        warn!(
+            "{}: instance is in the read-only mode, the files are discarded when it stops.",
+            instance
+        );
+    }
+    let root = std::env::current_dir()?.join(instance);
+    // the machines not registered in systemd-machined share the mounted filesystem as well
+    let machine = Some(ns_name.as_str()).filter(|_| inst.started && machine::machined().is_some());
+    let result = copy_mounted(&root, machine, source, destination, force);
+    if !inst.mounted {
+        unmount_fs(instance)?;
+        remove_mount(instance)?;
+    }
+    result?;
+    info!(
+        "Copied {} to {}.",
+        style(format_location(source)).cyan(),
+        style(format_location(destination)).cyan()
+    );
+
+    Ok(())
+}
+
+fn format_location(location: &CopyLocation) -> String {
+    match location {
+        CopyLocation::Host(path) => path.display().to_string(),
+        CopyLocation::Instance(instance, path) => format!("{}:{}", instance, path.display()),
+    }
+}
+
+#[test]
+fn test_parse_copy_location() {
+    assert_eq!(
+        parse_copy_location("main:/etc/hosts"),
+        CopyLocation::Instance("main".to_owned(), PathBuf::from("/etc/hosts"))
+    );
+    assert_eq!(
+        parse_copy_location("main:tmp"),
+        CopyLocation::Instance("main".to_owned(), PathBuf::from("/tmp"))
+    );
+    assert_eq!(
+        parse_copy_location("main:"),
+        CopyLocation::Instance("main".to_owned(), PathBuf::from("/"))
+    );
+    assert_eq!(
+        parse_copy_location("./a:b"),
+        CopyLocation::Host(PathBuf::from("./a:b"))
+    );
+    assert_eq!(
+        parse_copy_location("patch.diff"),
+        CopyLocation::Host(PathBuf::from("patch.diff"))
+    );
+}
+
+#[test]
+fn test_resolve_in_root() {
+    use std::os::unix::fs::symlink;
+
+    let root = tempfile::tempdir().unwrap();
+    let root = root.path();
+    fs::create_dir_all(root.join("usr/lib")).unwrap();
+    symlink("usr/lib", root.join("lib")).unwrap();
+    symlink("/usr", root.join("usr/lib/abs")).unwrap();
+    symlink("../../..", root.join("usr/lib/up")).unwrap();
+    symlink("loop", root.join("loop")).unwrap();
+    assert_eq!(
+        resolve_in_root(root, Path::new("/lib/file")).unwrap(),
+        root.join("usr/lib/file")
+    );
+    assert_eq!(
+        resolve_in_root(root, Path::new("/lib/abs/lib")).unwrap(),
+        root.join("usr/lib")
+    );
+    assert_eq!(
+        resolve_in_root(root, Path::new("/usr/../new")).unwrap(),
+        root.join("new")
+    );
+    assert!(resolve_in_root(root, Path::new("/../etc")).is_err());
+    assert!(resolve_in_root(root, Path::new("/lib/up/etc")).is_err());
+    assert!(resolve_in_root(root, Path::new("/loop")).is_err());
+}
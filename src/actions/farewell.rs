@@ -0,0 +1,189 @@
+//! Tearing down the workspace: the machines, the mounts and then the files
+
+use anyhow::{anyhow, Result};
+use console::{style, user_attended};
+use dialoguer::{theme::ColorfulTheme, Confirm, Input};
+use nix::{
+    errno::Errno,
+    mount::{umount2, MntFlags},
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    common::{mounts_under, parse_mountinfo},
+    error, info, machine, warn,
+};
+
+use super::packaging::list_output_dirs;
+
+/// Options of [farewell]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FarewellOptions {
+    /// Only print what would be torn down
+    pub dry_run: bool,
+    /// Also remove the output directories (`OUTPUT` and `OUTPUT-*`) in the workspace
+    pub remove_output: bool,
+}
+
+/// Everything to be torn down, in order
+#[derive(Debug, Default)]
+struct Teardown {
+    /// Machines registered in systemd-machined
+    machines: Vec<String>,
+    /// Mounts in the workspace, in the order to unmount them
+    mounts: Vec<PathBuf>,
+    /// Files and directories to be removed once nothing is mounted
+    removals: Vec<PathBuf>,
+}
+
+impl Teardown {
+    fn is_empty(&self) -> bool {
+        self.machines.is_empty() && self.mounts.is_empty() && self.removals.is_empty()
+    }
+
+    fn print(&self) {
+        for name in &self.machines {
+            eprintln!("{} {}", style("stop   ").yellow().bold(), name);
+        }
+        for mount in &self.mounts {
+            eprintln!("{} {}", style("unmount").yellow().bold(), mount.display());
+        }
+        for path in &self.removals {
+            eprintln!("{} {}", style("remove ").red().bold(), path.display());
+        }
+    }
+}
+
+fn plan_teardown(workspace: &Path, options: &FarewellOptions) -> Result<Teardown> {
+    let mut teardown = Teardown::default();
+    match machine::list_workspace_machines(workspace) {
+        Ok(machines) => teardown.machines = machines,
+        Err(e) => warn!("Unable to list the machines: {:#}", e),
+    }
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    teardown.mounts = mounts_under(&parse_mountinfo(&mountinfo), workspace);
+    let ciel_dir = workspace.join(".ciel");
+    if ciel_dir.exists() {
+        teardown.removals.push(ciel_dir);
+    }
+    if options.remove_output {
+        teardown.removals.extend(list_output_dirs(workspace, true)?);
+    }
+
+    Ok(teardown)
+}
+
+fn confirm_farewell(teardown: &Teardown) -> Result<bool> {
+    if !user_attended() {
+        eprintln!("DELETE THIS CIEL WORKSPACE?");
+        info!("Not controlled by an user. Automatically confirmed.");
+        return Ok(true);
+    }
+    info!("The following will be torn down:");
+    teardown.print();
+    let theme = ColorfulTheme::default();
+    let delete = Confirm::with_theme(&theme)
+        .with_prompt("DELETE THIS CIEL WORKSPACE?")
+        .default(false)
+        .interact()?;
+    if !delete {
+        info!("Not confirmed.");
+        return Ok(false);
+    }
+    info!(
+        "If you are absolutely sure, please type the following:\n{}",
+        style("Do as I say!").bold()
+    );
+    if Input::<String>::with_theme(&theme)
+        .with_prompt("Your turn")
+        .interact()?
+        != "Do as I say!"
+    {
+        info!("Prompt answered incorrectly. Not confirmed.");
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+/// Unmount the topmost mount at the path, lazily if it is busy.
+/// The path not being a mount point (any more) is not an error
+fn unmount(path: &Path) -> Result<()> {
+    match umount2(path, MntFlags::empty()) {
+        Ok(()) | Err(Errno::EINVAL) | Err(Errno::ENOENT) => Ok(()),
+        Err(Errno::EBUSY) => {
+            warn!("{} is busy, detaching it...", path.display());
+            umount2(path, MntFlags::MNT_DETACH)?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Stop the machines, unmount everything in the workspace and then remove the files.
+/// Nothing is removed if anything is left mounted, so that it is safe to run again
+fn execute_teardown(workspace: &Path, teardown: &Teardown) -> Result<()> {
+    let mut stop_failed = false;
+    for name in &teardown.machines {
+        info!("Stopping {}...", name);
+        if let Err(e) = machine::unregister_machine(name) {
+            error!("Unable to stop {}: {:#}", name, e);
+            stop_failed = true;
+        }
+    }
+    machine::clean_child_process();
+    for mount in &teardown.mounts {
+        info!("Un-mounting {}...", mount.display());
+        if let Err(e) = unmount(mount) {
+            error!("Unable to un-mount {}: {:#}", mount.display(), e);
+        }
+    }
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    let remaining = mounts_under(&parse_mountinfo(&mountinfo), workspace);
+    if !remaining.is_empty() {
+        for mount in &remaining {
+            error!("{} is still mounted", mount.display());
+        }
+        return Err(anyhow!(
+            "{} mounts could not be removed, nothing has been deleted. Please run `ciel farewell` again.",
+            remaining.len()
+        ));
+    }
+    if stop_failed {
+        warn!("Some machines could not be stopped, but nothing is mounted in the workspace.");
+    }
+    for path in &teardown.removals {
+        info!("Removing {}...", path.display());
+        match fs::remove_dir_all(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(anyhow!("Unable to remove {}: {}", path.display(), e))
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove everything in the current workspace
+pub fn farewell(path: &Path, options: &FarewellOptions) -> Result<()> {
+    let workspace = fs::canonicalize(path)?;
+    let teardown = plan_teardown(&workspace, options)?;
+    if options.dry_run {
+        if teardown.is_empty() {
+            info!("Nothing to tear down.");
+        } else {
+            info!("The following would be torn down:");
+            teardown.print();
+        }
+        return Ok(());
+    }
+    if !confirm_farewell(&teardown)? {
+        return Ok(());
+    }
+    info!("... as you wish. Commencing destruction ...");
+    execute_teardown(&workspace, &teardown)
+}
@@ -0,0 +1,157 @@
+//! Powering off the instances idle for longer than `auto-stop-after`
+
+use anyhow::{anyhow, Result};
+use console::style;
+use std::{
+    fs,
+    io::Write,
+    thread::sleep,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    common::{format_duration, format_time},
+    config, error, info,
+    machine::{self, CielInstance},
+    metadata, warn,
+};
+
+use super::stop_container;
+
+/// Where the instances stopped automatically are recorded
+const AUTO_STOP_LOG: &str = ".ciel/data/auto-stop.log";
+/// Interval between the checks in the watch mode
+pub const DEFAULT_WATCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Options of [gc_instances]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GcOptions {
+    /// Overrides `auto-stop-after`
+    pub idle_after: Option<Duration>,
+    /// Only print the instances that would be stopped
+    pub dry_run: bool,
+}
+
+/// Returns how long (in seconds) the instance has been idle, since it was started
+/// or since the last command run in it, whichever is the latest
+fn idle_time(boot_time: Option<u64>, last_activity: Option<u64>, now: u64) -> Option<u64> {
+    let since = boot_time.max(last_activity)?;
+
+    Some(now.saturating_sub(since))
+}
+
+fn log_stop(instance: &str, reason: &str) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(AUTO_STOP_LOG)?;
+    writeln!(log, "{}\t{}\t{}", format_time(now), instance, reason)?;
+
+    Ok(())
+}
+
+/// Returns why the instance should be stopped, `None` if it should be kept
+fn stop_reason(instance: &CielInstance, limit: Duration, now: u64) -> Result<Option<String>> {
+    if !instance.started {
+        return Ok(None);
+    }
+    let metadata = metadata::read_metadata(&instance.name).unwrap_or_default();
+    if metadata.keep_alive() {
+        return Ok(None);
+    }
+    if metadata::is_busy(&instance.name)? {
+        return Ok(None);
+    }
+    let idle = match idle_time(
+        instance.boot_time,
+        metadata::last_activity(&instance.name),
+        now,
+    ) {
+        Some(idle) => idle,
+        None => {
+            warn!(
+                "{}: unable to tell since when the instance is idle, skipping.",
+                instance.name
+            );
+            return Ok(None);
+        }
+    };
+    if idle <= limit.as_secs() {
+        return Ok(None);
+    }
+
+    Ok(Some(format!(
+        "idle for {} (limit {})",
+        format_duration(Duration::from_secs(idle)),
+        format_duration(limit)
+    )))
+}
+
+/// Power off the started instances idle for longer than the limit, except the ones labeled
+/// `keep-alive=true` and the ones ciel is running commands in
+pub fn gc_instances(options: &GcOptions) -> Result<()> {
+    let limit = options
+        .idle_after
+        .or_else(|| config::read_config().ok().and_then(|c| c.auto_stop_after))
+        .ok_or_else(|| {
+            anyhow!(
+                "No idle timeout is configured, please set `auto-stop-after` with `ciel config set`."
+            )
+        })?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut stopped = 0;
+    for instance in machine::list_instances()? {
+        let reason = match stop_reason(&instance, limit, now)? {
+            Some(reason) => reason,
+            None => continue,
+        };
+        if options.dry_run {
+            info!("{}: would be stopped, {}.", instance.name, reason);
+            continue;
+        }
+        info!("{}: {}, stopping...", instance.name, reason);
+        if let Err(e) = stop_container(&instance.name) {
+            error!("{}: unable to stop the instance: {:#}", instance.name, e);
+            continue;
+        }
+        stopped += 1;
+        if let Err(e) = log_stop(&instance.name, &reason) {
+            warn!("Unable to write to {}: {:#}", AUTO_STOP_LOG, e);
+        }
+    }
+    if !options.dry_run && stopped > 0 {
+        info!(
+            "{} idle instances stopped, see {} for the details.",
+            stopped,
+            style(AUTO_STOP_LOG).cyan()
+        );
+    }
+
+    Ok(())
+}
+
+/// Run [gc_instances] forever, the errors are printed and do not stop the loop
+pub fn watch_instances(options: &GcOptions, interval: Duration) -> Result<()> {
+    info!(
+        "Checking the idle instances every {}...",
+        format_duration(interval)
+    );
+    loop {
+        if let Err(e) = gc_instances(options) {
+            error!("{:#}", e);
+        }
+        sleep(interval);
+    }
+}
+
+#[test]
+fn test_idle_time() {
+    assert_eq!(idle_time(Some(100), None, 400), Some(300));
+    assert_eq!(idle_time(Some(100), Some(250), 400), Some(150));
+    // activity recorded before the instance was restarted
+    assert_eq!(idle_time(Some(300), Some(250), 400), Some(100));
+    assert_eq!(idle_time(None, Some(250), 400), Some(150));
+    assert_eq!(idle_time(None, None, 400), None);
+    assert_eq!(idle_time(Some(500), None, 400), Some(0));
+}
@@ -0,0 +1,86 @@
+use anyhow::{anyhow, Result};
+use console::style;
+use std::{
+    io::{BufRead, BufReader, Read},
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+};
+
+use crate::{info, warn};
+
+/// Print the lines from the reader with the name of the hook as the prefix
+fn forward_output<R: Read + Send + 'static>(
+    name: &'static str,
+    reader: R,
+    stderr: bool,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let prefix = style(format!("[hook {}]", name)).dim();
+        for line in BufReader::new(reader).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if stderr {
+                eprintln!("{} {}", prefix, line);
+            } else {
+                println!("{} {}", prefix, line);
+            }
+        }
+    })
+}
+
+/// Run the hook on the host and returns its exit code, returns 0 if the hook is not configured
+pub(crate) fn run_hook(
+    name: &'static str,
+    hook: Option<&Path>,
+    env: &[(&str, String)],
+) -> Result<i32> {
+    let hook = match hook {
+        Some(hook) => hook,
+        None => return Ok(0),
+    };
+    info!("Running {} hook: {}", name, hook.display());
+    let mut child = Command::new(hook)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("Unable to run {} hook {}: {}", name, hook.display(), e))?;
+    let stdout = forward_output(name, child.stdout.take().unwrap(), false);
+    let stderr = forward_output(name, child.stderr.take().unwrap(), true);
+    let status = child.wait()?;
+    stdout.join().ok();
+    stderr.join().ok();
+
+    Ok(status.code().unwrap_or(-1))
+}
+
+/// Run the pre-hook, the operation should be aborted if this returns an error
+pub(crate) fn run_pre_hook(
+    name: &'static str,
+    hook: Option<&Path>,
+    env: &[(&str, String)],
+) -> Result<()> {
+    let status = run_hook(name, hook, env)?;
+    if status != 0 {
+        return Err(anyhow!(
+            "The {} hook exited with status {}, aborting.",
+            name,
+            status
+        ));
+    }
+
+    Ok(())
+}
+
+/// Run the post-hook, failures are only reported as warnings
+pub(crate) fn run_post_hook(name: &'static str, hook: Option<&Path>, env: &[(&str, String)]) {
+    match run_hook(name, hook, env) {
+        Ok(0) => (),
+        Ok(status) => warn!("The {} hook exited with status {}.", name, status),
+        Err(e) => warn!("{}", e),
+    }
+}
@@ -0,0 +1,313 @@
+//! Migration of the workspaces created by ciel 2.x
+
+use anyhow::{anyhow, Result};
+use console::{style, user_attended};
+use dialoguer::{theme::ColorfulTheme, Confirm};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    common::{ciel_init, is_legacy_workspace, mounts_under, parse_mountinfo, CIEL_INST_DIR},
+    config::{self, CielConfig},
+    info, machine, warn,
+};
+
+/// Suffix of the original files replaced by the migration
+const LEGACY_SUFFIX: &str = ".ciel2";
+/// Layers of the instances, directly in the instance directories in ciel 2.x
+const LEGACY_LAYERS: &[&str] = &["diff", "diff.tmp", "local"];
+/// Where the layers of the instances are now
+const LAYERS_DIR: &str = "layers";
+const VERSION_FILE: &str = ".ciel/version";
+const MIGRATION_REPORT: &str = ".ciel/migration-report.txt";
+
+/// What is going to be migrated in the workspace
+#[derive(Debug, Default, PartialEq, Eq)]
+struct MigrationPlan {
+    /// The workspace version needs to be updated
+    version: bool,
+    /// The configuration file needs to be converted
+    config: bool,
+    /// Instances whose layers need to be relocated
+    instances: Vec<String>,
+    /// Problems to be resolved manually
+    attention: Vec<String>,
+}
+
+impl MigrationPlan {
+    fn is_empty(&self) -> bool {
+        !self.version && !self.config && self.instances.is_empty()
+    }
+
+    /// Returns the description of each step
+    fn steps(&self) -> Vec<String> {
+        let mut steps = Vec::new();
+        if self.config {
+            steps.push(format!(
+                "convert the configuration file (the original is kept as config.toml{})",
+                LEGACY_SUFFIX
+            ));
+        }
+        for instance in &self.instances {
+            steps.push(format!(
+                "move the layers of instance {} into {}/",
+                instance, LAYERS_DIR
+            ));
+        }
+        if self.version {
+            steps.push(format!(
+                "update the workspace version (the original is kept as version{})",
+                LEGACY_SUFFIX
+            ));
+        }
+
+        steps
+    }
+
+    fn print(&self) {
+        for step in self.steps() {
+            eprintln!("  - {}", step);
+        }
+        for item in &self.attention {
+            eprintln!("  {} {}", style("!").yellow().bold(), item);
+        }
+    }
+
+    /// Returns the content of the migration report
+    fn report(&self) -> String {
+        let mut report = String::from("Migrated from ciel 2.x:\n");
+        for step in self.steps() {
+            report.push_str(&format!("- {}\n", step));
+        }
+        if !self.attention.is_empty() {
+            report.push_str("\nNeeds manual attention:\n");
+            for item in &self.attention {
+                report.push_str(&format!("- {}\n", item));
+            }
+        }
+
+        report
+    }
+}
+
+/// Returns the legacy layers directly in the instance directory
+fn legacy_layers(instance: &Path) -> Vec<&'static str> {
+    LEGACY_LAYERS
+        .iter()
+        .filter(|x| instance.join(x).is_dir())
+        .copied()
+        .collect()
+}
+
+/// Plan the migration of the instances in the directory
+fn plan_instances(inst_dir: &Path, plan: &mut MigrationPlan) -> Result<()> {
+    let entries = match fs::read_dir(inst_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut instances = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            instances.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    instances.sort();
+    for instance in instances {
+        let path = inst_dir.join(&instance);
+        let layers = legacy_layers(&path);
+        if layers.is_empty() {
+            continue;
+        }
+        if layers
+            .iter()
+            .any(|x| path.join(LAYERS_DIR).join(x).exists())
+        {
+            plan.attention.push(format!(
+                "instance {} has the layers in both the old and the new layout, please merge {} manually",
+                instance,
+                path.display()
+            ));
+            continue;
+        }
+        plan.instances.push(instance);
+    }
+
+    Ok(())
+}
+
+/// Plan the migration of the current workspace
+fn plan_migration() -> Result<MigrationPlan> {
+    let mut plan = MigrationPlan {
+        version: Path::new(VERSION_FILE).is_file() && is_legacy_workspace()?,
+        ..Default::default()
+    };
+    if let Ok(data) = fs::read_to_string(config::config_location(".")) {
+        let (_, migrated) = CielConfig::load_config_with_migration(&data)?;
+        plan.config = migrated;
+        let (_, warnings) = CielConfig::load_config_with_warnings(&data)?;
+        plan.attention.extend(warnings);
+    }
+    plan_instances(Path::new(CIEL_INST_DIR), &mut plan)?;
+    if plan.version {
+        plan.attention.push(
+            "the machine names of the instances change, update the scripts referring to them"
+                .to_owned(),
+        );
+    }
+
+    Ok(plan)
+}
+
+/// Returns an error if anything is mounted in the workspace or any machine is running
+fn ensure_not_in_use() -> Result<()> {
+    let root = std::env::current_dir()?;
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    let mounts = mounts_under(&parse_mountinfo(&mountinfo), &root);
+    let machines = machine::list_workspace_machines(&root).unwrap_or_default();
+    if mounts.is_empty() && machines.is_empty() {
+        return Ok(());
+    }
+    for mount in &mounts {
+        warn!("{} is mounted", mount.display());
+    }
+    for machine in &machines {
+        warn!("{} is running", machine);
+    }
+
+    Err(anyhow!(
+        "Some instances are still in use, please stop and un-mount them with the old version of ciel (`ciel down`) first."
+    ))
+}
+
+/// Move the file aside with the legacy suffix
+fn move_aside(path: &Path) -> Result<PathBuf> {
+    let mut aside = path.as_os_str().to_owned();
+    aside.push(LEGACY_SUFFIX);
+    let aside = PathBuf::from(aside);
+    if aside.exists() {
+        return Err(anyhow!(
+            "{} already exists, please move it away first.",
+            aside.display()
+        ));
+    }
+    fs::rename(path, &aside)?;
+
+    Ok(aside)
+}
+
+fn execute_migration(plan: &MigrationPlan) -> Result<()> {
+    if plan.config {
+        info!("Converting the configuration file...");
+        let location = config::config_location(".");
+        let config = CielConfig::load_config(&fs::read_to_string(&location)?)?;
+        let aside = move_aside(&location)?;
+        fs::copy(&aside, &location)?;
+        config::write_config(&config)?;
+    }
+    for instance in &plan.instances {
+        info!("{}: relocating the layers...", instance);
+        let path = Path::new(CIEL_INST_DIR).join(instance);
+        let layers = path.join(LAYERS_DIR);
+        fs::create_dir_all(&layers)?;
+        for layer in legacy_layers(&path) {
+            fs::rename(path.join(layer), layers.join(layer))?;
+        }
+    }
+    if plan.version {
+        info!("Updating the workspace version...");
+        move_aside(Path::new(VERSION_FILE))?;
+        ciel_init()?;
+    }
+
+    Ok(())
+}
+
+/// Migrate the current workspace from ciel 2.x, only print the plan if `dry_run` is set.
+/// The original files are moved aside, and a report is written to the workspace
+pub fn migrate_workspace(dry_run: bool) -> Result<()> {
+    let plan = plan_migration()?;
+    if plan.is_empty() {
+        info!("This workspace is up to date, nothing to migrate.");
+        return Ok(());
+    }
+    if dry_run {
+        info!("The workspace would be migrated as follows:");
+        plan.print();
+        return Ok(());
+    }
+    ensure_not_in_use()?;
+    info!("Migrating the workspace as follows:");
+    plan.print();
+    execute_migration(&plan)?;
+    fs::write(MIGRATION_REPORT, plan.report())?;
+    info!(
+        "Workspace migrated, see {} for the details.",
+        style(MIGRATION_REPORT).cyan()
+    );
+    if !plan.attention.is_empty() {
+        warn!("Some items need manual attention, please check the report.");
+    }
+
+    Ok(())
+}
+
+/// Offer to migrate the current workspace if it was created by ciel 2.x.
+/// Returns whether the workspace still needs to be migrated
+pub fn offer_migration() -> Result<bool> {
+    let plan = plan_migration()?;
+    if plan.instances.is_empty() && !plan.version {
+        return Ok(false);
+    }
+    if !user_attended() {
+        warn!("This workspace was created by ciel 2.x.");
+        warn!("Run `ciel init --upgrade` to migrate it (`--dry-run` to show what would be done).");
+        return Ok(true);
+    }
+    info!("This workspace was created by ciel 2.x, it can be migrated as follows:");
+    plan.print();
+    let migrate = Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Migrate it now?")
+        .default(true)
+        .interact()?;
+    if !migrate {
+        return Ok(true);
+    }
+    migrate_workspace(false)?;
+
+    Ok(false)
+}
+
+#[test]
+fn test_plan_instances() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut plan = MigrationPlan::default();
+    plan_instances(&dir.path().join("nonexistent"), &mut plan).unwrap();
+    assert!(plan.is_empty());
+
+    for path in [
+        "old/diff",
+        "old/local",
+        "new/layers/diff",
+        "new/layers/local",
+        "both/diff",
+        "both/layers/diff",
+        "empty",
+    ] {
+        fs::create_dir_all(dir.path().join(path)).unwrap();
+    }
+    plan_instances(dir.path(), &mut plan).unwrap();
+    assert_eq!(plan.instances, vec!["old"]);
+    assert_eq!(plan.attention.len(), 1);
+    assert!(plan.attention[0].starts_with("instance both"));
+    assert_eq!(
+        plan.steps(),
+        vec![format!(
+            "move the layers of instance old into {}/",
+            LAYERS_DIR
+        )]
+    );
+}
@@ -1,42 +1,267 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use console::style;
+use std::path::Path;
 
-use crate::machine;
+use crate::{
+    common::CIEL_DIST_DIR,
+    config::{self, PackageManager},
+    machine,
+};
 
+mod bulk;
 mod container;
+mod copy;
+mod farewell;
+mod gc;
+mod hooks;
+mod legacy;
 mod onboarding;
 mod packaging;
+mod ps;
+mod status;
+mod trash;
+mod unit;
 
 // re-export all the functions from the sub
+pub use self::bulk::DEFAULT_BULK_JOBS;
 pub use self::container::*;
-pub use self::onboarding::onboarding;
+pub use self::copy::{copy_files, parse_copy_location, CopyLocation};
+pub use self::farewell::{farewell, FarewellOptions};
+pub use self::gc::{gc_instances, watch_instances, GcOptions, DEFAULT_WATCH_INTERVAL};
+pub use self::legacy::{migrate_workspace, offer_migration};
+pub use self::onboarding::{onboarding, OnboardingOptions};
 pub use self::packaging::*;
+pub use self::ps::show_processes;
+pub use self::status::{show_instance_status, show_status};
+pub use self::trash::{empty_trash, list_trash, restore_trash};
+pub use self::unit::{generate_unit, UnitOptions};
 
 const DEFAULT_MOUNTS: &[(&str, &str)] = &[
     ("OUTPUT/debs/", "/debs/"),
     ("TREE", "/tree"),
     ("SRCS", "/var/cache/acbs/tarballs"),
 ];
-const UPDATE_SCRIPT: &str = r#"export DEBIAN_FRONTEND=noninteractive;apt-get update -y --allow-releaseinfo-change && apt-get -y -o Dpkg::Options::="--force-confnew" full-upgrade --autoremove --purge && apt clean"#;
+const APT_UPDATE_SCRIPT: &str = "apt-get update -y --allow-releaseinfo-change";
+/// oma refreshes the metadata (including the local repository) before upgrading
+const OMA_UPGRADE_SCRIPT: &str = "oma upgrade -y --force-confnew";
+/// Path to oma in the base system
+const OMA_PATH: &str = "usr/bin/oma";
+/// Printed by oma if the OS is up to date
+const OMA_NOTHING_TO_DO: &str = "No need to do anything.";
+
+/// Options of updating the OS, see [update_script]
+#[derive(Debug, Clone, Copy)]
+pub struct UpdateOptions {
+    /// Run `apt full-upgrade` instead of `apt upgrade` (which never removes packages)
+    pub full_upgrade: bool,
+    /// Remove the obsolete packages (along with their configuration files) afterwards
+    pub autoremove: bool,
+    /// Remove the downloaded packages afterwards
+    pub clean: bool,
+    /// Update the OS even if some instances are running
+    pub force: bool,
+    /// Repair the interrupted package manager without asking
+    pub auto_repair: bool,
+    pub package_manager: PackageManager,
+}
+
+impl UpdateOptions {
+    /// Returns the options configured in the workspace (`update-*` and `package-manager`)
+    pub fn from_config(config: &config::CielConfig) -> Self {
+        UpdateOptions {
+            full_upgrade: config.update_full_upgrade(),
+            autoremove: config.update_autoremove(),
+            clean: config.update_clean(),
+            force: false,
+            auto_repair: false,
+            package_manager: config.package_manager,
+        }
+    }
+
+    /// Returns the package manager to be used (APT or oma), `auto` picks oma if it is installed
+    /// in the base system. oma always performs a full upgrade, so APT is picked for safe upgrades
+    fn resolve_package_manager(&self) -> Result<PackageManager> {
+        let installed = Path::new(CIEL_DIST_DIR).join(OMA_PATH).is_file();
+        match self.package_manager {
+            PackageManager::Auto if installed && self.full_upgrade => Ok(PackageManager::Oma),
+            PackageManager::Auto | PackageManager::Apt => Ok(PackageManager::Apt),
+            PackageManager::Oma if !installed => Err(anyhow!(
+                "oma is not installed in the base system, please set `package-manager` to `auto` or `apt`."
+            )),
+            PackageManager::Oma if !self.full_upgrade => Err(anyhow!(
+                "oma always performs a full upgrade, please set `package-manager` to `auto` or `apt` for safe upgrades."
+            )),
+            PackageManager::Oma => Ok(PackageManager::Oma),
+        }
+    }
+}
+
+/// Summary of updating the OS, collected from the output of the package manager
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UpdateSummary {
+    pub upgraded: usize,
+    pub installed: usize,
+    pub removed: usize,
+}
+
+impl UpdateSummary {
+    /// Sum up the `N upgraded, N newly installed, N to remove and N not upgraded.` lines printed
+    /// by APT and the `N package(s) will be upgraded:` lines printed by oma in the output.
+    /// Returns `None` if the output contains no summary at all
+    fn parse(output: &str) -> Option<Self> {
+        fn parse_apt(line: &str) -> Option<(usize, usize, usize)> {
+            let line = line.strip_suffix(" not upgraded.")?;
+            let (upgraded, rest) = line.split_once(" upgraded, ")?;
+            let (installed, rest) = rest.split_once(" newly installed, ")?;
+            let (removed, _) = rest.split_once(" to remove and ")?;
+
+            Some((
+                upgraded.parse().ok()?,
+                installed.parse().ok()?,
+                removed.parse().ok()?,
+            ))
+        }
+
+        fn parse_oma(line: &str) -> Option<(usize, usize, usize)> {
+            if line == OMA_NOTHING_TO_DO {
+                return Some((0, 0, 0));
+            }
+            let line = line.strip_suffix(':').unwrap_or(line);
+            let (count, action) = line.split_once(" package(s) will be ")?;
+            let count = count.parse().ok()?;
+            match action {
+                "upgraded" | "downgraded" | "reinstalled" => Some((count, 0, 0)),
+                "installed" => Some((0, count, 0)),
+                "removed" | "purged" => Some((0, 0, count)),
+                _ => None,
+            }
+        }
+
+        let mut summary = None;
+        for line in output.lines() {
+            // the output comes from a terminal, so it might be colored
+            let line = console::strip_ansi_codes(line);
+            let line = line.trim();
+            if let Some((upgraded, installed, removed)) =
+                parse_apt(line).or_else(|| parse_oma(line))
+            {
+                let summary = summary.get_or_insert_with(UpdateSummary::default);
+                summary.upgraded += upgraded;
+                summary.installed += installed;
+                summary.removed += removed;
+            }
+        }
+
+        summary
+    }
+
+    /// Returns whether nothing has been changed
+    pub fn is_up_to_date(&self) -> bool {
+        *self == UpdateSummary::default()
+    }
+}
+
+impl std::fmt::Display for UpdateSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_up_to_date() {
+            return write!(f, "nothing to do, the OS is up to date");
+        }
+
+        write!(
+            f,
+            "{} upgraded, {} newly installed, {} removed",
+            self.upgraded, self.installed, self.removed
+        )
+    }
+}
+
+/// Returns the first error reported by the package manager in the output,
+/// `E: ...` by APT or `ERROR ...` by oma
+fn package_manager_error(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let line = console::strip_ansi_codes(line);
+        let line = line.trim();
+        line.strip_prefix("E: ")
+            .or_else(|| line.strip_prefix("ERROR "))
+            .map(|x| x.trim().to_owned())
+    })
+}
+
+/// Returns the script for updating the OS with the package manager (APT or oma), the `packages`
+/// are installed before the downloaded packages are removed (if `clean` is set)
+fn update_script(options: &UpdateOptions, manager: PackageManager, packages: &[&str]) -> String {
+    let mut script = "export DEBIAN_FRONTEND=noninteractive;".to_owned();
+    if manager == PackageManager::Oma {
+        script.push_str(OMA_UPGRADE_SCRIPT);
+        if !packages.is_empty() {
+            script.push_str(&format!(" && oma install -y {}", packages.join(" ")));
+        }
+    } else {
+        script.push_str(APT_UPDATE_SCRIPT);
+        script.push_str(&format!(
+            r#" && apt-get -y -o Dpkg::Options::="--force-confnew" {}"#,
+            if options.full_upgrade {
+                "full-upgrade"
+            } else {
+                "upgrade"
+            }
+        ));
+        if !packages.is_empty() {
+            script.push_str(&format!(" && apt-get install -y {}", packages.join(" ")));
+        }
+    }
+    // oma shares the package database and the package cache with APT
+    if options.autoremove {
+        script.push_str(" && apt-get -y autoremove --purge");
+    }
+    if options.clean {
+        script.push_str(" && apt-get clean");
+    }
+
+    script
+}
 
 /// Ensure that the directories exist and mounted
-pub fn ensure_host_sanity() -> Result<(Vec<String>, Vec<(String, &'static str)>), std::io::Error> {
+pub fn ensure_host_sanity(
+    instance: &str,
+) -> Result<(Vec<String>, Vec<(String, String)>), std::io::Error> {
     use crate::warn;
 
     let mut extra_options = Vec::new();
-    let mut mounts: Vec<(String, &str)> = DEFAULT_MOUNTS
-        .into_iter()
-        .map(|x| (x.0.to_string(), x.1))
+    let mut mounts: Vec<(String, String)> = DEFAULT_MOUNTS
+        .iter()
+        .map(|x| (x.0.to_string(), x.1.to_string()))
         .collect();
-    if let Ok(c) = crate::config::read_config() {
-        extra_options = c.extra_options;
+    if let Ok(c) = config::read_config() {
+        let inst_config = c.for_instance(instance);
+        extra_options = inst_config.extra_options;
         if !c.local_sources {
             // remove SRCS
             mounts.swap_remove(2);
         }
-        if c.sep_mount {
-            mounts.push((format!("{}/debs", get_output_directory(true)), "/debs/"));
-            mounts.swap_remove(0);
+        let output_dir = get_output_directory(&c, inst_config.sep_mount);
+        if inst_config.sep_mount {
+            let legacy_dir = get_legacy_output_directory(&c);
+            if output_dir != legacy_dir && legacy_dir.is_dir() && !output_dir.exists() {
+                warn!(
+                    "{} was created with the previous output-dir-pattern, packages in it will not be used.",
+                    legacy_dir.display()
+                );
+                warn!(
+                    "To keep using them, stop all the instances and run: mv '{}' '{}'",
+                    legacy_dir.display(),
+                    output_dir.display()
+                );
+            }
+        }
+        mounts[0] = (
+            output_dir.join("debs").display().to_string(),
+            "/debs/".to_string(),
+        );
+        // replace the default TREE with the configured trees
+        mounts.retain(|x| x.1 != "/tree");
+        for tree in c.trees.iter() {
+            mounts.push((tree.source.display().to_string(), tree.location.clone()));
         }
     } else {
         warn!("This workspace is not yet configured, default settings are used.");
@@ -60,3 +285,86 @@ pub fn for_each_instance<F: Fn(&str) -> Result<()>>(func: &F) -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_update_summary() {
+    let output = "Reading package lists... Done\r\n\
+        Calculating upgrade... Done\r\n\
+        12 upgraded, 2 newly installed, 0 to remove and 0 not upgraded.\r\n\
+        Need to get 35.2 MB of archives.\r\n\
+        0 upgraded, 0 newly installed, 3 to remove and 0 not upgraded.\r\n";
+    let summary = UpdateSummary::parse(output).unwrap();
+    assert_eq!(
+        summary,
+        UpdateSummary {
+            upgraded: 12,
+            installed: 2,
+            removed: 3
+        }
+    );
+    assert_eq!(
+        summary.to_string(),
+        "12 upgraded, 2 newly installed, 3 removed"
+    );
+    let summary =
+        UpdateSummary::parse("0 upgraded, 0 newly installed, 0 to remove and 1 not upgraded.\n");
+    assert!(summary.unwrap().is_up_to_date());
+    let oma = "Pending Operations\r\n\
+        \x1b[1m5\x1b[0m package(s) will be upgraded:\r\n\
+        1 package(s) will be installed:\r\n\
+        1 package(s) will be removed:\r\n";
+    assert_eq!(
+        UpdateSummary::parse(oma),
+        Some(UpdateSummary {
+            upgraded: 5,
+            installed: 1,
+            removed: 1
+        })
+    );
+    assert!(UpdateSummary::parse("No need to do anything.\n")
+        .unwrap()
+        .is_up_to_date());
+    assert_eq!(UpdateSummary::parse("Done\n"), None);
+    assert_eq!(
+        package_manager_error(
+            "W: Some index files failed to download.\r\nE: Unable to locate package foo\r\n"
+        )
+        .as_deref(),
+        Some("Unable to locate package foo")
+    );
+    assert_eq!(
+        package_manager_error("\x1b[31mERROR\x1b[0m Failed to refresh the database\n").as_deref(),
+        Some("Failed to refresh the database")
+    );
+    assert_eq!(package_manager_error(output), None);
+}
+
+#[test]
+fn test_update_script() {
+    let mut options = UpdateOptions {
+        full_upgrade: true,
+        autoremove: true,
+        clean: true,
+        force: false,
+        auto_repair: false,
+        package_manager: PackageManager::Auto,
+    };
+    let script = update_script(&options, PackageManager::Apt, &["ccache"]);
+    assert!(script.contains(" full-upgrade && apt-get install -y ccache && apt-get -y autoremove --purge && apt-get clean"));
+    let script = update_script(&options, PackageManager::Oma, &["ccache"]);
+    assert!(script.contains("oma upgrade -y --force-confnew && oma install -y ccache"));
+    assert!(!script.contains("apt-get update"));
+    options.full_upgrade = false;
+    options.autoremove = false;
+    options.clean = false;
+    assert!(
+        update_script(&options, PackageManager::Apt, &[]).ends_with(r#"--force-confnew" upgrade"#)
+    );
+    options.package_manager = PackageManager::Oma;
+    assert!(options.resolve_package_manager().is_err());
+    options.package_manager = PackageManager::Auto;
+    assert_eq!(
+        options.resolve_package_manager().unwrap(),
+        PackageManager::Apt
+    );
+}
@@ -7,16 +7,40 @@ use crate::{
     cli::GIT_TREE_URL,
     common::*,
     config, error, info,
-    network::{download_git, pick_latest_tarball},
+    metadata::{self, InstanceMetadata},
+    network::{download_git, ensure_reachable, CloneOptions},
     overlayfs::create_new_instance_fs,
     repo::{init_repo, refresh_repo},
-    warn,
+    warn, workspace,
 };
 
-use super::{load_os, mount_fs};
+use super::{
+    get_output_directory, load_os_from, mount_fs, resolve_rootfs_source, LoadOsOptions,
+    TarballSelection,
+};
+
+/// Answers to the onboarding questions given on the command line
+#[derive(Debug, Default, Clone)]
+pub struct OnboardingOptions {
+    /// Configuration values (keys of `ciel config set`) applied on top of the template
+    pub settings: Vec<(&'static str, String)>,
+    /// URL of the ABBS tree, defaults to [GIT_TREE_URL]
+    pub tree_url: Option<String>,
+    pub tree: CloneOptions,
+    /// Instances to be created after the initialization
+    pub instances: Vec<String>,
+}
 
 /// Show interactive onboarding guide, triggered by issuing `ciel new`
-pub fn onboarding(custom_tarball: Option<&String>) -> Result<()> {
+/// If a template is specified, it will be used as the initial configuration,
+/// and no questions will be asked if `interactive` is false.
+/// The values given in `options` are used as the answers, or as the defaults of the questions
+pub fn onboarding(
+    custom_tarball: Option<&String>,
+    template: Option<&String>,
+    interactive: bool,
+    options: &OnboardingOptions,
+) -> Result<()> {
     let theme = ColorfulTheme::default();
     info!("Welcome to ciel!");
     if Path::new(".ciel").exists() {
@@ -24,26 +48,47 @@ pub fn onboarding(custom_tarball: Option<&String>) -> Result<()> {
         info!("Please run `ciel farewell` to nuke it before running this command.");
         return Err(anyhow!("Unable to create a ciel workspace."));
     }
-    info!("Before continuing, I need to ask you a few questions:");
-    let config = config::ask_for_config(None)?;
-    let mut init_instance: Option<String> = None;
-    if user_attended()
-        && Confirm::with_theme(&theme)
-            .with_prompt("Do you want to add a new instance now?")
-            .interact()?
-    {
-        let name: String = Input::with_theme(&theme)
-            .with_prompt("Name of the instance")
-            .interact_text()?;
-        init_instance = Some(name.clone());
+    let template = match template {
+        Some(template) => {
+            let path = config::find_template(template)?;
+            info!("Using configuration template from {}", path.display());
+            Some(config::load_template(path)?)
+        }
+        None => None,
+    };
+    // validate all the values before asking anything
+    let mut config = template.unwrap_or_default();
+    for (key, value) in &options.settings {
+        config::set_config_value(&mut config, key, value)?;
+    }
+    for instance in &options.instances {
+        validate_instance_name(instance)?;
+    }
+    let config = if interactive {
+        info!("Before continuing, I need to ask you a few questions:");
+        config::ask_for_config(Some(config))?
+    } else {
+        config
+    };
+    let init_instances = if interactive && user_attended() {
+        ask_for_instances(&theme, &options.instances)?
+    } else {
+        options.instances.clone()
+    };
+    if init_instances.is_empty() {
+        info!("Okay. You can always add a new instance later.");
+    } else {
         info!(
             "Understood. `{}` will be created after initialization is finished.",
-            name
+            init_instances.join("`, `")
         );
-    } else {
-        info!("Okay. You can always add a new instance later.");
     }
 
+    let tree_url = options.tree_url.as_deref().unwrap_or(GIT_TREE_URL);
+    // fail before anything is done if the tree could not be cloned later
+    if !Path::new("TREE").is_dir() {
+        ensure_reachable(tree_url, "Cloning the tree")?;
+    }
     info!("Initializing workspace...");
     ciel_init()?;
     info!("Initializing container OS...");
@@ -54,37 +99,49 @@ pub fn onboarding(custom_tarball: Option<&String>) -> Result<()> {
         }
         None => {
             info!("Searching for latest AOSC OS buildkit release...");
-            auto_pick_tarball(&theme)?
+            if interactive {
+                auto_pick_tarball(&theme, &config)?
+            } else {
+                resolve_rootfs_source(&config, &TarballSelection::default())?
+            }
         }
     };
-    load_os(&tarball_url, tarball_sha256)?;
+    let options = LoadOsOptions {
+        online: true,
+        ..Default::default()
+    };
+    load_os_from(&tarball_url, tarball_sha256, &options)?;
     info!("Initializing ABBS tree...");
     if Path::new("TREE").is_dir() {
         warn!("TREE already exists, skipping this step...");
     } else {
         // if TREE is a file, then remove it
         fs::remove_file("TREE").ok();
-        download_git(GIT_TREE_URL, Path::new("TREE"))?;
+        download_git(tree_url, Path::new("TREE"), &options.tree)?;
+        if let Some(branch) = &options.tree.branch {
+            workspace::record_branch(branch)?;
+        }
     }
-    config::apply_config(CIEL_DIST_DIR, &config)?;
+    config::apply_config(CIEL_DIST_DIR, &config, None)?;
     info!("Applying configurations...");
-    fs::write(
-        Path::new(CIEL_DATA_DIR).join("config.toml"),
-        config.save_config()?,
-    )?;
+    config::write_config(&config)?;
     info!("Configurations applied.");
     let cwd = std::env::current_dir()?;
     if config.local_repo {
         info!("Setting up local repository ...");
-        refresh_repo(&cwd.join("OUTPUT"))?;
+        refresh_repo(&cwd.join(get_output_directory(&config, config.sep_mount)))?;
         info!("Local repository ready.");
     }
-    if let Some(init_instance) = init_instance {
+    for init_instance in init_instances {
         create_new_instance_fs(CIEL_INST_DIR, &init_instance)?;
+        metadata::write_metadata(&init_instance, &InstanceMetadata::new()?)?;
         info!("{}: instance initialized.", init_instance);
         if config.local_repo {
             mount_fs(&init_instance)?;
-            init_repo(&cwd.join("OUTPUT"), &cwd.join(&init_instance))?;
+            init_repo(
+                &cwd.join(get_output_directory(&config, config.sep_mount)),
+                &cwd.join(&init_instance),
+            )?;
             info!("{}: local repository initialized.", init_instance);
         }
     }
@@ -92,24 +149,51 @@ pub fn onboarding(custom_tarball: Option<&String>) -> Result<()> {
     Ok(())
 }
 
+/// Ask whether to create instances, the given instances are proposed by default
+fn ask_for_instances(
+    theme: &dyn dialoguer::theme::Theme,
+    instances: &[String],
+) -> Result<Vec<String>> {
+    if !Confirm::with_theme(theme)
+        .with_prompt("Do you want to add a new instance now?")
+        .default(!instances.is_empty())
+        .interact()?
+    {
+        return Ok(Vec::new());
+    }
+    let names: String = Input::with_theme(theme)
+        .with_prompt("Name of the instances (separated by spaces)")
+        .with_initial_text(instances.join(" "))
+        .validate_with(|input: &String| -> Result<(), String> {
+            if input.trim().is_empty() {
+                return Err("at least one instance is required".to_owned());
+            }
+            input
+                .split_whitespace()
+                .try_for_each(validate_instance_name)
+                .map_err(|e| e.to_string())
+        })
+        .interact_text()?;
+
+    Ok(names.split_whitespace().map(|x| x.to_owned()).collect())
+}
+
 #[inline]
-fn auto_pick_tarball(theme: &dyn dialoguer::theme::Theme) -> Result<(String, Option<String>)> {
-    if let Ok(tarball) = pick_latest_tarball() {
-        info!(
-            "Ciel has picked buildkit for {}, released on {}",
-            tarball.arch, tarball.date
-        );
-        Ok((
-            format!("https://releases.aosc.io/{}", tarball.path),
-            Some(tarball.sha256sum),
-        ))
-    } else {
-        warn!(
+fn auto_pick_tarball(
+    theme: &dyn dialoguer::theme::Theme,
+    config: &config::CielConfig,
+) -> Result<(String, Option<String>)> {
+    match resolve_rootfs_source(config, &TarballSelection::default()) {
+        Ok(source) => Ok(source),
+        Err(e) => {
+            warn!("{}", e);
+            warn!(
             "Ciel was unable to find a suitable buildkit release. Please specify the URL manually."
         );
-        let tarball_url = Input::<String>::with_theme(theme)
-            .with_prompt("Tarball URL")
-            .interact_text()?;
-        Ok((tarball_url, None))
+            let tarball_url = Input::<String>::with_theme(theme)
+                .with_prompt("Tarball URL")
+                .interact_text()?;
+            Ok((tarball_url, None))
+        }
     }
 }
@@ -6,17 +6,23 @@ use serde::{Deserialize, Serialize};
 use std::{
     fs::{self, File},
     io::{BufRead, BufReader, Write},
-    path::Path,
+    path::{Path, PathBuf},
+    process::Command,
     thread::sleep,
     time::{Duration, Instant},
 };
 use walkdir::WalkDir;
 
-use crate::{common::create_spinner, config, error, info, repo, warn};
+use crate::{common::create_spinner, config, dpkg, error, info, metadata, repo, warn};
 
 use super::{
-    container::{get_output_directory, mount_fs, rollback_container, run_in_container},
-    UPDATE_SCRIPT,
+    container::{
+        check_dpkg_state, container_down, get_output_directory, mount_fs, rollback_container,
+        run_in_container, start_container, wait_for_network,
+    },
+    for_each_instance,
+    hooks::{run_post_hook, run_pre_hook},
+    update_script, UpdateOptions,
 };
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -27,10 +33,14 @@ pub struct BuildCheckPoint {
     attempts: usize,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct BuildSettings {
     pub offline: bool,
     pub stage2: bool,
+    /// Name of the ACBS tree to look up the packages in
+    pub tree: Option<String>,
+    /// Repair the interrupted package manager without asking
+    pub auto_repair: bool,
 }
 
 pub fn load_build_checkpoint<P: AsRef<Path>>(path: P) -> Result<BuildCheckPoint> {
@@ -126,13 +136,38 @@ fn expand_package_list<S: AsRef<str>, I: IntoIterator<Item = S>>(packages: I) ->
     expanded
 }
 
+/// Returns the command prefix to invoke acbs-build,
+/// MAKEFLAGS is set for the tools that do not honor autobuild's own setting
+fn acbs_build_command(conf: &config::CielConfig, tree: Option<&str>) -> Vec<String> {
+    let mut cmd = Vec::new();
+    if let Some(jobs) = conf.build_jobs() {
+        cmd.push("/usr/bin/env".to_string());
+        cmd.push(format!("MAKEFLAGS=-j{}", jobs));
+    }
+    cmd.push("/bin/acbs-build".to_string());
+    if let Some(tree) = tree {
+        cmd.push("-t".to_string());
+        cmd.push(tree.to_string());
+    }
+
+    cmd
+}
+
 #[inline]
 fn package_build_inner<P: AsRef<Path>>(
     packages: &[String],
     instance: &str,
+    conf: &config::CielConfig,
     root: P,
+    acbs_build: &[String],
 ) -> Result<(i32, usize)> {
     let total = packages.len();
+    let update_options = UpdateOptions::from_config(conf);
+    let update_script = update_script(
+        &update_options,
+        update_options.resolve_package_manager()?,
+        &[],
+    );
     let hostname = gethostname().map_or_else(
         |_| "unknown".to_string(),
         |s| s.into_string().unwrap_or_else(|_| "unknown".to_string()),
@@ -152,9 +187,12 @@ fn package_build_inner<P: AsRef<Path>>(
         mount_fs(instance)?;
         info!("Refreshing local repository...");
         repo::init_repo(root.as_ref(), Path::new(instance))?;
+        start_container(instance)?;
+        wait_for_network(instance)?;
         let mut status = -1;
         for i in 1..=5 {
-            status = run_in_container(instance, &["/bin/bash", "-ec", UPDATE_SCRIPT]).unwrap_or(-1);
+            status =
+                run_in_container(instance, &["/bin/bash", "-ec", &update_script]).unwrap_or(-1);
             if status == 0 {
                 break;
             } else {
@@ -170,7 +208,10 @@ fn package_build_inner<P: AsRef<Path>>(
             error!("Failed to update the OS before building packages");
             return Ok((status, index));
         }
-        let status = run_in_container(instance, &["/bin/acbs-build", "--", package])?;
+        let mut cmd = acbs_build.to_vec();
+        cmd.push("--".to_string());
+        cmd.push(package.to_string());
+        let status = run_in_container(instance, &cmd)?;
         if status != 0 {
             error!("Build failed with status: {}", status);
             return Ok((status, index));
@@ -255,6 +296,8 @@ pub fn package_build<S: AsRef<str>, K: Clone + ExactSizeIterator<Item = S>>(
         return Err(anyhow!("Please configure this workspace first!"));
     }
     let conf = conf.unwrap();
+    conf.hooks.validate()?;
+    let _lock = metadata::lock_instance(instance, "build")?;
     let mut attempts = 1usize;
 
     let packages = if let Some(p) = state {
@@ -276,26 +319,80 @@ pub fn package_build<S: AsRef<str>, K: Clone + ExactSizeIterator<Item = S>>(
         info!("Running in offline mode. Network access disabled.");
     }
 
+    if let Some(tree) = &settings.tree {
+        if !conf.trees.iter().any(|x| &x.name == tree) {
+            return Err(anyhow!(
+                "Tree `{}` is not configured, available trees: {}",
+                tree,
+                conf.trees
+                    .iter()
+                    .map(|x| x.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+    }
+    let acbs_build = acbs_build_command(&conf, settings.tree.as_deref());
+
     if settings.stage2 {
         std::env::set_var("CIEL_STAGE2", "ON");
         info!("Running in stage 2 mode. ACBS and autobuild3 may behave differently.");
     }
 
+    let output_dir = get_output_directory(&conf, conf.for_instance(instance).sep_mount);
+    let root = std::env::current_dir()?.join(output_dir);
+    let mut hook_env = vec![
+        ("CIEL_INSTANCE", instance.to_owned()),
+        ("CIEL_PACKAGES", packages.join(" ")),
+        ("CIEL_OUTPUT_DIR", root.display().to_string()),
+    ];
+    run_pre_hook("pre-build", conf.hooks.pre_build.as_deref(), &hook_env)?;
+    let status = package_build_all(
+        instance,
+        &conf,
+        packages,
+        &acbs_build,
+        root,
+        attempts,
+        settings.auto_repair,
+    )?;
+    hook_env.push(("CIEL_EXIT_CODE", status.to_string()));
+    run_post_hook("post-build", conf.hooks.post_build.as_deref(), &hook_env);
+
+    Ok(status)
+}
+
+/// Build the packages in the prepared container, a check-point is created if the build failed
+fn package_build_all(
+    instance: &str,
+    conf: &config::CielConfig,
+    packages: Vec<String>,
+    acbs_build: &[String],
+    root: std::path::PathBuf,
+    attempts: usize,
+    auto_repair: bool,
+) -> Result<i32> {
     mount_fs(instance)?;
     rollback_container(instance)?;
+    if check_dpkg_state(Path::new(instance), auto_repair)? {
+        let status = run_in_container(instance, &["/bin/bash", "-ec", dpkg::REPAIR_COMMAND])?;
+        if status != 0 {
+            return Err(anyhow!("Failed to repair the package manager: {}", status));
+        }
+        warn!("The package manager in the base system is still broken, run `ciel update-os --auto-repair` to repair it.");
+    }
 
     if !conf.local_repo {
-        let mut cmd = vec!["/bin/acbs-build".to_string(), "--".to_string()];
+        let mut cmd = acbs_build.to_vec();
+        cmd.push("--".to_string());
         cmd.extend(packages.into_iter());
         let status = run_in_container(instance, &cmd)?;
         return Ok(status);
     }
 
-    let output_dir = get_output_directory(conf.sep_mount);
-    let root = std::env::current_dir()?.join(output_dir);
     let total = packages.len();
     let start = Instant::now();
-    let (exit_status, progress) = package_build_inner(&packages, instance, root)?;
+    let (exit_status, progress) = package_build_inner(&packages, instance, conf, root, acbs_build)?;
     if exit_status != 0 {
         let checkpoint = BuildCheckPoint {
             packages,
@@ -317,14 +414,32 @@ pub fn package_build<S: AsRef<str>, K: Clone + ExactSizeIterator<Item = S>>(
     Ok(0)
 }
 
+/// Returns the output directories (`OUTPUT` and `OUTPUT-*`) in the directory
+pub(crate) fn list_output_dirs(dir: &Path, include_default: bool) -> Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    for entry in WalkDir::new(dir).min_depth(1).max_depth(1) {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy();
+        if entry.file_type().is_dir()
+            && (name.starts_with("OUTPUT-") || (include_default && name == "OUTPUT"))
+        {
+            dirs.push(entry.into_path());
+        }
+    }
+
+    Ok(dirs)
+}
+
 /// Clean up output directories
 pub fn cleanup_outputs() -> Result<()> {
     let spinner = create_spinner("Removing output directories ...", 200);
-    for entry in WalkDir::new(".").max_depth(1) {
-        let entry = entry?;
-        if entry.file_type().is_dir() && entry.file_name().to_string_lossy().starts_with("OUTPUT-")
-        {
-            fs::remove_dir_all(entry.path())?;
+    let mut locations = vec![PathBuf::from(".")];
+    if let Some(dir) = config::read_config().ok().and_then(|c| c.output_dir) {
+        locations.push(dir);
+    }
+    for location in locations.iter().filter(|x| x.is_dir()) {
+        for dir in list_output_dirs(location, false)? {
+            fs::remove_dir_all(dir)?;
         }
     }
     if Path::new("./SRCS").is_dir() {
@@ -338,6 +453,60 @@ pub fn cleanup_outputs() -> Result<()> {
     Ok(())
 }
 
+/// Set the location of the output directories and move the existing output directories there
+pub fn migrate_output_dir(value: &str) -> Result<()> {
+    let mut conf = config::read_config_raw()?;
+    let old = conf
+        .output_dir
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("."));
+    config::set_config_value(&mut conf, "output-dir", value)?;
+    let new = conf
+        .output_dir
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("."));
+    let dirs = if old.is_dir() {
+        list_output_dirs(&old, true)?
+    } else {
+        Vec::new()
+    };
+    for dir in dirs.iter() {
+        let target = new.join(dir.file_name().unwrap_or_default());
+        if target.exists() {
+            return Err(anyhow!(
+                "{} already exists, please move or remove it first.",
+                target.display()
+            ));
+        }
+    }
+    if !dirs.is_empty() {
+        info!("Un-mounting all the instances...");
+        for_each_instance(&container_down)?;
+    }
+    fs::create_dir_all(&new)?;
+    for dir in dirs {
+        let target = new.join(dir.file_name().unwrap_or_default());
+        info!("Moving {} to {} ...", dir.display(), target.display());
+        // `mv` also works across filesystems
+        let status = Command::new("mv")
+            .arg("-T")
+            .arg(&dir)
+            .arg(&target)
+            .status()?;
+        if !status.success() {
+            return Err(anyhow!(
+                "Unable to move {} to {}, the configuration is not changed.",
+                dir.display(),
+                target.display()
+            ));
+        }
+    }
+    config::write_config(&conf)?;
+    info!("Output directories are now located in {}.", new.display());
+
+    Ok(())
+}
+
 #[test]
 fn test_time_format() {
     let test_dur = 3661;
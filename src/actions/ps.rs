@@ -0,0 +1,285 @@
+//! Listing the processes running in an instance (`ciel ps`)
+
+use anyhow::{anyhow, Result};
+use console::style;
+use indicatif::HumanBytes;
+use serde::Serialize;
+use std::{collections::HashMap, fs, io::Write, path::PathBuf, time::Duration};
+
+use crate::{common::format_duration, info, machine};
+
+/// Fields of /proc/<pid>/stat used in the listing
+#[derive(Debug, PartialEq, Eq)]
+struct ProcStat {
+    comm: String,
+    ppid: i32,
+    /// Time spent in the user and the kernel mode, in clock ticks
+    cpu_ticks: u64,
+    /// When the process started, in clock ticks since the host booted
+    start_ticks: u64,
+    /// Resident set size, in pages
+    rss_pages: u64,
+}
+
+/// A process in the instance, with its children
+#[derive(Debug, Serialize)]
+struct ProcessInfo {
+    /// PID in the container
+    pid: i32,
+    /// PID on the host
+    host_pid: i32,
+    #[serde(skip)]
+    host_ppid: i32,
+    /// Seconds since the process started
+    elapsed: u64,
+    /// Average CPU usage since the process started, like `ps`
+    cpu_percent: Option<f64>,
+    /// Resident set size, in bytes
+    memory: Option<u64>,
+    command: String,
+    children: Vec<ProcessInfo>,
+}
+
+/// Parse the content of /proc/<pid>/stat, the command name may contain spaces and parentheses
+fn parse_stat(content: &str) -> Option<ProcStat> {
+    let start = content.find('(')?;
+    let end = content.rfind(')')?;
+    let fields = content
+        .get(end + 1..)?
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    // the fields after the command name, starting from the 3rd one (state)
+    let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+
+    Some(ProcStat {
+        comm: content.get(start + 1..end)?.to_owned(),
+        ppid: fields.get(1)?.parse().ok()?,
+        cpu_ticks: field(14)? + field(15)?,
+        start_ticks: field(22)?,
+        rss_pages: field(24)?,
+    })
+}
+
+/// Returns the PID of the process in its own PID namespace
+fn pid_in_namespace(host_pid: i32) -> Option<i32> {
+    let status = fs::read_to_string(format!("/proc/{}/status", host_pid)).ok()?;
+    status
+        .lines()
+        .find_map(|x| x.strip_prefix("NSpid:"))?
+        .split_whitespace()
+        .last()?
+        .parse()
+        .ok()
+}
+
+fn pid_namespace(host_pid: i32) -> Option<PathBuf> {
+    fs::read_link(format!("/proc/{}/ns/pid", host_pid)).ok()
+}
+
+/// Returns the processes in the PID namespace of the leader (the container), unsorted
+fn list_processes(leader: i32) -> Result<Vec<ProcessInfo>> {
+    let namespace = pid_namespace(leader)
+        .ok_or_else(|| anyhow!("Unable to inspect the leader process {}.", leader))?;
+    let ticks_per_second = match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        x if x > 0 => x as u64,
+        _ => 100,
+    };
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    let uptime = fs::read_to_string("/proc/uptime")?
+        .split_whitespace()
+        .next()
+        .and_then(|x| x.parse::<f64>().ok())
+        .ok_or_else(|| anyhow!("Unable to read the uptime of the host."))?;
+    let mut processes = Vec::new();
+    for entry in fs::read_dir("/proc")? {
+        let host_pid = match entry?
+            .file_name()
+            .to_str()
+            .and_then(|x| x.parse::<i32>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if pid_namespace(host_pid).as_ref() != Some(&namespace) {
+            continue;
+        }
+        // the process may have exited in the meantime
+        let stat = match fs::read_to_string(format!("/proc/{}/stat", host_pid))
+            .ok()
+            .and_then(|x| parse_stat(&x))
+        {
+            Some(stat) => stat,
+            None => continue,
+        };
+        let cmdline = fs::read(format!("/proc/{}/cmdline", host_pid)).unwrap_or_default();
+        let command = cmdline
+            .split(|x| *x == 0)
+            .filter(|x| !x.is_empty())
+            .map(String::from_utf8_lossy)
+            .collect::<Vec<_>>()
+            .join(" ");
+        let elapsed = (uptime - stat.start_ticks as f64 / ticks_per_second as f64).max(0.0);
+        processes.push(ProcessInfo {
+            pid: pid_in_namespace(host_pid).unwrap_or(host_pid),
+            host_pid,
+            host_ppid: stat.ppid,
+            elapsed: elapsed as u64,
+            cpu_percent: Some(elapsed)
+                .filter(|x| *x > 0.0)
+                .map(|x| stat.cpu_ticks as f64 / ticks_per_second as f64 / x * 100.0),
+            memory: Some(page_size)
+                .filter(|x| *x > 0)
+                .map(|x| stat.rss_pages * x as u64),
+            command: if command.is_empty() {
+                format!("[{}]", stat.comm)
+            } else {
+                command
+            },
+            children: Vec::new(),
+        });
+    }
+
+    Ok(processes)
+}
+
+/// Arrange the processes into trees by their parents, the processes whose parent is not
+/// in the list (e.g. the leader) are the roots. The siblings are sorted by their PIDs
+fn build_tree(processes: Vec<ProcessInfo>) -> Vec<ProcessInfo> {
+    let mut children: HashMap<i32, Vec<ProcessInfo>> = HashMap::new();
+    let pids = processes.iter().map(|x| x.host_pid).collect::<Vec<_>>();
+    let mut roots = Vec::new();
+    for process in processes {
+        if pids.contains(&process.host_ppid) && process.host_ppid != process.host_pid {
+            children.entry(process.host_ppid).or_default().push(process);
+        } else {
+            roots.push(process);
+        }
+    }
+    fn attach(process: &mut ProcessInfo, children: &mut HashMap<i32, Vec<ProcessInfo>>) {
+        process.children = children.remove(&process.host_pid).unwrap_or_default();
+        process.children.sort_by_key(|x| x.pid);
+        for child in process.children.iter_mut() {
+            attach(child, children);
+        }
+    }
+    roots.sort_by_key(|x| x.pid);
+    for root in roots.iter_mut() {
+        attach(root, &mut children);
+    }
+
+    roots
+}
+
+fn write_tree<W: Write>(
+    formatter: &mut W,
+    processes: &[ProcessInfo],
+    prefix: &str,
+    top: bool,
+) -> Result<()> {
+    let missing = || style("-").dim().to_string();
+    for (i, process) in processes.iter().enumerate() {
+        let last = i + 1 == processes.len();
+        let (branch, indent) = match (top, last) {
+            (true, _) => ("", ""),
+            (false, false) => ("├─ ", "│  "),
+            (false, true) => ("└─ ", "   "),
+        };
+        writeln!(
+            formatter,
+            "{}\t{}\t{}\t{}\t{}\t{}{}{}",
+            process.pid,
+            process.host_pid,
+            format_duration(Duration::from_secs(process.elapsed)),
+            process
+                .cpu_percent
+                .map_or_else(missing, |x| format!("{:.1}%", x)),
+            process
+                .memory
+                .map_or_else(missing, |x| HumanBytes(x).to_string()),
+            prefix,
+            branch,
+            process.command
+        )?;
+        write_tree(
+            formatter,
+            &process.children,
+            &format!("{}{}", prefix, indent),
+            false,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Show the tree of the processes running in the instance, in JSON if `json` is set
+pub fn show_processes(instance: &str, json: bool) -> Result<()> {
+    use tabwriter::TabWriter;
+
+    let status = machine::instance_status(instance)?;
+    let processes = match status.leader {
+        Some(leader) => build_tree(list_processes(leader as i32)?),
+        None => Vec::new(),
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&processes)?);
+        return Ok(());
+    }
+    if processes.is_empty() {
+        info!("{}: nothing is running in the instance.", instance);
+        return Ok(());
+    }
+    let mut formatter = TabWriter::new(std::io::stderr());
+    writeln!(
+        &mut formatter,
+        "PID\tHOST PID\tELAPSED\tCPU\tMEMORY\tCOMMAND"
+    )?;
+    write_tree(&mut formatter, &processes, "", true)?;
+    formatter.flush()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_stat() {
+    let stat = parse_stat(
+        "1234 (tmux: server (1)) S 1 1234 1234 0 -1 4194560 1072 0 0 0 150 50 0 0 20 0 1 0 \
+         8000 9658368 1024 18446744073709551615 0 0 0 0 0 0 0 4096 0 0 0 0 17 3 0 0 0 0 0\n",
+    )
+    .unwrap();
+    assert_eq!(
+        stat,
+        ProcStat {
+            comm: "tmux: server (1)".to_owned(),
+            ppid: 1,
+            cpu_ticks: 200,
+            start_ticks: 8000,
+            rss_pages: 1024,
+        }
+    );
+    assert_eq!(parse_stat("1234 (bash) S 1"), None);
+}
+
+#[test]
+fn test_build_tree() {
+    let process = |host_pid, host_ppid| ProcessInfo {
+        pid: host_pid - 100,
+        host_pid,
+        host_ppid,
+        elapsed: 0,
+        cpu_percent: None,
+        memory: None,
+        command: String::new(),
+        children: Vec::new(),
+    };
+    let tree = build_tree(vec![
+        process(104, 102),
+        process(101, 50),
+        process(103, 101),
+        process(102, 101),
+    ]);
+    assert_eq!(tree.len(), 1);
+    assert_eq!(tree[0].pid, 1);
+    let children = tree[0].children.iter().map(|x| x.pid).collect::<Vec<_>>();
+    assert_eq!(children, vec![2, 3]);
+    assert_eq!(tree[0].children[0].children[0].pid, 4);
+}
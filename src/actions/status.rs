@@ -0,0 +1,341 @@
+//! Summary of the state of the workspace
+
+use anyhow::Result;
+use console::style;
+use git2::Repository;
+use indicatif::HumanBytes;
+use serde::Serialize;
+use std::{collections::HashMap, fs, io::Write, path::Path, time::Duration};
+
+use crate::{
+    common::{
+        create_spinner, disk_usage, format_duration, format_time, CIEL_DIST_DIR, CIEL_INST_DIR,
+    },
+    config, diagnose,
+    logging::color_bool,
+    machine, warn, workspace,
+};
+
+/// The os-release files in the base system, in the order of precedence
+const OS_RELEASE_FILES: &[&str] = &["etc/os-release", "usr/lib/os-release"];
+
+#[derive(Debug, Serialize)]
+struct TreeStatus {
+    url: Option<String>,
+    branch: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct OsStatus {
+    name: Option<String>,
+    version: Option<String>,
+    /// The build date of the OS on AOSC OS
+    build_id: Option<String>,
+    arch: Option<String>,
+    disk_usage: u64,
+    /// Time of the last successful `update-os`, in seconds since the epoch
+    last_update: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct InstanceStatus {
+    name: String,
+    /// `None` if the state of the instance could not be inspected
+    mounted: Option<bool>,
+    running: Option<bool>,
+    booted: Option<bool>,
+    disk_usage: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct WorkspaceStatus {
+    root: String,
+    /// `None` if the tree is not cloned yet
+    tree: Option<TreeStatus>,
+    /// `None` if the base system is not loaded yet
+    os: Option<OsStatus>,
+    maintainer: Option<String>,
+    instances: Vec<InstanceStatus>,
+    /// Inconsistencies between the instances, the mounts and the machines
+    issues: Vec<String>,
+}
+
+/// Parse the content of os-release(5), the values are unquoted
+fn parse_os_release(content: &str) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|x| x.strip_suffix('"'))
+                .or_else(|| value.strip_prefix('\'').and_then(|x| x.strip_suffix('\'')))
+                .unwrap_or(value);
+            fields.insert(key.trim().to_owned(), value.replace("\\\"", "\""));
+        }
+    }
+
+    fields
+}
+
+fn tree_status() -> Option<TreeStatus> {
+    let repo = Repository::open("TREE").ok()?;
+    let url = repo
+        .find_remote("origin")
+        .ok()
+        .and_then(|x| x.url().map(|x| x.to_owned()));
+    let branch = repo
+        .head()
+        .ok()
+        .and_then(|x| x.shorthand().map(|x| x.to_owned()));
+
+    Some(TreeStatus { url, branch })
+}
+
+fn os_status() -> Option<OsStatus> {
+    let dist = Path::new(CIEL_DIST_DIR);
+    if !dist.join("usr").is_dir() {
+        return None;
+    }
+    let release = OS_RELEASE_FILES
+        .iter()
+        .find_map(|x| fs::read_to_string(dist.join(x)).ok())
+        .map(|x| parse_os_release(&x))
+        .unwrap_or_default();
+
+    Some(OsStatus {
+        name: release
+            .get("NAME")
+            .or_else(|| release.get("PRETTY_NAME"))
+            .cloned(),
+        version: release
+            .get("VERSION")
+            .or_else(|| release.get("VERSION_ID"))
+            .cloned(),
+        build_id: release.get("BUILD_ID").cloned(),
+        arch: workspace::workspace_arch(),
+        disk_usage: disk_usage(dist),
+        last_update: workspace::last_update(),
+    })
+}
+
+fn instances_status() -> Result<Vec<InstanceStatus>> {
+    let usage = |name: &str| disk_usage(&Path::new(CIEL_INST_DIR).join(name));
+    let instances = match machine::list_instances() {
+        Ok(instances) => instances
+            .into_iter()
+            .map(|x| InstanceStatus {
+                disk_usage: usage(&x.name),
+                mounted: Some(x.mounted),
+                running: Some(x.running),
+                booted: x.booted,
+                name: x.name,
+            })
+            .collect(),
+        Err(e) => {
+            warn!("Unable to inspect the instances: {:#}", e);
+            machine::list_instances_simple()?
+                .into_iter()
+                .map(|name| InstanceStatus {
+                    disk_usage: usage(&name),
+                    mounted: None,
+                    running: None,
+                    booted: None,
+                    name,
+                })
+                .collect()
+        }
+    };
+
+    Ok(instances)
+}
+
+fn collect_status() -> Result<WorkspaceStatus> {
+    let spinner = create_spinner("Inspecting the workspace...", 200);
+    let maintainer = config::read_config()
+        .ok()
+        .map(|x| x.primary_maintainer().to_owned())
+        .filter(|x| !x.is_empty());
+    let status = WorkspaceStatus {
+        root: std::env::current_dir()?.display().to_string(),
+        tree: tree_status(),
+        os: os_status(),
+        maintainer,
+        instances: instances_status()?,
+        issues: diagnose::detect_instance_issues()
+            .map(|x| x.iter().map(|x| x.to_string()).collect())
+            .unwrap_or_default(),
+    };
+    spinner.finish_and_clear();
+
+    Ok(status)
+}
+
+fn format_bool(value: Option<bool>) -> &'static str {
+    match value {
+        Some(value) => color_bool(value),
+        None => "\x1b[2m-\x1b[0m",
+    }
+}
+
+fn display(status: &WorkspaceStatus) -> Result<()> {
+    use tabwriter::TabWriter;
+
+    let missing = |x: &str| style(x.to_owned()).dim().to_string();
+    let mut formatter = TabWriter::new(std::io::stderr());
+    writeln!(&mut formatter, "Workspace:\t{}", status.root)?;
+    let tree = match &status.tree {
+        Some(tree) => format!(
+            "{} ({})",
+            tree.url.as_deref().unwrap_or("no remote"),
+            tree.branch.as_deref().unwrap_or("detached")
+        ),
+        None => missing("not cloned"),
+    };
+    writeln!(&mut formatter, "Tree:\t{}", tree)?;
+    match &status.os {
+        Some(os) => {
+            let mut name = os.name.clone().unwrap_or_else(|| "Unknown OS".to_owned());
+            if let Some(version) = &os.version {
+                name.push_str(&format!(" {}", version));
+            }
+            if let Some(build_id) = &os.build_id {
+                name.push_str(&format!(" (build {})", build_id));
+            }
+            writeln!(&mut formatter, "Base system:\t{}", name)?;
+            writeln!(
+                &mut formatter,
+                "Architecture:\t{}",
+                os.arch.clone().unwrap_or_else(|| missing("unknown"))
+            )?;
+            writeln!(
+                &mut formatter,
+                "Base system size:\t{}",
+                HumanBytes(os.disk_usage)
+            )?;
+            let last_update = match os.last_update {
+                Some(timestamp) => format_time(timestamp),
+                None => missing("never"),
+            };
+            writeln!(&mut formatter, "Last updated:\t{}", last_update)?;
+        }
+        None => writeln!(&mut formatter, "Base system:\t{}", missing("not loaded"))?,
+    }
+    writeln!(
+        &mut formatter,
+        "Maintainer:\t{}",
+        status
+            .maintainer
+            .clone()
+            .unwrap_or_else(|| missing("not configured"))
+    )?;
+    writeln!(&mut formatter)?;
+    if status.instances.is_empty() {
+        writeln!(&mut formatter, "No instances.")?;
+    } else {
+        writeln!(&mut formatter, "NAME\tMOUNTED\tRUNNING\tBOOTED\tSIZE")?;
+        for instance in &status.instances {
+            writeln!(
+                &mut formatter,
+                "{}\t{}\t{}\t{}\t{}",
+                instance.name,
+                format_bool(instance.mounted),
+                format_bool(instance.running),
+                format_bool(instance.booted),
+                HumanBytes(instance.disk_usage)
+            )?;
+        }
+    }
+    formatter.flush()?;
+    for issue in &status.issues {
+        warn!("Inconsistency: {}", issue);
+    }
+    if !status.issues.is_empty() {
+        warn!("Run `ciel doctor --repair-instances` to repair them.");
+    }
+
+    Ok(())
+}
+
+/// Show the status of the current workspace, in JSON if `json` is set
+pub fn show_status(json: bool) -> Result<()> {
+    let status = collect_status()?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+
+    display(&status)
+}
+
+fn display_instance(status: &machine::InstanceStatus) -> Result<()> {
+    use tabwriter::TabWriter;
+
+    let missing = |x: &str| style(x.to_owned()).dim().to_string();
+    let mut formatter = TabWriter::new(std::io::stderr());
+    writeln!(&mut formatter, "Instance:\t{}", status.name)?;
+    writeln!(&mut formatter, "Machine:\t{}", status.machine_name)?;
+    writeln!(&mut formatter, "Mounted:\t{}", color_bool(status.mounted))?;
+    writeln!(
+        &mut formatter,
+        "State:\t{}",
+        status
+            .state
+            .clone()
+            .unwrap_or_else(|| missing("not started"))
+    )?;
+    writeln!(&mut formatter, "Booted:\t{}", color_bool(status.booted))?;
+    if let Some(leader) = status.leader {
+        writeln!(&mut formatter, "Leader:\t{}", leader)?;
+    }
+    if let (Some(since), Some(uptime)) = (status.since, status.uptime) {
+        writeln!(
+            &mut formatter,
+            "Since:\t{} ({} ago)",
+            format_time(since),
+            format_duration(Duration::from_secs(uptime))
+        )?;
+    }
+    if !status.addresses.is_empty() {
+        let addresses = status
+            .addresses
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        writeln!(&mut formatter, "Addresses:\t{}", addresses.join(", "))?;
+    }
+    if let Some(unit) = &status.unit {
+        writeln!(&mut formatter, "Unit:\t{}", unit)?;
+    }
+    formatter.flush()?;
+
+    Ok(())
+}
+
+/// Show the runtime status of the instance, in JSON if `json` is set
+pub fn show_instance_status(instance: &str, json: bool) -> Result<()> {
+    let status = machine::instance_status(instance)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+
+    display_instance(&status)
+}
+
+#[test]
+fn test_parse_os_release() {
+    let fields = parse_os_release(
+        "# AOSC OS\nNAME=\"AOSC OS\"\nVERSION='11.4.0'\nBUILD_ID=20240101\n\
+         PRETTY_NAME=\"AOSC OS \\\"Hotaru\\\"\"\nINVALID\n",
+    );
+    assert_eq!(fields["NAME"], "AOSC OS");
+    assert_eq!(fields["VERSION"], "11.4.0");
+    assert_eq!(fields["BUILD_ID"], "20240101");
+    assert_eq!(fields["PRETTY_NAME"], "AOSC OS \"Hotaru\"");
+    assert_eq!(fields.len(), 4);
+}
@@ -0,0 +1,333 @@
+//! Keeping the upper layers discarded by `ciel rollback` so that they can be restored
+//! (`ciel trash`)
+
+use anyhow::{anyhow, Result};
+use console::style;
+use indicatif::HumanBytes;
+use std::{
+    fs,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    cache::pick_evictions,
+    common::{disk_usage, format_duration, format_time, parse_size},
+    config, info, metadata,
+    overlayfs::{self, LayerManager},
+    warn, workspace,
+};
+
+use super::container::ensure_instance_exists;
+
+/// Where the discarded upper layers are kept, as `<instance>-<timestamp>`
+const CIEL_TRASH_DIR: &str = ".ciel/trash";
+/// How long the layers are kept in the trash if `trash-max-age` is not set
+pub const DEFAULT_TRASH_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// A layer in the trash
+#[derive(Debug, PartialEq, Eq)]
+struct TrashEntry {
+    /// `<instance>-<timestamp>`
+    id: String,
+    instance: String,
+    /// When the layer was discarded, in seconds since the epoch
+    saved_at: u64,
+}
+
+impl TrashEntry {
+    fn path(&self) -> PathBuf {
+        Path::new(CIEL_TRASH_DIR).join(&self.id)
+    }
+}
+
+/// Parse the ID of the entry, the instance names may contain `-` as well
+fn parse_trash_id(id: &str) -> Option<TrashEntry> {
+    let (instance, timestamp) = id.rsplit_once('-')?;
+    if instance.is_empty() {
+        return None;
+    }
+
+    Some(TrashEntry {
+        id: id.to_owned(),
+        instance: instance.to_owned(),
+        saved_at: timestamp.parse().ok()?,
+    })
+}
+
+/// Returns the layers in the trash, from the oldest to the newest
+fn list_entries() -> Result<Vec<TrashEntry>> {
+    let dir = match fs::read_dir(CIEL_TRASH_DIR) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = Vec::new();
+    for entry in dir {
+        let entry = entry?;
+        // the layers being purged (`.<id>.removing`) are not valid IDs
+        if let Some(parsed) = entry.file_name().to_str().and_then(parse_trash_id) {
+            if entry.file_type()?.is_dir() {
+                entries.push(parsed);
+            }
+        }
+    }
+    entries.sort_by(|a, b| (a.saved_at, &a.id).cmp(&(b.saved_at, &b.id)));
+
+    Ok(entries)
+}
+
+/// Remove the layer, it is renamed first so that a partly removed layer is never restored
+fn purge_layer(path: &Path) -> Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid trash entry: {}", path.display()))?;
+    let removing = path.with_file_name(format!(".{}.removing", name.to_string_lossy()));
+    fs::rename(path, &removing)?;
+    fs::remove_dir_all(&removing)?;
+
+    Ok(())
+}
+
+/// Pick the layers to purge, the entries are `(saved at, size, path)`: the ones saved longer
+/// than `max_age` ago, and then the oldest ones until the total size is within `max_size`.
+/// `keep` is never picked
+fn pick_purges(
+    entries: Vec<(SystemTime, u64, PathBuf)>,
+    now: SystemTime,
+    max_age: Duration,
+    max_size: Option<u64>,
+    keep: &Path,
+) -> Vec<PathBuf> {
+    let (expired, kept): (Vec<_>, Vec<_>) = entries.into_iter().partition(|(saved_at, _, path)| {
+        path != keep && now.duration_since(*saved_at).map_or(false, |x| x > max_age)
+    });
+    let mut purges = expired.into_iter().map(|x| x.2).collect::<Vec<_>>();
+    if let Some(max_size) = max_size {
+        purges.extend(pick_evictions(kept, max_size, keep));
+    }
+
+    purges
+}
+
+/// Returns `trash-max-age` and `trash-max-size` of the workspace
+fn trash_limits() -> Result<(Duration, Option<u64>)> {
+    // the workspace may not be configured yet
+    let config = config::read_config().unwrap_or_default();
+    let max_size = match &config.trash_max_size {
+        Some(size) => {
+            Some(parse_size(size).ok_or_else(|| anyhow!("Invalid trash-max-size `{}`", size))?)
+        }
+        None => None,
+    };
+
+    Ok((
+        config.trash_max_age.unwrap_or(DEFAULT_TRASH_MAX_AGE),
+        max_size,
+    ))
+}
+
+/// Purge the layers exceeding `trash-max-age` or `trash-max-size` from the trash,
+/// the layer just saved (`keep`) is left alone
+pub(super) fn prune_trash(keep: Option<&str>) -> Result<()> {
+    let (max_age, max_size) = trash_limits()?;
+    let entries = list_entries()?
+        .into_iter()
+        .map(|x| {
+            let path = x.path();
+            // the sizes are only needed for the size limit
+            let size = if max_size.is_some() {
+                disk_usage(&path)
+            } else {
+                0
+            };
+            (UNIX_EPOCH + Duration::from_secs(x.saved_at), size, path)
+        })
+        .collect();
+    let keep = keep.map_or_else(PathBuf::new, |x| Path::new(CIEL_TRASH_DIR).join(x));
+    for path in pick_purges(entries, SystemTime::now(), max_age, max_size, &keep) {
+        info!(
+            "Purging {} from the trash (trash-max-age or trash-max-size exceeded)...",
+            path.display()
+        );
+        purge_layer(&path)?;
+    }
+
+    Ok(())
+}
+
+/// Roll back the un-mounted instance, its upper layer is moved into the trash unless `purge`
+/// is set, the trash is disabled (`trash-max-age = 0`) or there is nothing worth keeping.
+/// Returns the ID of the layer in the trash
+pub(super) fn discard_upper_layer(
+    instance: &str,
+    man: &mut dyn LayerManager,
+    purge: bool,
+) -> Result<Option<String>> {
+    let disabled = trash_limits()?.0.is_zero();
+    // keep the layer if unsure
+    if purge || disabled || !man.has_changes().unwrap_or(true) {
+        man.rollback()?;
+        return Ok(None);
+    }
+    fs::create_dir_all(CIEL_TRASH_DIR)?;
+    let mut saved_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    // rolled back more than once in a second, e.g. by a script
+    while Path::new(CIEL_TRASH_DIR)
+        .join(format!("{}-{}", instance, saved_at))
+        .exists()
+    {
+        saved_at += 1;
+    }
+    let id = format!("{}-{}", instance, saved_at);
+    man.rollback_to(&Path::new(CIEL_TRASH_DIR).join(&id))
+        .map_err(|e| {
+            match e.downcast_ref::<std::io::Error>().and_then(|x| x.raw_os_error()) {
+                Some(libc::EXDEV) => anyhow!(
+                    "{}: unable to move the upper layer into {} on another filesystem, use `--purge` to remove it instead.",
+                    instance,
+                    CIEL_TRASH_DIR
+                ),
+                _ => e,
+            }
+        })?;
+
+    Ok(Some(id))
+}
+
+/// List the layers in the trash
+pub fn list_trash() -> Result<()> {
+    use tabwriter::TabWriter;
+
+    let entries = list_entries()?;
+    if entries.is_empty() {
+        info!("The trash is empty.");
+        return Ok(());
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut formatter = TabWriter::new(std::io::stderr());
+    writeln!(&mut formatter, "ID\tINSTANCE\tSAVED\tAGE\tSIZE")?;
+    for entry in entries {
+        writeln!(
+            &mut formatter,
+            "{}\t{}\t{}\t{}\t{}",
+            entry.id,
+            entry.instance,
+            format_time(entry.saved_at),
+            format_duration(Duration::from_secs(now.saturating_sub(entry.saved_at))),
+            HumanBytes(disk_usage(&entry.path()))
+        )?;
+    }
+    formatter.flush()?;
+
+    Ok(())
+}
+
+/// Put the layer in the trash back as the upper layer of its instance, which must be
+/// un-mounted and have no changes
+pub fn restore_trash(id: &str) -> Result<()> {
+    let entry = parse_trash_id(id)
+        .filter(|x| x.path().is_dir())
+        .ok_or_else(|| anyhow!("`{}` is not in the trash, see `ciel trash list`.", id))?;
+    let instance = entry.instance.as_str();
+    ensure_instance_exists(instance)?;
+    let _lock = metadata::lock_instance(instance, "restore")?;
+    let man = &mut *overlayfs::get_overlayfs_manager(instance)?;
+    if man.is_mounted(&std::env::current_dir()?.join(instance))? {
+        return Err(anyhow!(
+            "{}: instance is mounted, un-mount it first (`ciel down -i {}`).",
+            instance,
+            instance
+        ));
+    }
+    if man.has_changes()? {
+        return Err(anyhow!(
+            "{}: instance has changes, roll it back first (`ciel rollback -i {}`, the changes are moved into the trash as well).",
+            instance,
+            instance
+        ));
+    }
+    match workspace::last_update() {
+        Some(updated) if updated > entry.saved_at => warn!(
+            "The OS has been updated since {} was saved, the restored files may be out of date.",
+            id
+        ),
+        _ => warn!(
+            "The base system may have changed since {} was saved (e.g. another instance was committed), check the instance before committing it.",
+            id
+        ),
+    }
+    man.restore_from(&entry.path())?;
+    info!("{}: restored {}.", instance, style(id).cyan());
+
+    Ok(())
+}
+
+/// Remove all the layers in the trash
+pub fn empty_trash() -> Result<()> {
+    let dir = match fs::read_dir(CIEL_TRASH_DIR) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            info!("The trash is empty.");
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut freed = 0;
+    for entry in dir {
+        let path = entry?.path();
+        freed += disk_usage(&path);
+        // also the ones left over by an interrupted purge
+        if path.is_dir() {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+    }
+    info!("Emptied the trash, {} freed.", HumanBytes(freed));
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_trash_id() {
+    assert_eq!(
+        parse_trash_id("main-x-1700000000"),
+        Some(TrashEntry {
+            id: "main-x-1700000000".to_owned(),
+            instance: "main-x".to_owned(),
+            saved_at: 1700000000,
+        })
+    );
+    assert_eq!(parse_trash_id("main"), None);
+    assert_eq!(parse_trash_id("-1700000000"), None);
+    assert_eq!(parse_trash_id("main-latest"), None);
+}
+
+#[test]
+fn test_pick_purges() {
+    let day = Duration::from_secs(86400);
+    let now = UNIX_EPOCH + day * 30;
+    let entries = || {
+        vec![
+            (now - day * 10, 100, PathBuf::from("a")),
+            (now - day * 2, 100, PathBuf::from("b")),
+            (now - day, 100, PathBuf::from("c")),
+            (now, 100, PathBuf::from("d")),
+        ]
+    };
+    assert_eq!(
+        pick_purges(entries(), now, day * 7, None, Path::new("d")),
+        vec![PathBuf::from("a")]
+    );
+    assert_eq!(
+        pick_purges(entries(), now, day * 7, Some(150), Path::new("d")),
+        vec![PathBuf::from("a"), PathBuf::from("b"), PathBuf::from("c")]
+    );
+    // the layer just saved is kept even if it is too large or too old
+    assert_eq!(
+        pick_purges(entries(), now, Duration::ZERO, Some(0), Path::new("d")),
+        vec![PathBuf::from("a"), PathBuf::from("b"), PathBuf::from("c")]
+    );
+}
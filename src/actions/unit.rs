@@ -0,0 +1,218 @@
+//! Generating the systemd units keeping the instances up (`ciel generate-unit`)
+
+use anyhow::{anyhow, Result};
+use console::style;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{config, info, machine, warn};
+
+use super::container::ensure_instance_exists;
+
+/// Where the system units are installed
+const SYSTEM_UNIT_DIR: &str = "/etc/systemd/system";
+
+/// Options of [generate_unit]
+#[derive(Debug, Default, Clone)]
+pub struct UnitOptions {
+    /// Run in the instance after it is booted, the service stops when it exits
+    pub command: Vec<String>,
+    /// Generate a unit of the user's service manager instead
+    pub user: bool,
+    /// Install the unit instead of printing it
+    pub install: bool,
+    /// Overwrite the installed unit if it is different
+    pub force: bool,
+}
+
+/// Quote the argument for the command lines in the units, see systemd.service(5)
+fn quote_exec_arg(arg: &str) -> String {
+    // `$` is an environment variable and `%` a specifier otherwise
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    if !escaped.is_empty()
+        && !escaped
+            .chars()
+            .any(|x| x.is_whitespace() || matches!(x, '"' | '\'' | '\\' | ';'))
+    {
+        return escaped;
+    }
+    let escaped = escaped
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+
+    format!("\"{}\"", escaped)
+}
+
+fn exec_line<S: AsRef<str>>(exe: &Path, args: &[S]) -> String {
+    let mut line = quote_exec_arg(&exe.to_string_lossy());
+    for arg in args {
+        line.push(' ');
+        line.push_str(&quote_exec_arg(arg.as_ref()));
+    }
+
+    line
+}
+
+/// Returns the unit starting the instance with ciel (at `exe`) in the workspace, and then
+/// running the command in it if any. The instance is stopped when the service stops
+fn render_unit(
+    exe: &Path,
+    workspace: &Path,
+    instance: &str,
+    command: &[String],
+    user: bool,
+) -> String {
+    // `%` starts a specifier
+    let workspace = workspace.to_string_lossy().replace('%', "%%");
+    let mut unit = format!(
+        "# Generated by `ciel generate-unit`\n[Unit]\nDescription=ciel instance {} in {}\n",
+        instance, workspace
+    );
+    // the user's service manager cannot depend on the system units
+    if user {
+        unit.push_str("After=network-online.target\n");
+    } else {
+        unit.push_str("Requires=systemd-machined.service\n");
+        unit.push_str("After=systemd-machined.service network-online.target\n");
+        unit.push_str("Wants=network-online.target\n");
+    }
+    unit.push_str("\n[Service]\n");
+    unit.push_str(&format!("WorkingDirectory={}\n", workspace));
+    let start = exec_line(exe, &["start", "-i", instance]);
+    let stop = exec_line(exe, &["stop", "-i", instance]);
+    if command.is_empty() {
+        unit.push_str("Type=oneshot\nRemainAfterExit=yes\n");
+        unit.push_str(&format!("ExecStart={}\n", start));
+        unit.push_str(&format!("ExecStop={}\n", stop));
+    } else {
+        let mut run = vec!["run", "-i", instance, "--"];
+        run.extend(command.iter().map(|x| x.as_str()));
+        unit.push_str("Type=exec\n");
+        unit.push_str(&format!("ExecStartPre={}\n", start));
+        unit.push_str(&format!("ExecStart={}\n", exec_line(exe, &run)));
+        // also run if the command failed
+        unit.push_str(&format!("ExecStopPost={}\n", stop));
+    }
+    // booting the instance is bounded by `boot-timeout` of ciel itself
+    unit.push_str("TimeoutStartSec=infinity\n");
+    unit.push_str(&format!(
+        "\n[Install]\nWantedBy={}\n",
+        if user {
+            "default.target"
+        } else {
+            "multi-user.target"
+        }
+    ));
+
+    unit
+}
+
+fn unit_dir(user: bool) -> Result<PathBuf> {
+    if !user {
+        return Ok(PathBuf::from(SYSTEM_UNIT_DIR));
+    }
+    let home = config::config_home()
+        .ok_or_else(|| anyhow!("Unable to find the configuration directory of the user."))?;
+
+    Ok(home.join("systemd/user"))
+}
+
+/// Write the unit, an existing unit with different content is only overwritten if `force` is set
+fn install_unit(path: &Path, unit: &str, user: bool, force: bool) -> Result<()> {
+    if let Ok(existing) = fs::read_to_string(path) {
+        if existing == unit {
+            info!("{} is up to date.", path.display());
+            return Ok(());
+        }
+        if !force {
+            config::print_diff(&config::unified_diff(
+                &existing,
+                unit,
+                &path.to_string_lossy(),
+                &path.to_string_lossy(),
+            ));
+            return Err(anyhow!(
+                "{} already exists and is different, use `--force` to overwrite it.",
+                path.display()
+            ));
+        }
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, unit)?;
+    info!("Installed {}.", style(path.display()).cyan());
+    let mut reload = Command::new("systemctl");
+    if user {
+        reload.arg("--user");
+    }
+    match reload.arg("daemon-reload").status() {
+        Ok(status) if status.success() => (),
+        _ => warn!("Unable to reload systemd, run `systemctl daemon-reload` to pick up the unit."),
+    }
+
+    Ok(())
+}
+
+/// Print (or install) the systemd unit booting the instance (and running the command in it)
+/// with ciel in the current workspace
+pub fn generate_unit(instance: &str, options: &UnitOptions) -> Result<()> {
+    ensure_instance_exists(instance)?;
+    let exe = std::env::current_exe()?;
+    let workspace = std::env::current_dir()?;
+    let unit = render_unit(&exe, &workspace, instance, &options.command, options.user);
+    if !options.install {
+        print!("{}", unit);
+        return Ok(());
+    }
+    let name = format!(
+        "ciel-{}.service",
+        machine::workspace_machine_name(instance)?
+    );
+    install_unit(
+        &unit_dir(options.user)?.join(&name),
+        &unit,
+        options.user,
+        options.force,
+    )?;
+    info!(
+        "Run `systemctl{} enable --now {}` to start it.",
+        if options.user { " --user" } else { "" },
+        name
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_render_unit() {
+    let unit = render_unit(
+        Path::new("/usr/bin/ciel"),
+        Path::new("/srv/ciel"),
+        "qa",
+        &["qa-bot".to_owned(), "--name".to_owned(), "a b$".to_owned()],
+        false,
+    );
+    assert!(unit.contains("Requires=systemd-machined.service\n"));
+    assert!(unit.contains("WorkingDirectory=/srv/ciel\n"));
+    assert!(unit.contains("ExecStartPre=/usr/bin/ciel start -i qa\n"));
+    assert!(unit.contains("ExecStart=/usr/bin/ciel run -i qa -- qa-bot --name \"a b$$\"\n"));
+    assert!(unit.contains("ExecStopPost=/usr/bin/ciel stop -i qa\n"));
+    assert!(unit.contains("WantedBy=multi-user.target\n"));
+
+    let unit = render_unit(
+        Path::new("/usr/bin/ciel"),
+        Path::new("/srv/ciel"),
+        "qa",
+        &[],
+        true,
+    );
+    assert!(!unit.contains("systemd-machined"));
+    assert!(unit.contains("Type=oneshot\nRemainAfterExit=yes\n"));
+    assert!(unit.contains("ExecStop=/usr/bin/ciel stop -i qa\n"));
+    assert!(unit.contains("WantedBy=default.target\n"));
+}
@@ -0,0 +1,247 @@
+//! Cache of the OS tarballs shared by all the workspaces (`~/.cache/ciel/tarballs`)
+
+use crate::{
+    common::sha256sum_file,
+    config, info,
+    network::{download_file_progress, DownloadOptions},
+    warn,
+};
+use anyhow::{anyhow, Result};
+use console::style;
+use indicatif::HumanBytes;
+use nix::sys::{
+    stat::utimes,
+    time::{TimeVal, TimeValLike},
+};
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Marks the files being downloaded (`.<entry>.<pid>.download`), they are not cache entries
+const DOWNLOADING_SUFFIX: &str = ".download";
+
+/// Returns the tarball cache directory (under `$XDG_CACHE_HOME` or `~/.cache`)
+pub fn tarball_cache_dir() -> Option<PathBuf> {
+    let cache_home = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|x| Path::new(&x).join(".cache")))?;
+
+    Some(cache_home.join("ciel/tarballs"))
+}
+
+/// Name of the cache entry, keyed by the checksum and the file name in the URL
+/// so that the same tarball fetched from different mirrors is only cached once
+fn cache_entry_name(url: &str, sha256: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+
+    format!("{}-{}", sha256.trim().to_ascii_lowercase(), file_name)
+}
+
+/// Returns the process downloading the file if it is an in-progress download
+fn download_owner(file_name: &str) -> Option<u32> {
+    let name = file_name.strip_prefix('.')?;
+    let (name, _) = name.split_once(DOWNLOADING_SUFFIX)?;
+
+    name.rsplit('.').next()?.parse().ok()
+}
+
+/// Returns whether the file is left by an interrupted download
+fn is_stale_download(file_name: &str) -> bool {
+    match download_owner(file_name) {
+        Some(pid) => !Path::new("/proc").join(pid.to_string()).exists(),
+        None => false,
+    }
+}
+
+/// Check the cached tarball, returns whether it is usable. A corrupted entry is removed
+fn validate_entry(entry: &Path, sha256: &str) -> Result<bool> {
+    if !entry.is_file() {
+        return Ok(false);
+    }
+    info!("Found the tarball in the cache, verifying...");
+    if !sha256sum_file(entry)?.eq_ignore_ascii_case(sha256.trim()) {
+        warn!("The cached tarball is corrupted, downloading it again...");
+        fs::remove_file(entry)?;
+        return Ok(false);
+    }
+    // the modification time tells the least recently used entries
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let now = TimeVal::microseconds(now.as_micros() as i64);
+    utimes(entry, &now, &now)?;
+
+    Ok(true)
+}
+
+/// Returns the path to the cached tarball, it is downloaded into the cache on a miss
+/// (or if the cached one is corrupted). Returns `None` if the cache is disabled
+/// (`tarball-cache-size = 0` in the user-level configuration) or unavailable
+pub fn fetch_cached_tarball(
+    url: &str,
+    sha256: &str,
+    options: &DownloadOptions,
+) -> Result<Option<PathBuf>> {
+    let limit = config::read_user_config()?.tarball_cache_size()?;
+    let dir = match tarball_cache_dir() {
+        Some(dir) if limit > 0 => dir,
+        _ => return Ok(None),
+    };
+    if let Err(e) = fs::create_dir_all(&dir) {
+        warn!(
+            "Unable to create the tarball cache {}: {}",
+            dir.display(),
+            e
+        );
+        return Ok(None);
+    }
+    let name = cache_entry_name(url, sha256);
+    let entry = dir.join(&name);
+    if validate_entry(&entry, sha256)? {
+        return Ok(Some(entry));
+    }
+    // downloaded to a name private to this process and then linked into place,
+    // so that the concurrent ciel processes never see a partial entry
+    let temp = dir.join(format!(".{}.{}{}", name, process::id(), DOWNLOADING_SUFFIX));
+    let temp_str = temp
+        .to_str()
+        .ok_or_else(|| anyhow!("Invalid cache path: {}", temp.display()))?;
+    info!("Downloading base OS tarball into the cache...");
+    download_file_progress(url, temp_str, Some(sha256), options)?;
+    let linked = fs::hard_link(&temp, &entry);
+    fs::remove_file(&temp)?;
+    match linked {
+        Ok(()) => (),
+        // the same tarball has been cached by another process in the meantime
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+        Err(e) => return Err(e.into()),
+    }
+    prune_cache(&dir, limit, &entry)?;
+
+    Ok(Some(entry))
+}
+
+/// Pick the least recently used entries to remove so that the total size is within the limit,
+/// the entries are `(last use, size, path)`. `keep` is never picked
+pub fn pick_evictions(
+    mut entries: Vec<(SystemTime, u64, PathBuf)>,
+    limit: u64,
+    keep: &Path,
+) -> Vec<PathBuf> {
+    let mut total: u64 = entries.iter().map(|x| x.1).sum();
+    entries.sort();
+    let mut evictions = Vec::new();
+    for (_, size, path) in entries {
+        if total <= limit {
+            break;
+        }
+        if path != keep {
+            total -= size;
+            evictions.push(path);
+        }
+    }
+
+    evictions
+}
+
+/// Remove the left-over downloads and the least recently used entries exceeding the size limit
+fn prune_cache(dir: &Path, limit: u64, keep: &Path) -> Result<()> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if is_stale_download(&file_name) {
+            fs::remove_file(entry.path()).ok();
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_file() && !file_name.starts_with('.') {
+            entries.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+    }
+    for path in pick_evictions(entries, limit, keep) {
+        info!(
+            "Removing {} from the tarball cache (tarball-cache-size exceeded)...",
+            path.display()
+        );
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove all the cached tarballs, the downloads in progress are left alone
+pub fn clean_tarball_cache() -> Result<()> {
+    let dir = match tarball_cache_dir() {
+        Some(dir) if dir.is_dir() => dir,
+        _ => {
+            info!("The tarball cache is empty.");
+            return Ok(());
+        }
+    };
+    let mut freed = 0;
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if download_owner(&file_name).is_some() && !is_stale_download(&file_name) {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            fs::remove_file(entry.path())?;
+            freed += metadata.len();
+        }
+    }
+    info!(
+        "The tarball cache has been emptied, {} freed.",
+        HumanBytes(freed)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_cache_entry_name() {
+    let sha256 = "A".repeat(64);
+    assert_eq!(
+        cache_entry_name(
+            "https://releases.aosc.io/os-amd64/buildkit/aosc-os_buildkit_20230101_amd64.tar.xz?mirror=1",
+            &sha256
+        ),
+        format!(
+            "{}-aosc-os_buildkit_20230101_amd64.tar.xz",
+            "a".repeat(64)
+        )
+    );
+    assert_eq!(download_owner(".abc-os.tar.xz.1234.download"), Some(1234));
+    assert_eq!(
+        download_owner(".abc-os.tar.xz.1234.download.part"),
+        Some(1234)
+    );
+    assert_eq!(download_owner("abc-os.tar.xz"), None);
+}
+
+#[test]
+fn test_pick_evictions() {
+    let time = |secs| UNIX_EPOCH + std::time::Duration::from_secs(secs);
+    let entries = vec![
+        (time(300), 100, PathBuf::from("newest")),
+        (time(100), 100, PathBuf::from("oldest")),
+        (time(200), 100, PathBuf::from("older")),
+    ];
+    assert!(pick_evictions(entries.clone(), 300, Path::new("newest")).is_empty());
+    assert_eq!(
+        pick_evictions(entries.clone(), 150, Path::new("newest")),
+        vec![PathBuf::from("oldest"), PathBuf::from("older")]
+    );
+    assert_eq!(
+        pick_evictions(entries, 150, Path::new("oldest")),
+        vec![PathBuf::from("older"), PathBuf::from("newest")]
+    );
+}
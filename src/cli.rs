@@ -35,6 +35,76 @@ pub fn build_cli() -> Command {
         .num_args(1)
         .env("CIEL_INST")
         .action(clap::ArgAction::Set);
+    let jobs_arg = Arg::new("jobs")
+        .short('j')
+        .long("jobs")
+        .num_args(1)
+        .value_parser(clap::value_parser!(usize))
+        .requires("all")
+        .help("Number of the instances operated on at the same time (default: 4)");
+    let depth_arg = Arg::new("depth")
+        .long("depth")
+        .num_args(1)
+        .value_parser(clap::value_parser!(u32).range(1..))
+        .help("Only fetch the latest N commits of the tree (a shallow clone, requires git)");
+    let branch_arg = Arg::new("branch")
+        .long("branch")
+        .num_args(1)
+        .help("Check out this branch of the tree instead of the default one");
+    let no_inhibit_arg = Arg::new("no-inhibit")
+        .long("no-inhibit")
+        .action(clap::ArgAction::SetTrue)
+        .help("Do not keep the host from sleeping (or shutting down right away) until it is done");
+    let tty_arg = Arg::new("tty")
+        .long("tty")
+        .short('t')
+        .action(clap::ArgAction::SetTrue)
+        .conflicts_with("no-tty")
+        .help("Always allocate a pseudo terminal for the command");
+    let no_tty_arg = Arg::new("no-tty")
+        .long("no-tty")
+        .short('T')
+        .action(clap::ArgAction::SetTrue)
+        .help("Never allocate a pseudo terminal, stdout and stderr are kept separated (the default if stdin or stdout is not a terminal)");
+    let env_arg = Arg::new("env")
+        .short('e')
+        .long("env")
+        .value_name("KEY[=VALUE]")
+        .action(clap::ArgAction::Append)
+        .help("Set an environment variable of the command (the value of ciel's environment if not given), overrides build-env");
+    let env_file_arg = Arg::new("env-file")
+        .long("env-file")
+        .value_name("PATH")
+        .action(clap::ArgAction::Append)
+        .help("Read the environment variables of the command from the file (KEY=VALUE per line)");
+    let workdir_arg = Arg::new("workdir")
+        .short('w')
+        .long("workdir")
+        .value_name("PATH")
+        .help("Working directory of the command in the container (an absolute path)");
+    let here_arg = Arg::new("here")
+        .long("here")
+        .action(clap::ArgAction::SetTrue)
+        .conflicts_with("workdir")
+        .help("Use the current directory in the tree as the working directory in the container");
+    let mount_arg = Arg::new("mount")
+        .long("mount")
+        .value_name("HOST:CONTAINER[:ro]")
+        .action(clap::ArgAction::Append)
+        .help("Bind mount a host path into the instance for this command only (never committed)");
+    let quick_arg = Arg::new("quick")
+        .long("quick")
+        .action(clap::ArgAction::SetTrue)
+        .help("Run the command without booting the instance if it is not started (faster, but no services are running, e.g. for `dpkg -l`)");
+    // `--flag` alone means true, `--flag=false` overrides a true default
+    let bool_arg = |name: &'static str| {
+        Arg::new(name)
+            .long(name)
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("true")
+            .value_parser(clap::builder::BoolishValueParser::new())
+    };
     Command::new("ciel")
         .version(env!("CARGO_PKG_VERSION"))
         .about("CIEL! is a nspawn container manager")
@@ -42,18 +112,57 @@ pub fn build_cli() -> Command {
         .subcommand(Command::new("version").about("Display the version of CIEL!"))
         .subcommand(Command::new("init")
             .arg(Arg::new("upgrade").long("upgrade").action(clap::ArgAction::SetTrue).help("Upgrade Ciel workspace from an older version"))
+            .arg(Arg::new("dry-run").long("dry-run").action(clap::ArgAction::SetTrue).requires("upgrade").help("Only show how the workspace would be upgraded"))
             .about("Initialize the work directory"))
         .subcommand(
             Command::new("load-os")
                 .arg(Arg::new("url").help("URL or path to the tarball"))
+                .arg(Arg::new("sha256").long("sha256").value_name("HEX").help("Expected SHA-256 checksum of the tarball (read from the published checksum file if not specified)"))
+                .arg(Arg::new("variant").long("variant").conflicts_with("url").help("Variant of the tarball picked from the mirror (default: BuildKit)"))
+                .arg(Arg::new("arch").long("arch").conflicts_with("url").help("Architecture of the tarball picked from the mirror (default: the host architecture)"))
+                .arg(Arg::new("date").long("date").value_name("YYYYMMDD").conflicts_with("url").help("Release date of the tarball picked from the mirror (default: the latest)"))
+                .arg(Arg::new("fastest-mirror").long("fastest-mirror").action(clap::ArgAction::SetTrue).conflicts_with("url").help("Download from the fastest release mirror (the benchmark results are reused for a day)"))
+                .arg(Arg::new("keep-tarball").long("keep-tarball").action(clap::ArgAction::SetTrue).help("Save the tarball before extracting it, so that an interrupted download can be resumed"))
+                .arg(Arg::new("no-cache").long("no-cache").action(clap::ArgAction::SetTrue).help("Download the tarball again instead of using the tarball cache"))
+                .arg(Arg::new("no-verify").long("no-verify").action(clap::ArgAction::SetTrue).conflicts_with("sha256").help("Do not verify the checksum of the tarball (dangerous)"))
+                .arg(Arg::new("online").long("online").action(clap::ArgAction::SetTrue).help("Allow network access even if isolate-network is enabled"))
+                .arg(Arg::new("stop-all").long("stop-all").action(clap::ArgAction::SetTrue).help("Stop and un-mount the instances in use without asking"))
+                .arg(Arg::new("restore").long("restore").action(clap::ArgAction::SetTrue).help("Roll back and mount the stopped instances again afterwards without asking"))
+                .arg(no_inhibit_arg.clone())
                 .about("Unpack OS tarball or fetch the latest BuildKit from the repository"),
         )
-        .subcommand(Command::new("update-os").about("Update the OS in the container"))
+        .subcommand(
+            Command::new("update-os")
+                .arg(Arg::new("online").long("online").action(clap::ArgAction::SetTrue).help("Allow network access even if isolate-network is enabled"))
+                .arg(Arg::new("full-upgrade").long("full-upgrade").action(clap::ArgAction::SetTrue).help("Run `apt full-upgrade`, which may remove packages (default: update-full-upgrade)"))
+                .arg(Arg::new("safe-upgrade").long("safe-upgrade").action(clap::ArgAction::SetTrue).conflicts_with("full-upgrade").help("Run `apt upgrade`, which never removes packages"))
+                .arg(Arg::new("autoremove").long("autoremove").action(clap::ArgAction::SetTrue).help("Remove the obsolete packages afterwards (default: update-autoremove)"))
+                .arg(Arg::new("no-autoremove").long("no-autoremove").action(clap::ArgAction::SetTrue).conflicts_with("autoremove").help("Keep the obsolete packages"))
+                .arg(Arg::new("clean").long("clean").action(clap::ArgAction::SetTrue).help("Remove the downloaded packages afterwards (default: update-clean)"))
+                .arg(Arg::new("no-clean").long("no-clean").action(clap::ArgAction::SetTrue).conflicts_with("clean").help("Keep the downloaded packages"))
+                .arg(Arg::new("force").long("force").action(clap::ArgAction::SetTrue).help("Update the OS even if some instances are running"))
+                .arg(Arg::new("auto-repair").long("auto-repair").action(clap::ArgAction::SetTrue).help("Repair the interrupted package manager (`dpkg --configure -a`) without asking"))
+                .arg(Arg::new("stop-all").long("stop-all").action(clap::ArgAction::SetTrue).conflicts_with("force").help("Stop and un-mount the instances in use without asking"))
+                .arg(Arg::new("restore").long("restore").action(clap::ArgAction::SetTrue).help("Roll back and mount the stopped instances again afterwards without asking"))
+                .arg(no_inhibit_arg.clone())
+                .about("Update the OS in the container"),
+        )
         .subcommand(
             Command::new("load-tree")
                 .arg(Arg::new("url").default_value(GIT_TREE_URL).help("URL to the git repository"))
+                .arg(depth_arg.clone())
+                .arg(branch_arg.clone())
                 .about("Clone package tree from the link provided or AOSC OS ABBS main repository"),
         )
+        .subcommand(
+            Command::new("tree")
+                .arg_required_else_help(true)
+                .subcommands(vec![
+                    Command::new("clone").arg(Arg::new("url").default_value(GIT_TREE_URL).help("URL to the git repository")).arg(depth_arg.clone()).arg(branch_arg.clone()).about("Clone the ABBS tree (same as load-tree)"),
+                    Command::new("switch").arg(Arg::new("BRANCH").required(true).help("Branch to switch to")).arg(Arg::new("force").long("force").action(clap::ArgAction::SetTrue).help("Switch even if the tree has uncommitted changes, which are carried over")).about("Switch the tree to another branch"),
+                ])
+                .about("ABBS tree operations"),
+        )
         .subcommand(
             Command::new("update-tree")
                 .arg(Arg::new("rebase").num_args(1).short('r').long("rebase").help("Rebase the specified branch from the updated upstream"))
@@ -62,29 +171,90 @@ pub fn build_cli() -> Command {
         )
         .subcommand(
             Command::new("new")
-            .arg(Arg::new("tarball").num_args(1).long("from-tarball").help("Create a new workspace from the specified tarball"))
+            .arg(Arg::new("tarball").num_args(1).long("load-os").visible_alias("from-tarball").help("Create a new workspace from the specified tarball (a URL or a path)"))
+            .arg(Arg::new("template").num_args(1).long("template").help("Use the configuration template (a path or a name under ~/.config/ciel/templates)"))
+            .arg(Arg::new("no-interact").long("no-interact").action(clap::ArgAction::SetTrue).help("Do not ask any questions, use the flags, the template or the default values"))
+            .arg(Arg::new("maintainer").num_args(1).long("maintainer").help("Maintainer information (e.g. `Name <user@example.com>`)"))
+            .arg(bool_arg("dnssec").help("Enable DNSSEC"))
+            .arg(Arg::new("apt-source").num_args(1).long("apt-source").action(clap::ArgAction::Append).help("APT source in the one-line style, can be specified multiple times"))
+            .arg(bool_arg("local-repo").help("Enable local packages repository"))
+            .arg(bool_arg("local-sources").help("Enable local sources caching"))
+            .arg(bool_arg("sep-mount").help("Use different OUTPUT dir for different branches"))
+            .arg(bool_arg("volatile").help("Use volatile mode for filesystem operations"))
+            .arg(Arg::new("tree").num_args(1).long("tree").help("URL of the ABBS tree to be cloned"))
+            .arg(Arg::new("instance").num_args(1).short('i').long("instance").action(clap::ArgAction::Append).help("Instance to be created, can be specified multiple times"))
+            .arg(depth_arg)
+            .arg(branch_arg)
             .about("Create a new CIEL workspace")
         )
         .subcommand(
             Command::new("list")
                 .alias("ls")
+                .arg(Arg::new("verbose").short('v').long("verbose").action(clap::ArgAction::SetTrue).help("Show the effective systemd-nspawn options of the instances"))
+                .arg(Arg::new("format").long("format").value_parser(["table", "json"]).default_value("table").help("Output format"))
+                .arg(Arg::new("json").long("json").action(clap::ArgAction::SetTrue).conflicts_with("format").help("Print the instances in JSON (same as --format json)"))
+                .arg(Arg::new("fast").long("fast").action(clap::ArgAction::SetTrue).help("Do not compute the disk usage of the instances in JSON"))
+                .arg(Arg::new("label").long("label").num_args(1).action(clap::ArgAction::Append).value_name("KEY=VALUE").help("Only list the instances with the label, can be specified multiple times"))
                 .about("List all the instances under the specified working directory"),
         )
         .subcommand(
             Command::new("add")
                 .arg(Arg::new("INSTANCE").required(true))
+                .arg(Arg::new("description").long("description").num_args(1).help("Description of the instance"))
+                .arg(Arg::new("label").long("label").num_args(1).action(clap::ArgAction::Append).value_name("KEY=VALUE").help("Label of the instance, can be specified multiple times"))
+                .arg(Arg::new("network").long("network").value_name("MODE").value_parser(["host", "none", "veth"]).help("Network of the instance: shared with the host, none, or a virtual link to the host"))
+                .arg(Arg::new("port").short('p').long("port").num_args(1).action(clap::ArgAction::Append).value_name("[PROTOCOL:]HOST[:CONTAINER]").help("Forward a port from the host (veth network only), can be specified multiple times"))
                 .about("Add a new instance"),
         )
+        .subcommand(
+            Command::new("describe")
+                .arg(Arg::new("INSTANCE").required(true))
+                .arg(Arg::new("description").long("description").num_args(1).help("Set the description of the instance (empty to remove it)"))
+                .arg(Arg::new("label").long("label").num_args(1).action(clap::ArgAction::Append).value_name("KEY=VALUE").help("Add or change a label of the instance, can be specified multiple times"))
+                .arg(Arg::new("unlabel").long("unlabel").num_args(1).action(clap::ArgAction::Append).value_name("KEY").help("Remove a label of the instance, can be specified multiple times"))
+                .about("Show or change the description and the labels of an instance"),
+        )
+        .subcommand(
+            Command::new("gc-instances")
+                .arg(Arg::new("after").long("after").num_args(1).value_name("DURATION").help("Stop the instances idle for longer than this (e.g. `2h`, overrides auto-stop-after)"))
+                .arg(Arg::new("dry-run").long("dry-run").action(clap::ArgAction::SetTrue).help("Only show the instances that would be stopped"))
+                .arg(Arg::new("watch").long("watch").action(clap::ArgAction::SetTrue).help("Keep checking the instances periodically"))
+                .arg(Arg::new("interval").long("interval").num_args(1).value_name("DURATION").requires("watch").help("Interval between the checks in the watch mode (default: 1m)"))
+                .about("Stop the instances idle for longer than auto-stop-after (except the ones labeled keep-alive=true)"),
+        )
+        .subcommand(
+            Command::new("rename")
+                .arg(Arg::new("INSTANCE").required(true).help("Instance to be renamed"))
+                .arg(Arg::new("NEW_NAME").required(true).help("New name of the instance"))
+                .about("Rename an instance"),
+        )
+        .subcommand(
+            Command::new("clone")
+                .arg(Arg::new("INSTANCE").required(true).help("Instance to be cloned"))
+                .arg(Arg::new("NEW_NAME").required(true).help("Name of the new instance"))
+                .about("Create a new instance with a copy of an instance, including its uncommitted changes"),
+        )
         .subcommand(
             Command::new("del")
                 .alias("rm")
-                .arg(Arg::new("INSTANCE").required(true))
+                .arg(Arg::new("INSTANCE").required_unless_present("all"))
+                .arg(Arg::new("all").long("all").action(clap::ArgAction::SetTrue).conflicts_with("INSTANCE").help("Remove all the instances in parallel and summarize the results"))
+                .arg(Arg::new("yes").short('y').long("yes").action(clap::ArgAction::SetTrue).requires("all").help("Remove all the instances without asking for confirmation"))
+                .arg(jobs_arg.clone())
                 .about("Remove an instance"),
         )
         .subcommand(
             Command::new("shell")
                 .alias("sh")
                 .arg(instance_arg.clone().help("Instance to be used"))
+                .arg(tty_arg.clone())
+                .arg(no_tty_arg.clone())
+                .arg(env_arg.clone())
+                .arg(env_file_arg.clone())
+                .arg(workdir_arg.clone())
+                .arg(here_arg.clone())
+                .arg(mount_arg.clone())
+                .arg(quick_arg.clone())
                 .arg(Arg::new("COMMANDS").required(false).num_args(1..))
                 .about("Start an interactive shell"),
         )
@@ -92,49 +262,188 @@ pub fn build_cli() -> Command {
             Command::new("run")
                 .alias("exec")
                 .arg(instance_arg.clone().help("Instance to run command in"))
+                .arg(tty_arg.clone())
+                .arg(no_tty_arg.clone())
+                .arg(env_arg.clone())
+                .arg(env_file_arg.clone())
+                .arg(workdir_arg.clone())
+                .arg(here_arg.clone())
+                .arg(mount_arg.clone())
+                .arg(quick_arg.clone())
                 .arg(Arg::new("COMMANDS").required(true).num_args(1..))
                 .about("Lower-level version of 'shell', without login environment, without sourcing ~/.bash_profile"),
         )
         .subcommand(
             Command::new("config")
                 .arg(instance_arg.clone().help("Instance to be configured"))
+                .arg(Arg::new("volatile").long("volatile").num_args(1).value_parser(["on", "off"]).requires("INSTANCE").help("Enable or disable the volatile mode of the instance"))
+                .arg(Arg::new("add-option").long("add-option").num_args(1).allow_hyphen_values(true).requires("INSTANCE").conflicts_with("remove-option").help("Add systemd-nspawn options for the instance (e.g. --add-option=--capability=CAP_NET_ADMIN)"))
+                .arg(Arg::new("remove-option").long("remove-option").num_args(1).allow_hyphen_values(true).requires("INSTANCE").help("Remove systemd-nspawn options of the instance"))
                 .arg(Arg::new("g").short('g').action(clap::ArgAction::SetTrue).conflicts_with("INSTANCE").help("Configure base system instead of an instance"))
+                .arg(Arg::new("upgrade").long("upgrade").action(clap::ArgAction::SetTrue).conflicts_with_all(["INSTANCE", "g"]).help("Ask for the options introduced by newer versions of ciel and apply them"))
+                .subcommand(
+                    Command::new("set")
+                        .arg(Arg::new("KEY").required(true).help("Configuration key (e.g. volatile-mount or instance.<name>.volatile-mount)"))
+                        .arg(Arg::new("VALUE").required(true).help("New value of the key"))
+                        .arg(Arg::new("strict").long("strict").action(clap::ArgAction::SetTrue).help("Reject unknown systemd-nspawn options instead of warning about them"))
+                        .arg(Arg::new("migrate").long("migrate").action(clap::ArgAction::SetTrue).help("Move the existing output directories when setting output-dir"))
+                        .about("Set a configuration value non-interactively"),
+                )
+                .subcommand(
+                    Command::new("show")
+                        .arg(Arg::new("json").long("json").action(clap::ArgAction::SetTrue).help("Print the configuration in JSON format"))
+                        .about("Show the effective configuration"),
+                )
+                .subcommand(
+                    Command::new("set-maintainer")
+                        .arg(Arg::new("MAINTAINER").required(true).help("Index or value of the maintainer (e.g. 1 or \"Name <email@example.com>\")"))
+                        .about("Switch the active maintainer"),
+                )
+                .subcommand(
+                    Command::new("apply")
+                        .arg(Arg::new("dry-run").long("dry-run").action(clap::ArgAction::SetTrue).help("Only show the changes to be made"))
+                        .about("Apply the configuration to the base system (or the instance) without asking"),
+                )
+                .subcommand(
+                    Command::new("export")
+                        .arg(Arg::new("FILE").help("Path to the exported file (prints to stdout if not specified)"))
+                        .arg(Arg::new("include-secrets").long("include-secrets").action(clap::ArgAction::SetTrue).help("Do not redact the credentials in the URLs"))
+                        .about("Export the configuration of the workspace"),
+                )
+                .subcommand(
+                    Command::new("import")
+                        .arg(Arg::new("FILE").required(true).help("Path to the exported file"))
+                        .arg(Arg::new("force").long("force").action(clap::ArgAction::SetTrue).help("Stop the running instances and import anyway"))
+                        .about("Import the configuration exported by `ciel config export`"),
+                )
+                .subcommand(
+                    Command::new("restore")
+                        .about("Restore the configuration from the backup (config.toml.bak)"),
+                )
+                .subcommand(
+                    Command::new("get")
+                        .arg(Arg::new("KEY").required(true).help("Configuration key"))
+                        .about("Print a configuration value"),
+                )
                 .about("Configure system and toolchain for building interactively"),
         )
         .subcommand(
             Command::new("commit")
                 .arg(instance_arg.clone().help("Instance to be committed"))
+                .arg(no_inhibit_arg.clone())
+                .arg(
+                    Arg::new("no-progress")
+                        .long("no-progress")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Do not scan the upper layer to show the progress"),
+                )
                 .about("Commit changes onto the shared underlying OS"),
         )
+        .subcommand(
+            Command::new("ccache-stats")
+                .arg(instance_arg.clone().help("Instance to inspect"))
+                .about("Show the ccache statistics (e.g. hit rate) of the specified instance"),
+        )
+        .subcommand(
+            Command::new("status")
+                .arg(Arg::new("json").long("json").action(clap::ArgAction::SetTrue).help("Print the status in JSON"))
+                .arg(Arg::new("INSTANCE").short('i').num_args(1).help("Show the runtime status of the instance instead (leader PID, uptime, state and addresses)"))
+                .about("Show the status of the workspace and its instances"),
+        )
+        .subcommand(
+            Command::new("generate-unit")
+                .arg(Arg::new("INSTANCE").env("CIEL_INST").required(true).help("Instance to be kept up by the unit"))
+                .arg(Arg::new("command").long("command").num_args(1..).allow_hyphen_values(true).value_name("COMMAND").help("Command to run in the instance once it is booted, the service stops when it exits"))
+                .arg(Arg::new("user").long("user").action(clap::ArgAction::SetTrue).help("Generate a unit of the user's service manager"))
+                .arg(Arg::new("install").long("install").action(clap::ArgAction::SetTrue).help("Install the unit (to /etc/systemd/system, or ~/.config/systemd/user with --user) instead of printing it"))
+                .arg(Arg::new("force").long("force").short('f').action(clap::ArgAction::SetTrue).requires("install").help("Overwrite the installed unit even if it is different"))
+                .about("Generate a systemd service booting an instance (and running a command in it) with ciel"),
+        )
+        .subcommand(
+            Command::new("ps")
+                .arg(Arg::new("INSTANCE").env("CIEL_INST").required(true).help("Instance to inspect"))
+                .arg(Arg::new("json").long("json").action(clap::ArgAction::SetTrue).help("Print the processes in JSON"))
+                .about("Show the tree of the processes running in an instance"),
+        )
         .subcommand(
             Command::new("doctor")
+                .arg(Arg::new("fix").long("fix").action(clap::ArgAction::SetTrue).help("Repair the problems that can be fixed safely (e.g. unmount stale mounts)"))
+                .arg(Arg::new("relocate").long("relocate").action(clap::ArgAction::SetTrue).help("Clean up after the workspace is moved to another directory"))
+                .arg(Arg::new("repair-instances").long("repair-instances").action(clap::ArgAction::SetTrue).help("Repair the stale mounts, the stale machines and the missing layers of the instances"))
+                .arg(Arg::new("yes").short('y').long("yes").action(clap::ArgAction::SetTrue).requires("repair-instances").help("Repair the instances without asking for confirmation"))
                 .about("Diagnose problems (hopefully)"),
         )
         .subcommand(
             Command::new("build")
                 .arg(Arg::new("FETCH").short('g').action(clap::ArgAction::SetTrue).help("Fetch source packages only"))
                 .arg(Arg::new("OFFLINE").short('x').long("offline").action(clap::ArgAction::SetTrue).env("CIEL_OFFLINE").help("Disable network in the container during the build"))
+                .arg(Arg::new("auto-repair").long("auto-repair").action(clap::ArgAction::SetTrue).help("Repair the interrupted package manager (`dpkg --configure -a`) without asking"))
+                .arg(Arg::new("memory").long("memory").value_name("SIZE").env("CIEL_MEMORY_MAX").help("Limit the memory of the instance during the build (e.g. 16G), overrides memory-max"))
+                .arg(Arg::new("cpus").long("cpus").value_name("CPUS").env("CIEL_CPU_QUOTA").help("Limit the CPU time of the instance during the build (e.g. 8 or 800%), overrides cpu-quota"))
                 .arg(instance_arg.clone().help("Instance to build in"))
+                .arg(Arg::new("TREE").long("tree").num_args(1).help("Name of the ACBS tree to look up the packages in"))
                 .arg(Arg::new("STAGE2").long("stage2").short('2').action(clap::ArgAction::SetTrue).env("CIEL_STAGE2").help("Use stage 2 mode instead of the regular build mode"))
                 .arg(Arg::new("CONTINUE").conflicts_with("SELECT").short('c').long("resume").alias("continue").num_args(1).help("Continue from a Ciel checkpoint"))
                 .arg(Arg::new("SELECT").num_args(0..=1).long("stage-select").help("Select the starting point for a build"))
                 .arg(Arg::new("PACKAGES").conflicts_with("CONTINUE").num_args(1..))
+                .arg(no_inhibit_arg.clone())
                 .about("Build the packages using the specified instance"),
         )
         .subcommand(
             Command::new("rollback")
                 .arg(instance_arg.clone().help("Instance to be rolled back"))
+                .arg(Arg::new("all").long("all").action(clap::ArgAction::SetTrue).help("Roll back all the instances in parallel and summarize the results"))
+                .arg(Arg::new("stop").long("stop").action(clap::ArgAction::SetTrue).requires("all").help("Stop the running instances first instead of skipping them"))
+                .arg(Arg::new("purge").long("purge").action(clap::ArgAction::SetTrue).help("Remove the changes instead of moving them into the trash"))
+                .arg(jobs_arg.clone())
                 .about("Rollback all or specified instance"),
         )
+        .subcommand(
+            Command::new("trash")
+                .arg_required_else_help(true)
+                .subcommands(vec![
+                    Command::new("list").about("List the changes discarded by rollback"),
+                    Command::new("restore").arg(Arg::new("ID").required(true).help("ID of the discarded changes (see `ciel trash list`)")).about("Restore the discarded changes into their (un-mounted) instance"),
+                    Command::new("empty").about("Remove all the discarded changes"),
+                ])
+                .about("Manage the changes discarded by rollback"),
+        )
         .subcommand(
             Command::new("down")
                 .alias("umount")
                 .arg(instance_arg.clone().help("Instance to be un-mounted"))
+                .arg(Arg::new("all").long("all").action(clap::ArgAction::SetTrue).help("Stop and un-mount all the instances in parallel and summarize the results"))
+                .arg(jobs_arg.clone())
                 .about("Shutdown and unmount all or one instance"),
         )
+        .subcommand(
+            Command::new("start")
+                .alias("boot")
+                .arg(instance_arg.clone().help("Instance to be started"))
+                .arg(Arg::new("no-wait").long("no-wait").action(clap::ArgAction::SetTrue).help("Return as soon as systemd-nspawn is spawned, without waiting for the container to boot"))
+                .arg(Arg::new("read-only").long("read-only").action(clap::ArgAction::SetTrue).help("Discard all the changes made in the instance when it stops, it cannot be committed until then"))
+                .about("Start an instance without running anything in it"),
+        )
+        .subcommand(
+            Command::new("attach")
+                .arg(Arg::new("INSTANCE").env("CIEL_INST").required(true).help("Instance to be attached to"))
+                .arg(Arg::new("console").long("console").action(clap::ArgAction::SetTrue).help("Follow the console output of the instance (read-only) instead of opening a shell"))
+                .about("Open a shell in a booted instance (e.g. while a build is running), detach with ^] pressed three times"),
+        )
+        .subcommand(
+            Command::new("cp")
+                .arg(Arg::new("SOURCE").required(true).help("File or directory to be copied, `<instance>:<path>` for a path in the instance"))
+                .arg(Arg::new("DESTINATION").required(true).help("Where to copy to, `<instance>:<path>` for a path in the instance"))
+                .arg(Arg::new("force").long("force").short('f').action(clap::ArgAction::SetTrue).help("Overwrite the existing files"))
+                .about("Copy files between the host and an instance"),
+        )
         .subcommand(
             Command::new("stop")
                 .arg(instance_arg.clone().help("Instance to be stopped"))
+                .arg(Arg::new("all").long("all").action(clap::ArgAction::SetTrue).help("Stop all the instances in parallel and summarize the results"))
+                .arg(jobs_arg.clone())
+                .arg(Arg::new("force").long("force").short('f').action(clap::ArgAction::SetTrue).help("Kill the instance right away instead of powering it off"))
+                .arg(Arg::new("timeout").long("timeout").value_name("DURATION").help("How long to wait at each step (poweroff, SIGTERM, SIGKILL) before going further, overrides stop-timeout"))
                 .about("Shuts down an instance"),
         )
         .subcommand(
@@ -145,6 +454,8 @@ pub fn build_cli() -> Command {
         .subcommand(
             Command::new("farewell")
                 .alias("harakiri")
+                .arg(Arg::new("dry-run").long("dry-run").action(clap::ArgAction::SetTrue).help("List what would be unmounted and deleted without doing anything"))
+                .arg(Arg::new("output").long("output").action(clap::ArgAction::SetTrue).help("Also remove the output directories in the workspace"))
                 .about("Remove everything related to CIEL!"),
         )
         .subcommand(
@@ -156,6 +467,8 @@ pub fn build_cli() -> Command {
         )
         .subcommand(
             Command::new("clean")
+                .arg(Arg::new("apt-cache").long("apt-cache").action(clap::ArgAction::SetTrue).help("Empty the shared APT cache instead"))
+                .arg(Arg::new("tarball-cache").long("tarball-cache").action(clap::ArgAction::SetTrue).conflicts_with("apt-cache").help("Empty the OS tarball cache shared by all the workspaces (~/.cache/ciel/tarballs) instead"))
                 .about("Clean all the output directories and source cache directories")
         )
         .subcommands({
@@ -179,6 +492,34 @@ pub fn build_cli() -> Command {
                     .default_value(".")
                     .num_args(1..)
                     .help("Set the CIEL! working directory"),
+                Arg::new("proxy")
+                    .long("proxy")
+                    .value_name("URL")
+                    .help("Proxy for the downloads by ciel, overrides the proxies in the configuration and the environment"),
+                Arg::new("limit-rate")
+                    .long("limit-rate")
+                    .value_name("BYTES/S")
+                    .help("Limit the download speed of ciel (e.g. 500K, 2M), overrides limit-rate in the configuration"),
+                Arg::new("offline")
+                    .long("offline")
+                    .action(clap::ArgAction::SetTrue)
+                    .help("Offline mode, the operations needing the network fail immediately"),
+                Arg::new("boot-timeout")
+                    .long("boot-timeout")
+                    .value_name("DURATION")
+                    .help("How long to wait for the instances to boot (e.g. 30s, 2m), overrides boot-timeout in the configuration"),
+                Arg::new("log")
+                    .long("log")
+                    .value_name("PATH")
+                    .help("Also save the output of the commands run in the instances to the file, with timestamps (overrides log-dir)"),
+                Arg::new("no-probe")
+                    .long("no-probe")
+                    .action(clap::ArgAction::SetTrue)
+                    .help("Do not check whether the hosts are reachable (with HEAD requests) before the operations needing them"),
+                Arg::new("wait")
+                    .long("wait")
+                    .action(clap::ArgAction::SetTrue)
+                    .help("Wait for the instances busy with another operation (e.g. a build in another terminal) instead of failing"),
                 Arg::new("batch")
                     .short('b')
                     .long("batch")
@@ -0,0 +1,145 @@
+//! Saving the output of the commands run in the instances (`--log` and `log-dir`)
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use time::{macros::format_description, OffsetDateTime};
+
+use crate::config;
+
+lazy_static! {
+    /// When ciel is started, all the commands of an invocation are logged in the same file
+    static ref STARTED: (Instant, OffsetDateTime) = (Instant::now(), OffsetDateTime::now_utc());
+}
+
+/// Returns where to save the output of the commands run in the instance, either `--log` or a
+/// file named after the instance and the time ciel is started in `log-dir`
+pub fn log_path(instance: &str) -> Option<PathBuf> {
+    if let Some(path) = crate::common::global_options().log_file {
+        return Some(path);
+    }
+    let log_dir = config::read_config().ok()?.log_dir?;
+    let time = STARTED
+        .1
+        .format(format_description!(
+            "[year][month][day]-[hour][minute][second]"
+        ))
+        .ok()?;
+
+    Some(log_dir.join(format!("{}-{}.log", instance, time)))
+}
+
+/// Prefix the line with the time since ciel is started
+fn format_line(elapsed: Duration, line: &[u8]) -> Vec<u8> {
+    let mut formatted = format!("[{:>12.6}] ", elapsed.as_secs_f64()).into_bytes();
+    formatted.extend_from_slice(line);
+    if !line.ends_with(b"\n") {
+        formatted.push(b'\n');
+    }
+
+    formatted
+}
+
+/// Log file of the commands, shared by the output streams
+pub struct CommandLog {
+    file: File,
+}
+
+impl CommandLog {
+    /// Open the log file for appending, so that the lines written by the concurrent
+    /// invocations do not overwrite each other
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        // make sure that the clock starts before the first line
+        lazy_static::initialize(&STARTED);
+
+        Ok(Self { file })
+    }
+
+    /// Write the line in a single write, so that the lines are never interleaved
+    fn write_line(&self, line: &[u8]) -> Result<()> {
+        (&self.file).write_all(&format_line(STARTED.0.elapsed(), line))?;
+
+        Ok(())
+    }
+}
+
+/// Copy everything from `input` to `output` as it is, and line by line to the log if any.
+/// Returns what is read if `capture` is set
+pub fn tee<R: Read, W: Write>(
+    mut input: R,
+    mut output: W,
+    log: Option<&CommandLog>,
+    capture: bool,
+) -> Result<Vec<u8>> {
+    let mut captured = Vec::new();
+    let mut line = Vec::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        output.write_all(&buf[..n])?;
+        output.flush()?;
+        if capture {
+            captured.extend_from_slice(&buf[..n]);
+        }
+        if let Some(log) = log {
+            for byte in &buf[..n] {
+                line.push(*byte);
+                if *byte == b'\n' {
+                    log.write_line(&line)?;
+                    line.clear();
+                }
+            }
+        }
+    }
+    if let (Some(log), false) = (log, line.is_empty()) {
+        log.write_line(&line)?;
+    }
+
+    Ok(captured)
+}
+
+#[test]
+fn test_tee() {
+    assert_eq!(
+        format_line(Duration::from_millis(1500), b"hello\n"),
+        b"[    1.500000] hello\n"
+    );
+    assert_eq!(
+        format_line(Duration::from_secs(0), b"no newline"),
+        b"[    0.000000] no newline\n"
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("logs/main.log");
+    let log = CommandLog::open(&path).unwrap();
+    let mut output = Vec::new();
+    let captured = tee(&b"one\ntwo\nthree"[..], &mut output, Some(&log), true).unwrap();
+    assert_eq!(output, b"one\ntwo\nthree");
+    assert_eq!(captured, output);
+    // appended to the existing file
+    let log = CommandLog::open(&path).unwrap();
+    tee(&b"four\n"[..], std::io::sink(), Some(&log), false).unwrap();
+    let content = fs::read_to_string(&path).unwrap();
+    let lines = content
+        .lines()
+        .map(|x| x.split_once("] ").unwrap().1)
+        .collect::<Vec<_>>();
+    assert_eq!(lines, vec!["one", "two", "three", "four"]);
+}
@@ -2,19 +2,76 @@ use anyhow::{anyhow, Result};
 use lazy_static::lazy_static;
 use sha2::{Digest, Sha256};
 use std::fs::{self, File};
-use std::os::unix::prelude::MetadataExt;
 use std::{
-    io::{Read, Write},
+    collections::HashSet,
+    io::{self, Read, Write},
+    os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
+    sync::RwLock,
     time::Duration,
 };
+use time::{macros::format_description, OffsetDateTime};
+use walkdir::WalkDir;
 
 pub const CURRENT_CIEL_VERSION: usize = 3;
 const CURRENT_CIEL_VERSION_STR: &str = "3";
 pub const CIEL_DIST_DIR: &str = ".ciel/container/dist";
+/// Where the OS is extracted before replacing the base system, left behind if interrupted
+pub const CIEL_DIST_STAGING_DIR: &str = ".ciel/container/dist.incomplete";
 pub const CIEL_INST_DIR: &str = ".ciel/container/instances";
 pub const CIEL_DATA_DIR: &str = ".ciel/data";
-const SKELETON_DIRS: &[&str] = &[CIEL_DIST_DIR, CIEL_INST_DIR, CIEL_DATA_DIR];
+/// Shared APT cache of the instances (`shared-apt-cache`)
+pub const CIEL_APT_CACHE_DIR: &str = ".ciel/cache/apt";
+pub const SKELETON_DIRS: &[&str] = &[CIEL_DIST_DIR, CIEL_INST_DIR, CIEL_DATA_DIR];
+/// Number of the UIDs (and GIDs) of a container with user namespacing (`private-users`),
+/// the size of the ranges picked by systemd-nspawn
+pub const CONTAINER_UID_RANGE: u32 = 0x10000;
+/// The machine name (`$name-$id`) must be a valid hostname of at most 64 characters,
+/// and the workspace ID takes up to 9 of them
+const MAX_INSTANCE_NAME_LEN: usize = 55;
+
+/// Options of the whole invocation given on the command line, kept in the process instead of
+/// the environment so that the hooks and the nested ciel invocations do not inherit them
+#[derive(Debug, Default, Clone)]
+pub struct GlobalOptions {
+    /// `--proxy`, overrides the configured proxies
+    pub proxy: Option<String>,
+    /// `--limit-rate` in bytes per second, overrides `limit-rate`
+    pub limit_rate: Option<u64>,
+    /// `--offline`, only the probing on the host is affected
+    pub offline: bool,
+    /// `--no-probe`
+    pub no_probe: bool,
+    /// `--boot-timeout`, overrides `boot-timeout`
+    pub boot_timeout: Option<Duration>,
+    /// `--wait`, wait for the instances locked by another operation instead of failing
+    pub wait_lock: bool,
+    /// `--log`, overrides `log-dir`
+    pub log_file: Option<PathBuf>,
+    /// `ciel start --read-only`
+    pub read_only: bool,
+    /// `--online` of `ciel load-os` and `ciel update-os`, the network access is allowed even if
+    /// `isolate-network` is enabled
+    pub online: bool,
+    /// `ciel build --memory`, overrides `memory-max` during the build
+    pub memory_max: Option<String>,
+    /// `ciel build --cpus`, overrides `cpu-quota` during the build
+    pub cpu_quota: Option<String>,
+}
+
+lazy_static! {
+    static ref GLOBAL_OPTIONS: RwLock<GlobalOptions> = RwLock::new(GlobalOptions::default());
+}
+
+/// Returns the options of this invocation
+pub fn global_options() -> GlobalOptions {
+    GLOBAL_OPTIONS.read().unwrap().clone()
+}
+
+/// Set the options of this invocation, before running the command
+pub fn set_global_options(options: GlobalOptions) {
+    *GLOBAL_OPTIONS.write().unwrap() = options;
+}
 
 lazy_static! {
     static ref SPINNER_STYLE: indicatif::ProgressStyle =
@@ -52,33 +109,251 @@ pub fn sha256sum<R: Read>(mut reader: R) -> Result<String> {
     Ok(format!("{:x}", hasher.finalize()))
 }
 
-/// Extract the given .tar.xz stream and preserve all the file attributes
-pub fn extract_tar_xz<R: Read>(reader: R, path: &Path) -> Result<()> {
-    let decompress = xz2::read::XzDecoder::new(reader);
-    let mut tar_processor = tar::Archive::new(decompress);
+/// A reader calculating the SHA-256 checksum of the data read through it
+pub struct Sha256Reader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Sha256Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Sha256Reader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Read the rest of the data and returns the checksum of all the data
+    pub fn finish(mut self) -> Result<String> {
+        io::copy(&mut self, &mut io::sink())?;
+
+        Ok(format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for Sha256Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+
+        Ok(n)
+    }
+}
+
+/// Compression format of a tarball
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarballFormat {
+    Xz,
+    Zstd,
+    Gzip,
+}
+
+/// Magic numbers of the supported compression formats
+const TARBALL_MAGICS: &[(&[u8], TarballFormat)] = &[
+    (b"\xfd7zXZ\x00", TarballFormat::Xz),
+    (b"\x28\xb5\x2f\xfd", TarballFormat::Zstd),
+    (b"\x1f\x8b", TarballFormat::Gzip),
+];
+
+/// Detect the compression format from the first few bytes of the tarball,
+/// the file name is not used since it may be mangled by the mirrors
+pub fn detect_tarball_format(header: &[u8]) -> Result<TarballFormat> {
+    TARBALL_MAGICS
+        .iter()
+        .find(|(magic, _)| header.starts_with(magic))
+        .map(|(_, format)| *format)
+        .ok_or_else(|| anyhow!("Unknown tarball format, only xz, zstd and gzip are supported"))
+}
+
+/// Extract the given compressed tarball stream (see [TarballFormat]) and preserve all the
+/// file attributes, `on_entry` is called after each entry is extracted
+pub fn extract_tarball<R: Read>(mut reader: R, path: &Path, on_entry: impl FnMut()) -> Result<()> {
+    let mut header = Vec::with_capacity(6);
+    reader.by_ref().take(6).read_to_end(&mut header)?;
+    let format = detect_tarball_format(&header)?;
+    let reader = io::Cursor::new(header).chain(reader);
+    match format {
+        TarballFormat::Xz => unpack_tar(xz2::read::XzDecoder::new(reader), path, on_entry),
+        TarballFormat::Zstd => unpack_tar(zstd::Decoder::new(reader)?, path, on_entry),
+        TarballFormat::Gzip => unpack_tar(flate2::read::GzDecoder::new(reader), path, on_entry),
+    }
+}
+
+fn unpack_tar<R: Read>(reader: R, path: &Path, mut on_entry: impl FnMut()) -> Result<()> {
+    let mut tar_processor = tar::Archive::new(reader);
     tar_processor.set_unpack_xattrs(true);
     tar_processor.set_preserve_permissions(true);
-    tar_processor.unpack(path)?;
+    // like `Archive::unpack`, the directories are extracted at last,
+    // so that their permissions and modification times are not altered by their contents
+    let mut directories = Vec::new();
+    for entry in tar_processor.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type() == tar::EntryType::Directory {
+            directories.push(entry);
+        } else {
+            entry.unpack_in(path)?;
+        }
+        on_entry();
+    }
+    for mut directory in directories {
+        directory.unpack_in(path)?;
+    }
 
     Ok(())
 }
 
-pub fn extract_system_tarball(path: &Path, total: u64) -> Result<()> {
+/// Parse the size with an optional binary unit suffix (e.g. `512M`, `4GiB`, `1048576`)
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let unit_start = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(unit_start);
+    let shift = match unit.trim().trim_end_matches("iB").trim_end_matches('B') {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" | "m" => 20,
+        "G" | "g" => 30,
+        "T" | "t" => 40,
+        _ => return None,
+    };
+
+    number.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Parse the duration with unit suffixes (e.g. `90s`, `30m`, `1h30m`, `2d`), plain numbers are seconds
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let duration = duration.trim();
+    if duration.is_empty() {
+        return None;
+    }
+    if let Ok(seconds) = duration.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let mut total = 0u64;
+    let mut rest = duration;
+    while !rest.is_empty() {
+        let unit_start = rest.find(|c: char| !c.is_ascii_digit())?;
+        let number = rest[..unit_start].parse::<u64>().ok()?;
+        let multiplier = match rest[unit_start..].chars().next()? {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return None,
+        };
+        total = total.checked_add(number.checked_mul(multiplier)?)?;
+        rest = &rest[unit_start + 1..];
+    }
+
+    Some(Duration::from_secs(total))
+}
+
+/// Format the time (in seconds since the epoch) like `2024-01-01 00:00:00 UTC`
+pub fn format_time(timestamp: u64) -> String {
+    OffsetDateTime::from_unix_timestamp(timestamp as i64)
+        .ok()
+        .and_then(|x| {
+            x.format(format_description!(
+                "[year]-[month]-[day] [hour]:[minute]:[second] UTC"
+            ))
+            .ok()
+        })
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+/// Format the duration in the format accepted by [parse_duration], e.g. `1h30m`
+pub fn format_duration(duration: Duration) -> String {
+    let mut seconds = duration.as_secs();
+    if seconds == 0 {
+        return "0s".to_owned();
+    }
+    let mut formatted = String::new();
+    for (unit, length) in [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)] {
+        if seconds >= length {
+            formatted.push_str(&format!("{}{}", seconds / length, unit));
+            seconds %= length;
+        }
+    }
+
+    formatted
+}
+
+/// Find the checksum of the file in the content of a checksum file (as generated by `sha256sum`),
+/// a checksum without a file name is also accepted
+pub fn parse_checksum_file(content: &str, file_name: &str) -> Option<String> {
+    content.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        let checksum = parts.next()?;
+        match parts.next() {
+            // `*` marks the binary mode
+            Some(name) => {
+                let name = Path::new(name.trim_start_matches('*')).file_name()?;
+                (name == file_name).then(|| checksum.to_owned())
+            }
+            None => Some(checksum.to_owned()),
+        }
+    })
+}
+
+/// Calculate the SHA-256 checksum of the file, showing the progress
+pub fn sha256sum_file(path: &Path) -> Result<String> {
     let f = File::open(path)?;
+    let progress_bar = indicatif::ProgressBar::new(f.metadata()?.len());
+    progress_bar.set_style(
+        indicatif::ProgressStyle::default_bar()
+            .template(make_progress_bar!("Verifying tarball..."))
+            .unwrap(),
+    );
+    progress_bar.set_draw_target(indicatif::ProgressDrawTarget::stderr_with_hz(5));
+    let checksum = sha256sum(progress_bar.wrap_read(f))?;
+    progress_bar.finish_and_clear();
+
+    Ok(checksum)
+}
+
+/// Extract the OS tarball from the stream into the staging directory, with the progress shown
+/// (`total` is the size of the stream, 0 if unknown). The staging directory replaces the base
+/// system in [install_staged_system]. `rate_limit` is the download speed limit shown if any
+pub fn stage_system_tarball<R: Read>(reader: R, total: u64, rate_limit: Option<u64>) -> Result<()> {
+    fs::create_dir_all(CIEL_DIST_STAGING_DIR)?;
     let progress_bar = indicatif::ProgressBar::new(total);
     progress_bar.set_style(
         indicatif::ProgressStyle::default_bar()
-            .template(make_progress_bar!("Extracting tarball..."))
+            .template(make_progress_bar!("Extracting tarball... {msg}"))
             .unwrap(),
     );
     progress_bar.set_draw_target(indicatif::ProgressDrawTarget::stderr_with_hz(5));
-    let reader = progress_bar.wrap_read(f);
-    extract_tar_xz(reader, &PathBuf::from(CIEL_DIST_DIR))?;
+    let reader = progress_bar.wrap_read(reader);
+    let limit = rate_limit
+        .map(|x| format!(", limited to {}/s", indicatif::HumanBytes(x)))
+        .unwrap_or_default();
+    let mut files = 0u64;
+    extract_tarball(reader, Path::new(CIEL_DIST_STAGING_DIR), || {
+        files += 1;
+        progress_bar.set_message(format!("{} files{}", files, limit));
+    })?;
     progress_bar.finish_and_clear();
 
     Ok(())
 }
 
+/// Replace the base system with the staging directory
+pub fn install_staged_system() -> Result<()> {
+    if Path::new(CIEL_DIST_DIR).exists() {
+        fs::remove_dir_all(CIEL_DIST_DIR)?;
+    }
+    fs::rename(CIEL_DIST_STAGING_DIR, CIEL_DIST_DIR)?;
+
+    Ok(())
+}
+
+pub fn extract_system_tarball(path: &Path, total: u64) -> Result<()> {
+    stage_system_tarball(File::open(path)?, total, None)?;
+
+    install_staged_system()
+}
+
 pub fn ciel_init() -> Result<()> {
     for dir in SKELETON_DIRS {
         fs::create_dir_all(dir)?;
@@ -89,30 +364,116 @@ pub fn ciel_init() -> Result<()> {
     Ok(())
 }
 
-/// Find the ciel directory
-pub fn find_ciel_dir<P: AsRef<Path>>(start: P) -> Result<PathBuf> {
-    let start_path = fs::metadata(start.as_ref())?;
-    let start_dev = start_path.dev();
-    let mut current_dir = start.as_ref().to_path_buf();
-    loop {
-        if !current_dir.exists() {
-            return Err(anyhow!("Hit filesystem ceiling!"));
-        }
-        let current_dev = current_dir.metadata()?.dev();
-        if current_dev != start_dev {
-            return Err(anyhow!("Hit filesystem boundary!"));
+/// Unescape the octal escapes (e.g. `\040` for spaces) in the fields of mountinfo
+fn unescape_mountinfo(field: &str) -> String {
+    let mut result = Vec::with_capacity(field.len());
+    let bytes = field.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .filter(|x| bytes[i] == b'\\' && x.iter().all(|x| (b'0'..=b'7').contains(x)));
+        if let Some(octal) = octal {
+            let code = octal
+                .iter()
+                .fold(0u32, |acc, x| acc * 8 + (x - b'0') as u32);
+            result.push(code as u8);
+            i += 4;
+        } else {
+            result.push(bytes[i]);
+            i += 1;
         }
-        if current_dir.join(".ciel").is_dir() {
-            return Ok(current_dir);
+    }
+
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+/// Parse the content of `/proc/self/mountinfo`, returns the mount points and the filesystem types
+pub fn parse_mountinfo(content: &str) -> Vec<(PathBuf, String)> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (mount, fs) = line.split_once(" - ")?;
+            let mount_point = mount.split(' ').nth(4)?;
+            let fs_type = fs.split(' ').next()?;
+            Some((
+                PathBuf::from(unescape_mountinfo(mount_point)),
+                fs_type.to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// Returns the mounts under the directory (but not the directory itself) in the order to unmount
+/// them: the latest first, so that the nested and the stacked mounts go before the ones below
+pub fn mounts_under(mounts: &[(PathBuf, String)], dir: &Path) -> Vec<PathBuf> {
+    mounts
+        .iter()
+        .rev()
+        .map(|(mount, _)| mount)
+        .filter(|x| x.starts_with(dir) && *x != dir)
+        .cloned()
+        .collect()
+}
+
+/// Returns the disk space used by the files in the directory, the hard links are counted once
+/// and the other filesystems mounted in the directory are skipped
+pub fn disk_usage(dir: &Path) -> u64 {
+    let mut seen = HashSet::new();
+    let mut total = 0;
+    for entry in WalkDir::new(dir)
+        .same_file_system(true)
+        .into_iter()
+        .flatten()
+    {
+        if let Ok(metadata) = entry.metadata() {
+            if metadata.nlink() > 1 && !seen.insert((metadata.dev(), metadata.ino())) {
+                continue;
+            }
+            total += metadata.blocks() * 512;
         }
-        current_dir = current_dir.join("..");
     }
+
+    total
 }
 
 pub fn is_instance_exists(instance: &str) -> bool {
     Path::new(CIEL_INST_DIR).join(instance).is_dir()
 }
 
+/// Check if the name can be used for an instance: it becomes a directory in the workspace
+/// and the prefix of the machine name, so only the hostname-safe characters are allowed
+pub fn validate_instance_name(name: &str) -> Result<()> {
+    if name.is_empty() {
+        return Err(anyhow!("Instance name must not be empty."));
+    }
+    if name.len() > MAX_INSTANCE_NAME_LEN {
+        return Err(anyhow!(
+            "Instance name `{}` is too long (at most {} characters).",
+            name,
+            MAX_INSTANCE_NAME_LEN
+        ));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '-' || *c == '_' || *c == '.'))
+    {
+        return Err(anyhow!(
+            "Instance name `{}` contains {:?}, only letters, digits, `-`, `_` and `.` are allowed.",
+            name,
+            c
+        ));
+    }
+    if name.starts_with('-') || name.starts_with('.') {
+        return Err(anyhow!(
+            "Instance name `{}` must not start with `-` or `.`.",
+            name
+        ));
+    }
+
+    Ok(())
+}
+
 pub fn is_legacy_workspace() -> Result<bool> {
     let mut f = fs::File::open(".ciel/version")?;
     // TODO: use a more robust check
@@ -121,3 +482,122 @@ pub fn is_legacy_workspace() -> Result<bool> {
 
     Ok(buf[0] < CURRENT_CIEL_VERSION_STR.as_bytes()[0])
 }
+
+#[test]
+fn test_parse_checksum_file() {
+    let checksum = "a".repeat(64);
+    assert_eq!(
+        parse_checksum_file(&format!("{}\n", checksum), "os.tar.xz"),
+        Some(checksum.clone())
+    );
+    let content = format!(
+        "{}  other.tar.xz\n{} *os.tar.xz\n",
+        "b".repeat(64),
+        checksum
+    );
+    assert_eq!(
+        parse_checksum_file(&content, "os.tar.xz"),
+        Some(checksum.clone())
+    );
+    assert_eq!(parse_checksum_file(&content, "missing.tar.xz"), None);
+    assert_eq!(
+        parse_checksum_file(&format!("{}  ./os/os.tar.xz", checksum), "os.tar.xz"),
+        Some(checksum)
+    );
+    assert_eq!(parse_checksum_file("", "os.tar.xz"), None);
+}
+
+#[test]
+fn test_extract_tarball() {
+    let fixtures: [&[u8]; 3] = [
+        include_bytes!("fixtures/rootfs.tar.xz"),
+        include_bytes!("fixtures/rootfs.tar.zst"),
+        include_bytes!("fixtures/rootfs.tar.gz"),
+    ];
+    let formats = [TarballFormat::Xz, TarballFormat::Zstd, TarballFormat::Gzip];
+    for (&fixture, format) in fixtures.iter().zip(formats) {
+        assert_eq!(detect_tarball_format(fixture).unwrap(), format);
+        let dir = tempfile::tempdir().unwrap();
+        let mut entries = 0;
+        extract_tarball(fixture, dir.path(), || entries += 1).unwrap();
+        assert_eq!(entries, 6);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("etc/os-release")).unwrap(),
+            "NAME=\"AOSC OS\"\nID=aosc\n"
+        );
+        assert!(dir.path().join("usr/bin/true").is_file());
+        assert_eq!(
+            fs::read_link(dir.path().join("bin")).unwrap(),
+            Path::new("usr/bin")
+        );
+    }
+    assert!(detect_tarball_format(b"ustar").is_err());
+    assert!(extract_tarball(&b""[..], Path::new("/nonexistent"), || ()).is_err());
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("1048576"), Some(1 << 20));
+    assert_eq!(parse_size("512M"), Some(512 << 20));
+    assert_eq!(parse_size("4GiB"), Some(4 << 30));
+    assert_eq!(parse_size("2 GB"), Some(2 << 30));
+    assert_eq!(parse_size("0"), Some(0));
+    assert_eq!(parse_size("G"), None);
+    assert_eq!(parse_size("-1"), None);
+    assert_eq!(parse_size("4X"), None);
+}
+
+#[test]
+fn test_validate_instance_name() {
+    for name in ["main", "stable-1.2", "test_old", "X86"] {
+        assert!(validate_instance_name(name).is_ok(), "{}", name);
+    }
+    for name in [
+        "",
+        "a/b",
+        "..",
+        ".hidden",
+        "-i",
+        "with space",
+        "ü",
+        "a".repeat(56).as_str(),
+    ] {
+        assert!(validate_instance_name(name).is_err(), "{}", name);
+    }
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+    assert_eq!(parse_duration("30m"), Some(Duration::from_secs(1800)));
+    assert_eq!(parse_duration("1h30m"), Some(Duration::from_secs(5400)));
+    assert_eq!(parse_duration(" 2d "), Some(Duration::from_secs(172800)));
+    assert_eq!(parse_duration(""), None);
+    assert_eq!(parse_duration("h"), None);
+    assert_eq!(parse_duration("1x"), None);
+    assert_eq!(parse_duration("1h30"), None);
+    assert_eq!(format_duration(Duration::from_secs(5400)), "1h30m");
+    assert_eq!(format_duration(Duration::from_secs(90061)), "1d1h1m1s");
+    assert_eq!(format_duration(Duration::from_secs(0)), "0s");
+}
+
+#[test]
+fn test_mounts_under() {
+    let mounts = parse_mountinfo(
+        "22 1 0:21 / / rw - ext4 /dev/sda1 rw\n\
+         61 22 0:50 / /buildroots/ciel rw - ext4 /dev/sda2 rw\n\
+         62 61 0:51 / /buildroots/ciel/main rw - overlay overlay rw\n\
+         63 62 0:52 / /buildroots/ciel/main/debs rw - ext4 /dev/sda2 rw\n\
+         64 22 0:53 / /buildroots/ciel-old/main rw - overlay overlay rw\n\
+         65 61 0:54 / /buildroots/ciel/main rw - overlay overlay rw\n",
+    );
+    assert_eq!(
+        mounts_under(&mounts, Path::new("/buildroots/ciel")),
+        vec![
+            PathBuf::from("/buildroots/ciel/main"),
+            PathBuf::from("/buildroots/ciel/main/debs"),
+            PathBuf::from("/buildroots/ciel/main"),
+        ]
+    );
+    assert!(mounts_under(&mounts, Path::new("/srv")).is_empty());
+}
@@ -0,0 +1,346 @@
+//! APT sources related APIs (validation and format conversion)
+
+/// Mapping between the one-line style options and the deb822 style fields
+const OPTION_FIELDS: &[(&str, &str)] = &[
+    ("arch", "Architectures"),
+    ("lang", "Languages"),
+    ("target", "Targets"),
+    ("pdiffs", "PDiffs"),
+    ("by-hash", "By-Hash"),
+    ("allow-insecure", "Allow-Insecure"),
+    ("allow-weak", "Allow-Weak"),
+    ("allow-downgrade-to-insecure", "Allow-Downgrade-To-Insecure"),
+    ("trusted", "Trusted"),
+    ("signed-by", "Signed-By"),
+    ("check-valid-until", "Check-Valid-Until"),
+    ("valid-until-min", "Valid-Until-Min"),
+    ("valid-until-max", "Valid-Until-Max"),
+    ("check-date", "Check-Date"),
+    ("date-max-future", "Date-Max-Future"),
+    ("inrelease-path", "InRelease-Path"),
+];
+
+/// A single entry of the one-line style sources.list
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SourceEntry {
+    kind: String,
+    options: Vec<(String, String)>,
+    uri: String,
+    suite: String,
+    components: Vec<String>,
+}
+
+/// A deb822 style stanza, entries with the same URI are merged together
+#[derive(Debug)]
+struct SourceStanza {
+    types: Vec<String>,
+    uri: String,
+    suites: Vec<String>,
+    components: Vec<String>,
+    options: Vec<(String, String)>,
+}
+
+#[inline]
+fn push_unique(list: &mut Vec<String>, item: &str) {
+    if !list.iter().any(|x| x == item) {
+        list.push(item.to_owned());
+    }
+}
+
+fn parse_sources_list(sources: &str) -> Result<Vec<SourceEntry>, String> {
+    let mut entries = Vec::new();
+    for (index, line) in sources.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let lineno = index + 1;
+        let mut parts = line.split_whitespace();
+        let kind = parts.next().unwrap_or_default();
+        if kind != "deb" && kind != "deb-src" {
+            return Err(format!(
+                "Line {}: expected `deb` or `deb-src`, found `{}`.",
+                lineno, kind
+            ));
+        }
+        let mut rest = parts
+            .take_while(|x| !x.starts_with('#'))
+            .collect::<Vec<_>>();
+        let mut options = Vec::new();
+        // parse the options (e.g. `[trusted=yes]`)
+        if rest.first().map_or(false, |x| x.starts_with('[')) {
+            let end = rest
+                .iter()
+                .position(|x| x.ends_with(']'))
+                .ok_or_else(|| format!("Line {}: unterminated option list.", lineno))?;
+            let option_list = rest.drain(..=end).collect::<Vec<_>>().join(" ");
+            let option_list = option_list.trim_start_matches('[').trim_end_matches(']');
+            for option in option_list.split_whitespace() {
+                let (key, value) = option
+                    .split_once('=')
+                    .ok_or_else(|| format!("Line {}: invalid option `{}`.", lineno, option))?;
+                options.push((key.to_owned(), value.to_owned()));
+            }
+        }
+        match rest.as_slice() {
+            [] => return Err(format!("Line {}: missing repository URI.", lineno)),
+            [uri, ..] if !uri.contains(':') => {
+                return Err(format!("Line {}: `{}` is not a valid URI.", lineno, uri))
+            }
+            [_] => return Err(format!("Line {}: missing suite name.", lineno)),
+            [uri, suite, components @ ..] => entries.push(SourceEntry {
+                kind: kind.to_owned(),
+                options,
+                uri: uri.to_string(),
+                suite: suite.to_string(),
+                components: components.iter().map(|x| x.to_string()).collect(),
+            }),
+        }
+    }
+
+    Ok(entries)
+}
+
+fn format_sources_list(entries: &[SourceEntry]) -> String {
+    let mut output = String::new();
+    for entry in entries {
+        output.push_str(&entry.kind);
+        if !entry.options.is_empty() {
+            let options = entry
+                .options
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<_>>();
+            output.push_str(&format!(" [{}]", options.join(" ")));
+        }
+        output.push_str(&format!(" {} {}", entry.uri, entry.suite));
+        for component in entry.components.iter() {
+            output.push(' ');
+            output.push_str(component);
+        }
+        output.push('\n');
+    }
+
+    output
+}
+
+/// Validate the content of the sources.list (one-line style)
+pub fn validate_apt_sources(sources: &str) -> Result<(), String> {
+    if parse_sources_list(sources)?.is_empty() {
+        return Err(
+            "No repository was specified (sources.list is empty or only contains comments)."
+                .to_owned(),
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns the distinct URIs of the repositories in the sources.list (one-line style)
+pub fn source_uris(sources: &str) -> Vec<String> {
+    let mut uris = Vec::new();
+    for entry in parse_sources_list(sources).unwrap_or_default() {
+        push_unique(&mut uris, &entry.uri);
+    }
+
+    uris
+}
+
+/// Convert the one-line style sources.list to a deb822 style .sources file
+pub fn sources_list_to_deb822(sources: &str) -> Result<String, String> {
+    let mut stanzas: Vec<SourceStanza> = Vec::new();
+    for entry in parse_sources_list(sources)? {
+        // merge the suites of the entries with the same URI
+        let stanza = stanzas.iter_mut().find(|x| {
+            x.types == [entry.kind.as_str()]
+                && x.uri == entry.uri
+                && x.components == entry.components
+                && x.options == entry.options
+        });
+        if let Some(stanza) = stanza {
+            push_unique(&mut stanza.suites, &entry.suite);
+            continue;
+        }
+        stanzas.push(SourceStanza {
+            types: vec![entry.kind],
+            uri: entry.uri,
+            suites: vec![entry.suite],
+            components: entry.components,
+            options: entry.options,
+        });
+    }
+    // merge deb and deb-src stanzas if they are otherwise identical
+    let mut merged: Vec<SourceStanza> = Vec::with_capacity(stanzas.len());
+    for stanza in stanzas {
+        let target = merged.iter_mut().find(|x| {
+            x.uri == stanza.uri
+                && x.suites == stanza.suites
+                && x.components == stanza.components
+                && x.options == stanza.options
+        });
+        if let Some(target) = target {
+            for kind in stanza.types.iter() {
+                push_unique(&mut target.types, kind);
+            }
+            continue;
+        }
+        merged.push(stanza);
+    }
+
+    let mut output = Vec::with_capacity(merged.len());
+    for stanza in merged {
+        let mut lines = vec![
+            format!("Types: {}", stanza.types.join(" ")),
+            format!("URIs: {}", stanza.uri),
+            format!("Suites: {}", stanza.suites.join(" ")),
+        ];
+        if !stanza.components.is_empty() {
+            lines.push(format!("Components: {}", stanza.components.join(" ")));
+        }
+        for (key, value) in stanza.options {
+            let field = OPTION_FIELDS
+                .iter()
+                .find(|x| x.0 == key)
+                .map_or(key.as_str(), |x| x.1);
+            lines.push(format!("{}: {}", field, value.replace(',', " ")));
+        }
+        output.push(lines.join("\n"));
+    }
+
+    Ok(output.join("\n\n") + "\n")
+}
+
+/// Convert the deb822 style .sources file to a one-line style sources.list
+pub fn deb822_to_sources_list(sources: &str) -> Result<String, String> {
+    let mut entries = Vec::new();
+    let mut stanza: Vec<(String, String)> = Vec::new();
+    // add an empty line at the end to terminate the last stanza
+    for (index, line) in sources.lines().chain(std::iter::once("")).enumerate() {
+        if line.starts_with('#') {
+            continue;
+        }
+        if line.trim().is_empty() {
+            if !stanza.is_empty() {
+                entries.extend(stanza_to_entries(&stanza, index)?);
+                stanza.clear();
+            }
+            continue;
+        }
+        if line.starts_with(' ') || line.starts_with('\t') {
+            // continuation line
+            let last = stanza
+                .last_mut()
+                .ok_or_else(|| format!("Line {}: unexpected continuation line.", index + 1))?;
+            last.1.push(' ');
+            last.1.push_str(line.trim());
+            continue;
+        }
+        let (key, value) = line
+            .split_once(':')
+            .ok_or_else(|| format!("Line {}: expected a `Key: value` field.", index + 1))?;
+        stanza.push((key.trim().to_owned(), value.trim().to_owned()));
+    }
+
+    Ok(format_sources_list(&entries))
+}
+
+fn stanza_to_entries(
+    stanza: &[(String, String)],
+    lineno: usize,
+) -> Result<Vec<SourceEntry>, String> {
+    let field = |name: &str| {
+        stanza
+            .iter()
+            .find(|x| x.0.eq_ignore_ascii_case(name))
+            .map(|x| x.1.split_whitespace().collect::<Vec<_>>())
+    };
+    let missing = |name: &str| format!("Stanza ending at line {}: missing `{}`.", lineno, name);
+    if let Some(enabled) = field("Enabled") {
+        if enabled == ["no"] {
+            return Ok(Vec::new());
+        }
+    }
+    let types = field("Types").ok_or_else(|| missing("Types"))?;
+    let uris = field("URIs").ok_or_else(|| missing("URIs"))?;
+    let suites = field("Suites").ok_or_else(|| missing("Suites"))?;
+    let components = field("Components").unwrap_or_default();
+    let mut options = Vec::new();
+    for (key, value) in stanza {
+        let key = key.as_str();
+        if ["Types", "URIs", "Suites", "Components", "Enabled"]
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(key))
+        {
+            continue;
+        }
+        let option = OPTION_FIELDS
+            .iter()
+            .find(|x| x.1.eq_ignore_ascii_case(key))
+            .map_or(key, |x| x.0);
+        let value = value.split_whitespace().collect::<Vec<_>>().join(",");
+        options.push((option.to_owned(), value));
+    }
+
+    let mut entries = Vec::new();
+    for kind in types.iter() {
+        for uri in uris.iter() {
+            for suite in suites.iter() {
+                entries.push(SourceEntry {
+                    kind: kind.to_string(),
+                    options: options.clone(),
+                    uri: uri.to_string(),
+                    suite: suite.to_string(),
+                    components: components.iter().map(|x| x.to_string()).collect(),
+                });
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+#[test]
+fn test_validate_apt_sources() {
+    assert_eq!(
+        validate_apt_sources("deb https://repo.aosc.io/debs/ stable main"),
+        Ok(())
+    );
+    assert_eq!(
+        validate_apt_sources("# local\ndeb [trusted=yes] file:///debs/ /\n"),
+        Ok(())
+    );
+    assert_eq!(
+        validate_apt_sources(
+            "deb https://repo.aosc.io/debs/ stable main\ndob https://repo.aosc.io/debs/ stable main"
+        ),
+        Err("Line 2: expected `deb` or `deb-src`, found `dob`.".to_owned())
+    );
+    assert_eq!(
+        validate_apt_sources("deb https://repo.aosc.io/debs/"),
+        Err("Line 1: missing suite name.".to_owned())
+    );
+    assert_eq!(
+        validate_apt_sources("deb [arch=amd64 stable main"),
+        Err("Line 1: unterminated option list.".to_owned())
+    );
+    assert!(validate_apt_sources("").is_err());
+    assert!(validate_apt_sources("# deb https://repo.aosc.io/debs/ stable main\n").is_err());
+    assert_eq!(
+        source_uris("deb https://repo.aosc.io/debs/ stable main\ndeb [trusted=yes] file:///debs/ local main\ndeb https://repo.aosc.io/debs/ testing main\n"),
+        vec!["https://repo.aosc.io/debs/", "file:///debs/"]
+    );
+}
+
+#[test]
+fn test_deb822_conversion() {
+    let sources = "deb [arch=amd64,arm64] https://repo.aosc.io/debs/ stable main\n\
+                   deb [arch=amd64,arm64] https://repo.aosc.io/debs/ testing main\n\
+                   deb [trusted=yes] file:///debs/ /\n";
+    let deb822 = sources_list_to_deb822(sources).unwrap();
+    assert_eq!(
+        deb822,
+        "Types: deb\nURIs: https://repo.aosc.io/debs/\nSuites: stable testing\nComponents: main\nArchitectures: amd64 arm64\n\n\
+         Types: deb\nURIs: file:///debs/\nSuites: /\nTrusted: yes\n"
+    );
+    assert_eq!(deb822_to_sources_list(&deb822).unwrap(), sources);
+}
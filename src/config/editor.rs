@@ -0,0 +1,147 @@
+//! Selection and invocation of the text editor
+
+use crate::warn;
+use anyhow::{anyhow, Result};
+use console::style;
+use std::{fs, io::Write, process::Command};
+
+/// The editor used when nothing else could be found
+const FALLBACK_EDITOR: &str = "nano";
+
+/// Split the command line into the program and its arguments,
+/// single quotes, double quotes and backslashes are handled like in the shell
+pub fn split_command(command: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(anyhow!("unterminated single quote in `{}`", command)),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => {
+                                return Err(anyhow!("unterminated double quote in `{}`", command))
+                            }
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(anyhow!("unterminated double quote in `{}`", command)),
+                    }
+                }
+            }
+            '\\' => {
+                let word = word.get_or_insert_with(String::new);
+                match chars.next() {
+                    Some(c) => word.push(c),
+                    None => return Err(anyhow!("trailing backslash in `{}`", command)),
+                }
+            }
+            c if c.is_whitespace() => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    if words.is_empty() {
+        return Err(anyhow!("the editor command is empty"));
+    }
+
+    Ok(words)
+}
+
+/// Detect the editor from `$VISUAL`, `$EDITOR` and the `editor` alternative,
+/// returns `None` if nothing is found (the fallback editor will be used)
+pub fn detect_editor() -> Option<String> {
+    for name in ["VISUAL", "EDITOR"] {
+        if let Some(prog) = std::env::var(name).ok().filter(|x| !x.trim().is_empty()) {
+            return Some(prog);
+        }
+    }
+
+    which::which("editor")
+        .ok()
+        .map(|x| x.to_string_lossy().into_owned())
+}
+
+/// Returns the command line of the editor, the configured editor is preferred.
+/// Falls back to the detected one if the configured editor could not be found
+pub fn editor_command(configured: Option<&str>) -> Vec<String> {
+    if let Some(configured) = configured {
+        match split_command(configured) {
+            Ok(command) if which::which(&command[0]).is_ok() => return command,
+            Ok(command) => warn!(
+                "Editor `{}` could not be found, falling back to the default editor.",
+                command[0]
+            ),
+            Err(e) => warn!(
+                "Invalid editor `{}`: {}, falling back to the default editor.",
+                configured, e
+            ),
+        }
+    }
+
+    detect_editor()
+        .and_then(|x| split_command(&x).ok())
+        .unwrap_or_else(|| vec![FALLBACK_EDITOR.to_owned()])
+}
+
+/// Edit the text with the editor, the temporary file has the specified extension (e.g. `.list`).
+/// Returns `None` if the file was not saved
+pub fn edit(command: &[String], text: &str, extension: &str) -> Result<Option<String>> {
+    let mut file = tempfile::Builder::new()
+        .prefix("ciel-")
+        .suffix(extension)
+        .tempfile()?;
+    file.write_all(text.as_bytes())?;
+    file.flush()?;
+    let modified = fs::metadata(file.path())?.modified()?;
+    let status = Command::new(&command[0])
+        .args(&command[1..])
+        .arg(file.path())
+        .status()
+        .map_err(|e| anyhow!("Unable to launch the editor `{}`: {}", command[0], e))?;
+    if !status.success() {
+        return Err(anyhow!("The editor exited with {}", status));
+    }
+    if fs::metadata(file.path())?.modified()? == modified {
+        return Ok(None);
+    }
+
+    Ok(Some(fs::read_to_string(file.path())?))
+}
+
+#[test]
+fn test_split_command() {
+    assert_eq!(split_command("vim").unwrap(), vec!["vim"]);
+    assert_eq!(
+        split_command("  code   --wait ").unwrap(),
+        vec!["code", "--wait"]
+    );
+    assert_eq!(
+        split_command(r#"'/opt/My Editor/bin/edit' -c "set ft=\"sources\"" a\ b"#).unwrap(),
+        vec!["/opt/My Editor/bin/edit", "-c", "set ft=\"sources\"", "a b"]
+    );
+    assert_eq!(split_command("emacs ''").unwrap(), vec!["emacs", ""]);
+    assert!(split_command("vim 'foo").is_err());
+    assert!(split_command(" ").is_err());
+    assert_ne!(
+        editor_command(Some("/nonexistent/ciel-editor --wait"))[0],
+        "/nonexistent/ciel-editor"
+    );
+}
@@ -0,0 +1,107 @@
+//! Host-side hook scripts invoked before and after some of the actions
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+/// Paths to the hook scripts, relative paths are resolved against the workspace
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HooksConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_build: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_build: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_update_os: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_update_os: Option<PathBuf>,
+}
+
+impl HooksConfig {
+    pub fn is_empty(&self) -> bool {
+        self == &HooksConfig::default()
+    }
+
+    /// Returns the configured hooks along with their names
+    fn hooks(&self) -> [(&'static str, Option<&PathBuf>); 4] {
+        [
+            ("pre-build", self.pre_build.as_ref()),
+            ("post-build", self.post_build.as_ref()),
+            ("pre-update-os", self.pre_update_os.as_ref()),
+            ("post-update-os", self.post_update_os.as_ref()),
+        ]
+    }
+
+    /// Check that all the configured hooks exist and are executable
+    pub fn validate(&self) -> Result<()> {
+        for (name, path) in self.hooks() {
+            if let Some(path) = path {
+                validate_hook(name, path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check that the hook script is an executable file
+pub fn validate_hook(name: &str, path: &Path) -> Result<()> {
+    let meta = path.metadata().map_err(|e| {
+        anyhow!(
+            "Hook `{}` ({}) is not usable: {}. Please fix it with `ciel config set hooks.{} ...`.",
+            name,
+            path.display(),
+            e,
+            name
+        )
+    })?;
+    if !meta.is_file() {
+        return Err(anyhow!(
+            "Hook `{}` ({}) is not a file.",
+            name,
+            path.display()
+        ));
+    }
+    if meta.permissions().mode() & 0o111 == 0 {
+        return Err(anyhow!(
+            "Hook `{}` ({}) is not executable. Please run `chmod +x {}`.",
+            name,
+            path.display(),
+            path.display()
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_validate_hooks() {
+    use std::fs;
+
+    let dir = tempfile::tempdir().unwrap();
+    let script = dir.path().join("post-build.sh");
+    fs::write(&script, "#!/bin/sh\n").unwrap();
+    let mut hooks = HooksConfig {
+        post_build: Some(script.clone()),
+        ..Default::default()
+    };
+    assert!(hooks
+        .validate()
+        .unwrap_err()
+        .to_string()
+        .contains("is not executable"));
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    assert!(hooks.validate().is_ok());
+    hooks.pre_update_os = Some(dir.path().join("missing.sh"));
+    assert!(hooks
+        .validate()
+        .unwrap_err()
+        .to_string()
+        .starts_with("Hook `pre-update-os`"));
+    hooks.pre_update_os = Some(dir.path().to_owned());
+    assert!(hooks.validate().is_err());
+}
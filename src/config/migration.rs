@@ -0,0 +1,73 @@
+//! Configuration file migrations
+
+use crate::common::CURRENT_CIEL_VERSION;
+use anyhow::{anyhow, Result};
+use toml::{value::Table, Value};
+
+/// The oldest configuration version that can be migrated
+const MIN_SUPPORTED_VERSION: usize = 2;
+
+type Migration = fn(&mut Table) -> Result<()>;
+
+/// Migrations in ascending order, each one upgrades the configuration from the specified version
+const MIGRATIONS: &[(usize, Migration)] = &[(2, migrate_v2_to_v3)];
+
+/// Ciel 2 used different key names and did not have the volatile mount option
+fn migrate_v2_to_v3(config: &mut Table) -> Result<()> {
+    if let Some(sep_mount) = config.remove("sep_mount") {
+        config.insert("branch-exclusive-output".to_owned(), sep_mount);
+    }
+    if let Some(extra_options) = config.remove("extra_options") {
+        config.insert("nspawn-extra-options".to_owned(), extra_options);
+    }
+    config
+        .entry("branch-exclusive-output")
+        .or_insert(Value::Boolean(true));
+    config
+        .entry("nspawn-extra-options")
+        .or_insert(Value::Array(Vec::new()));
+    config
+        .entry("volatile-mount")
+        .or_insert(Value::Boolean(false));
+
+    Ok(())
+}
+
+/// Upgrade the configuration to the current version, returns whether a migration happened
+pub fn migrate(config: &mut Table) -> Result<bool> {
+    let version = match config.get("version") {
+        Some(version) => version
+            .as_integer()
+            .and_then(|v| usize::try_from(v).ok())
+            .ok_or_else(|| anyhow!("Invalid configuration version: {}", version))?,
+        // very old versions did not record the version
+        None => MIN_SUPPORTED_VERSION,
+    };
+    if version > CURRENT_CIEL_VERSION {
+        return Err(anyhow!(
+            "This configuration was created by a newer ciel (version {}, supported up to {}). Please upgrade ciel.",
+            version,
+            CURRENT_CIEL_VERSION
+        ));
+    }
+    if version < MIN_SUPPORTED_VERSION {
+        return Err(anyhow!(
+            "This configuration is too old (version {}) to be migrated.",
+            version
+        ));
+    }
+    if version == CURRENT_CIEL_VERSION {
+        return Ok(false);
+    }
+    for (from, migration) in MIGRATIONS {
+        if *from >= version {
+            migration(config)?;
+        }
+    }
+    config.insert(
+        "version".to_owned(),
+        Value::Integer(CURRENT_CIEL_VERSION as i64),
+    );
+
+    Ok(true)
+}
@@ -0,0 +1,4873 @@
+//! This module contains configuration files related APIs
+
+use self::apt::{
+    deb822_to_sources_list, source_uris, sources_list_to_deb822, validate_apt_sources,
+};
+use self::editor::{detect_editor, editor_command, split_command};
+use crate::common::{
+    format_duration, global_options, parse_duration, parse_size, CIEL_APT_CACHE_DIR, CIEL_DATA_DIR,
+    CIEL_INST_DIR, CONTAINER_UID_RANGE, CURRENT_CIEL_VERSION,
+};
+use crate::{info, warn};
+use anyhow::{anyhow, Result};
+use console::{style, user_attended};
+use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use std::{
+    fs,
+    io::{Read, Write},
+};
+
+mod apt;
+mod editor;
+mod export;
+mod hooks;
+mod migration;
+mod nspawn;
+mod plan;
+mod strict;
+mod template;
+mod user;
+
+pub use self::export::{export_config, import_config, WorkspaceMetadata};
+pub use self::hooks::HooksConfig;
+pub use self::nspawn::{
+    merge_nspawn_options, validate_nspawn_options, validate_nspawn_options_with,
+};
+pub use self::plan::{print_diff, unified_diff, PlannedChange};
+pub use self::template::{find_template, load_template};
+pub use self::user::{config_home, read_user_config};
+
+const DEFAULT_CONFIG_LOCATION: &str = ".ciel/data/config.toml";
+const DEFAULT_CONFIG_BACKUP_LOCATION: &str = ".ciel/data/config.toml.bak";
+const DEFAULT_APT_SOURCE: &str = "deb https://repo.aosc.io/debs/ stable main";
+const DEFAULT_AB3_CONFIG_LOCATION: &str = "usr/lib/autobuild3/etc/autobuild/ab3cfg.sh";
+const DEFAULT_APT_LIST_LOCATION: &str = "etc/apt/sources.list";
+const DEFAULT_APT_CIEL_LIST_LOCATION: &str = "etc/apt/sources.list.d/ciel.list";
+const DEFAULT_APT_DEB822_LOCATION: &str = "etc/apt/sources.list.d/ciel.sources";
+const APT_SOURCES_ERROR_PREFIX: &str = "# ciel: ";
+const ISOLATED_APT_SOURCES: &str =
+    "# Remote repositories are disabled since the network is isolated (isolate-network)\n";
+const DEFAULT_APT_PROXY_LOCATION: &str = "etc/apt/apt.conf.d/10ciel-proxy";
+const DEFAULT_APT_KEEP_ARCHIVES_LOCATION: &str = "etc/apt/apt.conf.d/10ciel-keep-archives";
+/// Keep the downloaded packages in the shared cache instead of removing them after installation
+const APT_KEEP_ARCHIVES_CONF: &str = "APT::Keep-Downloaded-Packages \"true\";\nBinary::apt::APT::Keep-Downloaded-Packages \"true\";\n";
+const DEFAULT_PROFILE_PROXY_LOCATION: &str = "etc/profile.d/ciel-proxy.sh";
+const DEFAULT_LOCAL_PIN_LOCATION: &str = "etc/apt/preferences.d/ciel-local.pref";
+const DEFAULT_RESOLV_LOCATION: &str = "etc/systemd/resolved.conf";
+const DEFAULT_ACBS_CONFIG: &str = "etc/acbs/forest.conf";
+const DEFAULT_LOCALE_LOCATION: &str = "etc/locale.conf";
+const DEFAULT_LOCALTIME_LOCATION: &str = "etc/localtime";
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+/// The default naming scheme of the branch-exclusive output directories
+pub const DEFAULT_OUTPUT_DIR_PATTERN: &str = "OUTPUT-{branch}";
+/// Number of retries of the interrupted downloads if not configured
+const DEFAULT_DOWNLOAD_RETRIES: usize = 5;
+/// Number of connections of the parallel downloads if not configured
+const DEFAULT_DOWNLOAD_CONNECTIONS: usize = 4;
+/// Paths in the container that are mounted by ciel itself
+const RESERVED_CONTAINER_PATHS: &[&str] = &[
+    "/debs",
+    "/tree",
+    "/var/cache/acbs/tarballs",
+    CCACHE_CONTAINER_DIR,
+];
+/// Where the ccache directory is mounted in the container
+pub const CCACHE_CONTAINER_DIR: &str = "/var/cache/ccache";
+/// Where the shared APT cache is mounted in the container
+const APT_ARCHIVES_CONTAINER_DIR: &str = "/var/cache/apt/archives";
+/// Where the persisted home directory is mounted in the container (`persist-home`)
+const HOME_CONTAINER_DIR: &str = "/root";
+/// Where the persisted home directory is kept, in the instance directory
+const HOME_DIR: &str = "home";
+/// Maximum length of a hostname label (RFC 1123)
+const MAX_HOSTNAME_LEN: usize = 63;
+/// Hostnames resolved to the loopback addresses by the usual /etc/hosts
+const RESERVED_HOSTNAMES: &[&str] = &["localhost", "localdomain", "ip6-localhost", "ip6-loopback"];
+/// All the configuration keys (in the order of display)
+const CONFIG_KEYS: &[&str] = &[
+    "maintainer",
+    "dnssec",
+    "dns-servers",
+    "apt-sources",
+    "sources-format",
+    "manage-sources-list",
+    "extra-mounts",
+    "use-ccache",
+    "ccache-dir",
+    "parallelism",
+    "timezone",
+    "locale",
+    "trees",
+    "rootfs-url",
+    "rootfs-arch",
+    "rootfs-keyring",
+    "download-retries",
+    "download-connections",
+    "limit-rate",
+    "fastest-mirror",
+    "update-full-upgrade",
+    "update-autoremove",
+    "update-clean",
+    "package-manager",
+    "network",
+    "private-users",
+    "editor",
+    "http-proxy",
+    "https-proxy",
+    "no-proxy",
+    "ca-bundle",
+    "local-repo",
+    "local-repo-priority",
+    "isolate-network",
+    "local-sources",
+    "nspawn-extra-options",
+    "branch-exclusive-output",
+    "output-dir-pattern",
+    "output-dir",
+    "volatile-mount",
+    "clear-machine-id",
+    "shared-apt-cache",
+    "persist-home",
+    "auto-stop-after",
+    "boot-timeout",
+    "stop-timeout",
+    "network-timeout",
+    "memory-max",
+    "cpu-quota",
+    "tasks-max",
+    "log-dir",
+    "trash-max-age",
+    "trash-max-size",
+    "hooks.pre-build",
+    "hooks.post-build",
+    "hooks.pre-update-os",
+    "hooks.post-update-os",
+];
+/// Configuration keys that can be overridden per-instance
+const INSTANCE_CONFIG_KEYS: &[&str] = &[
+    "nspawn-extra-options",
+    "branch-exclusive-output",
+    "volatile-mount",
+    "persist-home",
+    "apt-sources",
+    "memory-max",
+    "cpu-quota",
+    "tasks-max",
+    "network",
+    "ports",
+    "hostname",
+    "read-only",
+    "private-users",
+];
+/// Keys of the values written into the files in the base system and the instances (e.g.
+/// ab3cfg.sh and the APT sources), see [plan_config]. `build-env.*` are written as well
+const GENERATED_FILE_KEYS: &[&str] = &[
+    "maintainer",
+    "dnssec",
+    "dns-servers",
+    "apt-sources",
+    "sources-format",
+    "manage-sources-list",
+    "use-ccache",
+    "parallelism",
+    "timezone",
+    "locale",
+    "trees",
+    "local-repo",
+    "local-repo-priority",
+    "isolate-network",
+    "http-proxy",
+    "https-proxy",
+    "no-proxy",
+    "shared-apt-cache",
+];
+/// Keys of the resource limits of the instances, in the order of [ResourceLimits::properties]
+const LIMIT_KEYS: &[&str] = &["memory-max", "cpu-quota", "tasks-max"];
+/// Environment variables that override the configuration keys
+const ENV_OVERRIDES: &[(&str, &str)] = &[
+    ("CIEL_MAINTAINER", "maintainer"),
+    ("CIEL_APT_SOURCES", "apt-sources"),
+    ("CIEL_DNSSEC", "dnssec"),
+    ("CIEL_LOCAL_REPO", "local-repo"),
+    ("CIEL_NSPAWN_EXTRA_OPTIONS", "nspawn-extra-options"),
+];
+/// Keys introduced by newer versions of ciel (named the same in the file and for `config set`)
+/// and their prompts, the user is asked for them if they are absent from the configuration file
+const UPGRADE_PROMPTS: &[(&str, &str)] = &[
+    (
+        "isolate-network",
+        "Disable the network in the container (only the local repository is usable)",
+    ),
+    (
+        "output-dir-pattern",
+        "Name of the branch-exclusive output directories (supports {branch} and {arch})",
+    ),
+    (
+        "volatile-mount",
+        "Use volatile mode for filesystem operations",
+    ),
+    (
+        "clear-machine-id",
+        "Generate a transient machine-id for each instance",
+    ),
+    (
+        "shared-apt-cache",
+        "Share the downloaded APT packages among the instances",
+    ),
+    (
+        "sources-format",
+        "Format of the APT sources (list or deb822)",
+    ),
+    (
+        "manage-sources-list",
+        "Let ciel manage /etc/apt/sources.list",
+    ),
+    ("use-ccache", "Enable ccache"),
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CielConfig {
+    version: usize,
+    #[serde(
+        rename = "maintainer",
+        deserialize_with = "deserialize_maintainers",
+        serialize_with = "serialize_maintainers"
+    )]
+    maintainers: Vec<String>,
+    #[serde(rename = "active-maintainer", default)]
+    active_maintainer: usize,
+    dnssec: bool,
+    #[serde(rename = "dns-servers", default, skip_serializing_if = "Vec::is_empty")]
+    pub dns_servers: Vec<String>,
+    apt_sources: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_proxy: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub https_proxy: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub no_proxy: Option<String>,
+    /// Certificates (in PEM) trusted by ciel in addition to the system ones, e.g. a private CA
+    #[serde(rename = "ca-bundle", default, skip_serializing_if = "Option::is_none")]
+    pub ca_bundle: Option<PathBuf>,
+    pub local_repo: bool,
+    /// Disable the network in the container, only the local repository is usable
+    #[serde(rename = "isolate-network", default)]
+    pub isolate_network: bool,
+    /// APT pin priority of the local repository
+    #[serde(
+        rename = "local-repo-priority",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub local_repo_priority: Option<u16>,
+    pub local_sources: bool,
+    #[serde(rename = "nspawn-extra-options")]
+    pub extra_options: Vec<String>,
+    #[serde(rename = "branch-exclusive-output")]
+    pub sep_mount: bool,
+    /// Name of the branch-exclusive output directory, supports `{branch}` and `{arch}`
+    #[serde(rename = "output-dir-pattern", default = "default_output_dir_pattern")]
+    pub output_dir_pattern: String,
+    /// Directory containing the output directories, defaults to the workspace
+    #[serde(
+        rename = "output-dir",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub output_dir: Option<PathBuf>,
+    #[serde(rename = "volatile-mount", default)]
+    pub volatile_mount: bool,
+    /// Let the instances generate a transient machine-id instead of sharing the one of the base system
+    #[serde(rename = "clear-machine-id", default)]
+    pub clear_machine_id: bool,
+    /// Keep the downloaded packages in a cache shared by all the instances
+    #[serde(rename = "shared-apt-cache", default)]
+    pub shared_apt_cache: bool,
+    /// Keep `/root` of the instances in their instance directories, so that the shell history
+    /// and the dotfiles survive the rollbacks. It is bind-mounted, so never committed
+    #[serde(rename = "persist-home", default)]
+    pub persist_home: bool,
+    /// Power off the instances idle for longer than this (`ciel gc-instances`)
+    #[serde(
+        rename = "auto-stop-after",
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub auto_stop_after: Option<Duration>,
+    /// How long to wait for the instances to boot
+    #[serde(
+        rename = "boot-timeout",
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub boot_timeout: Option<Duration>,
+    /// How long to wait at each step of stopping the instances (poweroff, SIGTERM, SIGKILL)
+    #[serde(
+        rename = "stop-timeout",
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub stop_timeout: Option<Duration>,
+    /// How long to wait for the network in the instances before updating the OS and building
+    /// packages, 0 to skip the wait
+    #[serde(
+        rename = "network-timeout",
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub network_timeout: Option<Duration>,
+    /// Resource limits of the instances, see [ResourceLimits]
+    #[serde(
+        rename = "memory-max",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub memory_max: Option<String>,
+    #[serde(rename = "cpu-quota", default, skip_serializing_if = "Option::is_none")]
+    pub cpu_quota: Option<String>,
+    #[serde(rename = "tasks-max", default, skip_serializing_if = "Option::is_none")]
+    pub tasks_max: Option<String>,
+    /// Purge the upper layers discarded by `ciel rollback` older than this from the trash,
+    /// 0 to remove them right away
+    #[serde(
+        rename = "trash-max-age",
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub trash_max_age: Option<Duration>,
+    /// Purge the oldest layers in the trash when their total size exceeds this
+    #[serde(
+        rename = "trash-max-size",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub trash_max_size: Option<String>,
+    /// Save the output of the commands run in the instances in this directory
+    #[serde(rename = "log-dir", default, skip_serializing_if = "Option::is_none")]
+    pub log_dir: Option<PathBuf>,
+    #[serde(rename = "sources-format", default)]
+    pub sources_format: SourcesFormat,
+    /// Whether ciel owns `/etc/apt/sources.list` (otherwise it is left untouched)
+    #[serde(rename = "manage-sources-list", default)]
+    pub manage_sources_list: bool,
+    #[serde(
+        rename = "extra-mounts",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub extra_mounts: Vec<MountSpec>,
+    #[serde(rename = "use-ccache", default)]
+    pub use_ccache: bool,
+    /// Host directory for ccache, defaults to `.ciel/data/ccache`
+    #[serde(
+        rename = "ccache-dir",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ccache_dir: Option<PathBuf>,
+    /// Number of parallel build jobs (`None` or 0 means automatic)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parallelism: Option<usize>,
+    /// Timezone of the container (e.g. `Asia/Shanghai`), unset means leaving it untouched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// Locale of the container (e.g. `en_US.UTF-8`), unset means leaving it untouched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    /// ACBS trees, the first one is the `TREE` directory of the workspace by default
+    #[serde(rename = "tree", default = "default_trees")]
+    pub trees: Vec<TreeConfig>,
+    /// Where to load the OS from: a tarball (URL or path) or a mirror (ends with `/`)
+    #[serde(
+        rename = "rootfs-url",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub rootfs_url: Option<String>,
+    /// Architecture of the OS tarball picked from the mirror
+    #[serde(
+        rename = "rootfs-arch",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub rootfs_arch: Option<String>,
+    /// Keyring to verify the signatures of the release manifests with (using gpgv)
+    #[serde(
+        rename = "rootfs-keyring",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub rootfs_keyring: Option<PathBuf>,
+    /// Number of retries of the interrupted downloads
+    #[serde(
+        rename = "download-retries",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub download_retries: Option<usize>,
+    /// Number of connections downloading the OS tarball in parallel, `1` disables it
+    #[serde(
+        rename = "download-connections",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub download_connections: Option<usize>,
+    /// Download speed limit in bytes per second (e.g. `500K`, `2M`)
+    #[serde(
+        rename = "limit-rate",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub limit_rate: Option<String>,
+    /// Download the OS from the fastest release mirror
+    #[serde(rename = "fastest-mirror", default)]
+    pub fastest_mirror: bool,
+    /// Run `apt full-upgrade` (instead of `apt upgrade`) when updating the OS, defaults to true
+    #[serde(
+        rename = "update-full-upgrade",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub update_full_upgrade: Option<bool>,
+    /// Remove the obsolete packages after updating the OS, defaults to true
+    #[serde(
+        rename = "update-autoremove",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub update_autoremove: Option<bool>,
+    /// Remove the downloaded packages after updating the OS,
+    /// defaults to true unless the shared APT cache is enabled
+    #[serde(
+        rename = "update-clean",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub update_clean: Option<bool>,
+    /// The package manager used for updating the OS
+    #[serde(rename = "package-manager", default)]
+    pub package_manager: PackageManager,
+    /// The network of the instances
+    #[serde(default)]
+    pub network: NetworkMode,
+    /// User namespacing of the instances
+    #[serde(rename = "private-users", default)]
+    pub private_users: PrivateUsers,
+    /// Editor (with the arguments, e.g. `code --wait`) used for editing the configuration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub editor: Option<String>,
+    /// Environment variables for the builds, exported in ab3cfg.sh and the container
+    #[serde(
+        rename = "build-env",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub build_env: BTreeMap<String, String>,
+    /// Scripts executed on the host before and after the builds and OS updates
+    #[serde(default, skip_serializing_if = "HooksConfig::is_empty")]
+    pub hooks: HooksConfig,
+    /// Per-instance overrides, stored as `[instance.<name>]` tables
+    #[serde(
+        rename = "instance",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub instances: BTreeMap<String, InstanceOverrides>,
+    /// Keys absent from the configuration file (see [UPGRADE_PROMPTS]), filled with the defaults
+    #[serde(skip)]
+    defaulted_keys: Vec<&'static str>,
+}
+
+/// The maintainer field could be either a single string (older versions) or a list
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Maintainers {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+fn deserialize_maintainers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    Ok(match Maintainers::deserialize(deserializer)? {
+        Maintainers::Single(maintainer) => vec![maintainer],
+        Maintainers::Multiple(maintainers) => maintainers,
+    })
+}
+
+#[allow(clippy::ptr_arg)]
+fn serialize_maintainers<S: Serializer>(
+    maintainers: &Vec<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    // keep the single string format for compatibility with older versions
+    if maintainers.len() == 1 {
+        return maintainers[0].serialize(serializer);
+    }
+
+    maintainers.serialize(serializer)
+}
+
+/// Durations are stored in the format of [parse_duration], e.g. `1h30m`
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_duration(&value)
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid duration `{}`", value)))
+}
+
+fn serialize_duration<S: Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match duration {
+        Some(duration) => format_duration(*duration).serialize(serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// The format used for writing the APT sources into the container
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourcesFormat {
+    /// One-line style (`/etc/apt/sources.list.d/ciel.list`)
+    #[default]
+    List,
+    /// Deb822 style (`/etc/apt/sources.list.d/ciel.sources`)
+    Deb822,
+}
+
+/// The package manager used for updating the OS
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageManager {
+    /// oma if it is installed in the base system, otherwise APT
+    #[default]
+    Auto,
+    Apt,
+    Oma,
+}
+
+/// The network of the instance
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkMode {
+    /// Share the network of the host
+    #[default]
+    Host,
+    /// No network other than the loopback
+    None,
+    /// A virtual Ethernet link to the host (`--network-veth`), configured by systemd-networkd
+    Veth,
+}
+
+impl std::fmt::Display for NetworkMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            NetworkMode::Host => "host",
+            NetworkMode::None => "none",
+            NetworkMode::Veth => "veth",
+        })
+    }
+}
+
+/// Parse the network mode (`host`, `none` or `veth`)
+pub fn parse_network_mode(value: &str) -> Result<NetworkMode> {
+    match value {
+        "host" => Ok(NetworkMode::Host),
+        "none" => Ok(NetworkMode::None),
+        "veth" => Ok(NetworkMode::Veth),
+        _ => Err(anyhow!(
+            "Invalid network mode: expected `host`, `none` or `veth`, got `{}`",
+            value
+        )),
+    }
+}
+
+/// User namespacing of the instance: root (and every other user) in the instance is mapped to
+/// an unprivileged UID on the host unless it is off
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum PrivateUsers {
+    #[default]
+    Off,
+    /// A free range of UIDs picked by systemd-nspawn
+    Pick,
+    /// The first UID on the host and the number of the UIDs
+    Range(u32, u32),
+}
+
+impl std::fmt::Display for PrivateUsers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrivateUsers::Off => f.write_str("off"),
+            PrivateUsers::Pick => f.write_str("pick"),
+            PrivateUsers::Range(start, count) => write!(f, "{}:{}", start, count),
+        }
+    }
+}
+
+impl TryFrom<String> for PrivateUsers {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        parse_private_users(&value)
+    }
+}
+
+impl From<PrivateUsers> for String {
+    fn from(value: PrivateUsers) -> Self {
+        value.to_string()
+    }
+}
+
+/// Parse the user namespacing (`off`, `pick` or `START[:COUNT]`), the range must not overlap
+/// with the users of the host (below 65536)
+pub fn parse_private_users(value: &str) -> Result<PrivateUsers> {
+    let invalid = || {
+        anyhow!(
+            "Invalid private-users: expected `off`, `pick` or `START[:COUNT]`, got `{}`",
+            value
+        )
+    };
+    let (start, count) = match value {
+        "off" => return Ok(PrivateUsers::Off),
+        "pick" => return Ok(PrivateUsers::Pick),
+        _ => match value.split_once(':') {
+            Some((start, count)) => (start, count.parse::<u32>().map_err(|_| invalid())?),
+            None => (value, CONTAINER_UID_RANGE),
+        },
+    };
+    let start = start.parse::<u32>().map_err(|_| invalid())?;
+    if start < CONTAINER_UID_RANGE {
+        return Err(anyhow!(
+            "The UID range of private-users must start from {} or above, the UIDs below are the users of the host",
+            CONTAINER_UID_RANGE
+        ));
+    }
+    if count == 0 || count > CONTAINER_UID_RANGE || start.checked_add(count).is_none() {
+        return Err(anyhow!(
+            "The UID range of private-users must have 1 to {} UIDs within the valid UIDs",
+            CONTAINER_UID_RANGE
+        ));
+    }
+
+    Ok(PrivateUsers::Range(start, count))
+}
+
+/// An ACBS tree, mounted into the container and listed in forest.conf
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TreeConfig {
+    pub name: String,
+    /// Directory on the host, relative to the workspace
+    pub source: PathBuf,
+    /// Where the tree is mounted in the container
+    pub location: String,
+    /// Trees with higher priorities are searched first
+    #[serde(default)]
+    pub priority: i32,
+}
+
+impl TreeConfig {
+    /// Check the tree name and paths
+    pub fn validate(&self) -> Result<()> {
+        if self.name.is_empty()
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(anyhow!(
+                "Invalid tree name `{}`: only letters, digits, `-` and `_` are allowed",
+                self.name
+            ));
+        }
+        if !self.location.starts_with('/') {
+            return Err(anyhow!(
+                "Location of tree `{}` should be an absolute path, got `{}`",
+                self.name,
+                self.location
+            ));
+        }
+        if self.source.is_absolute()
+            || self
+                .source
+                .components()
+                .any(|x| x == std::path::Component::ParentDir)
+        {
+            return Err(anyhow!(
+                "Source of tree `{}` should be a directory inside the workspace, got {}",
+                self.name,
+                self.source.display()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns where the directory on the host is in the container,
+    /// `None` if it is not in the tree of the workspace
+    pub fn container_path(&self, workspace: &Path, dir: &Path) -> Option<PathBuf> {
+        let source = workspace.join(&self.source);
+        // the tree may be a symbolic link
+        let source = fs::canonicalize(&source).unwrap_or(source);
+        let relative = dir.strip_prefix(&source).ok()?;
+
+        Some(Path::new(&self.location).join(relative))
+    }
+}
+
+fn default_trees() -> Vec<TreeConfig> {
+    vec![TreeConfig {
+        name: "default".to_owned(),
+        source: PathBuf::from("TREE"),
+        location: "/tree/".to_owned(),
+        priority: 0,
+    }]
+}
+
+/// An extra bind mount from the host into the container
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MountSpec {
+    pub host: PathBuf,
+    pub container: PathBuf,
+    #[serde(rename = "read-only", default)]
+    pub read_only: bool,
+}
+
+impl MountSpec {
+    /// Parse the mount specification in the form of `host:container[:ro]`
+    pub fn parse(spec: &str) -> Result<MountSpec> {
+        let mut parts = spec.split(':');
+        let host = parts.next().unwrap_or_default();
+        let container = parts
+            .next()
+            .ok_or_else(|| anyhow!("Invalid mount `{}`: expected `host:container[:ro]`", spec))?;
+        let read_only = match parts.next() {
+            None | Some("rw") => false,
+            Some("ro") => true,
+            Some(option) => return Err(anyhow!("Invalid mount option `{}` in `{}`", option, spec)),
+        };
+        let mount = MountSpec {
+            host: PathBuf::from(host),
+            container: PathBuf::from(container),
+            read_only,
+        };
+        mount.validate_paths()?;
+
+        Ok(mount)
+    }
+
+    /// Check the container path, it should be absolute and not clash with the mounts from ciel
+    fn validate_paths(&self) -> Result<()> {
+        if !self.host.is_absolute() {
+            return Err(anyhow!(
+                "Host path {} should be an absolute path.",
+                self.host.display()
+            ));
+        }
+        if !self.container.is_absolute() {
+            return Err(anyhow!(
+                "Container path {} should be an absolute path.",
+                self.container.display()
+            ));
+        }
+        for reserved in RESERVED_CONTAINER_PATHS {
+            if self.container.starts_with(reserved)
+                || Path::new(reserved).starts_with(&self.container)
+            {
+                return Err(anyhow!(
+                    "Container path {} clashes with {}, which is managed by ciel.",
+                    self.container.display(),
+                    reserved
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the mount specification, including whether the host path exists
+    pub fn validate(&self) -> Result<()> {
+        self.validate_paths()?;
+        if !self.host.exists() {
+            return Err(anyhow!(
+                "Host path {} of the extra mount does not exist. Please create it or remove the mount with `ciel config set extra-mounts ...`.",
+                self.host.display()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the corresponding systemd-nspawn option
+    pub fn to_nspawn_option(&self) -> String {
+        format!(
+            "--bind{}={}:{}",
+            if self.read_only { "-ro" } else { "" },
+            self.host.display(),
+            self.container.display()
+        )
+    }
+}
+
+impl std::fmt::Display for MountSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.host.display(), self.container.display())?;
+        if self.read_only {
+            write!(f, ":ro")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A subset of the configuration that can be overridden for a single instance
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceOverrides {
+    /// Extra systemd-nspawn options, merged after the global ones
+    #[serde(
+        rename = "nspawn-extra-options",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub extra_options: Option<Vec<String>>,
+    #[serde(
+        rename = "branch-exclusive-output",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub sep_mount: Option<bool>,
+    #[serde(
+        rename = "volatile-mount",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub volatile_mount: Option<bool>,
+    #[serde(
+        rename = "persist-home",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub persist_home: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub apt_sources: Option<String>,
+    #[serde(
+        rename = "memory-max",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub memory_max: Option<String>,
+    #[serde(rename = "cpu-quota", default, skip_serializing_if = "Option::is_none")]
+    pub cpu_quota: Option<String>,
+    #[serde(rename = "tasks-max", default, skip_serializing_if = "Option::is_none")]
+    pub tasks_max: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkMode>,
+    /// Ports forwarded from the host (`[PROTOCOL:]HOST[:CONTAINER]`), only in the veth mode
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<String>,
+    /// Hostname of the instance, derived from the instance name if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// Always mount the instance in the read-only mode, the changes are discarded when it stops
+    #[serde(rename = "read-only", default, skip_serializing_if = "Option::is_none")]
+    pub read_only: Option<bool>,
+    #[serde(
+        rename = "private-users",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub private_users: Option<PrivateUsers>,
+}
+
+/// The effective configuration of an instance (global values merged with the overrides)
+#[derive(Debug, Clone)]
+pub struct InstanceConfig {
+    pub extra_options: Vec<String>,
+    pub sep_mount: bool,
+    pub volatile_mount: bool,
+    pub persist_home: bool,
+    pub apt_sources: String,
+    pub limits: ResourceLimits,
+    pub network: NetworkMode,
+    pub ports: Vec<String>,
+    pub hostname: String,
+    pub read_only: bool,
+    pub private_users: PrivateUsers,
+}
+
+impl InstanceConfig {
+    /// Returns the URIs of the APT repositories of the instance
+    pub fn apt_source_uris(&self) -> Vec<String> {
+        source_uris(&self.apt_sources)
+    }
+
+    /// Returns the systemd-nspawn options of the network mode and the port forwards
+    pub fn network_options(&self) -> Result<Vec<String>> {
+        if !self.ports.is_empty() && self.network != NetworkMode::Veth {
+            return Err(anyhow!(
+                "Ports can only be forwarded in the veth network mode, but the network mode is `{}` ({}). Set `network` to `veth` or remove the ports.",
+                self.network,
+                if self.network == NetworkMode::Host {
+                    "the ports in the instance are already the ports of the host"
+                } else {
+                    "the instance has no network to forward to"
+                }
+            ));
+        }
+        let options = match self.network {
+            NetworkMode::Host => Vec::new(),
+            NetworkMode::None => vec!["--private-network".to_owned()],
+            NetworkMode::Veth => std::iter::once("--network-veth".to_owned())
+                .chain(self.ports.iter().map(|x| format!("--port={}", x)))
+                .collect(),
+        };
+
+        Ok(options)
+    }
+
+    /// Returns the systemd-nspawn options of the user namespacing. The files are mapped with
+    /// the idmapped mounts if possible, otherwise systemd-nspawn shifts their ownership
+    /// (which is shifted back when the instance is committed)
+    pub fn private_users_options(&self) -> Vec<String> {
+        let range = match self.private_users {
+            PrivateUsers::Off => return Vec::new(),
+            PrivateUsers::Pick => "pick".to_owned(),
+            PrivateUsers::Range(start, count) => format!("{}:{}", start, count),
+        };
+
+        vec![
+            format!("--private-users={}", range),
+            "--private-users-ownership=auto".to_owned(),
+        ]
+    }
+}
+
+/// Resource limits of an instance (`memory-max`, `cpu-quota` and `tasks-max`), applied to the
+/// unit of the container. `None` or `infinity` means no limit
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ResourceLimits {
+    pub memory_max: Option<String>,
+    pub cpu_quota: Option<String>,
+    pub tasks_max: Option<String>,
+}
+
+impl ResourceLimits {
+    /// Returns the one-shot limits of this invocation (e.g. `ciel build --memory`), which
+    /// override the limits of all the instances (even the per-instance ones)
+    pub fn one_shot() -> Self {
+        let options = global_options();
+
+        ResourceLimits {
+            memory_max: options.memory_max,
+            cpu_quota: options.cpu_quota,
+            tasks_max: None,
+        }
+    }
+
+    fn values(&self) -> [Option<&String>; 3] {
+        [
+            self.memory_max.as_ref(),
+            self.cpu_quota.as_ref(),
+            self.tasks_max.as_ref(),
+        ]
+    }
+
+    /// Returns the limits with the ones set in `other` taking precedence
+    pub fn merge(&self, other: &ResourceLimits) -> ResourceLimits {
+        ResourceLimits {
+            memory_max: other.memory_max.clone().or_else(|| self.memory_max.clone()),
+            cpu_quota: other.cpu_quota.clone().or_else(|| self.cpu_quota.clone()),
+            tasks_max: other.tasks_max.clone().or_else(|| self.tasks_max.clone()),
+        }
+    }
+
+    /// Returns the systemd properties of all the limits, the absent limits are reset as well,
+    /// so that removing a limit lifts it from a running instance. `cpu-quota` is checked against
+    /// the host when it is set, here it is capped to the CPUs of the host instead
+    pub fn properties(&self) -> Result<Vec<String>> {
+        LIMIT_KEYS
+            .iter()
+            .zip(self.values())
+            .map(|(key, value)| {
+                let value = value.map_or("infinity", |x| x.trim());
+                if *key != "cpu-quota" || value == "infinity" {
+                    return limit_property(key, value)
+                        .map_err(|e| anyhow!("Invalid value for `{}`: {}", key, e));
+                }
+                let percent = cpu_quota_percent(value)
+                    .map_err(|e| anyhow!("Invalid value for `{}`: {}", key, e))?;
+                let host = std::thread::available_parallelism().map_or(1, |x| x.get()) as u64 * 100;
+                if percent > host {
+                    warn!(
+                        "cpu-quota {} exceeds the CPUs of the host, limiting to {}% instead.",
+                        value, host
+                    );
+                }
+
+                Ok(format!("CPUQuota={}%", percent.min(host)))
+            })
+            .collect()
+    }
+}
+
+impl CielConfig {
+    pub fn save_config(&self) -> Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    pub fn load_config(data: &str) -> Result<CielConfig> {
+        Ok(Self::load_config_with_migration(data)?.0)
+    }
+
+    /// Loads the configuration, also returns the warnings about the unknown keys
+    pub fn load_config_with_warnings(data: &str) -> Result<(CielConfig, Vec<String>)> {
+        let (config, _, warnings) = Self::parse_config(data)?;
+
+        Ok((config, warnings))
+    }
+
+    /// Loads the configuration and upgrades it to the current version if needed,
+    /// also returns whether a migration happened
+    pub fn load_config_with_migration(data: &str) -> Result<(CielConfig, bool)> {
+        let (config, migrated, _) = Self::parse_config(data)?;
+
+        Ok((config, migrated))
+    }
+
+    /// Parses the configuration strictly, unknown keys (typos or keys from newer versions of ciel)
+    /// are ignored with warnings. Returns the configuration, whether a migration happened and the warnings
+    fn parse_config(data: &str) -> Result<(CielConfig, bool, Vec<String>)> {
+        let mut table: toml::value::Table = toml::from_str(data)?;
+        // checked before the migration, which fills the keys missing from the older versions
+        let defaulted_keys = UPGRADE_PROMPTS
+            .iter()
+            .map(|x| x.0)
+            .filter(|x| !table.contains_key(*x))
+            .collect();
+        let migrated = migration::migrate(&mut table)?;
+        let mut warnings = Vec::new();
+        let mut config: CielConfig = match toml::Value::Table(table.clone()).try_into() {
+            Ok(config) => config,
+            Err(e) => {
+                warnings = strict::strip_unknown_keys(&mut table);
+                if warnings.is_empty() {
+                    return Err(e.into());
+                }
+                toml::Value::Table(table).try_into()?
+            }
+        };
+        config.defaulted_keys = defaulted_keys;
+        for maintainer in config.maintainers.iter() {
+            validate_maintainer(maintainer)
+                .map_err(|e| anyhow!("Invalid maintainer `{}`: {}", maintainer, e))?;
+        }
+        for name in config.build_env.keys() {
+            validate_env_name(name)?;
+        }
+        // written into apt.conf, a hand-edited or imported file must not inject directives
+        for (key, proxy) in [
+            ("http_proxy", &config.http_proxy),
+            ("https_proxy", &config.https_proxy),
+        ] {
+            if let Some(proxy) = proxy {
+                validate_proxy(proxy).map_err(|e| anyhow!("Invalid {}: {}", key, e))?;
+            }
+        }
+
+        Ok((config, migrated, warnings))
+    }
+
+    /// Returns the keys that were absent from the configuration file and use the default values
+    pub fn defaulted_keys(&self) -> &[&'static str] {
+        &self.defaulted_keys
+    }
+
+    /// Returns the environment variables for the proxies
+    fn proxy_environment(&self) -> Vec<(String, String)> {
+        let mut env = Vec::new();
+        for (name, value) in [
+            ("http_proxy", &self.http_proxy),
+            ("https_proxy", &self.https_proxy),
+            ("no_proxy", &self.no_proxy),
+        ] {
+            if let Some(value) = value {
+                env.push((name.to_owned(), value.clone()));
+                env.push((name.to_uppercase(), value.clone()));
+            }
+        }
+
+        env
+    }
+
+    /// Returns the environment variables to be exported into the container
+    pub fn container_environment(&self) -> Vec<(String, String)> {
+        let mut env = self.proxy_environment();
+        for (name, value) in self.build_env.iter() {
+            env.push((name.clone(), value.clone()));
+        }
+
+        env
+    }
+
+    /// Returns the bind mount for the ccache directory if ccache is enabled
+    pub fn ccache_mount(&self) -> Option<MountSpec> {
+        if !self.use_ccache {
+            return None;
+        }
+        let host = self
+            .ccache_dir
+            .clone()
+            .unwrap_or_else(|| Path::new(CIEL_DATA_DIR).join("ccache"));
+
+        Some(MountSpec {
+            host,
+            container: PathBuf::from(CCACHE_CONTAINER_DIR),
+            read_only: false,
+        })
+    }
+
+    /// Returns the bind mount for the shared APT cache if it is enabled
+    pub fn apt_cache_mount(&self) -> Option<MountSpec> {
+        if !self.shared_apt_cache {
+            return None;
+        }
+
+        Some(MountSpec {
+            host: PathBuf::from(CIEL_APT_CACHE_DIR),
+            container: PathBuf::from(APT_ARCHIVES_CONTAINER_DIR),
+            read_only: false,
+        })
+    }
+
+    /// Returns the bind mount for the persisted home directory of the instance if it is enabled,
+    /// the directory is kept outside of the layers, so that rolling back leaves it untouched
+    pub fn home_mount(&self, instance: &str) -> Option<MountSpec> {
+        if !self.for_instance(instance).persist_home {
+            return None;
+        }
+
+        Some(MountSpec {
+            host: Path::new(CIEL_INST_DIR).join(instance).join(HOME_DIR),
+            container: PathBuf::from(HOME_CONTAINER_DIR),
+            read_only: false,
+        })
+    }
+
+    /// Update the paths pointing into the workspace at its old location after it is moved,
+    /// they are made relative to the workspace where possible. Returns whether any path is changed
+    pub fn relocate_paths(&mut self, old_root: &Path, new_root: &Path) -> bool {
+        let mut changed = false;
+        // `output-dir` must be absolute
+        if let Some(Ok(relative)) = self.output_dir.as_ref().map(|x| x.strip_prefix(old_root)) {
+            self.output_dir = if relative.as_os_str().is_empty() {
+                None
+            } else {
+                Some(new_root.join(relative))
+            };
+            changed = true;
+        }
+        let hooks = &mut self.hooks;
+        let paths = self
+            .ccache_dir
+            .iter_mut()
+            .chain(self.log_dir.iter_mut())
+            .chain(self.rootfs_keyring.iter_mut())
+            .chain(self.ca_bundle.iter_mut())
+            .chain(self.extra_mounts.iter_mut().map(|x| &mut x.host))
+            .chain(self.trees.iter_mut().map(|x| &mut x.source))
+            .chain(hooks.pre_build.iter_mut())
+            .chain(hooks.post_build.iter_mut())
+            .chain(hooks.pre_update_os.iter_mut())
+            .chain(hooks.post_update_os.iter_mut());
+        for path in paths {
+            if let Ok(relative) = path.strip_prefix(old_root) {
+                *path = if relative.as_os_str().is_empty() {
+                    PathBuf::from(".")
+                } else {
+                    relative.to_owned()
+                };
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    /// Returns the URIs of the APT repositories of the workspace
+    pub fn apt_source_uris(&self) -> Vec<String> {
+        source_uris(&self.apt_sources)
+    }
+
+    /// Returns the number of retries of the interrupted downloads
+    pub fn download_retries(&self) -> usize {
+        self.download_retries.unwrap_or(DEFAULT_DOWNLOAD_RETRIES)
+    }
+
+    /// Returns whether `apt full-upgrade` is used for updating the OS
+    pub fn update_full_upgrade(&self) -> bool {
+        self.update_full_upgrade.unwrap_or(true)
+    }
+
+    /// Returns whether the obsolete packages are removed after updating the OS
+    pub fn update_autoremove(&self) -> bool {
+        self.update_autoremove.unwrap_or(true)
+    }
+
+    /// Returns whether the downloaded packages are removed after updating the OS,
+    /// the packages in the shared APT cache are kept for the instances by default
+    pub fn update_clean(&self) -> bool {
+        self.update_clean.unwrap_or(!self.shared_apt_cache)
+    }
+
+    /// Returns the number of connections of the parallel downloads
+    pub fn download_connections(&self) -> usize {
+        self.download_connections
+            .unwrap_or(DEFAULT_DOWNLOAD_CONNECTIONS)
+    }
+
+    /// Returns the number of parallel build jobs, `None` means automatic
+    pub fn build_jobs(&self) -> Option<usize> {
+        self.parallelism.filter(|x| *x > 0)
+    }
+
+    /// Returns the active maintainer
+    pub fn primary_maintainer(&self) -> &str {
+        self.maintainers
+            .get(self.active_maintainer)
+            .or_else(|| self.maintainers.first())
+            .map_or("", |x| x.as_str())
+    }
+
+    /// Select the active maintainer by its index or its value.
+    /// Unknown maintainers will be validated and added to the list
+    pub fn select_maintainer(&mut self, maintainer: &str) -> Result<()> {
+        if let Ok(index) = maintainer.parse::<usize>() {
+            if index >= self.maintainers.len() {
+                return Err(anyhow!(
+                    "Maintainer #{} does not exist (there are {} maintainers).",
+                    index,
+                    self.maintainers.len()
+                ));
+            }
+            self.active_maintainer = index;
+            return Ok(());
+        }
+        if let Some(index) = self.maintainers.iter().position(|x| x == maintainer) {
+            self.active_maintainer = index;
+            return Ok(());
+        }
+        validate_maintainer(&maintainer.to_owned())
+            .map_err(|e| anyhow!("Invalid maintainer `{}`: {}", maintainer, e))?;
+        self.maintainers.push(maintainer.to_owned());
+        self.active_maintainer = self.maintainers.len() - 1;
+
+        Ok(())
+    }
+
+    /// Returns the configuration of the specified instance,
+    /// unknown instances will get the global values
+    pub fn for_instance(&self, name: &str) -> InstanceConfig {
+        let overrides = self.instances.get(name).cloned().unwrap_or_default();
+
+        InstanceConfig {
+            extra_options: merge_nspawn_options(
+                &self.extra_options,
+                overrides.extra_options.as_deref().unwrap_or_default(),
+            ),
+            sep_mount: overrides.sep_mount.unwrap_or(self.sep_mount),
+            volatile_mount: overrides.volatile_mount.unwrap_or(self.volatile_mount),
+            persist_home: overrides.persist_home.unwrap_or(self.persist_home),
+            apt_sources: overrides
+                .apt_sources
+                .unwrap_or_else(|| self.apt_sources.clone()),
+            limits: ResourceLimits {
+                memory_max: overrides.memory_max.or_else(|| self.memory_max.clone()),
+                cpu_quota: overrides.cpu_quota.or_else(|| self.cpu_quota.clone()),
+                tasks_max: overrides.tasks_max.or_else(|| self.tasks_max.clone()),
+            },
+            network: overrides.network.unwrap_or(self.network),
+            ports: overrides.ports,
+            hostname: overrides.hostname.unwrap_or_else(|| default_hostname(name)),
+            read_only: overrides.read_only.unwrap_or(false),
+            private_users: overrides.private_users.unwrap_or(self.private_users),
+        }
+    }
+}
+
+#[inline]
+fn default_output_dir_pattern() -> String {
+    DEFAULT_OUTPUT_DIR_PATTERN.to_owned()
+}
+
+impl Default for CielConfig {
+    fn default() -> Self {
+        CielConfig {
+            version: CURRENT_CIEL_VERSION,
+            maintainers: vec!["Bot <null@aosc.io>".to_string()],
+            active_maintainer: 0,
+            dnssec: false,
+            dns_servers: Vec::new(),
+            apt_sources: DEFAULT_APT_SOURCE.to_string(),
+            http_proxy: None,
+            https_proxy: None,
+            no_proxy: None,
+            ca_bundle: None,
+            local_repo: true,
+            local_repo_priority: None,
+            isolate_network: false,
+            local_sources: true,
+            extra_options: Vec::new(),
+            sep_mount: true,
+            output_dir_pattern: default_output_dir_pattern(),
+            output_dir: None,
+            volatile_mount: false,
+            clear_machine_id: false,
+            shared_apt_cache: false,
+            persist_home: false,
+            auto_stop_after: None,
+            boot_timeout: None,
+            stop_timeout: None,
+            network_timeout: None,
+            memory_max: None,
+            cpu_quota: None,
+            tasks_max: None,
+            trash_max_age: None,
+            trash_max_size: None,
+            log_dir: None,
+            sources_format: SourcesFormat::List,
+            manage_sources_list: false,
+            extra_mounts: Vec::new(),
+            use_ccache: false,
+            ccache_dir: None,
+            parallelism: None,
+            timezone: None,
+            locale: None,
+            trees: default_trees(),
+            rootfs_url: None,
+            rootfs_arch: None,
+            rootfs_keyring: None,
+            download_retries: None,
+            download_connections: None,
+            update_full_upgrade: None,
+            update_autoremove: None,
+            update_clean: None,
+            package_manager: PackageManager::Auto,
+            network: NetworkMode::Host,
+            private_users: PrivateUsers::Off,
+            limit_rate: None,
+            fastest_mirror: false,
+            editor: None,
+            build_env: BTreeMap::new(),
+            hooks: HooksConfig::default(),
+            instances: BTreeMap::new(),
+            defaulted_keys: Vec::new(),
+        }
+    }
+}
+
+/// Validate the maintainer information (in the form of `Name <local@domain>`),
+/// the positions in the error messages are byte offsets
+#[allow(clippy::ptr_arg)]
+fn validate_maintainer(maintainer: &String) -> Result<(), String> {
+    let maintainer = maintainer.trim_end();
+    // the maintainer ends up in ab3cfg.sh as `MTER="..."`, which must stay a plain string
+    if let Some((pos, c)) = maintainer
+        .char_indices()
+        .find(|(_, c)| ['"', '\\', '$', '`'].contains(c) || c.is_control())
+    {
+        return Err(format!(
+            "{:?} at position {} is not allowed, it would break the shell quoting in ab3cfg.sh",
+            c, pos
+        ));
+    }
+    let lt = maintainer.find('<').ok_or_else(|| {
+        format!(
+            "missing '<' before the email address at position {}",
+            maintainer.len()
+        )
+    })?;
+    if let Some(pos) = maintainer[..lt].find('>') {
+        return Err(format!("unexpected '>' in name at position {}", pos));
+    }
+    if maintainer[..lt].trim().is_empty() {
+        return Err(format!("missing name before '<' at position {}", lt));
+    }
+    let email_start = lt + 1;
+    let gt = maintainer[email_start..]
+        .find('>')
+        .map(|x| x + email_start)
+        .ok_or_else(|| {
+            format!(
+                "missing '>' after the email address at position {}",
+                maintainer.len()
+            )
+        })?;
+    if let Some((pos, c)) = maintainer[gt + 1..].char_indices().next() {
+        return Err(format!(
+            "unexpected text after '>' at position {}: {:?}",
+            gt + 1 + pos,
+            c
+        ));
+    }
+    let email = &maintainer[email_start..gt];
+    if let Some((pos, c)) = email
+        .char_indices()
+        .find(|(_, c)| *c == '<' || c.is_whitespace())
+    {
+        return Err(format!(
+            "unexpected {:?} in email address at position {}",
+            c,
+            email_start + pos
+        ));
+    }
+    let at = email
+        .find('@')
+        .ok_or_else(|| format!("missing '@' in email address at position {}", gt))?;
+    if at == 0 {
+        return Err(format!(
+            "empty local part in email address at position {}",
+            email_start
+        ));
+    }
+    let domain_start = email_start + at + 1;
+    let domain = &maintainer[domain_start..gt];
+    if let Some(pos) = domain.find('@') {
+        return Err(format!(
+            "unexpected second '@' in email address at position {}",
+            domain_start + pos
+        ));
+    }
+    if domain.is_empty() {
+        return Err(format!(
+            "email domain is missing at position {}",
+            domain_start
+        ));
+    }
+    if !domain.contains('.') {
+        return Err(format!(
+            "missing top-level domain in email address at position {}",
+            gt
+        ));
+    }
+    if domain.starts_with('.') || domain.ends_with('.') || domain.contains("..") {
+        return Err(format!(
+            "invalid domain `{}` in email address at position {}",
+            domain, domain_start
+        ));
+    }
+
+    Ok(())
+}
+
+/// Parse the list of DNS servers (separated by commas or whitespaces)
+fn parse_dns_servers(servers: &str) -> Result<Vec<String>, String> {
+    let mut result = Vec::new();
+    for server in servers.split(|c: char| c == ',' || c.is_whitespace()) {
+        if server.is_empty() {
+            continue;
+        }
+        if server.parse::<IpAddr>().is_err() {
+            return Err(format!("`{}` is not a valid IP address.", server));
+        }
+        result.push(server.to_owned());
+    }
+
+    Ok(result)
+}
+
+/// Asks for the proxy setting, uses the value from the host environment as the default value
+fn ask_for_proxy(
+    theme: &dyn dialoguer::theme::Theme,
+    prompt: &str,
+    env_name: &str,
+    current: &Option<String>,
+) -> Result<Option<String>> {
+    let default = current
+        .clone()
+        .or_else(|| std::env::var(env_name).ok())
+        .or_else(|| std::env::var(env_name.to_uppercase()).ok())
+        .unwrap_or_default();
+    let value = Input::<String>::with_theme(theme)
+        .with_prompt(prompt)
+        .default(default)
+        .allow_empty(true)
+        .interact_text()?;
+
+    if env_name == "no_proxy" {
+        validate_no_proxy(&value)
+    } else {
+        validate_proxy(&value)
+    }
+}
+
+#[inline]
+fn normalize_proxy(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_owned())
+    }
+}
+
+/// Validate the proxy URL, it is written into the APT configuration as a quoted string
+fn validate_proxy(value: &str) -> Result<Option<String>> {
+    let proxy = match normalize_proxy(value) {
+        Some(proxy) => proxy,
+        None => return Ok(None),
+    };
+    if let Some(c) = proxy
+        .chars()
+        .find(|c| c.is_whitespace() || c.is_control() || *c == '"' || *c == ';')
+    {
+        return Err(anyhow!(
+            "Invalid proxy `{}`: {:?} is not allowed, percent-encode it in the credentials",
+            proxy.escape_debug(),
+            c
+        ));
+    }
+    crate::network::parse_proxy_url(&proxy)?;
+
+    Ok(Some(proxy))
+}
+
+/// Whether APT can bypass the proxy for the host (`Acquire::http::Proxy::<host>`):
+/// only the exact host names and IPv4 addresses, no wildcards, domain suffixes or CIDR ranges
+fn is_apt_proxy_host(host: &str) -> bool {
+    !host.is_empty()
+        && !host.starts_with('.')
+        && !host.starts_with('-')
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+/// Validate the comma-separated hosts without proxy
+fn validate_no_proxy(value: &str) -> Result<Option<String>> {
+    let no_proxy = match normalize_proxy(value) {
+        Some(no_proxy) => no_proxy,
+        None => return Ok(None),
+    };
+    for host in no_proxy
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+    {
+        if !is_apt_proxy_host(host) {
+            return Err(anyhow!(
+                "Unsupported no-proxy entry `{}`: APT only accepts the exact host names and IPv4 addresses (no wildcards, domain suffixes, ports or CIDR ranges)",
+                host.escape_debug()
+            ));
+        }
+    }
+
+    Ok(Some(no_proxy))
+}
+
+/// Expand the placeholders (`{branch}` and `{arch}`) in the output directory pattern
+pub fn expand_output_dir_pattern(pattern: &str, branch: &str, arch: &str) -> Result<String> {
+    let mut output = String::with_capacity(pattern.len());
+    let mut rest = pattern;
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("unterminated placeholder in `{}`", pattern))?;
+        match &rest[start + 1..start + end] {
+            "branch" => output.push_str(branch),
+            "arch" => output.push_str(arch),
+            placeholder => {
+                return Err(anyhow!(
+                    "unknown placeholder `{{{}}}`, only `{{branch}}` and `{{arch}}` are supported",
+                    placeholder
+                ))
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+    output.push_str(rest);
+
+    Ok(output)
+}
+
+/// Check if the output directory pattern always produces a safe relative path
+fn validate_output_dir_pattern(pattern: &str) -> Result<()> {
+    let expanded = expand_output_dir_pattern(pattern, "branch", "arch")?;
+    let path = Path::new(&expanded);
+    if expanded.is_empty()
+        || !path
+            .components()
+            .all(|x| matches!(x, std::path::Component::Normal(_)))
+    {
+        return Err(anyhow!(
+            "`{}` should be a relative path without `..`",
+            pattern
+        ));
+    }
+    if !pattern.contains("{branch}") {
+        warn!("The output directory pattern does not contain `{{branch}}`, all the branches will share the same directory.");
+    }
+
+    Ok(())
+}
+
+/// Check if the timezone exists in the zoneinfo directory of the host
+fn validate_timezone(timezone: &str) -> Result<(), String> {
+    validate_timezone_in(Path::new(ZONEINFO_DIR), timezone)
+}
+
+fn validate_timezone_in(zoneinfo: &Path, timezone: &str) -> Result<(), String> {
+    let path = Path::new(timezone);
+    if timezone.is_empty()
+        || !path
+            .components()
+            .all(|x| matches!(x, std::path::Component::Normal(_)))
+    {
+        return Err("expected a timezone name like `Asia/Shanghai`".to_owned());
+    }
+    if !zoneinfo.join(path).is_file() {
+        return Err(format!(
+            "unknown timezone (not found in {})",
+            zoneinfo.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Get the timezone of the host from /etc/localtime
+fn host_timezone() -> Option<String> {
+    let target = fs::read_link("/etc/localtime").ok()?;
+    let target = target.to_string_lossy();
+    let (_, timezone) = target.split_once("zoneinfo/")?;
+
+    Some(timezone.to_owned())
+}
+
+/// Asks for additional ACBS trees, the first (default) tree is always kept
+fn ask_for_trees(
+    theme: &dyn dialoguer::theme::Theme,
+    current: &[TreeConfig],
+) -> Result<Vec<TreeConfig>> {
+    let mut trees = current.iter().take(1).cloned().collect::<Vec<_>>();
+    if trees.is_empty() {
+        trees = default_trees();
+    }
+    for tree in current.iter().skip(1) {
+        if Confirm::with_theme(theme)
+            .with_prompt(format!(
+                "Keep tree `{}` ({} at {})",
+                tree.name,
+                tree.source.display(),
+                tree.location
+            ))
+            .default(true)
+            .interact()?
+        {
+            trees.push(tree.clone());
+        }
+    }
+    loop {
+        let name = Input::<String>::with_theme(theme)
+            .with_prompt("Name of the tree (leave empty to finish)")
+            .allow_empty(true)
+            .interact_text()?;
+        if name.is_empty() {
+            break;
+        }
+        let source = Input::<String>::with_theme(theme)
+            .with_prompt("Directory of the tree (relative to the workspace)")
+            .default(name.to_uppercase())
+            .interact_text()?;
+        let location = Input::<String>::with_theme(theme)
+            .with_prompt("Location in the container")
+            .default(format!("/{}/", name))
+            .interact_text()?;
+        let priority = Input::<i32>::with_theme(theme)
+            .with_prompt("Priority (trees with higher priorities are searched first)")
+            .default(0)
+            .interact_text()?;
+        let tree = TreeConfig {
+            name,
+            source: PathBuf::from(source),
+            location,
+            priority,
+        };
+        let mut candidate = trees.clone();
+        candidate.push(tree);
+        match validate_trees(&candidate) {
+            Ok(()) => trees = candidate,
+            Err(e) => warn!("{}", e),
+        }
+    }
+
+    Ok(trees)
+}
+
+/// Asks for the extra bind mounts, an empty host path finishes the list
+fn ask_for_mounts(
+    theme: &dyn dialoguer::theme::Theme,
+    current: &[MountSpec],
+) -> Result<Vec<MountSpec>> {
+    let mut mounts = Vec::new();
+    if !current.is_empty() {
+        info!(
+            "Current extra mounts: {}",
+            current
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        if Confirm::with_theme(theme)
+            .with_prompt("Keep the current extra mounts")
+            .default(true)
+            .interact()?
+        {
+            mounts.extend_from_slice(current);
+        }
+    }
+    loop {
+        let host = Input::<String>::with_theme(theme)
+            .with_prompt("Host path (leave empty to finish)")
+            .allow_empty(true)
+            .validate_with(|input: &String| -> Result<(), String> {
+                if input.is_empty() || Path::new(input).is_dir() {
+                    Ok(())
+                } else {
+                    Err("Please enter an existing directory.".to_owned())
+                }
+            })
+            .interact_text()?;
+        if host.is_empty() {
+            break;
+        }
+        let container = Input::<String>::with_theme(theme)
+            .with_prompt("Container path")
+            .validate_with(|input: &String| -> Result<(), String> {
+                MountSpec::parse(&format!("{}:{}", host, input))
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            })
+            .interact_text()?;
+        let read_only = Confirm::with_theme(theme)
+            .with_prompt("Read-only")
+            .default(false)
+            .interact()?;
+        mounts.push(MountSpec {
+            host: PathBuf::from(host),
+            container: PathBuf::from(container),
+            read_only,
+        });
+    }
+
+    Ok(mounts)
+}
+
+/// Shows a series of prompts to let the user select the configurations
+pub fn ask_for_config(config: Option<CielConfig>) -> Result<CielConfig> {
+    let mut config = config.unwrap_or_default();
+    if !user_attended() {
+        info!("Not controlled by an user. Default values are used.");
+        return Ok(config);
+    }
+    let theme = ColorfulTheme::default();
+    let mut selection = config.maintainers.len();
+    if !config.maintainers.is_empty() {
+        let mut items = config.maintainers.clone();
+        items.push("Add a new maintainer ...".to_owned());
+        selection = Select::with_theme(&theme)
+            .with_prompt("Maintainer Information")
+            .items(&items)
+            .default(config.active_maintainer.min(config.maintainers.len() - 1))
+            .interact()?;
+    }
+    if selection < config.maintainers.len() {
+        config.active_maintainer = selection;
+    } else {
+        let maintainer = Input::<String>::with_theme(&theme)
+            .with_prompt("New Maintainer Information")
+            .validate_with(validate_maintainer)
+            .interact_text()?;
+        config.select_maintainer(&maintainer)?;
+    }
+    config.dnssec = Confirm::with_theme(&theme)
+        .with_prompt("Enable DNSSEC")
+        .default(config.dnssec)
+        .interact()?;
+    let dns_servers = Input::<String>::with_theme(&theme)
+        .with_prompt("DNS servers (comma-separated, leave empty to use the host resolver)")
+        .default(config.dns_servers.join(", "))
+        .allow_empty(true)
+        .validate_with(|input: &String| parse_dns_servers(input).map(|_| ()))
+        .interact_text()?;
+    config.dns_servers = parse_dns_servers(&dns_servers).map_err(|e| anyhow!("{}", e))?;
+    config.http_proxy = ask_for_proxy(&theme, "HTTP proxy", "http_proxy", &config.http_proxy)?;
+    config.https_proxy = ask_for_proxy(&theme, "HTTPS proxy", "https_proxy", &config.https_proxy)?;
+    if config.http_proxy.is_some() || config.https_proxy.is_some() {
+        config.no_proxy = ask_for_proxy(
+            &theme,
+            "Hosts without proxy (comma-separated)",
+            "no_proxy",
+            &config.no_proxy,
+        )?;
+    } else {
+        config.no_proxy = None;
+    }
+    config.sources_format = match Select::with_theme(&theme)
+        .with_prompt("Format of the APT sources")
+        .items(&["One-line style (sources.list)", "Deb822 style (.sources)"])
+        .default(if config.sources_format == SourcesFormat::Deb822 {
+            1
+        } else {
+            0
+        })
+        .interact()?
+    {
+        1 => SourcesFormat::Deb822,
+        _ => SourcesFormat::List,
+    };
+    let deb822 = config.sources_format == SourcesFormat::Deb822;
+    let edit_source = Confirm::with_theme(&theme)
+        .with_prompt("Edit APT sources")
+        .default(false)
+        .interact()?;
+    if edit_source {
+        let mut sources = if config.apt_sources.is_empty() {
+            DEFAULT_APT_SOURCE.to_owned()
+        } else {
+            config.apt_sources.clone()
+        };
+        if deb822 {
+            // the sources are always stored in the one-line style
+            sources = sources_list_to_deb822(&sources).unwrap_or(sources);
+        }
+        if config.editor.is_none() && detect_editor().is_none() {
+            let editor = Input::<String>::with_theme(&theme)
+                .with_prompt("Editor to use (e.g. `vim` or `code --wait`, leave empty to use nano)")
+                .allow_empty(true)
+                .validate_with(|input: &String| -> Result<(), String> {
+                    if input.trim().is_empty() {
+                        return Ok(());
+                    }
+                    split_command(input).map(|_| ()).map_err(|e| e.to_string())
+                })
+                .interact_text()?;
+            config.editor = Some(editor.trim().to_owned()).filter(|x| !x.is_empty());
+        }
+        let command = editor_command(config.editor.as_deref());
+        loop {
+            let edited = editor::edit(
+                &command,
+                &sources,
+                if deb822 { ".sources" } else { ".list" },
+            )?;
+            let edited = match edited {
+                Some(edited) => edited,
+                None => {
+                    config.apt_sources = DEFAULT_APT_SOURCE.to_owned();
+                    break;
+                }
+            };
+            // remove the error messages from the previous attempt
+            sources = edited
+                .lines()
+                .filter(|line| !line.starts_with(APT_SOURCES_ERROR_PREFIX))
+                .fold(String::with_capacity(edited.len()), |acc, x| acc + x + "\n");
+            let result = if deb822 {
+                deb822_to_sources_list(&sources)
+            } else {
+                Ok(sources.clone())
+            };
+            match result.and_then(|list| validate_apt_sources(&list).map(|_| list)) {
+                Ok(list) => {
+                    config.apt_sources = list;
+                    break;
+                }
+                Err(e) => {
+                    warn!("{}", e);
+                    sources = format!("{}{}\n{}", APT_SOURCES_ERROR_PREFIX, e, sources);
+                }
+            }
+        }
+    }
+    if Confirm::with_theme(&theme)
+        .with_prompt("Configure extra bind mounts")
+        .default(false)
+        .interact()?
+    {
+        config.extra_mounts = ask_for_mounts(&theme, &config.extra_mounts)?;
+    }
+    config.use_ccache = Confirm::with_theme(&theme)
+        .with_prompt("Enable ccache")
+        .default(config.use_ccache)
+        .interact()?;
+    if config.use_ccache {
+        let ccache_dir = Input::<String>::with_theme(&theme)
+            .with_prompt("ccache directory on the host (leave empty to use the default)")
+            .allow_empty(true)
+            .with_initial_text(
+                config
+                    .ccache_dir
+                    .as_ref()
+                    .map(|x| x.display().to_string())
+                    .unwrap_or_default(),
+            )
+            .interact_text()?;
+        config.ccache_dir = if ccache_dir.trim().is_empty() {
+            None
+        } else {
+            Some(PathBuf::from(ccache_dir.trim()))
+        };
+    }
+    let cpus = std::thread::available_parallelism().map_or(1, |x| x.get());
+    let parallelism = Input::<usize>::with_theme(&theme)
+        .with_prompt("Number of parallel build jobs (0 = automatic)")
+        .default(config.parallelism.unwrap_or(cpus))
+        .interact_text()?;
+    config.parallelism = if parallelism > 0 {
+        Some(parallelism)
+    } else {
+        None
+    };
+    let timezone = Input::<String>::with_theme(&theme)
+        .with_prompt("Timezone of the container (leave empty to keep the container's setting)")
+        .allow_empty(true)
+        .with_initial_text(
+            config
+                .timezone
+                .clone()
+                .or_else(host_timezone)
+                .unwrap_or_default(),
+        )
+        .validate_with(|input: &String| -> Result<(), String> {
+            if input.is_empty() {
+                return Ok(());
+            }
+            validate_timezone(input)
+        })
+        .interact_text()?;
+    config.timezone = Some(timezone).filter(|x| !x.is_empty());
+    let locale = Input::<String>::with_theme(&theme)
+        .with_prompt("Locale of the container (leave empty to keep the container's setting)")
+        .allow_empty(true)
+        .with_initial_text(
+            config
+                .locale
+                .clone()
+                .or_else(|| std::env::var("LANG").ok())
+                .unwrap_or_default(),
+        )
+        .interact_text()?;
+    config.locale = Some(locale.trim().to_owned()).filter(|x| !x.is_empty());
+    if Confirm::with_theme(&theme)
+        .with_prompt("Configure additional ACBS trees")
+        .default(config.trees.len() > 1)
+        .interact()?
+    {
+        config.trees = ask_for_trees(&theme, &config.trees)?;
+    }
+    if Confirm::with_theme(&theme)
+        .with_prompt("Configure advanced options (OS tarball source)")
+        .default(false)
+        .interact()?
+    {
+        let rootfs_url = Input::<String>::with_theme(&theme)
+            .with_prompt(
+                "OS tarball URL, path or mirror (ends with `/`, leave empty to use the default)",
+            )
+            .allow_empty(true)
+            .with_initial_text(config.rootfs_url.clone().unwrap_or_default())
+            .interact_text()?;
+        config.rootfs_url = Some(rootfs_url.trim().to_owned()).filter(|x| !x.is_empty());
+        let rootfs_arch = Input::<String>::with_theme(&theme)
+            .with_prompt("Architecture of the OS tarball (leave empty to use the host's)")
+            .allow_empty(true)
+            .with_initial_text(config.rootfs_arch.clone().unwrap_or_default())
+            .interact_text()?;
+        config.rootfs_arch = Some(rootfs_arch.trim().to_owned()).filter(|x| !x.is_empty());
+    }
+    config.local_sources = Confirm::with_theme(&theme)
+        .with_prompt("Enable local sources caching")
+        .default(config.local_sources)
+        .interact()?;
+    config.local_repo = Confirm::with_theme(&theme)
+        .with_prompt("Enable local packages repository")
+        .default(config.local_repo)
+        .interact()?;
+    config.sep_mount = Confirm::with_theme(&theme)
+        .with_prompt("Use different OUTPUT dir for different branches")
+        .default(config.sep_mount)
+        .interact()?;
+    config.volatile_mount = Confirm::with_theme(&theme)
+        .with_prompt("Use volatile mode for filesystem operations")
+        .default(config.volatile_mount)
+        .interact()?;
+
+    Ok(config)
+}
+
+/// Ask for the values of the keys absent from the configuration file (usually introduced
+/// by a newer version of ciel), all the other values are preserved
+pub fn upgrade_interactive(old: CielConfig) -> Result<CielConfig> {
+    let mut config = old;
+    let keys = std::mem::take(&mut config.defaulted_keys);
+    if keys.is_empty() {
+        return Ok(config);
+    }
+    if !user_attended() {
+        info!(
+            "Not controlled by an user. Default values are used for: {}",
+            keys.join(", ")
+        );
+        return Ok(config);
+    }
+    let theme = ColorfulTheme::default();
+    for (key, prompt) in UPGRADE_PROMPTS.iter().filter(|x| keys.contains(&x.0)) {
+        let current = get_config_value(&config, key)?;
+        if let Ok(current) = current.parse::<bool>() {
+            let value = Confirm::with_theme(&theme)
+                .with_prompt(*prompt)
+                .default(current)
+                .interact()?;
+            set_config_value(&mut config, key, &value.to_string())?;
+            continue;
+        }
+        loop {
+            let value = Input::<String>::with_theme(&theme)
+                .with_prompt(*prompt)
+                .default(current.clone())
+                .interact_text()?;
+            match set_config_value(&mut config, key, &value) {
+                Ok(()) => break,
+                Err(e) => warn!("{}", e),
+            }
+        }
+    }
+
+    Ok(config)
+}
+
+/// Reads the configuration file from the current workspace,
+/// with the overrides from the environment variables applied
+pub fn read_config() -> Result<CielConfig> {
+    read_config_from(".")
+}
+
+/// Reads the configuration file from the specified workspace,
+/// with the overrides from the environment variables applied
+pub fn read_config_from<P: AsRef<Path>>(workspace: P) -> Result<CielConfig> {
+    let mut config = read_config_raw_from(workspace)?;
+    apply_env_overrides(&mut config, |name| std::env::var(name).ok())?;
+
+    Ok(config)
+}
+
+/// Reads the configuration file from the current workspace as-is.
+/// Use this function if the configuration is going to be saved again
+pub fn read_config_raw() -> Result<CielConfig> {
+    read_config_raw_from(".")
+}
+
+/// Reads the configuration file from the specified workspace as-is
+pub fn read_config_raw_from<P: AsRef<Path>>(workspace: P) -> Result<CielConfig> {
+    CielConfig::load_config(&read_config_data(workspace)?)
+}
+
+/// Print the warnings about the configuration file of the current workspace (e.g. unknown keys)
+pub fn print_config_warnings() {
+    for warning in config_warnings() {
+        warn!("{}", warning);
+    }
+}
+
+/// Returns the warnings about the configuration file of the current workspace
+pub fn config_warnings() -> Vec<String> {
+    read_config_data(".")
+        .and_then(|data| CielConfig::load_config_with_warnings(&data))
+        .map(|x| x.1)
+        .unwrap_or_default()
+}
+
+/// Reads the content of the configuration file of the specified workspace
+fn read_config_data<P: AsRef<Path>>(workspace: P) -> Result<String> {
+    let location = config_location(workspace);
+    let mut f = std::fs::File::open(&location).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => anyhow!(
+            "No configuration found at {}, this workspace is not configured yet. Please run `ciel config -g` (or `ciel new` to create a new workspace).",
+            location.display()
+        ),
+        _ => anyhow!("Unable to read {}: {}", location.display(), e),
+    })?;
+    let mut data = String::new();
+    f.read_to_string(&mut data)?;
+
+    Ok(data)
+}
+
+/// Returns the path to the configuration file of the specified workspace
+#[inline]
+pub fn config_location<P: AsRef<Path>>(workspace: P) -> PathBuf {
+    workspace.as_ref().join(DEFAULT_CONFIG_LOCATION)
+}
+
+/// Upgrades the configuration file of the current workspace if it was created by an older ciel,
+/// the upgraded file is saved after the confirmation from the user
+pub fn upgrade_config(confirm: bool) -> Result<()> {
+    upgrade_config_in(".", confirm)
+}
+
+/// Upgrades the configuration file of the specified workspace, see [upgrade_config]
+pub fn upgrade_config_in<P: AsRef<Path>>(workspace: P, confirm: bool) -> Result<()> {
+    let data = match fs::read_to_string(config_location(&workspace)) {
+        Ok(data) => data,
+        // not configured yet
+        Err(_) => return Ok(()),
+    };
+    let (config, migrated) = CielConfig::load_config_with_migration(&data)?;
+    if !migrated {
+        return Ok(());
+    }
+    if confirm {
+        if !user_attended() {
+            warn!("The configuration file was created by an older version of ciel.");
+            warn!("Run `ciel init --upgrade` to save the upgraded configuration.");
+            return Ok(());
+        }
+        let upgrade = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(
+                "The configuration file was created by an older version of ciel. Upgrade it now?",
+            )
+            .default(true)
+            .interact()?;
+        if !upgrade {
+            return Ok(());
+        }
+    }
+    write_config_to(workspace, &config)?;
+    info!("Configuration file has been upgraded.");
+
+    Ok(())
+}
+
+/// Applies the overrides from the environment variables (e.g. `CIEL_MAINTAINER`)
+fn apply_env_overrides<F: Fn(&str) -> Option<String>>(
+    config: &mut CielConfig,
+    lookup: F,
+) -> Result<()> {
+    for (name, key) in ENV_OVERRIDES {
+        if let Some(value) = lookup(name) {
+            set_config_value(config, key, &value)
+                .map_err(|e| anyhow!("Invalid value in ${}: {}", name, e))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the configuration file to the current workspace atomically
+pub fn write_config(config: &CielConfig) -> Result<()> {
+    write_config_to(".", config)
+}
+
+/// Writes the configuration file to the specified workspace atomically,
+/// the previous version is kept as `config.toml.bak`
+pub fn write_config_to<P: AsRef<Path>>(workspace: P, config: &CielConfig) -> Result<()> {
+    persist_config(workspace.as_ref(), &config.save_config()?)
+}
+
+fn persist_config(workspace: &Path, content: &str) -> Result<()> {
+    let config_path = config_location(workspace);
+    if let Ok(current) = fs::read(&config_path) {
+        // nothing changed, do not touch the file (and the backup)
+        if current == content.as_bytes() {
+            return Ok(());
+        }
+        fs::write(workspace.join(DEFAULT_CONFIG_BACKUP_LOCATION), current)?;
+    }
+    let parent = config_path
+        .parent()
+        .ok_or_else(|| anyhow!("Parent directory is root."))?;
+    fs::create_dir_all(parent)?;
+    let mut f = tempfile::NamedTempFile::new_in(parent)?;
+    f.write_all(content.as_bytes())?;
+    f.as_file().sync_all()?;
+    f.persist(&config_path)?;
+
+    Ok(())
+}
+
+/// Restores the configuration file of the current workspace from the backup,
+/// the current configuration becomes the new backup
+pub fn restore_config() -> Result<()> {
+    let backup_path = Path::new(DEFAULT_CONFIG_BACKUP_LOCATION);
+    let backup = fs::read_to_string(backup_path)
+        .map_err(|e| anyhow!("Unable to read the backup {}: {}", backup_path.display(), e))?;
+    CielConfig::load_config(&backup)
+        .map_err(|e| anyhow!("The backup configuration is invalid: {}", e))?;
+    if !user_attended() {
+        return Err(anyhow!(
+            "Restoring the configuration requires confirmation, please run this command in a terminal."
+        ));
+    }
+    let restore = Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Replace the current configuration with the backup?")
+        .default(false)
+        .interact()?;
+    if !restore {
+        info!("Configuration is not changed.");
+        return Ok(());
+    }
+    persist_config(Path::new("."), &backup)?;
+    info!("Configuration has been restored from the backup.");
+
+    Ok(())
+}
+
+#[inline]
+fn parse_bool(key: &str, value: &str) -> Result<bool> {
+    match value {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(anyhow!(
+            "Invalid value for `{}`: expected a boolean (true/false), got `{}`",
+            key,
+            value
+        )),
+    }
+}
+
+/// Parse the boolean, an empty value resets it to the default
+#[inline]
+fn parse_optional_bool(key: &str, value: &str) -> Result<Option<bool>> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    parse_bool(key, value).map(Some)
+}
+
+/// Parse a duration (see [parse_duration]), an empty value means unset
+fn parse_optional_duration(key: &str, value: &str) -> Result<Option<Duration>> {
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+
+    parse_duration(value).map(Some).ok_or_else(|| {
+        anyhow!(
+            "Invalid value for `{}`: expected a duration like `30m` or `1h30m`, got `{}`",
+            key,
+            value
+        )
+    })
+}
+
+#[inline]
+fn parse_list(value: &str) -> Vec<String> {
+    value.split_whitespace().map(|x| x.to_owned()).collect()
+}
+
+/// Parse the path to a hook script (an empty value removes the hook)
+fn parse_hook(key: &str, value: &str) -> Result<Option<PathBuf>> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let path = PathBuf::from(value);
+    hooks::validate_hook(key.trim_start_matches("hooks."), &path)?;
+
+    Ok(Some(path))
+}
+
+#[inline]
+fn display_path(path: &Option<PathBuf>) -> String {
+    path.as_ref()
+        .map(|x| x.display().to_string())
+        .unwrap_or_default()
+}
+
+/// Check if the name is a valid shell identifier (and hence a valid variable name)
+pub fn validate_env_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    };
+    if !valid {
+        return Err(anyhow!(
+            "Invalid environment variable name `{}`: only letters, digits and underscores are allowed, and it must not start with a digit",
+            name
+        ));
+    }
+
+    Ok(())
+}
+
+/// Parse `cpu-quota`, either a number of CPUs (e.g. `1.5`) or a percentage of a CPU
+/// (e.g. `150%`), returns the percentage. It must not exceed the number of CPUs of the host
+fn parse_cpu_quota(value: &str, cpus: usize) -> Result<u64> {
+    let percent = cpu_quota_percent(value)?;
+    if percent > cpus as u64 * 100 {
+        return Err(anyhow!(
+            "{}% exceeds the {} CPUs of the host ({}%)",
+            percent,
+            cpus,
+            cpus * 100
+        ));
+    }
+
+    Ok(percent)
+}
+
+/// Parse `cpu-quota` like [parse_cpu_quota], without checking it against the host
+fn cpu_quota_percent(value: &str) -> Result<u64> {
+    let percent = match value.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<u64>().ok(),
+        None => value
+            .parse::<f64>()
+            .ok()
+            .filter(|x| x.is_finite() && *x >= 0.0)
+            .map(|x| (x * 100.0).round() as u64),
+    }
+    .ok_or_else(|| anyhow!("expected a number of CPUs (e.g. 8) or a percentage (e.g. 800%)"))?;
+    if percent == 0 {
+        return Err(anyhow!("must not be 0"));
+    }
+
+    Ok(percent)
+}
+
+/// Check the value of the resource limit and returns the corresponding systemd property,
+/// `infinity` lifts the limit
+pub fn limit_property(key: &str, value: &str) -> Result<String> {
+    let value = value.trim();
+    let property = match (key, value) {
+        ("memory-max", "infinity") => "MemoryMax=infinity".to_owned(),
+        ("memory-max", _) => format!(
+            "MemoryMax={}",
+            parse_size(value)
+                .filter(|x| *x > 0)
+                .ok_or_else(|| anyhow!("expected a size (e.g. 16G)"))?
+        ),
+        // an empty assignment resets the quota
+        ("cpu-quota", "infinity") => "CPUQuota=".to_owned(),
+        ("cpu-quota", _) => {
+            let cpus = std::thread::available_parallelism().map_or(1, |x| x.get());
+            format!("CPUQuota={}%", parse_cpu_quota(value, cpus)?)
+        }
+        ("tasks-max", "infinity") => "TasksMax=infinity".to_owned(),
+        ("tasks-max", _) => format!(
+            "TasksMax={}",
+            value
+                .parse::<u64>()
+                .ok()
+                .filter(|x| *x > 0)
+                .ok_or_else(|| anyhow!("expected a positive number"))?
+        ),
+        _ => return Err(anyhow!("Unknown resource limit: `{}`", key)),
+    };
+
+    Ok(property)
+}
+
+/// Check the port forward in the form of `[PROTOCOL:]HOST[:CONTAINER]` (e.g. `8080:80`),
+/// as accepted by `systemd-nspawn --port`
+pub fn validate_port(value: &str) -> Result<()> {
+    let mut parts = value.split(':').collect::<Vec<_>>();
+    if let Some(protocol) = parts.first().filter(|_| parts.len() > 1) {
+        if protocol.chars().all(|c| c.is_ascii_alphabetic()) {
+            if *protocol != "tcp" && *protocol != "udp" {
+                return Err(anyhow!(
+                    "Invalid port `{}`: the protocol must be `tcp` or `udp`",
+                    value
+                ));
+            }
+            parts.remove(0);
+        }
+    }
+    if parts.is_empty()
+        || parts.len() > 2
+        || !parts
+            .iter()
+            .all(|x| x.parse::<u16>().map_or(false, |x| x > 0))
+    {
+        return Err(anyhow!(
+            "Invalid port `{}`: expected `[PROTOCOL:]HOST[:CONTAINER]` (e.g. `8080:80`)",
+            value
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the hostname of the instance if it is not set: the instance name, with the characters
+/// not allowed in a hostname (`_` and `.`) replaced by `-`
+fn default_hostname(instance: &str) -> String {
+    instance
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect::<String>()
+        .trim_matches('-')
+        .to_owned()
+}
+
+/// Check the hostname of an instance: a single label as in RFC 1123 (letters, digits and `-`,
+/// not starting or ending with `-`), which does not resolve to the host itself
+pub fn validate_hostname(hostname: &str) -> Result<()> {
+    if hostname.is_empty() || hostname.len() > MAX_HOSTNAME_LEN {
+        return Err(anyhow!(
+            "Invalid hostname `{}`: must be 1 to {} characters long",
+            hostname,
+            MAX_HOSTNAME_LEN
+        ));
+    }
+    if let Some(c) = hostname
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '-'))
+    {
+        return Err(anyhow!(
+            "Invalid hostname `{}`: contains {:?}, only letters, digits and `-` are allowed",
+            hostname,
+            c
+        ));
+    }
+    if hostname.starts_with('-') || hostname.ends_with('-') {
+        return Err(anyhow!(
+            "Invalid hostname `{}`: must not start or end with `-`",
+            hostname
+        ));
+    }
+    if RESERVED_HOSTNAMES
+        .iter()
+        .any(|x| x.eq_ignore_ascii_case(hostname))
+    {
+        return Err(anyhow!(
+            "Invalid hostname `{}`: it resolves to the loopback addresses",
+            hostname
+        ));
+    }
+    let host = nix::unistd::gethostname()
+        .ok()
+        .and_then(|x| x.into_string().ok())
+        .unwrap_or_default();
+    // nss-myhostname resolves the hostname of the host (without the domain) to itself
+    if host
+        .split('.')
+        .next()
+        .map_or(false, |x| x.eq_ignore_ascii_case(hostname))
+    {
+        return Err(anyhow!(
+            "Invalid hostname `{}`: it is the hostname of the host",
+            hostname
+        ));
+    }
+
+    Ok(())
+}
+
+/// Parse the value of the resource limit for the configuration, empty means no limit
+fn parse_limit(key: &str, value: &str) -> Result<Option<String>> {
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+    limit_property(key, value).map_err(|e| anyhow!("Invalid value for `{}`: {}", key, e))?;
+
+    Ok(Some(value.trim().to_owned()))
+}
+
+/// Parse an environment variable in the form of `KEY=VALUE`, or `KEY` to take the value
+/// from `lookup` (the environment of ciel). Returns `None` if `KEY` has no value to take
+pub fn parse_env_var(
+    var: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<Option<(String, String)>> {
+    let (name, value) = match var.split_once('=') {
+        Some((name, value)) => (name, Some(value.to_owned())),
+        None => (var, None),
+    };
+    validate_env_name(name)?;
+    if value.as_deref().map_or(false, |x| x.contains('\0')) {
+        return Err(anyhow!(
+            "Invalid value of environment variable `{}`: it must not contain NUL characters",
+            name
+        ));
+    }
+
+    Ok(value.or_else(|| lookup(name)).map(|x| (name.to_owned(), x)))
+}
+
+/// Parse the environment file: a `KEY=VALUE` per line (optionally prefixed by `export`), the
+/// values may be quoted, and the empty lines and the lines starting with `#` are ignored
+pub fn parse_env_file(content: &str) -> Result<Vec<(String, String)>> {
+    let mut env = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Line {}: expected `KEY=VALUE`", i + 1))?;
+        validate_env_name(name).map_err(|e| anyhow!("Line {}: {}", i + 1, e))?;
+        let value = value
+            .strip_prefix('"')
+            .and_then(|x| x.strip_suffix('"'))
+            .or_else(|| value.strip_prefix('\'').and_then(|x| x.strip_suffix('\'')))
+            .unwrap_or(value);
+        env.push((name.to_owned(), value.to_owned()));
+    }
+
+    Ok(env)
+}
+
+/// Escape the value so that it can be placed inside double quotes in a shell script
+fn shell_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '"' | '$' | '`') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Parse and validate the systemd-nspawn options
+fn parse_nspawn_options(value: &str, strict: bool) -> Result<Vec<String>> {
+    let options = parse_list(value);
+    validate_nspawn_options_with(&options, strict)
+        .map_err(|e| anyhow!("Invalid nspawn options:\n  {}", e.join("\n  ")))?;
+
+    Ok(options)
+}
+
+/// Parse the tree specification in the form of `name:source:location[:priority]`
+fn parse_tree(spec: &str) -> Result<TreeConfig> {
+    let parts = spec.split(':').collect::<Vec<_>>();
+    let (name, source, location, priority) = match parts.as_slice() {
+        [name, source, location] => (name, source, location, 0),
+        [name, source, location, priority] => (
+            name,
+            source,
+            location,
+            priority
+                .parse()
+                .map_err(|_| anyhow!("Invalid priority `{}` in `{}`", priority, spec))?,
+        ),
+        _ => {
+            return Err(anyhow!(
+                "Invalid tree `{}`: expected `name:source:location[:priority]`",
+                spec
+            ))
+        }
+    };
+
+    Ok(TreeConfig {
+        name: name.to_string(),
+        source: PathBuf::from(source),
+        location: location.to_string(),
+        priority,
+    })
+}
+
+/// Check the trees, the names and the locations should be unique
+fn validate_trees(trees: &[TreeConfig]) -> Result<()> {
+    if trees.is_empty() {
+        return Err(anyhow!("At least one tree is required"));
+    }
+    for (index, tree) in trees.iter().enumerate() {
+        tree.validate()?;
+        if trees[..index].iter().any(|x| x.name == tree.name) {
+            return Err(anyhow!("Duplicated tree name `{}`", tree.name));
+        }
+        if trees[..index]
+            .iter()
+            .any(|x| x.location.trim_end_matches('/') == tree.location.trim_end_matches('/'))
+        {
+            return Err(anyhow!("Duplicated tree location `{}`", tree.location));
+        }
+    }
+
+    Ok(())
+}
+
+/// Generate the ACBS forest.conf, trees with higher priorities come first
+fn generate_forest_conf(config: &CielConfig) -> String {
+    let mut trees = config.trees.iter().collect::<Vec<_>>();
+    trees.sort_by_key(|x| std::cmp::Reverse(x.priority));
+    let mut content = String::new();
+    for tree in trees {
+        if !content.is_empty() {
+            content.push('\n');
+        }
+        content.push_str(&format!("[{}]\nlocation = {}\n", tree.name, tree.location));
+        if tree.priority != 0 {
+            content.push_str(&format!("priority = {}\n", tree.priority));
+        }
+    }
+
+    content
+}
+
+/// Set the value of the key in the given configuration
+/// Keys like `instance.<name>.<key>` will set the per-instance override
+/// Keys like `build-env.<name>` will set the build environment variable (empty value removes it)
+pub fn set_config_value(config: &mut CielConfig, key: &str, value: &str) -> Result<()> {
+    if let Some(name) = key.strip_prefix("build-env.") {
+        validate_env_name(name)?;
+        if value.is_empty() {
+            config.build_env.remove(name);
+        } else {
+            config.build_env.insert(name.to_owned(), value.to_owned());
+        }
+        return Ok(());
+    }
+    if let Some(key) = key.strip_prefix("instance.") {
+        let (instance, key) = key
+            .split_once('.')
+            .ok_or_else(|| anyhow!("Invalid key: `instance.{}`", key))?;
+        let overrides = config.instances.entry(instance.to_owned()).or_default();
+        match key {
+            "nspawn-extra-options" => {
+                overrides.extra_options = Some(parse_nspawn_options(value, false)?)
+            }
+            "branch-exclusive-output" => overrides.sep_mount = Some(parse_bool(key, value)?),
+            "volatile-mount" => overrides.volatile_mount = Some(parse_bool(key, value)?),
+            "persist-home" => overrides.persist_home = Some(parse_bool(key, value)?),
+            "apt-sources" => {
+                validate_apt_sources(value).map_err(|e| anyhow!("Invalid apt sources: {}", e))?;
+                overrides.apt_sources = Some(value.to_owned());
+            }
+            "memory-max" => overrides.memory_max = parse_limit(key, value)?,
+            "cpu-quota" => overrides.cpu_quota = parse_limit(key, value)?,
+            "tasks-max" => overrides.tasks_max = parse_limit(key, value)?,
+            "network" if value.is_empty() => overrides.network = None,
+            "network" => overrides.network = Some(parse_network_mode(value)?),
+            "ports" => {
+                let ports = parse_list(&value.replace(',', " "));
+                for port in ports.iter() {
+                    validate_port(port)?;
+                }
+                overrides.ports = ports;
+            }
+            "hostname" if value.is_empty() => overrides.hostname = None,
+            "hostname" => {
+                validate_hostname(value)?;
+                overrides.hostname = Some(value.to_owned());
+            }
+            "read-only" => overrides.read_only = Some(parse_bool(key, value)?),
+            "private-users" if value.is_empty() => overrides.private_users = None,
+            "private-users" => overrides.private_users = Some(parse_private_users(value)?),
+            _ => return Err(anyhow!("Unknown per-instance configuration key: `{}`", key)),
+        }
+        config
+            .for_instance(instance)
+            .network_options()
+            .map_err(|e| anyhow!("{}: {}", instance, e))?;
+        return Ok(());
+    }
+    match key {
+        "maintainer" => config.select_maintainer(value)?,
+        "dnssec" => config.dnssec = parse_bool(key, value)?,
+        "dns-servers" => {
+            config.dns_servers = parse_dns_servers(value)
+                .map_err(|e| anyhow!("Invalid value for `{}`: {}", key, e))?
+        }
+        "apt-sources" => {
+            validate_apt_sources(value).map_err(|e| anyhow!("Invalid apt sources: {}", e))?;
+            config.apt_sources = value.to_owned();
+        }
+        "sources-format" => {
+            config.sources_format = match value {
+                "list" => SourcesFormat::List,
+                "deb822" => SourcesFormat::Deb822,
+                _ => {
+                    return Err(anyhow!(
+                        "Invalid value for `{}`: expected `list` or `deb822`, got `{}`",
+                        key,
+                        value
+                    ))
+                }
+            }
+        }
+        "http-proxy" => config.http_proxy = validate_proxy(value)?,
+        "https-proxy" => config.https_proxy = validate_proxy(value)?,
+        "no-proxy" => config.no_proxy = validate_no_proxy(value)?,
+        "ca-bundle" => {
+            config.ca_bundle = if value.is_empty() {
+                None
+            } else {
+                Some(PathBuf::from(value))
+            }
+        }
+        "extra-mounts" => {
+            config.extra_mounts = value
+                .split_whitespace()
+                .map(MountSpec::parse)
+                .collect::<Result<Vec<_>>>()?
+        }
+        "use-ccache" => config.use_ccache = parse_bool(key, value)?,
+        "ccache-dir" => {
+            config.ccache_dir = if value.is_empty() {
+                None
+            } else {
+                Some(PathBuf::from(value))
+            }
+        }
+        "parallelism" => {
+            config.parallelism = match value {
+                "" | "auto" | "0" => None,
+                _ => Some(value.parse().map_err(|_| {
+                    anyhow!(
+                        "Invalid value for `{}`: expected a number or `auto`, got `{}`",
+                        key,
+                        value
+                    )
+                })?),
+            }
+        }
+        "timezone" => {
+            config.timezone = if value.is_empty() {
+                None
+            } else {
+                validate_timezone(value)
+                    .map_err(|e| anyhow!("Invalid timezone `{}`: {}", value, e))?;
+                Some(value.to_owned())
+            }
+        }
+        "locale" => config.locale = Some(value.to_owned()).filter(|x| !x.is_empty()),
+        "output-dir-pattern" => {
+            validate_output_dir_pattern(value)
+                .map_err(|e| anyhow!("Invalid value for `{}`: {}", key, e))?;
+            config.output_dir_pattern = value.to_owned();
+        }
+        "output-dir" => {
+            config.output_dir = if value.is_empty() {
+                None
+            } else if Path::new(value).is_absolute() {
+                Some(PathBuf::from(value))
+            } else {
+                return Err(anyhow!(
+                    "Invalid value for `{}`: expected an absolute path, got `{}`",
+                    key,
+                    value
+                ));
+            }
+        }
+        "manage-sources-list" => config.manage_sources_list = parse_bool(key, value)?,
+        "trees" => {
+            let trees = value
+                .split_whitespace()
+                .map(parse_tree)
+                .collect::<Result<Vec<_>>>()?;
+            validate_trees(&trees)?;
+            config.trees = trees;
+        }
+        "local-repo" => config.local_repo = parse_bool(key, value)?,
+        "rootfs-url" => config.rootfs_url = Some(value.to_owned()).filter(|x| !x.is_empty()),
+        "rootfs-arch" => config.rootfs_arch = Some(value.to_owned()).filter(|x| !x.is_empty()),
+        "rootfs-keyring" => {
+            config.rootfs_keyring = if value.is_empty() {
+                None
+            } else {
+                Some(PathBuf::from(value))
+            }
+        }
+        "download-retries" => {
+            config.download_retries = if value.is_empty() {
+                None
+            } else {
+                Some(value.parse().map_err(|_| {
+                    anyhow!(
+                        "Invalid value for `{}`: expected a number, got `{}`",
+                        key,
+                        value
+                    )
+                })?)
+            }
+        }
+        "download-connections" => {
+            config.download_connections = if value.is_empty() {
+                None
+            } else {
+                match value.parse() {
+                    Ok(connections) if connections > 0 => Some(connections),
+                    _ => {
+                        return Err(anyhow!(
+                            "Invalid value for `{}`: expected a positive number, got `{}`",
+                            key,
+                            value
+                        ))
+                    }
+                }
+            }
+        }
+        "limit-rate" => {
+            config.limit_rate = if value.trim().is_empty() {
+                None
+            } else {
+                crate::network::parse_rate_limit(value)
+                    .map_err(|e| anyhow!("Invalid value for `{}`: {}", key, e))?;
+                Some(value.trim().to_owned())
+            }
+        }
+        "fastest-mirror" => config.fastest_mirror = parse_bool(key, value)?,
+        "update-full-upgrade" => config.update_full_upgrade = parse_optional_bool(key, value)?,
+        "update-autoremove" => config.update_autoremove = parse_optional_bool(key, value)?,
+        "update-clean" => config.update_clean = parse_optional_bool(key, value)?,
+        "network" => config.network = parse_network_mode(value)?,
+        "private-users" => config.private_users = parse_private_users(value)?,
+        "package-manager" => {
+            config.package_manager = match value {
+                "auto" => PackageManager::Auto,
+                "apt" => PackageManager::Apt,
+                "oma" => PackageManager::Oma,
+                _ => {
+                    return Err(anyhow!(
+                        "Invalid value for `{}`: expected `auto`, `apt` or `oma`, got `{}`",
+                        key,
+                        value
+                    ))
+                }
+            }
+        }
+        "editor" => {
+            config.editor = if value.trim().is_empty() {
+                None
+            } else {
+                split_command(value).map_err(|e| anyhow!("Invalid value for `{}`: {}", key, e))?;
+                Some(value.trim().to_owned())
+            }
+        }
+        "isolate-network" => config.isolate_network = parse_bool(key, value)?,
+        "clear-machine-id" => config.clear_machine_id = parse_bool(key, value)?,
+        "shared-apt-cache" => config.shared_apt_cache = parse_bool(key, value)?,
+        "persist-home" => config.persist_home = parse_bool(key, value)?,
+        "auto-stop-after" => config.auto_stop_after = parse_optional_duration(key, value)?,
+        "boot-timeout" => {
+            config.boot_timeout = parse_optional_duration(key, value)?;
+            if config.boot_timeout.map_or(false, |x| x.is_zero()) {
+                return Err(anyhow!("Invalid value for `{}`: must not be 0", key));
+            }
+        }
+        "memory-max" => config.memory_max = parse_limit(key, value)?,
+        "cpu-quota" => config.cpu_quota = parse_limit(key, value)?,
+        "tasks-max" => config.tasks_max = parse_limit(key, value)?,
+        "network-timeout" => config.network_timeout = parse_optional_duration(key, value)?,
+        "stop-timeout" => {
+            config.stop_timeout = parse_optional_duration(key, value)?;
+            if config.stop_timeout.map_or(false, |x| x.is_zero()) {
+                return Err(anyhow!("Invalid value for `{}`: must not be 0", key));
+            }
+        }
+        "trash-max-age" => config.trash_max_age = parse_optional_duration(key, value)?,
+        "trash-max-size" => {
+            config.trash_max_size = if value.trim().is_empty() {
+                None
+            } else {
+                parse_size(value).ok_or_else(|| {
+                    anyhow!("Invalid value for `{}`: expected a size (e.g. 20G)", key)
+                })?;
+                Some(value.trim().to_owned())
+            }
+        }
+        "log-dir" => {
+            config.log_dir = if value.is_empty() {
+                None
+            } else {
+                Some(PathBuf::from(value))
+            }
+        }
+        "hooks.pre-build" => config.hooks.pre_build = parse_hook(key, value)?,
+        "hooks.post-build" => config.hooks.post_build = parse_hook(key, value)?,
+        "hooks.pre-update-os" => config.hooks.pre_update_os = parse_hook(key, value)?,
+        "hooks.post-update-os" => config.hooks.post_update_os = parse_hook(key, value)?,
+        "local-repo-priority" => {
+            config.local_repo_priority = if value.is_empty() {
+                None
+            } else {
+                Some(value.parse().map_err(|_| {
+                    anyhow!(
+                        "Invalid value for `{}`: expected a number between 0 and 65535, got `{}`",
+                        key,
+                        value
+                    )
+                })?)
+            }
+        }
+        "local-sources" => config.local_sources = parse_bool(key, value)?,
+        "nspawn-extra-options" => config.extra_options = parse_nspawn_options(value, false)?,
+        "branch-exclusive-output" => config.sep_mount = parse_bool(key, value)?,
+        "volatile-mount" => config.volatile_mount = parse_bool(key, value)?,
+        _ => return Err(anyhow!("Unknown configuration key: `{}`", key)),
+    }
+
+    Ok(())
+}
+
+/// Get the value of the key in the given configuration as a string
+pub fn get_config_value(config: &CielConfig, key: &str) -> Result<String> {
+    if let Some(name) = key.strip_prefix("build-env.") {
+        return Ok(config.build_env.get(name).cloned().unwrap_or_default());
+    }
+    if let Some(key) = key.strip_prefix("instance.") {
+        let (instance, key) = key
+            .split_once('.')
+            .ok_or_else(|| anyhow!("Invalid key: `instance.{}`", key))?;
+        let inst_config = config.for_instance(instance);
+        return Ok(match key {
+            "nspawn-extra-options" => inst_config.extra_options.join(" "),
+            "branch-exclusive-output" => inst_config.sep_mount.to_string(),
+            "volatile-mount" => inst_config.volatile_mount.to_string(),
+            "persist-home" => inst_config.persist_home.to_string(),
+            "apt-sources" => inst_config.apt_sources,
+            "network" => inst_config.network.to_string(),
+            "ports" => inst_config.ports.join(" "),
+            "hostname" => inst_config.hostname,
+            "read-only" => inst_config.read_only.to_string(),
+            "private-users" => inst_config.private_users.to_string(),
+            "memory-max" => inst_config.limits.memory_max.unwrap_or_default(),
+            "cpu-quota" => inst_config.limits.cpu_quota.unwrap_or_default(),
+            "tasks-max" => inst_config.limits.tasks_max.unwrap_or_default(),
+            _ => return Err(anyhow!("Unknown per-instance configuration key: `{}`", key)),
+        });
+    }
+
+    Ok(match key {
+        "maintainer" => config.primary_maintainer().to_owned(),
+        "maintainers" => config.maintainers.join("\n"),
+        "dnssec" => config.dnssec.to_string(),
+        "dns-servers" => config.dns_servers.join(" "),
+        "apt-sources" => config.apt_sources.clone(),
+        "sources-format" => match config.sources_format {
+            SourcesFormat::List => "list".to_owned(),
+            SourcesFormat::Deb822 => "deb822".to_owned(),
+        },
+        "http-proxy" => config.http_proxy.clone().unwrap_or_default(),
+        "https-proxy" => config.https_proxy.clone().unwrap_or_default(),
+        "no-proxy" => config.no_proxy.clone().unwrap_or_default(),
+        "ca-bundle" => display_path(&config.ca_bundle),
+        "extra-mounts" => config
+            .extra_mounts
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(" "),
+        "use-ccache" => config.use_ccache.to_string(),
+        "ccache-dir" => config
+            .ccache_dir
+            .as_ref()
+            .map(|x| x.display().to_string())
+            .unwrap_or_default(),
+        "parallelism" => config
+            .build_jobs()
+            .map_or_else(|| "auto".to_owned(), |x| x.to_string()),
+        "timezone" => config.timezone.clone().unwrap_or_default(),
+        "locale" => config.locale.clone().unwrap_or_default(),
+        "output-dir-pattern" => config.output_dir_pattern.clone(),
+        "output-dir" => display_path(&config.output_dir),
+        "manage-sources-list" => config.manage_sources_list.to_string(),
+        "trees" => config
+            .trees
+            .iter()
+            .map(|x| {
+                format!(
+                    "{}:{}:{}:{}",
+                    x.name,
+                    x.source.display(),
+                    x.location,
+                    x.priority
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+        "local-repo" => config.local_repo.to_string(),
+        "rootfs-url" => config.rootfs_url.clone().unwrap_or_default(),
+        "rootfs-arch" => config.rootfs_arch.clone().unwrap_or_default(),
+        "rootfs-keyring" => display_path(&config.rootfs_keyring),
+        "download-retries" => config.download_retries().to_string(),
+        "download-connections" => config.download_connections().to_string(),
+        "limit-rate" => config.limit_rate.clone().unwrap_or_default(),
+        "fastest-mirror" => config.fastest_mirror.to_string(),
+        "update-full-upgrade" => config.update_full_upgrade().to_string(),
+        "update-autoremove" => config.update_autoremove().to_string(),
+        "update-clean" => config.update_clean().to_string(),
+        "network" => config.network.to_string(),
+        "private-users" => config.private_users.to_string(),
+        "package-manager" => match config.package_manager {
+            PackageManager::Auto => "auto".to_owned(),
+            PackageManager::Apt => "apt".to_owned(),
+            PackageManager::Oma => "oma".to_owned(),
+        },
+        "editor" => config.editor.clone().unwrap_or_default(),
+        "isolate-network" => config.isolate_network.to_string(),
+        "clear-machine-id" => config.clear_machine_id.to_string(),
+        "shared-apt-cache" => config.shared_apt_cache.to_string(),
+        "persist-home" => config.persist_home.to_string(),
+        "auto-stop-after" => config
+            .auto_stop_after
+            .map(format_duration)
+            .unwrap_or_default(),
+        "boot-timeout" => config.boot_timeout.map(format_duration).unwrap_or_default(),
+        "stop-timeout" => config.stop_timeout.map(format_duration).unwrap_or_default(),
+        "network-timeout" => config
+            .network_timeout
+            .map(format_duration)
+            .unwrap_or_default(),
+        "memory-max" => config.memory_max.clone().unwrap_or_default(),
+        "cpu-quota" => config.cpu_quota.clone().unwrap_or_default(),
+        "tasks-max" => config.tasks_max.clone().unwrap_or_default(),
+        "trash-max-age" => config
+            .trash_max_age
+            .map(format_duration)
+            .unwrap_or_default(),
+        "trash-max-size" => config.trash_max_size.clone().unwrap_or_default(),
+        "log-dir" => config
+            .log_dir
+            .as_ref()
+            .map(|x| x.display().to_string())
+            .unwrap_or_default(),
+        "hooks.pre-build" => display_path(&config.hooks.pre_build),
+        "hooks.post-build" => display_path(&config.hooks.post_build),
+        "hooks.pre-update-os" => display_path(&config.hooks.pre_update_os),
+        "hooks.post-update-os" => display_path(&config.hooks.post_update_os),
+        "local-repo-priority" => config
+            .local_repo_priority
+            .map(|x| x.to_string())
+            .unwrap_or_default(),
+        "local-sources" => config.local_sources.to_string(),
+        "nspawn-extra-options" => config.extra_options.join(" "),
+        "branch-exclusive-output" => config.sep_mount.to_string(),
+        "volatile-mount" => config.volatile_mount.to_string(),
+        _ => return Err(anyhow!("Unknown configuration key: `{}`", key)),
+    })
+}
+
+/// Set the value of the key in the workspace configuration and save it.
+/// If `strict` is set, unknown systemd-nspawn options are rejected
+pub fn set_value(key: &str, value: &str, strict: bool) -> Result<()> {
+    if strict && key.ends_with("nspawn-extra-options") {
+        parse_nspawn_options(value, true)?;
+    }
+    // only a missing configuration starts from the defaults, a broken one is never overwritten
+    let mut config = match std::fs::metadata(config_location(".")) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => CielConfig::default(),
+        _ => read_config_raw()?,
+    };
+    set_config_value(&mut config, key, value)?;
+
+    write_config(&config)
+}
+
+/// Returns the instances whose generated files (e.g. ab3cfg.sh and the APT sources) are changed
+/// by the key: `None` if there are none, `Some(None)` for all of them
+pub fn generated_files_scope(key: &str) -> Option<Option<String>> {
+    if let Some((instance, key)) = key
+        .strip_prefix("instance.")
+        .and_then(|x| x.split_once('.'))
+    {
+        return Some(Some(instance.to_owned())).filter(|_| key == "apt-sources");
+    }
+    if key.starts_with("build-env.") || GENERATED_FILE_KEYS.contains(&key) {
+        return Some(None);
+    }
+
+    None
+}
+
+/// Returns the configuration with the network mode and the port forwards of the instance set,
+/// the ports are checked against the network mode. The configuration is not saved
+pub fn with_instance_network(
+    instance: &str,
+    network: Option<&str>,
+    ports: &[String],
+) -> Result<CielConfig> {
+    let mut config = read_config_raw()?;
+    if let Some(network) = network {
+        set_config_value(
+            &mut config,
+            &format!("instance.{}.network", instance),
+            network,
+        )?;
+    }
+    set_config_value(
+        &mut config,
+        &format!("instance.{}.ports", instance),
+        &ports.join(" "),
+    )?;
+
+    Ok(config)
+}
+
+/// Add the systemd-nspawn options (separated by spaces) to the per-instance list and save the configuration
+pub fn add_instance_options(instance: &str, options: &str) -> Result<()> {
+    let mut config = read_config_raw()?;
+    let overrides = config.instances.entry(instance.to_owned()).or_default();
+    let mut extra_options = overrides.extra_options.take().unwrap_or_default();
+    extra_options.extend(parse_list(options));
+    overrides.extra_options = Some(parse_nspawn_options(&extra_options.join(" "), false)?);
+
+    write_config(&config)
+}
+
+/// Remove the systemd-nspawn options (separated by spaces) from the per-instance list and save the configuration
+pub fn remove_instance_options(instance: &str, options: &str) -> Result<()> {
+    let mut config = read_config_raw()?;
+    let overrides = config
+        .instances
+        .get_mut(instance)
+        .ok_or_else(|| anyhow!("Instance `{}` has no extra options.", instance))?;
+    let extra_options = overrides.extra_options.get_or_insert_with(Vec::new);
+    let options = parse_list(options);
+    if options.is_empty() {
+        return Err(anyhow!("No option was specified."));
+    }
+    let position = extra_options
+        .windows(options.len())
+        .position(|x| x == options.as_slice())
+        .ok_or_else(|| {
+            anyhow!(
+                "`{}` is not in the extra options of instance `{}`.",
+                options.join(" "),
+                instance
+            )
+        })?;
+    extra_options.drain(position..position + options.len());
+    if extra_options.is_empty() {
+        overrides.extra_options = None;
+    }
+
+    write_config(&config)
+}
+
+/// Switch the active maintainer (by index or value) and save the configuration
+pub fn set_maintainer(maintainer: &str) -> Result<()> {
+    let mut config = read_config_raw()?;
+    config.select_maintainer(maintainer)?;
+
+    write_config(&config)
+}
+
+/// Get the value of the key in the workspace configuration
+pub fn get_value(key: &str) -> Result<String> {
+    let config = read_config()?;
+
+    get_config_value(&config, key)
+}
+
+/// Returns the keys whose values are different from the default configuration
+fn modified_keys(config: &CielConfig) -> Result<Vec<&'static str>> {
+    let default = CielConfig::default();
+    let mut modified = Vec::new();
+    for key in CONFIG_KEYS {
+        if get_config_value(config, key)? != get_config_value(&default, key)? {
+            modified.push(*key);
+        }
+    }
+
+    Ok(modified)
+}
+
+/// Print all the configuration values, values different from the defaults are marked
+pub fn display(config: &CielConfig) -> Result<()> {
+    use tabwriter::TabWriter;
+
+    let modified = modified_keys(config)?;
+    let mut formatter = TabWriter::new(std::io::stdout());
+    writeln!(&mut formatter, "KEY\tVALUE")?;
+    for key in CONFIG_KEYS {
+        let value = get_config_value(config, key)?;
+        let marker = if modified.contains(key) { "*" } else { " " };
+        writeln!(
+            &mut formatter,
+            "{}{}\t{}",
+            marker,
+            key,
+            value.trim_end().replace('\n', "; ")
+        )?;
+    }
+    for (instance, overrides) in config.instances.iter() {
+        for key in INSTANCE_CONFIG_KEYS {
+            let is_set = match *key {
+                "nspawn-extra-options" => overrides.extra_options.is_some(),
+                "branch-exclusive-output" => overrides.sep_mount.is_some(),
+                "volatile-mount" => overrides.volatile_mount.is_some(),
+                "persist-home" => overrides.persist_home.is_some(),
+                "memory-max" => overrides.memory_max.is_some(),
+                "cpu-quota" => overrides.cpu_quota.is_some(),
+                "tasks-max" => overrides.tasks_max.is_some(),
+                "network" => overrides.network.is_some(),
+                "ports" => !overrides.ports.is_empty(),
+                "hostname" => overrides.hostname.is_some(),
+                "read-only" => overrides.read_only.is_some(),
+                "private-users" => overrides.private_users.is_some(),
+                _ => overrides.apt_sources.is_some(),
+            };
+            if !is_set {
+                continue;
+            }
+            let key = format!("instance.{}.{}", instance, key);
+            let value = get_config_value(config, &key)?;
+            writeln!(
+                &mut formatter,
+                "*{}\t{}",
+                key,
+                value.trim_end().replace('\n', "; ")
+            )?;
+        }
+    }
+    for (name, value) in config.build_env.iter() {
+        writeln!(&mut formatter, "*build-env.{}\t{}", name, value)?;
+    }
+    formatter.flush()?;
+    eprintln!("(* = differs from the default value)");
+
+    Ok(())
+}
+
+/// Print all the configuration values in JSON format
+pub fn display_json(config: &CielConfig, workspace: bool) -> Result<()> {
+    let mut value = serde_json::to_value(config)?;
+    // always use a list for the maintainers for a stable output
+    if let Some(maintainer) = value.get_mut("maintainer") {
+        if maintainer.is_string() {
+            *maintainer = serde_json::Value::Array(vec![maintainer.take()]);
+        }
+    }
+    let output = serde_json::json!({
+        "workspace": workspace,
+        "config": value,
+        "modified": modified_keys(config)?,
+    });
+    println!("{}", serde_json::to_string_pretty(&output)?);
+
+    Ok(())
+}
+
+/// Show the effective configuration of the current workspace
+pub fn show(json: bool) -> Result<()> {
+    let (config, workspace) = match read_config() {
+        Ok(config) => {
+            print_config_warnings();
+            (config, true)
+        }
+        Err(_) => {
+            warn!("No workspace configuration was found, showing the default values.");
+            (CielConfig::default(), false)
+        }
+    };
+    if json {
+        return display_json(&config, workspace);
+    }
+
+    display(&config)
+}
+
+/// Generate the content of the resolved.conf, based on the existing content.
+/// Only the keys managed by ciel in the `[Resolve]` section are updated
+fn generate_resolved_conf(existing: &str, config: &CielConfig) -> String {
+    const MANAGED_KEYS: &[&str] = &["DNSSEC", "DNS", "FallbackDNS"];
+    let mut managed = vec![format!(
+        "DNSSEC={}",
+        if config.dnssec {
+            "allow-downgrade"
+        } else {
+            "no"
+        }
+    )];
+    if !config.dns_servers.is_empty() {
+        managed.push(format!("DNS={}", config.dns_servers.join(" ")));
+        // the built-in fallback servers are probably unreachable as well
+        managed.push("FallbackDNS=".to_owned());
+    }
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut in_resolve = false;
+    let mut found = false;
+    for line in existing.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            if in_resolve {
+                // insert the managed keys at the end of the `[Resolve]` section
+                let end = lines.len() - lines.iter().rev().take_while(|x| x.is_empty()).count();
+                lines.splice(end..end, managed.drain(..));
+            }
+            in_resolve = trimmed == "[Resolve]";
+            found |= in_resolve;
+        } else if in_resolve {
+            let key = trimmed.split_once('=').map(|x| x.0.trim());
+            if key.map_or(false, |x| MANAGED_KEYS.contains(&x)) {
+                continue;
+            }
+        }
+        lines.push(line.to_owned());
+    }
+    if !found {
+        lines.push("[Resolve]".to_owned());
+    }
+    lines.extend(managed);
+
+    lines.join("\n") + "\n"
+}
+
+/// Generate the APT preferences pinning the local repository (which has an empty origin)
+fn generate_local_pin(priority: u16) -> String {
+    format!(
+        "# Generated by ciel, do not edit\nPackage: *\nPin: origin \"\"\nPin-Priority: {}\n",
+        priority
+    )
+}
+
+/// Generate the APT configuration for the proxies
+fn generate_apt_proxy_conf(config: &CielConfig) -> String {
+    let mut content = String::new();
+    if let Some(proxy) = &config.http_proxy {
+        content.push_str(&format!("Acquire::http::Proxy \"{}\";\n", proxy));
+    }
+    if let Some(proxy) = &config.https_proxy {
+        content.push_str(&format!("Acquire::https::Proxy \"{}\";\n", proxy));
+    }
+    if let Some(no_proxy) = &config.no_proxy {
+        for host in no_proxy
+            .split(',')
+            .map(|x| x.trim())
+            // the hand-edited configuration is not validated
+            .filter(|x| is_apt_proxy_host(x))
+        {
+            content.push_str(&format!("Acquire::http::Proxy::{} \"DIRECT\";\n", host));
+            content.push_str(&format!("Acquire::https::Proxy::{} \"DIRECT\";\n", host));
+        }
+    }
+
+    content
+}
+
+/// Generate the shell profile for the proxies
+fn generate_profile_proxy(config: &CielConfig) -> String {
+    let mut content = "# Generated by ciel, do not edit\n".to_owned();
+    for (name, value) in config.proxy_environment() {
+        content.push_str(&format!("export {}=\"{}\"\n", name, shell_escape(&value)));
+    }
+
+    content
+}
+
+/// Generate the autobuild3 configuration (the file is regenerated as a whole every time)
+fn generate_ab3_config(config: &CielConfig) -> String {
+    let mut content = format!(
+        "#!/bin/bash\nABMPM=dpkg\nABAPMS=\nABINSTALL=dpkg\nMTER=\"{}\"",
+        config.primary_maintainer()
+    );
+    if let Some(jobs) = config.build_jobs() {
+        content.push_str(&format!("\nABTHREADS={}", jobs));
+    }
+    if config.use_ccache {
+        content.push_str(&format!(
+            "\nexport USE_CCACHE=1\nexport CCACHE_DIR=\"{}\"",
+            CCACHE_CONTAINER_DIR
+        ));
+    }
+    for (name, value) in config.build_env.iter() {
+        content.push_str(&format!("\nexport {}=\"{}\"", name, shell_escape(value)));
+    }
+
+    content
+}
+
+/// Plan the writes of the APT sources to the files owned by ciel, other files are left untouched
+/// unless `manage-sources-list` is enabled
+fn plan_apt_sources(
+    rootfs: &Path,
+    config: &CielConfig,
+    apt_sources: &str,
+    changes: &mut Vec<PlannedChange>,
+) -> Result<()> {
+    let apt_list_path = rootfs.join(DEFAULT_APT_LIST_LOCATION);
+    let apt_ciel_list_path = rootfs.join(DEFAULT_APT_CIEL_LIST_LOCATION);
+    let apt_deb822_path = rootfs.join(DEFAULT_APT_DEB822_LOCATION);
+    // older versions of ciel wrote the sources to sources.list directly,
+    // they are moved to sources.list.d (written below)
+    if !config.manage_sources_list
+        && fs::read(&apt_list_path).map_or(false, |x| x == apt_sources.as_bytes())
+    {
+        changes.extend(PlannedChange::remove(apt_list_path.clone()));
+    }
+    let (path, content) = match (config.sources_format, config.manage_sources_list) {
+        // remote repositories are unreachable anyway, only keep the local repository
+        (_, true) if config.isolate_network => {
+            (apt_list_path.clone(), ISOLATED_APT_SOURCES.to_owned())
+        }
+        (_, false) if config.isolate_network => {
+            (apt_ciel_list_path.clone(), ISOLATED_APT_SOURCES.to_owned())
+        }
+        (SourcesFormat::List, true) => (apt_list_path.clone(), apt_sources.to_owned()),
+        (SourcesFormat::List, false) => (apt_ciel_list_path.clone(), apt_sources.to_owned()),
+        (SourcesFormat::Deb822, _) => (
+            apt_deb822_path.clone(),
+            sources_list_to_deb822(apt_sources).map_err(|e| anyhow!("{}", e))?,
+        ),
+    };
+    changes.extend(PlannedChange::write(path.clone(), content));
+    // remove the other files owned by ciel to avoid duplicated entries
+    for stale_path in [apt_ciel_list_path, apt_deb822_path] {
+        if stale_path != path && stale_path.is_file() {
+            changes.extend(PlannedChange::remove(stale_path));
+        }
+    }
+    if config.manage_sources_list && path != apt_list_path {
+        changes.extend(PlannedChange::write(
+            apt_list_path,
+            "# Managed by ciel, see sources.list.d/ciel.sources\n".to_owned(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Plan the changes to be made by [apply_config] without touching the rootfs
+pub fn apply_config_plan<P: AsRef<Path>>(
+    root: P,
+    config: &CielConfig,
+) -> Result<Vec<PlannedChange>> {
+    plan_config(root.as_ref(), config, None)
+}
+
+/// Same as [apply_config_plan], with the overrides of the instance applied
+pub fn apply_instance_config_plan<P: AsRef<Path>>(
+    root: P,
+    config: &CielConfig,
+    instance: &str,
+) -> Result<Vec<PlannedChange>> {
+    plan_config(root.as_ref(), config, Some(instance))
+}
+
+/// Plan the changes to the rootfs, the overrides of the instance are applied if specified
+fn plan_config(
+    rootfs: &Path,
+    config: &CielConfig,
+    instance: Option<&str>,
+) -> Result<Vec<PlannedChange>> {
+    let apt_sources = match instance {
+        Some(instance) => config.for_instance(instance).apt_sources,
+        None => config.apt_sources.clone(),
+    };
+    if !apt_sources.is_empty() {
+        validate_apt_sources(&apt_sources)
+            .map_err(|e| anyhow!("Refusing to write a malformed sources.list: {}", e))?;
+    }
+    let mut changes = Vec::new();
+    // write maintainer information
+    changes.extend(PlannedChange::write(
+        rootfs.join(DEFAULT_AB3_CONFIG_LOCATION),
+        generate_ab3_config(config),
+    ));
+    // write ciel's APT sources (sources.list.d/ciel.list or the deb822 style .sources file)
+    if !apt_sources.is_empty() {
+        plan_apt_sources(rootfs, config, &apt_sources, &mut changes)?;
+    }
+    // write the pin for the local repository (or remove the stale one)
+    let local_pin_path = rootfs.join(DEFAULT_LOCAL_PIN_LOCATION);
+    changes.extend(match config.local_repo_priority {
+        Some(priority) if config.local_repo => {
+            PlannedChange::write(local_pin_path, generate_local_pin(priority))
+        }
+        _ => PlannedChange::remove(local_pin_path),
+    });
+    // write DNSSEC and DNS servers configuration
+    let resolv_path = rootfs.join(DEFAULT_RESOLV_LOCATION);
+    let existing = fs::read_to_string(&resolv_path).unwrap_or_default();
+    changes.extend(PlannedChange::write(
+        resolv_path,
+        generate_resolved_conf(&existing, config),
+    ));
+    // write proxy configuration (or remove the stale one)
+    let apt_proxy_path = rootfs.join(DEFAULT_APT_PROXY_LOCATION);
+    let profile_proxy_path = rootfs.join(DEFAULT_PROFILE_PROXY_LOCATION);
+    if config.http_proxy.is_some() || config.https_proxy.is_some() {
+        changes.extend(PlannedChange::write(
+            apt_proxy_path,
+            generate_apt_proxy_conf(config),
+        ));
+        changes.extend(PlannedChange::write(
+            profile_proxy_path,
+            generate_profile_proxy(config),
+        ));
+    } else {
+        changes.extend(PlannedChange::remove(apt_proxy_path));
+        changes.extend(PlannedChange::remove(profile_proxy_path));
+    }
+    // keep the packages in the shared APT cache (or remove the stale configuration)
+    let keep_archives_path = rootfs.join(DEFAULT_APT_KEEP_ARCHIVES_LOCATION);
+    changes.extend(if config.shared_apt_cache {
+        PlannedChange::write(keep_archives_path, APT_KEEP_ARCHIVES_CONF.to_owned())
+    } else {
+        PlannedChange::remove(keep_archives_path)
+    });
+    // write timezone and locale settings
+    if let Some(timezone) = &config.timezone {
+        changes.extend(PlannedChange::symlink(
+            rootfs.join(DEFAULT_LOCALTIME_LOCATION),
+            Path::new("..")
+                .join(ZONEINFO_DIR.trim_start_matches('/'))
+                .join(timezone),
+        ));
+    }
+    if let Some(locale) = &config.locale {
+        changes.extend(PlannedChange::write(
+            rootfs.join(DEFAULT_LOCALE_LOCATION),
+            format!("LANG={}\n", locale),
+        ));
+    }
+    // write acbs configuration
+    changes.extend(PlannedChange::write(
+        rootfs.join(DEFAULT_ACBS_CONFIG),
+        generate_forest_conf(config),
+    ));
+
+    Ok(changes)
+}
+
+/// Applies the given configuration (th configuration itself will not be saved to the disk)
+/// If an instance is specified, its overrides will be applied as well
+pub fn apply_config<P: AsRef<Path>>(
+    root: P,
+    config: &CielConfig,
+    instance: Option<&str>,
+) -> Result<()> {
+    for change in plan_config(root.as_ref(), config, instance)? {
+        change.execute()?;
+    }
+
+    Ok(())
+}
+
+/// Plan the changes to the configuration file of the current workspace
+pub fn config_file_plan(config: &CielConfig) -> Result<Option<PlannedChange>> {
+    let location = config_location(std::env::current_dir()?);
+
+    Ok(PlannedChange::write(location, config.save_config()?))
+}
+
+/// Print the planned changes, `root` is stripped from the paths
+pub fn print_config_plan<P: AsRef<Path>>(root: P, changes: &[PlannedChange]) {
+    if changes.is_empty() {
+        info!("The configuration is up to date, nothing to change.");
+        return;
+    }
+    for change in changes.iter() {
+        change.print(root.as_ref());
+    }
+}
+
+#[test]
+fn test_validate_maintainer() {
+    assert_eq!(
+        validate_maintainer(&"test <aosc@aosc.io>".to_owned()),
+        Ok(())
+    );
+    assert_eq!(
+        validate_maintainer(&"test <aosc@aosc.io;".to_owned()),
+        Err("missing '>' after the email address at position 19".to_owned())
+    );
+    assert_eq!(
+        validate_maintainer(&"張三 <zhangsan@aosc.io>".to_owned()),
+        Ok(())
+    );
+    assert_eq!(
+        validate_maintainer(&"Jean-Luc O. Picard <picard@aosc.io> \t".to_owned()),
+        Ok(())
+    );
+    assert_eq!(
+        validate_maintainer(&"Doe, John <john@aosc.io>".to_owned()),
+        Ok(())
+    );
+    assert_eq!(
+        validate_maintainer(&"test <aosc+ciel@mail.aosc.io>".to_owned()),
+        Ok(())
+    );
+    assert_eq!(
+        validate_maintainer(&"\"Doe, John\" <john@aosc.io>".to_owned()),
+        Err(
+            "'\"' at position 0 is not allowed, it would break the shell quoting in ab3cfg.sh"
+                .to_owned()
+        )
+    );
+    assert_eq!(
+        validate_maintainer(&"test\\ <aosc@aosc.io>".to_owned()),
+        Err(
+            "'\\\\' at position 4 is not allowed, it would break the shell quoting in ab3cfg.sh"
+                .to_owned()
+        )
+    );
+    assert_eq!(
+        validate_maintainer(&"$(reboot) <aosc@aosc.io>".to_owned()),
+        Err(
+            "'$' at position 0 is not allowed, it would break the shell quoting in ab3cfg.sh"
+                .to_owned()
+        )
+    );
+    assert_eq!(
+        validate_maintainer(&"a <@>".to_owned()),
+        Err("empty local part in email address at position 3".to_owned())
+    );
+    assert_eq!(
+        validate_maintainer(&"x <user@>".to_owned()),
+        Err("email domain is missing at position 8".to_owned())
+    );
+    assert_eq!(
+        validate_maintainer(&"test <aosc@aosc..io>".to_owned()),
+        Err("invalid domain `aosc..io` in email address at position 11".to_owned())
+    );
+    assert_eq!(
+        validate_maintainer(&"test <ao sc@aosc.io>".to_owned()),
+        Err("unexpected ' ' in email address at position 8".to_owned())
+    );
+    assert_eq!(
+        validate_maintainer(&"test <aosc@aosc.io> <ciel@aosc.io>".to_owned()),
+        Err("unexpected text after '>' at position 19: ' '".to_owned())
+    );
+    assert_eq!(
+        validate_maintainer(&"test <a@b@aosc.io>".to_owned()),
+        Err("unexpected second '@' in email address at position 9".to_owned())
+    );
+    assert_eq!(
+        validate_maintainer(&"test > <aosc@aosc.io>".to_owned()),
+        Err("unexpected '>' in name at position 5".to_owned())
+    );
+    assert_eq!(
+        validate_maintainer(&"test <aosc.aosc.io>".to_owned()),
+        Err("missing '@' in email address at position 18".to_owned())
+    );
+    assert_eq!(
+        validate_maintainer(&"test <aosc@localhost>".to_owned()),
+        Err("missing top-level domain in email address at position 20".to_owned())
+    );
+    assert_eq!(
+        validate_maintainer(&"test <@aosc.io>".to_owned()),
+        Err("empty local part in email address at position 6".to_owned())
+    );
+    assert_eq!(
+        validate_maintainer(&" <aosc@aosc.io>".to_owned()),
+        Err("missing name before '<' at position 1".to_owned())
+    );
+    assert_eq!(
+        validate_maintainer(&"test <aosc@aosc.io> x".to_owned()),
+        Err("unexpected text after '>' at position 19: ' '".to_owned())
+    );
+}
+
+#[test]
+fn test_instance_overrides() {
+    let data = r#"
+version = 3
+maintainer = "Bot <null@aosc.io>"
+dnssec = false
+apt_sources = "deb https://repo.aosc.io/debs/ stable main"
+local_repo = true
+local_sources = true
+nspawn-extra-options = []
+branch-exclusive-output = true
+volatile-mount = false
+
+[instance.quick]
+volatile-mount = true
+nspawn-extra-options = ["--private-network"]
+"#;
+    let config = CielConfig::load_config(data).unwrap();
+    let quick = config.for_instance("quick");
+    assert!(quick.volatile_mount);
+    assert!(quick.sep_mount);
+    assert_eq!(quick.extra_options, vec!["--private-network".to_string()]);
+    let mut config = config;
+    config.extra_options = vec!["--capability=CAP_IPC_LOCK".to_owned(), "--quiet".to_owned()];
+    assert_eq!(
+        config.for_instance("quick").extra_options,
+        vec!["--capability=CAP_IPC_LOCK", "--quiet", "--private-network"]
+    );
+    config.extra_options.clear();
+    let unknown = config.for_instance("unknown");
+    assert!(!unknown.volatile_mount);
+    assert!(unknown.extra_options.is_empty());
+    // round-trip
+    let config = CielConfig::load_config(&config.save_config().unwrap()).unwrap();
+    assert_eq!(
+        config.instances["quick"].extra_options,
+        Some(vec!["--private-network".to_string()])
+    );
+    assert_eq!(config.instances["quick"].volatile_mount, Some(true));
+    assert_eq!(config.instances["quick"].apt_sources, None);
+}
+
+#[test]
+fn test_set_get_value() {
+    let mut config = CielConfig::default();
+    set_config_value(&mut config, "volatile-mount", "true").unwrap();
+    assert!(config.volatile_mount);
+    set_config_value(&mut config, "nspawn-extra-options", "--a --b").unwrap();
+    assert_eq!(
+        get_config_value(&config, "nspawn-extra-options").unwrap(),
+        "--a --b"
+    );
+    set_config_value(&mut config, "instance.test.volatile-mount", "0").unwrap();
+    assert_eq!(
+        get_config_value(&config, "instance.test.volatile-mount").unwrap(),
+        "false"
+    );
+    assert!(set_config_value(&mut config, "local-repo", "maybe").is_err());
+    assert!(set_config_value(&mut config, "maintainer", "test").is_err());
+    assert!(set_config_value(&mut config, "no-such-key", "1").is_err());
+}
+
+#[test]
+fn test_env_overrides() {
+    let mut config = CielConfig {
+        dnssec: true,
+        ..Default::default()
+    };
+    apply_env_overrides(&mut config, |name| match name {
+        "CIEL_DNSSEC" => Some("0".to_string()),
+        "CIEL_LOCAL_REPO" => Some("false".to_string()),
+        "CIEL_NSPAWN_EXTRA_OPTIONS" => Some("--a  --b=c".to_string()),
+        _ => None,
+    })
+    .unwrap();
+    // environment variables take precedence over the file
+    assert!(!config.dnssec);
+    assert!(!config.local_repo);
+    assert_eq!(config.extra_options, vec!["--a", "--b=c"]);
+    assert_eq!(config.primary_maintainer(), "Bot <null@aosc.io>");
+    assert!(apply_env_overrides(&mut config, |name| match name {
+        "CIEL_DNSSEC" => Some("maybe".to_string()),
+        _ => None,
+    })
+    .is_err());
+
+    apply_env_overrides(&mut config, |name| match name {
+        "CIEL_MAINTAINER" => Some("Test <test@aosc.io>".to_string()),
+        _ => None,
+    })
+    .unwrap();
+    assert_eq!(config.primary_maintainer(), "Test <test@aosc.io>");
+}
+
+#[test]
+fn test_multiple_maintainers() {
+    let data = r#"
+version = 3
+maintainer = ["Bot <null@aosc.io>", "Test <test@aosc.io>"]
+active-maintainer = 1
+dnssec = false
+apt_sources = "deb https://repo.aosc.io/debs/ stable main"
+local_repo = true
+local_sources = true
+nspawn-extra-options = []
+branch-exclusive-output = true
+"#;
+    let mut config = CielConfig::load_config(data).unwrap();
+    assert_eq!(config.primary_maintainer(), "Test <test@aosc.io>");
+    config.select_maintainer("0").unwrap();
+    assert_eq!(config.primary_maintainer(), "Bot <null@aosc.io>");
+    assert!(config.select_maintainer("2").is_err());
+    assert!(config.select_maintainer("invalid").is_err());
+    config.select_maintainer("New <new@aosc.io>").unwrap();
+    assert_eq!(config.active_maintainer, 2);
+    // single string format is still supported
+    let config = CielConfig::default();
+    assert!(config
+        .save_config()
+        .unwrap()
+        .contains("maintainer = \"Bot <null@aosc.io>\""));
+    let config = CielConfig::load_config(&config.save_config().unwrap()).unwrap();
+    assert_eq!(config.maintainers, vec!["Bot <null@aosc.io>".to_string()]);
+}
+
+#[test]
+fn test_dns_servers() {
+    let mut config = CielConfig::default();
+    assert_eq!(
+        generate_resolved_conf("", &config),
+        "[Resolve]\nDNSSEC=no\n"
+    );
+    set_config_value(&mut config, "dns-servers", "10.0.0.1, fd00::1").unwrap();
+    assert_eq!(
+        generate_resolved_conf("", &config),
+        "[Resolve]\nDNSSEC=no\nDNS=10.0.0.1 fd00::1\nFallbackDNS=\n"
+    );
+    assert!(set_config_value(&mut config, "dns-servers", "10.0.0.256").is_err());
+}
+
+#[test]
+fn test_proxy_config() {
+    let mut config = CielConfig::default();
+    set_config_value(&mut config, "http-proxy", "http://10.0.0.1:3128").unwrap();
+    set_config_value(&mut config, "no-proxy", "localhost, repo.lan").unwrap();
+    assert_eq!(
+        generate_apt_proxy_conf(&config),
+        "Acquire::http::Proxy \"http://10.0.0.1:3128\";\n\
+         Acquire::http::Proxy::localhost \"DIRECT\";\n\
+         Acquire::https::Proxy::localhost \"DIRECT\";\n\
+         Acquire::http::Proxy::repo.lan \"DIRECT\";\n\
+         Acquire::https::Proxy::repo.lan \"DIRECT\";\n"
+    );
+    assert_eq!(config.container_environment().len(), 4);
+    set_config_value(&mut config, "http-proxy", "").unwrap();
+    assert_eq!(config.http_proxy, None);
+    // nothing that breaks out of the quoted string in apt.conf
+    for proxy in [
+        "http://proxy\";\nAcquire::x \"y",
+        "http://proxy;3128",
+        "socks5://proxy:1080",
+        "http://",
+    ] {
+        assert!(set_config_value(&mut config, "http-proxy", proxy).is_err());
+    }
+    assert_eq!(config.http_proxy, None);
+    for no_proxy in ["*", "10.0.0.0/8", ".lan", "repo.lan:8080", "::1", "a\"b"] {
+        assert!(set_config_value(&mut config, "no-proxy", no_proxy).is_err());
+    }
+    assert_eq!(config.no_proxy.as_deref(), Some("localhost, repo.lan"));
+    let injected = format!(
+        "{}http_proxy = '{}'\n",
+        include_str!("fixtures/config-v3.toml"),
+        "http://x\"; APT::Foo \"1"
+    );
+    let err = CielConfig::load_config(&injected).unwrap_err();
+    assert!(err.to_string().contains("Invalid http_proxy"));
+    config.no_proxy = Some("*,10.0.0.1".to_owned());
+    assert_eq!(
+        generate_apt_proxy_conf(&config),
+        "Acquire::http::Proxy::10.0.0.1 \"DIRECT\";\n\
+         Acquire::https::Proxy::10.0.0.1 \"DIRECT\";\n"
+    );
+}
+
+#[test]
+fn test_config_migration() {
+    let (config, migrated) =
+        CielConfig::load_config_with_migration(include_str!("fixtures/config-v2.toml")).unwrap();
+    assert!(migrated);
+    assert_eq!(config.version, CURRENT_CIEL_VERSION);
+    assert!(!config.sep_mount);
+    assert!(!config.volatile_mount);
+    assert!(config.extra_options.is_empty());
+    let (config, migrated) =
+        CielConfig::load_config_with_migration(include_str!("fixtures/config-v3.toml")).unwrap();
+    assert!(!migrated);
+    assert!(config.volatile_mount);
+    assert_eq!(config.extra_options, vec!["--capability=CAP_NET_ADMIN"]);
+    let newer = include_str!("fixtures/config-v3.toml").replace("version = 3", "version = 99");
+    let err = CielConfig::load_config_with_migration(&newer).unwrap_err();
+    assert!(err.to_string().contains("created by a newer ciel"));
+}
+
+#[test]
+fn test_extra_mounts() {
+    let mut config = CielConfig::default();
+    set_config_value(
+        &mut config,
+        "extra-mounts",
+        "/srv/distfiles:/distfiles:ro /tmp:/ccache",
+    )
+    .unwrap();
+    assert_eq!(
+        config.extra_mounts[0].to_nspawn_option(),
+        "--bind-ro=/srv/distfiles:/distfiles"
+    );
+    assert_eq!(
+        config.extra_mounts[1].to_nspawn_option(),
+        "--bind=/tmp:/ccache"
+    );
+    assert!(config.extra_mounts[1].validate().is_ok());
+    assert!(MountSpec::parse("/tmp:relative").is_err());
+    assert!(MountSpec::parse("/tmp:/tree/sub").is_err());
+    assert!(MountSpec::parse("/tmp:/").is_err());
+    let config = CielConfig::load_config(&config.save_config().unwrap()).unwrap();
+    assert_eq!(
+        get_config_value(&config, "extra-mounts").unwrap(),
+        "/srv/distfiles:/distfiles:ro /tmp:/ccache"
+    );
+}
+
+#[test]
+fn test_build_env() {
+    let mut config = CielConfig::default();
+    set_config_value(&mut config, "build-env.NOCACHE", "1").unwrap();
+    set_config_value(&mut config, "build-env.ABSTRIP", "$HOME \"quoted\"").unwrap();
+    assert!(set_config_value(&mut config, "build-env.1ABC", "1").is_err());
+    assert!(set_config_value(&mut config, "build-env.A-B", "1").is_err());
+    assert!(generate_ab3_config(&config)
+        .ends_with("\nexport ABSTRIP=\"\\$HOME \\\"quoted\\\"\"\nexport NOCACHE=\"1\""));
+    assert!(config
+        .container_environment()
+        .contains(&("NOCACHE".to_owned(), "1".to_owned())));
+    set_config_value(&mut config, "build-env.NOCACHE", "").unwrap();
+    assert!(!generate_ab3_config(&config).contains("NOCACHE"));
+    let config = CielConfig::load_config(&config.save_config().unwrap()).unwrap();
+    assert_eq!(
+        get_config_value(&config, "build-env.ABSTRIP").unwrap(),
+        "$HOME \"quoted\""
+    );
+}
+
+#[test]
+fn test_ccache() {
+    let mut config = CielConfig::default();
+    assert!(config.ccache_mount().is_none());
+    assert!(!generate_ab3_config(&config).contains("CCACHE"));
+    set_config_value(&mut config, "use-ccache", "yes").unwrap();
+    assert!(generate_ab3_config(&config).contains("\nexport CCACHE_DIR=\"/var/cache/ccache\""));
+    assert_eq!(
+        config.ccache_mount().unwrap().host,
+        Path::new(".ciel/data/ccache")
+    );
+    set_config_value(&mut config, "ccache-dir", "/srv/ccache").unwrap();
+    assert_eq!(
+        config.ccache_mount().unwrap().to_nspawn_option(),
+        "--bind=/srv/ccache:/var/cache/ccache"
+    );
+    assert!(MountSpec::parse("/tmp:/var/cache/ccache").is_err());
+}
+
+#[test]
+fn test_parallelism() {
+    let mut config = CielConfig::default();
+    assert!(!generate_ab3_config(&config).contains("ABTHREADS"));
+    assert_eq!(get_config_value(&config, "parallelism").unwrap(), "auto");
+    config.parallelism = Some(0);
+    assert!(!generate_ab3_config(&config).contains("ABTHREADS"));
+    set_config_value(&mut config, "parallelism", "8").unwrap();
+    assert!(generate_ab3_config(&config).contains("\nABTHREADS=8"));
+    assert!(set_config_value(&mut config, "parallelism", "many").is_err());
+    let config = CielConfig::load_config(&config.save_config().unwrap()).unwrap();
+    assert_eq!(config.build_jobs(), Some(8));
+}
+
+#[test]
+fn test_download_retries() {
+    let mut config = CielConfig::default();
+    assert_eq!(get_config_value(&config, "download-retries").unwrap(), "5");
+    set_config_value(&mut config, "download-retries", "0").unwrap();
+    assert_eq!(config.download_retries(), 0);
+    assert!(set_config_value(&mut config, "download-retries", "-1").is_err());
+    set_config_value(&mut config, "download-retries", "").unwrap();
+    assert_eq!(config.download_retries, None);
+}
+
+#[test]
+fn test_update_options() {
+    let mut config = CielConfig::default();
+    assert_eq!(
+        get_config_value(&config, "update-full-upgrade").unwrap(),
+        "true"
+    );
+    assert!(config.update_autoremove());
+    assert!(config.update_clean());
+    config.shared_apt_cache = true;
+    assert_eq!(get_config_value(&config, "update-clean").unwrap(), "false");
+    set_config_value(&mut config, "update-clean", "yes").unwrap();
+    assert!(config.update_clean());
+    set_config_value(&mut config, "update-full-upgrade", "false").unwrap();
+    assert!(!config.update_full_upgrade());
+    assert!(set_config_value(&mut config, "update-autoremove", "maybe").is_err());
+    set_config_value(&mut config, "update-full-upgrade", "").unwrap();
+    assert_eq!(config.update_full_upgrade, None);
+}
+
+#[test]
+fn test_package_manager() {
+    let mut config = CielConfig::default();
+    assert_eq!(
+        get_config_value(&config, "package-manager").unwrap(),
+        "auto"
+    );
+    set_config_value(&mut config, "package-manager", "oma").unwrap();
+    assert_eq!(config.package_manager, PackageManager::Oma);
+    assert!(set_config_value(&mut config, "package-manager", "dnf").is_err());
+    let saved = toml::to_string(&config).unwrap();
+    assert!(saved.contains("package-manager = \"oma\""));
+}
+
+#[test]
+fn test_download_connections() {
+    let mut config = CielConfig::default();
+    assert_eq!(
+        get_config_value(&config, "download-connections").unwrap(),
+        "4"
+    );
+    set_config_value(&mut config, "download-connections", "1").unwrap();
+    assert_eq!(config.download_connections(), 1);
+    assert!(set_config_value(&mut config, "download-connections", "0").is_err());
+    assert!(set_config_value(&mut config, "download-connections", "many").is_err());
+    set_config_value(&mut config, "download-connections", "").unwrap();
+    assert_eq!(config.download_connections, None);
+}
+
+#[test]
+fn test_ca_bundle() {
+    let mut config = CielConfig::default();
+    assert_eq!(get_config_value(&config, "ca-bundle").unwrap(), "");
+    set_config_value(&mut config, "ca-bundle", "/etc/ssl/private-ca.pem").unwrap();
+    assert_eq!(
+        config.ca_bundle,
+        Some(PathBuf::from("/etc/ssl/private-ca.pem"))
+    );
+    let config = CielConfig::load_config(&toml::to_string(&config).unwrap()).unwrap();
+    assert_eq!(
+        get_config_value(&config, "ca-bundle").unwrap(),
+        "/etc/ssl/private-ca.pem"
+    );
+}
+
+#[test]
+fn test_limit_rate() {
+    let mut config = CielConfig::default();
+    set_config_value(&mut config, "limit-rate", "500K").unwrap();
+    assert_eq!(get_config_value(&config, "limit-rate").unwrap(), "500K");
+    assert!(set_config_value(&mut config, "limit-rate", "0").is_err());
+    assert!(set_config_value(&mut config, "limit-rate", "fast").is_err());
+    assert_eq!(config.limit_rate.as_deref(), Some("500K"));
+    set_config_value(&mut config, "limit-rate", "").unwrap();
+    assert_eq!(config.limit_rate, None);
+}
+
+#[test]
+fn test_auto_stop_after() {
+    let mut config = CielConfig::default();
+    set_config_value(&mut config, "auto-stop-after", "90m").unwrap();
+    assert_eq!(config.auto_stop_after, Some(Duration::from_secs(5400)));
+    assert_eq!(
+        get_config_value(&config, "auto-stop-after").unwrap(),
+        "1h30m"
+    );
+    assert!(set_config_value(&mut config, "auto-stop-after", "soon").is_err());
+    let saved = config.save_config().unwrap();
+    assert!(saved.contains("auto-stop-after = \"1h30m\""));
+    let mut config = CielConfig::load_config(&saved).unwrap();
+    assert_eq!(config.auto_stop_after, Some(Duration::from_secs(5400)));
+    set_config_value(&mut config, "auto-stop-after", "").unwrap();
+    assert_eq!(config.auto_stop_after, None);
+}
+
+#[test]
+fn test_boot_timeout() {
+    let mut config = CielConfig::default();
+    set_config_value(&mut config, "boot-timeout", "2m").unwrap();
+    assert_eq!(config.boot_timeout, Some(Duration::from_secs(120)));
+    assert_eq!(get_config_value(&config, "boot-timeout").unwrap(), "2m");
+    assert!(set_config_value(&mut config, "boot-timeout", "0").is_err());
+    set_config_value(&mut config, "stop-timeout", "30").unwrap();
+    assert_eq!(config.stop_timeout, Some(Duration::from_secs(30)));
+    assert_eq!(get_config_value(&config, "stop-timeout").unwrap(), "30s");
+    assert!(set_config_value(&mut config, "stop-timeout", "0s").is_err());
+    // 0 skips the wait
+    set_config_value(&mut config, "network-timeout", "0").unwrap();
+    assert_eq!(config.network_timeout, Some(Duration::from_secs(0)));
+    assert_eq!(get_config_value(&config, "network-timeout").unwrap(), "0s");
+    set_config_value(&mut config, "boot-timeout", "").unwrap();
+    assert_eq!(get_config_value(&config, "boot-timeout").unwrap(), "");
+}
+
+#[test]
+fn test_generated_files_scope() {
+    assert_eq!(generated_files_scope("maintainer"), Some(None));
+    assert_eq!(generated_files_scope("build-env.NOCACHE"), Some(None));
+    assert_eq!(
+        generated_files_scope("instance.main.apt-sources"),
+        Some(Some("main".to_owned()))
+    );
+    assert_eq!(generated_files_scope("instance.main.memory-max"), None);
+    assert_eq!(generated_files_scope("boot-timeout"), None);
+}
+
+#[test]
+fn test_trash_limits() {
+    let mut config = CielConfig::default();
+    set_config_value(&mut config, "trash-max-age", "3d").unwrap();
+    assert_eq!(config.trash_max_age, Some(Duration::from_secs(3 * 86400)));
+    assert_eq!(get_config_value(&config, "trash-max-age").unwrap(), "3d");
+    set_config_value(&mut config, "trash-max-size", "20G").unwrap();
+    assert_eq!(config.trash_max_size.as_deref(), Some("20G"));
+    assert!(set_config_value(&mut config, "trash-max-size", "a lot").is_err());
+    let saved = config.save_config().unwrap();
+    assert!(saved.contains("trash-max-age = \"3d\""));
+    set_config_value(&mut config, "trash-max-size", "").unwrap();
+    assert_eq!(config.trash_max_size, None);
+}
+
+#[test]
+fn test_fastest_mirror() {
+    let mut config = CielConfig::default();
+    assert_eq!(
+        get_config_value(&config, "fastest-mirror").unwrap(),
+        "false"
+    );
+    set_config_value(&mut config, "fastest-mirror", "true").unwrap();
+    assert!(config.fastest_mirror);
+    let config = CielConfig::load_config(&toml::to_string(&config).unwrap()).unwrap();
+    assert!(config.fastest_mirror);
+}
+
+#[test]
+fn test_timezone_locale() {
+    let zoneinfo = tempfile::tempdir().unwrap();
+    fs::create_dir_all(zoneinfo.path().join("Asia")).unwrap();
+    fs::write(zoneinfo.path().join("Asia/Shanghai"), b"TZif").unwrap();
+    assert_eq!(
+        validate_timezone_in(zoneinfo.path(), "Asia/Shanghai"),
+        Ok(())
+    );
+    assert!(validate_timezone_in(zoneinfo.path(), "Asia/Nowhere").is_err());
+    assert!(validate_timezone_in(zoneinfo.path(), "../Asia/Shanghai").is_err());
+    assert!(validate_timezone_in(zoneinfo.path(), "/etc/passwd").is_err());
+
+    let rootfs = tempfile::tempdir().unwrap();
+    let mut config = CielConfig::default();
+    apply_config(rootfs.path(), &config, None).unwrap();
+    assert!(!rootfs.path().join(DEFAULT_LOCALE_LOCATION).exists());
+    assert!(rootfs
+        .path()
+        .join(DEFAULT_LOCALTIME_LOCATION)
+        .symlink_metadata()
+        .is_err());
+    config.timezone = Some("Asia/Shanghai".to_owned());
+    config.locale = Some("en_US.UTF-8".to_owned());
+    apply_config(rootfs.path(), &config, None).unwrap();
+    assert_eq!(
+        fs::read_link(rootfs.path().join(DEFAULT_LOCALTIME_LOCATION)).unwrap(),
+        Path::new("../usr/share/zoneinfo/Asia/Shanghai")
+    );
+    assert_eq!(
+        fs::read_to_string(rootfs.path().join(DEFAULT_LOCALE_LOCATION)).unwrap(),
+        "LANG=en_US.UTF-8\n"
+    );
+}
+
+#[test]
+fn test_workspace_root() {
+    let workspace = tempfile::tempdir().unwrap();
+    let cwd = std::env::current_dir().unwrap();
+    assert!(read_config_raw_from(workspace.path()).is_err());
+    let config = CielConfig {
+        sep_mount: true,
+        ..Default::default()
+    };
+    write_config_to(workspace.path(), &config).unwrap();
+    assert!(workspace.path().join(".ciel/data/config.toml").is_file());
+    assert!(read_config_raw_from(workspace.path()).unwrap().sep_mount);
+    assert_eq!(std::env::current_dir().unwrap(), cwd);
+}
+
+#[test]
+fn test_config_backup() {
+    let workspace = tempfile::tempdir().unwrap();
+    let backup_path = workspace.path().join(DEFAULT_CONFIG_BACKUP_LOCATION);
+    let config = CielConfig::default();
+    write_config_to(workspace.path(), &config).unwrap();
+    assert!(!backup_path.exists());
+    // identical content should not create a backup
+    write_config_to(workspace.path(), &config).unwrap();
+    assert!(!backup_path.exists());
+    let modified = CielConfig {
+        volatile_mount: true,
+        ..Default::default()
+    };
+    write_config_to(workspace.path(), &modified).unwrap();
+    assert_eq!(
+        fs::read_to_string(&backup_path).unwrap(),
+        config.save_config().unwrap()
+    );
+    assert!(
+        read_config_raw_from(workspace.path())
+            .unwrap()
+            .volatile_mount
+    );
+}
+
+#[test]
+fn test_output_dir_pattern() {
+    assert_eq!(
+        expand_output_dir_pattern(DEFAULT_OUTPUT_DIR_PATTERN, "stable", "amd64").unwrap(),
+        "OUTPUT-stable"
+    );
+    assert_eq!(
+        expand_output_dir_pattern("debs-{branch}-{arch}", "stable", "amd64").unwrap(),
+        "debs-stable-amd64"
+    );
+    assert!(expand_output_dir_pattern("debs-{branch", "stable", "amd64").is_err());
+    assert!(expand_output_dir_pattern("debs-{version}", "stable", "amd64").is_err());
+    let mut config = CielConfig::default();
+    set_config_value(&mut config, "output-dir-pattern", "debs-{branch}").unwrap();
+    assert_eq!(config.output_dir_pattern, "debs-{branch}");
+    assert!(set_config_value(&mut config, "output-dir-pattern", "../{branch}").is_err());
+    assert!(set_config_value(&mut config, "output-dir-pattern", "/srv/{branch}").is_err());
+    assert!(set_config_value(&mut config, "output-dir-pattern", "").is_err());
+}
+
+#[test]
+fn test_resolved_conf_dnssec() {
+    let rootfs = tempfile::tempdir().unwrap();
+    let resolv_path = rootfs.path().join(DEFAULT_RESOLV_LOCATION);
+    fs::create_dir_all(resolv_path.parent().unwrap()).unwrap();
+    fs::write(&resolv_path, include_str!("fixtures/resolved.conf")).unwrap();
+    let mut config = CielConfig {
+        dnssec: true,
+        ..Default::default()
+    };
+    apply_config(rootfs.path(), &config, None).unwrap();
+    assert_eq!(
+        fs::read_to_string(&resolv_path).unwrap(),
+        "# This file is part of systemd.\n\
+         [Resolve]\n\
+         #DNS=\n\
+         Domains=lan\n\
+         LLMNR=no\n\
+         DNSSEC=allow-downgrade\n\
+         \n\
+         [Extra]\n\
+         DNS=kept\n"
+    );
+    // disable DNSSEC and set the DNS servers
+    config.dnssec = false;
+    config.dns_servers = vec!["10.0.0.1".to_owned()];
+    apply_config(rootfs.path(), &config, None).unwrap();
+    assert_eq!(
+        fs::read_to_string(&resolv_path).unwrap(),
+        "# This file is part of systemd.\n\
+         [Resolve]\n\
+         #DNS=\n\
+         Domains=lan\n\
+         LLMNR=no\n\
+         DNSSEC=no\n\
+         DNS=10.0.0.1\n\
+         FallbackDNS=\n\
+         \n\
+         [Extra]\n\
+         DNS=kept\n"
+    );
+    // and enable DNSSEC again
+    config.dnssec = true;
+    config.dns_servers.clear();
+    apply_config(rootfs.path(), &config, None).unwrap();
+    assert!(fs::read_to_string(&resolv_path)
+        .unwrap()
+        .contains("LLMNR=no\nDNSSEC=allow-downgrade\n\n[Extra]"));
+}
+
+#[test]
+fn test_apt_sources_location() {
+    let rootfs = tempfile::tempdir().unwrap();
+    let list_path = rootfs.path().join(DEFAULT_APT_LIST_LOCATION);
+    let ciel_list_path = rootfs.path().join(DEFAULT_APT_CIEL_LIST_LOCATION);
+    let mut config = CielConfig::default();
+    // sources.list written by older versions is moved
+    fs::create_dir_all(list_path.parent().unwrap()).unwrap();
+    fs::write(&list_path, DEFAULT_APT_SOURCE).unwrap();
+    apply_config(rootfs.path(), &config, None).unwrap();
+    assert!(!list_path.exists());
+    assert_eq!(
+        fs::read_to_string(&ciel_list_path).unwrap(),
+        DEFAULT_APT_SOURCE
+    );
+    // user's own sources.list is left untouched
+    let user_sources = "deb https://debug.example.com/debs/ stable main\n";
+    fs::write(&list_path, user_sources).unwrap();
+    apply_config(rootfs.path(), &config, None).unwrap();
+    apply_config(rootfs.path(), &config, None).unwrap();
+    assert_eq!(fs::read_to_string(&list_path).unwrap(), user_sources);
+    assert_eq!(
+        fs::read_to_string(&ciel_list_path).unwrap(),
+        DEFAULT_APT_SOURCE
+    );
+    // sources.list is only written if ciel owns it
+    config.manage_sources_list = true;
+    apply_config(rootfs.path(), &config, None).unwrap();
+    assert_eq!(fs::read_to_string(&list_path).unwrap(), DEFAULT_APT_SOURCE);
+    assert!(!ciel_list_path.exists());
+}
+
+#[test]
+fn test_trees() {
+    let mut config = CielConfig::default();
+    assert_eq!(
+        generate_forest_conf(&config),
+        "[default]\nlocation = /tree/\n"
+    );
+    set_config_value(
+        &mut config,
+        "trees",
+        "default:TREE:/tree/ overlay:OVERLAY:/overlay/:10",
+    )
+    .unwrap();
+    assert_eq!(
+        generate_forest_conf(&config),
+        "[overlay]\nlocation = /overlay/\npriority = 10\n\n[default]\nlocation = /tree/\n"
+    );
+    assert_eq!(
+        get_config_value(&config, "trees").unwrap(),
+        "default:TREE:/tree/:0 overlay:OVERLAY:/overlay/:10"
+    );
+    assert!(set_config_value(&mut config, "trees", "a:A:/a/ a:B:/b/").is_err());
+    assert!(set_config_value(&mut config, "trees", "a:A:/a/ b:B:/a").is_err());
+    assert!(set_config_value(&mut config, "trees", "a:../A:/a/").is_err());
+    assert!(set_config_value(&mut config, "trees", "a:A:relative").is_err());
+    let config = CielConfig::load_config(&config.save_config().unwrap()).unwrap();
+    assert_eq!(config.trees.len(), 2);
+    let workspace = Path::new("/nonexistent/ciel");
+    assert_eq!(
+        config.trees[0].container_path(workspace, &workspace.join("TREE/extra-libs/foo")),
+        Some(PathBuf::from("/tree/extra-libs/foo"))
+    );
+    assert_eq!(
+        config.trees[1].container_path(workspace, &workspace.join("OVERLAY")),
+        Some(PathBuf::from("/overlay/"))
+    );
+    assert_eq!(
+        config.trees[0].container_path(workspace, &workspace.join("TREES")),
+        None
+    );
+}
+
+#[test]
+fn test_local_repo_priority() {
+    assert_eq!(
+        generate_local_pin(1001),
+        "# Generated by ciel, do not edit\nPackage: *\nPin: origin \"\"\nPin-Priority: 1001\n"
+    );
+    let rootfs = tempfile::tempdir().unwrap();
+    let pin_path = rootfs.path().join(DEFAULT_LOCAL_PIN_LOCATION);
+    let mut config = CielConfig::default();
+    apply_config(rootfs.path(), &config, None).unwrap();
+    assert!(!pin_path.exists());
+    set_config_value(&mut config, "local-repo-priority", "1001").unwrap();
+    apply_config(rootfs.path(), &config, None).unwrap();
+    apply_config(rootfs.path(), &config, None).unwrap();
+    assert_eq!(
+        fs::read_to_string(&pin_path).unwrap(),
+        generate_local_pin(1001)
+    );
+    config.local_repo = false;
+    apply_config(rootfs.path(), &config, None).unwrap();
+    assert!(!pin_path.exists());
+    config.local_repo = true;
+    apply_config(rootfs.path(), &config, None).unwrap();
+    set_config_value(&mut config, "local-repo-priority", "").unwrap();
+    apply_config(rootfs.path(), &config, None).unwrap();
+    assert!(!pin_path.exists());
+    assert!(set_config_value(&mut config, "local-repo-priority", "70000").is_err());
+}
+
+#[test]
+fn test_isolate_network() {
+    let rootfs = tempfile::tempdir().unwrap();
+    let ciel_list_path = rootfs.path().join(DEFAULT_APT_CIEL_LIST_LOCATION);
+    let mut config = CielConfig::default();
+    set_config_value(&mut config, "isolate-network", "yes").unwrap();
+    apply_config(rootfs.path(), &config, None).unwrap();
+    assert_eq!(
+        fs::read_to_string(&ciel_list_path).unwrap(),
+        ISOLATED_APT_SOURCES
+    );
+    config.isolate_network = false;
+    apply_config(rootfs.path(), &config, None).unwrap();
+    assert_eq!(
+        fs::read_to_string(&ciel_list_path).unwrap(),
+        DEFAULT_APT_SOURCE
+    );
+}
+
+#[test]
+fn test_shared_apt_cache() {
+    let rootfs = tempfile::tempdir().unwrap();
+    let keep_archives_path = rootfs.path().join(DEFAULT_APT_KEEP_ARCHIVES_LOCATION);
+    let mut config = CielConfig::default();
+    assert!(config.apt_cache_mount().is_none());
+    set_config_value(&mut config, "shared-apt-cache", "true").unwrap();
+    assert_eq!(
+        config.apt_cache_mount().unwrap().to_nspawn_option(),
+        "--bind=.ciel/cache/apt:/var/cache/apt/archives"
+    );
+    apply_config(rootfs.path(), &config, None).unwrap();
+    assert_eq!(
+        fs::read_to_string(&keep_archives_path).unwrap(),
+        APT_KEEP_ARCHIVES_CONF
+    );
+    config.shared_apt_cache = false;
+    apply_config(rootfs.path(), &config, None).unwrap();
+    assert!(!keep_archives_path.exists());
+}
+
+#[test]
+fn test_home_mount() {
+    let mut config = CielConfig::default();
+    assert!(config.home_mount("main").is_none());
+    set_config_value(&mut config, "instance.main.persist-home", "true").unwrap();
+    assert_eq!(
+        config.home_mount("main").unwrap().to_nspawn_option(),
+        "--bind=.ciel/container/instances/main/home:/root"
+    );
+    assert!(config.home_mount("other").is_none());
+    set_config_value(&mut config, "persist-home", "true").unwrap();
+    set_config_value(&mut config, "instance.main.persist-home", "false").unwrap();
+    assert!(config.home_mount("main").is_none());
+    assert!(config.home_mount("other").is_some());
+    assert_eq!(
+        get_config_value(&config, "instance.other.persist-home").unwrap(),
+        "true"
+    );
+}
+
+#[test]
+fn test_relocate_paths() {
+    let mut config = CielConfig {
+        output_dir: Some(PathBuf::from("/home/user/ciel/debs")),
+        ccache_dir: Some(PathBuf::from("/home/user/ciel/.ciel/data/ccache")),
+        extra_mounts: vec![
+            MountSpec::parse("/home/user/ciel/SRCS:/srcs").unwrap(),
+            MountSpec::parse("/var/cache/distfiles:/distfiles").unwrap(),
+        ],
+        ..Default::default()
+    };
+    config.trees[0].source = PathBuf::from("/home/user/ciel/TREE");
+    assert!(config.relocate_paths(Path::new("/home/user/ciel"), Path::new("/srv/ciel")));
+    assert_eq!(config.output_dir, Some(PathBuf::from("/srv/ciel/debs")));
+    assert_eq!(config.ccache_dir, Some(PathBuf::from(".ciel/data/ccache")));
+    assert_eq!(config.extra_mounts[0].host, Path::new("SRCS"));
+    assert_eq!(
+        config.extra_mounts[1].host,
+        Path::new("/var/cache/distfiles")
+    );
+    assert_eq!(config.trees[0].source, Path::new("TREE"));
+    assert!(!config.relocate_paths(Path::new("/home/user/ciel"), Path::new("/srv/ciel")));
+}
+
+#[test]
+fn test_hooks() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let script = dir.path().join("pre-build.sh");
+    fs::write(&script, "#!/bin/sh\n").unwrap();
+    let mut config = CielConfig::default();
+    assert!(set_config_value(&mut config, "hooks.pre-build", script.to_str().unwrap()).is_err());
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    set_config_value(&mut config, "hooks.pre-build", script.to_str().unwrap()).unwrap();
+    assert_eq!(config.hooks.pre_build.as_ref(), Some(&script));
+    let saved = config.save_config().unwrap();
+    assert!(saved.contains("[hooks]\npre-build = "));
+    assert_eq!(CielConfig::load_config(&saved).unwrap().hooks, config.hooks);
+    set_config_value(&mut config, "hooks.pre-build", "").unwrap();
+    assert!(config.hooks.is_empty());
+    assert!(!config.save_config().unwrap().contains("[hooks]"));
+}
+
+#[test]
+fn test_unknown_keys() {
+    let base = include_str!("fixtures/config-v3.toml");
+    let (config, warnings) = CielConfig::load_config_with_warnings(base).unwrap();
+    assert!(warnings.is_empty());
+    assert!(config.volatile_mount);
+
+    // typo'd keys are ignored with a suggestion
+    let data = base.replace("volatile-mount", "volatile-muont");
+    let (config, warnings) = CielConfig::load_config_with_warnings(&data).unwrap();
+    assert!(!config.volatile_mount);
+    assert_eq!(
+        warnings,
+        vec!["Unknown configuration key `volatile-muont`, did you mean `volatile-mount`?"]
+    );
+
+    // extra and nested tables
+    let data = format!(
+        "{}\n[hooks]\npre-buld = \"/bin/true\"\n\n[instance.main]\nvolatile-mount = true\n\n[extra]\nfoo = 1\n",
+        base
+    );
+    let (config, warnings) = CielConfig::load_config_with_warnings(&data).unwrap();
+    assert!(config.for_instance("main").volatile_mount);
+    assert!(config.hooks.is_empty());
+    assert_eq!(
+        warnings,
+        vec![
+            "Unknown configuration key `extra`, it is ignored.",
+            "Unknown configuration key `hooks.pre-buld`, did you mean `hooks.pre-build`?",
+        ]
+    );
+
+    // keys from newer versions are accepted once the version check passes
+    let data = format!("{}\nfancy-new-feature = true\n", base);
+    let (_, warnings) = CielConfig::load_config_with_warnings(&data).unwrap();
+    assert_eq!(
+        warnings,
+        vec!["Unknown configuration key `fancy-new-feature`, it is ignored."]
+    );
+    let data = data.replace("version = 3", "version = 99");
+    assert!(CielConfig::load_config_with_warnings(&data).is_err());
+
+    // type errors are still errors
+    let data = base.replace("volatile-mount = true", "volatile-mount = \"yes\"");
+    assert!(CielConfig::load_config_with_warnings(&data).is_err());
+}
+
+#[test]
+fn test_apply_config_plan() {
+    use self::plan::ChangeKind;
+
+    let rootfs = tempfile::tempdir().unwrap();
+    let mut config = CielConfig::default();
+    let changes = apply_config_plan(rootfs.path(), &config).unwrap();
+    assert!(changes.iter().all(|x| x.kind == ChangeKind::Create));
+    assert!(changes
+        .iter()
+        .any(|x| x.path == rootfs.path().join(DEFAULT_APT_CIEL_LIST_LOCATION)));
+    // nothing is written by planning
+    assert!(fs::read_dir(rootfs.path()).unwrap().next().is_none());
+
+    apply_config(rootfs.path(), &config, None).unwrap();
+    assert!(apply_config_plan(rootfs.path(), &config)
+        .unwrap()
+        .is_empty());
+
+    config.dns_servers = vec!["192.0.2.1".to_owned()];
+    config.http_proxy = Some("http://proxy:3128".to_owned());
+    let changes = apply_config_plan(rootfs.path(), &config).unwrap();
+    let resolv = changes
+        .iter()
+        .find(|x| x.path == rootfs.path().join(DEFAULT_RESOLV_LOCATION))
+        .unwrap();
+    assert_eq!(resolv.kind, ChangeKind::Modify);
+    assert!(resolv.diff.contains("\n+DNS=192.0.2.1\n"));
+    assert_eq!(
+        changes
+            .iter()
+            .filter(|x| x.kind == ChangeKind::Create)
+            .count(),
+        2
+    );
+
+    apply_config(rootfs.path(), &config, None).unwrap();
+    config.http_proxy = None;
+    let changes = apply_config_plan(rootfs.path(), &config).unwrap();
+    assert_eq!(changes.len(), 2);
+    assert!(changes.iter().all(|x| x.kind == ChangeKind::Remove));
+}
+
+#[test]
+fn test_defaulted_keys() {
+    let config = CielConfig::load_config(include_str!("fixtures/config-v3.toml")).unwrap();
+    assert_eq!(
+        config.defaulted_keys(),
+        &[
+            "isolate-network",
+            "output-dir-pattern",
+            "clear-machine-id",
+            "shared-apt-cache",
+            "sources-format",
+            "manage-sources-list",
+            "use-ccache",
+        ]
+    );
+    // the values in the file are preserved
+    assert!(config.volatile_mount);
+    assert_eq!(config.extra_options, vec!["--capability=CAP_NET_ADMIN"]);
+
+    let saved = config.save_config().unwrap();
+    let config = CielConfig::load_config(&saved).unwrap();
+    assert!(config.defaulted_keys().is_empty());
+
+    let config = CielConfig::load_config(include_str!("fixtures/config-v2.toml")).unwrap();
+    assert!(config.defaulted_keys().contains(&"volatile-mount"));
+}
+
+#[test]
+fn test_editor() {
+    let mut config = CielConfig::default();
+    set_config_value(&mut config, "editor", " code --wait ").unwrap();
+    assert_eq!(config.editor.as_deref(), Some("code --wait"));
+    assert_eq!(get_config_value(&config, "editor").unwrap(), "code --wait");
+    assert!(set_config_value(&mut config, "editor", "vim 'unterminated").is_err());
+    set_config_value(&mut config, "editor", "").unwrap();
+    assert!(config.editor.is_none());
+}
+
+#[test]
+fn test_env_vars() {
+    let lookup = |name: &str| (name == "HOME").then(|| "/root".to_owned());
+    assert_eq!(
+        parse_env_var("ABBS_NO_STRIP=1", lookup).unwrap(),
+        Some(("ABBS_NO_STRIP".to_owned(), "1".to_owned()))
+    );
+    assert_eq!(
+        parse_env_var("MSG=a b=c", lookup).unwrap(),
+        Some(("MSG".to_owned(), "a b=c".to_owned()))
+    );
+    assert_eq!(
+        parse_env_var("HOME", lookup).unwrap(),
+        Some(("HOME".to_owned(), "/root".to_owned()))
+    );
+    assert_eq!(parse_env_var("UNSET", lookup).unwrap(), None);
+    assert!(parse_env_var("1A=b", lookup).is_err());
+    assert!(parse_env_var("=b", lookup).is_err());
+
+    let env = parse_env_file("# comment\n\nA=1\nexport B=\"two words\"\nC='x'\nD=\n").unwrap();
+    assert_eq!(
+        env,
+        vec![
+            ("A".to_owned(), "1".to_owned()),
+            ("B".to_owned(), "two words".to_owned()),
+            ("C".to_owned(), "x".to_owned()),
+            ("D".to_owned(), "".to_owned()),
+        ]
+    );
+    assert!(parse_env_file("A=1\nNOVALUE\n").is_err());
+    assert!(parse_env_file("A-B=1\n").is_err());
+}
+
+#[test]
+fn test_log_dir() {
+    let mut config = CielConfig::default();
+    set_config_value(&mut config, "log-dir", ".ciel/data/logs").unwrap();
+    assert_eq!(config.log_dir, Some(PathBuf::from(".ciel/data/logs")));
+    assert_eq!(
+        get_config_value(&config, "log-dir").unwrap(),
+        ".ciel/data/logs"
+    );
+    let mut config = CielConfig::load_config(&config.save_config().unwrap()).unwrap();
+    assert_eq!(config.log_dir, Some(PathBuf::from(".ciel/data/logs")));
+    set_config_value(&mut config, "log-dir", "").unwrap();
+    assert_eq!(config.log_dir, None);
+}
+
+#[test]
+fn test_resource_limits() {
+    assert_eq!(parse_cpu_quota("150%", 4).unwrap(), 150);
+    assert_eq!(parse_cpu_quota("1.5", 4).unwrap(), 150);
+    assert_eq!(parse_cpu_quota("4", 4).unwrap(), 400);
+    assert!(parse_cpu_quota("5", 4).is_err());
+    assert!(parse_cpu_quota("401%", 4).is_err());
+    assert!(parse_cpu_quota("0", 4).is_err());
+    assert!(parse_cpu_quota("-1", 4).is_err());
+    assert!(parse_cpu_quota("many", 4).is_err());
+    // only checked against the host when it is set, a larger quota is capped at boot
+    assert_eq!(cpu_quota_percent("100000%").unwrap(), 100000);
+    let limits = ResourceLimits {
+        cpu_quota: Some("100000%".to_owned()),
+        ..Default::default()
+    };
+    let cpus = std::thread::available_parallelism().map_or(1, |x| x.get());
+    assert_eq!(
+        limits.properties().unwrap()[1],
+        format!("CPUQuota={}%", cpus * 100)
+    );
+    assert_eq!(
+        limit_property("memory-max", "16G").unwrap(),
+        "MemoryMax=17179869184"
+    );
+    assert_eq!(limit_property("tasks-max", "512").unwrap(), "TasksMax=512");
+    assert_eq!(
+        limit_property("cpu-quota", "infinity").unwrap(),
+        "CPUQuota="
+    );
+    assert!(limit_property("memory-max", "0").is_err());
+    assert!(limit_property("tasks-max", "lots").is_err());
+
+    let mut config = CielConfig::default();
+    set_config_value(&mut config, "memory-max", "8G").unwrap();
+    set_config_value(&mut config, "instance.big.memory-max", "infinity").unwrap();
+    set_config_value(&mut config, "instance.big.tasks-max", "4096").unwrap();
+    assert!(set_config_value(&mut config, "tasks-max", "0").is_err());
+    assert_eq!(
+        get_config_value(&config, "instance.big.memory-max").unwrap(),
+        "infinity"
+    );
+    let config = CielConfig::load_config(&toml::to_string(&config).unwrap()).unwrap();
+    assert_eq!(
+        config.for_instance("small").limits.properties().unwrap(),
+        vec!["MemoryMax=8589934592", "CPUQuota=", "TasksMax=infinity"]
+    );
+    let limits = config.for_instance("big").limits;
+    assert_eq!(
+        limits.properties().unwrap(),
+        vec!["MemoryMax=infinity", "CPUQuota=", "TasksMax=4096"]
+    );
+    let overrides = ResourceLimits {
+        tasks_max: Some("128".to_owned()),
+        ..Default::default()
+    };
+    assert_eq!(limits.merge(&overrides).tasks_max.as_deref(), Some("128"));
+    assert_eq!(
+        limits.merge(&overrides).memory_max.as_deref(),
+        Some("infinity")
+    );
+    // removing the limit
+    let mut config = config;
+    set_config_value(&mut config, "memory-max", "").unwrap();
+    assert_eq!(config.memory_max, None);
+}
+
+#[test]
+fn test_network_mode() {
+    for port in ["8080:80", "8080", "udp:5353:53", "tcp:443"] {
+        assert!(validate_port(port).is_ok(), "{}", port);
+    }
+    for port in ["", "http:80", "0:80", "8080:70000", "1:2:3", "tcp:", "a:b"] {
+        assert!(validate_port(port).is_err(), "{}", port);
+    }
+
+    let mut config = CielConfig::default();
+    assert_eq!(get_config_value(&config, "network").unwrap(), "host");
+    assert!(set_config_value(&mut config, "network", "bridge").is_err());
+    // ports are only forwarded in the veth mode
+    assert!(set_config_value(&mut config, "instance.web.ports", "8080:80").is_err());
+    set_config_value(&mut config, "instance.web.network", "veth").unwrap();
+    set_config_value(&mut config, "instance.web.ports", "8080:80, udp:5353:53").unwrap();
+    assert_eq!(
+        get_config_value(&config, "instance.web.ports").unwrap(),
+        "8080:80 udp:5353:53"
+    );
+    assert!(set_config_value(&mut config, "instance.web.network", "none").is_err());
+    let config = CielConfig::load_config(&toml::to_string(&config).unwrap()).unwrap();
+    assert_eq!(
+        config.for_instance("web").network_options().unwrap(),
+        vec!["--network-veth", "--port=8080:80", "--port=udp:5353:53"]
+    );
+    assert!(config
+        .for_instance("main")
+        .network_options()
+        .unwrap()
+        .is_empty());
+    let mut config = config;
+    set_config_value(&mut config, "network", "none").unwrap();
+    assert_eq!(
+        config.for_instance("main").network_options().unwrap(),
+        vec!["--private-network"]
+    );
+    assert_eq!(config.for_instance("web").network, NetworkMode::Veth);
+}
+
+#[test]
+fn test_hostname() {
+    assert!(validate_hostname("build-1").is_ok());
+    assert!(validate_hostname("1st").is_ok());
+    assert!(validate_hostname(&"a".repeat(63)).is_ok());
+    assert!(validate_hostname(&"a".repeat(64)).is_err());
+    assert!(validate_hostname("").is_err());
+    assert!(validate_hostname("-build").is_err());
+    assert!(validate_hostname("build-").is_err());
+    assert!(validate_hostname("build_1").is_err());
+    assert!(validate_hostname("build.local").is_err());
+    assert!(validate_hostname("LocalHost").is_err());
+    let host = nix::unistd::gethostname().unwrap().into_string().unwrap();
+    assert!(validate_hostname(host.split('.').next().unwrap()).is_err());
+    assert_eq!(default_hostname("main"), "main");
+    assert_eq!(default_hostname("build_1.amd64-"), "build-1-amd64");
+
+    let mut config = CielConfig::default();
+    assert_eq!(
+        get_config_value(&config, "instance.build_1.hostname").unwrap(),
+        "build-1"
+    );
+    set_config_value(&mut config, "instance.main.hostname", "builder").unwrap();
+    assert!(set_config_value(&mut config, "instance.main.hostname", "-x").is_err());
+    assert_eq!(config.for_instance("main").hostname, "builder");
+    // the instance is renamed along with its configuration
+    let overrides = config.instances.remove("main").unwrap();
+    config.instances.insert("stable".to_owned(), overrides);
+    assert_eq!(config.for_instance("stable").hostname, "builder");
+    set_config_value(&mut config, "instance.stable.hostname", "").unwrap();
+    assert_eq!(config.for_instance("stable").hostname, "stable");
+}
+
+#[test]
+fn test_read_only() {
+    let mut config = CielConfig::default();
+    assert!(!config.for_instance("main").read_only);
+    set_config_value(&mut config, "instance.main.read-only", "true").unwrap();
+    assert!(config.for_instance("main").read_only);
+    assert!(!config.for_instance("other").read_only);
+    assert!(set_config_value(&mut config, "read-only", "true").is_err());
+    let config = CielConfig::load_config(&config.save_config().unwrap()).unwrap();
+    assert_eq!(
+        get_config_value(&config, "instance.main.read-only").unwrap(),
+        "true"
+    );
+}
+
+#[test]
+fn test_private_users() {
+    assert_eq!(parse_private_users("off").unwrap(), PrivateUsers::Off);
+    assert_eq!(parse_private_users("pick").unwrap(), PrivateUsers::Pick);
+    assert_eq!(
+        parse_private_users("524288").unwrap(),
+        PrivateUsers::Range(524288, 65536)
+    );
+    assert_eq!(
+        parse_private_users("524288:1000").unwrap(),
+        PrivateUsers::Range(524288, 1000)
+    );
+    for value in [
+        "on",
+        "0:65536",
+        "1000",
+        "524288:0",
+        "524288:65537",
+        "4294967295:2",
+    ] {
+        assert!(parse_private_users(value).is_err(), "{}", value);
+    }
+
+    let mut config = CielConfig::default();
+    assert!(config
+        .for_instance("main")
+        .private_users_options()
+        .is_empty());
+    set_config_value(&mut config, "private-users", "pick").unwrap();
+    set_config_value(&mut config, "instance.main.private-users", "524288:65536").unwrap();
+    let config = CielConfig::load_config(&config.save_config().unwrap()).unwrap();
+    assert_eq!(
+        config.for_instance("main").private_users_options(),
+        vec![
+            "--private-users=524288:65536",
+            "--private-users-ownership=auto"
+        ]
+    );
+    assert_eq!(
+        config.for_instance("other").private_users,
+        PrivateUsers::Pick
+    );
+    assert_eq!(get_config_value(&config, "private-users").unwrap(), "pick");
+    assert!(CielConfig::load_config(
+        &config
+            .save_config()
+            .unwrap()
+            .replace("private-users = \"pick\"", "private-users = \"all\"")
+    )
+    .is_err());
+}
@@ -0,0 +1,318 @@
+//! Validation of the extra options passed to systemd-nspawn
+
+use crate::warn;
+use console::style;
+use std::path::Path;
+
+/// Known systemd-nspawn options and whether they take an argument
+const NSPAWN_OPTIONS: &[(&str, bool)] = &[
+    ("--quiet", false),
+    ("-q", false),
+    ("--read-only", false),
+    ("--volatile", true),
+    ("--chdir", true),
+    ("--pivot-root", true),
+    ("--user", true),
+    ("-u", true),
+    ("--uuid", true),
+    ("--hostname", true),
+    ("--slice", true),
+    ("--property", true),
+    ("--register", true),
+    ("--keep-unit", false),
+    ("--private-users", true),
+    ("--private-users-ownership", true),
+    ("--private-network", false),
+    ("--network-namespace-path", true),
+    ("--network-interface", true),
+    ("--network-macvlan", true),
+    ("--network-ipvlan", true),
+    ("--network-veth", false),
+    ("-n", false),
+    ("--network-veth-extra", true),
+    ("--network-bridge", true),
+    ("--network-zone", true),
+    ("--port", true),
+    ("-p", true),
+    ("--capability", true),
+    ("--drop-capability", true),
+    ("--no-new-privileges", true),
+    ("--system-call-filter", true),
+    ("--rlimit", true),
+    ("--oom-score-adjust", true),
+    ("--cpu-affinity", true),
+    ("--personality", true),
+    ("--kill-signal", true),
+    ("--notify-ready", true),
+    ("--suppress-sync", true),
+    ("--selinux-context", true),
+    ("-Z", true),
+    ("--selinux-apifs-context", true),
+    ("-L", true),
+    ("--bind", true),
+    ("--bind-ro", true),
+    ("--bind-user", true),
+    ("--tmpfs", true),
+    ("--overlay", true),
+    ("--overlay-ro", true),
+    ("--inaccessible", true),
+    ("--setenv", true),
+    ("-E", true),
+    ("--link-journal", true),
+    ("--resolv-conf", true),
+    ("--timezone", true),
+    ("--console", true),
+    ("--settings", true),
+    ("--load-credential", true),
+    ("--set-credential", true),
+];
+
+/// Options that conflict with the ones managed by ciel
+const CONFLICTING_OPTIONS: &[&str] = &[
+    "--directory",
+    "-D",
+    "--machine",
+    "-M",
+    "--image",
+    "-i",
+    "--template",
+    "--ephemeral",
+    "-x",
+    "--as-pid2",
+    "-a",
+];
+
+/// Options that can be specified multiple times, all the occurrences take effect
+const REPEATABLE_OPTIONS: &[&str] = &[
+    "--property",
+    "--network-interface",
+    "--network-macvlan",
+    "--network-ipvlan",
+    "--network-veth-extra",
+    "--port",
+    "--capability",
+    "--drop-capability",
+    "--system-call-filter",
+    "--rlimit",
+    "--bind",
+    "--bind-ro",
+    "--bind-user",
+    "--tmpfs",
+    "--overlay",
+    "--overlay-ro",
+    "--inaccessible",
+    "--setenv",
+    "--load-credential",
+    "--set-credential",
+];
+
+/// Short options and their long forms
+const SHORT_OPTIONS: &[(&str, &str)] = &[
+    ("-q", "--quiet"),
+    ("-u", "--user"),
+    ("-n", "--network-veth"),
+    ("-p", "--port"),
+    ("-Z", "--selinux-context"),
+    ("-L", "--selinux-apifs-context"),
+    ("-E", "--setenv"),
+];
+
+/// Returns the long form of the option name
+fn canonical_name(name: &str) -> &str {
+    SHORT_OPTIONS
+        .iter()
+        .find(|x| x.0 == name)
+        .map_or(name, |x| x.1)
+}
+
+/// Split the options into groups, each group is an option along with its separate value (if any)
+fn group_options(options: &[String]) -> Vec<(String, Vec<String>)> {
+    let mut groups = Vec::new();
+    let mut iter = options.iter();
+    while let Some(option) = iter.next() {
+        let (name, inline_value) = match option.split_once('=') {
+            Some((name, _)) => (name, true),
+            None => (option.as_str(), false),
+        };
+        let mut group = vec![option.clone()];
+        let takes_value = NSPAWN_OPTIONS.iter().any(|x| x.0 == name && x.1);
+        if takes_value && !inline_value {
+            group.extend(iter.next().cloned());
+        }
+        groups.push((canonical_name(name).to_owned(), group));
+    }
+
+    groups
+}
+
+/// Merge the per-instance options after the global ones, an option in `instance`
+/// replaces the conflicting ones in `global` unless the option can be specified multiple times
+pub fn merge_nspawn_options(global: &[String], instance: &[String]) -> Vec<String> {
+    let instance = group_options(instance);
+    let mut merged = group_options(global)
+        .into_iter()
+        .filter(|(name, _)| {
+            REPEATABLE_OPTIONS.contains(&name.as_str()) || !instance.iter().any(|x| &x.0 == name)
+        })
+        .collect::<Vec<_>>();
+    merged.extend(instance);
+
+    merged.into_iter().flat_map(|x| x.1).collect()
+}
+
+/// Check the source path of the `--bind`/`--bind-ro` options
+fn validate_bind_source(value: &str) -> Option<String> {
+    let source = value.split(':').next().unwrap_or_default();
+    // `+` means relative to the container and `-` means ignored if missing
+    if source.starts_with('+') || source.starts_with('-') {
+        return None;
+    }
+    if !Path::new(source).exists() {
+        return Some(format!(
+            "source path `{}` of the bind mount does not exist",
+            source
+        ));
+    }
+
+    None
+}
+
+/// Validate the extra systemd-nspawn options, returns all the problems found.
+/// Unknown options are only reported as warnings unless `strict` is set
+pub fn validate_nspawn_options_with(options: &[String], strict: bool) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+    let mut iter = options.iter();
+    while let Some(option) = iter.next() {
+        let (name, inline_value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option.as_str(), None),
+        };
+        if !name.starts_with('-') {
+            errors.push(format!("`{}` is not an option", option));
+            continue;
+        }
+        if CONFLICTING_OPTIONS.contains(&name) {
+            errors.push(format!(
+                "`{}` conflicts with the options used by ciel",
+                name
+            ));
+            continue;
+        }
+        let takes_value = match NSPAWN_OPTIONS.iter().find(|x| x.0 == name) {
+            Some((_, takes_value)) => *takes_value,
+            None if strict => {
+                errors.push(format!("`{}` is not a known systemd-nspawn option", name));
+                continue;
+            }
+            None => {
+                warn!(
+                    "`{}` is not a known systemd-nspawn option, please make sure it is correct.",
+                    name
+                );
+                continue;
+            }
+        };
+        let value = match (takes_value, inline_value) {
+            (true, Some(value)) => Some(value),
+            (true, None) => match iter.next() {
+                Some(value) => Some(value.as_str()),
+                None => {
+                    errors.push(format!("`{}` requires a value", name));
+                    continue;
+                }
+            },
+            (false, Some(_)) if name.starts_with("--") => {
+                errors.push(format!("`{}` does not take a value", name));
+                continue;
+            }
+            _ => None,
+        };
+        if let (Some(value), "--bind" | "--bind-ro") = (value, name) {
+            errors.extend(validate_bind_source(value));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Validate the extra systemd-nspawn options, see [validate_nspawn_options_with]
+#[inline]
+pub fn validate_nspawn_options(options: &[String]) -> Result<(), Vec<String>> {
+    validate_nspawn_options_with(options, false)
+}
+
+#[test]
+fn test_validate_nspawn_options() {
+    let options = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        validate_nspawn_options(&options(&[
+            "--capability=CAP_NET_ADMIN",
+            "--bind-ro",
+            "/tmp:/mnt",
+            "--private-network",
+            "--bind=+/var:/mnt2",
+        ])),
+        Ok(())
+    );
+    assert_eq!(validate_nspawn_options(&options(&["--frobnicate"])), Ok(()));
+    assert_eq!(
+        validate_nspawn_options_with(&options(&["--frobnicate"]), true),
+        Err(vec![
+            "`--frobnicate` is not a known systemd-nspawn option".to_owned()
+        ])
+    );
+    assert_eq!(
+        validate_nspawn_options(&options(&[
+            "--bind=/nonexistent/ciel:/mnt",
+            "-D",
+            "--machine=foo",
+            "--setenv",
+        ])),
+        Err(vec![
+            "source path `/nonexistent/ciel` of the bind mount does not exist".to_owned(),
+            "`-D` conflicts with the options used by ciel".to_owned(),
+            "`--machine` conflicts with the options used by ciel".to_owned(),
+            "`--setenv` requires a value".to_owned(),
+        ])
+    );
+}
+
+#[test]
+fn test_merge_nspawn_options() {
+    let options = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        merge_nspawn_options(
+            &options(&[
+                "--capability=CAP_IPC_LOCK",
+                "--private-users",
+                "pick",
+                "-E",
+                "A=1",
+                "--quiet",
+            ]),
+            &options(&[
+                "--capability=CAP_NET_ADMIN",
+                "--private-users=no",
+                "--setenv=B=2"
+            ]),
+        ),
+        options(&[
+            "--capability=CAP_IPC_LOCK",
+            "-E",
+            "A=1",
+            "--quiet",
+            "--capability=CAP_NET_ADMIN",
+            "--private-users=no",
+            "--setenv=B=2",
+        ])
+    );
+    assert_eq!(
+        merge_nspawn_options(&options(&["-q"]), &options(&["--quiet"])),
+        options(&["--quiet"])
+    );
+    assert!(merge_nspawn_options(&[], &[]).is_empty());
+}
@@ -0,0 +1,264 @@
+//! Planning the changes made to the rootfs by apply_config, also used for the dry-run mode
+
+use anyhow::Result;
+use console::style;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Number of the context lines in the diff
+const DIFF_CONTEXT: usize = 3;
+
+/// The kind of a planned change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Create,
+    Modify,
+    Remove,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Content {
+    File(String),
+    Symlink(PathBuf),
+}
+
+/// A change to be made to a file in the rootfs
+#[derive(Debug, Clone)]
+pub struct PlannedChange {
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+    /// Unified diff against the current content of the file
+    pub diff: String,
+    content: Option<Content>,
+}
+
+/// Read the current content of the path, symlinks are not followed
+fn read_current(path: &Path) -> Option<Content> {
+    let meta = path.symlink_metadata().ok()?;
+    if meta.file_type().is_symlink() {
+        return fs::read_link(path).ok().map(Content::Symlink);
+    }
+    fs::read(path)
+        .ok()
+        .map(|x| Content::File(String::from_utf8_lossy(&x).into_owned()))
+}
+
+impl Content {
+    fn as_text(&self) -> String {
+        match self {
+            Content::File(content) => content.clone(),
+            Content::Symlink(target) => format!("symbolic link to {}\n", target.display()),
+        }
+    }
+}
+
+impl PlannedChange {
+    /// Plan to replace the path with the content, returns `None` if nothing would change
+    fn replace(path: PathBuf, content: Content) -> Option<PlannedChange> {
+        let current = read_current(&path);
+        if current.as_ref() == Some(&content) {
+            return None;
+        }
+        let old = current.as_ref().map(|x| x.as_text()).unwrap_or_default();
+        let (kind, old_name) = match current {
+            Some(_) => (ChangeKind::Modify, format!("a{}", path.display())),
+            None => (ChangeKind::Create, "/dev/null".to_owned()),
+        };
+        let diff = unified_diff(
+            &old,
+            &content.as_text(),
+            &old_name,
+            &format!("b{}", path.display()),
+        );
+
+        Some(PlannedChange {
+            path,
+            kind,
+            diff,
+            content: Some(content),
+        })
+    }
+
+    /// Plan to write the file, returns `None` if the content is the same
+    pub(super) fn write(path: PathBuf, content: String) -> Option<PlannedChange> {
+        Self::replace(path, Content::File(content))
+    }
+
+    /// Plan to (re)create the symbolic link, returns `None` if the target is the same
+    pub(super) fn symlink(path: PathBuf, target: PathBuf) -> Option<PlannedChange> {
+        Self::replace(path, Content::Symlink(target))
+    }
+
+    /// Plan to remove the file, returns `None` if the file does not exist
+    pub(super) fn remove(path: PathBuf) -> Option<PlannedChange> {
+        let current = read_current(&path)?;
+        let diff = unified_diff(
+            &current.as_text(),
+            "",
+            &format!("a{}", path.display()),
+            "/dev/null",
+        );
+
+        Some(PlannedChange {
+            path,
+            kind: ChangeKind::Remove,
+            diff,
+            content: None,
+        })
+    }
+
+    /// Make the change to the filesystem
+    pub fn execute(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match &self.content {
+            Some(Content::File(content)) => {
+                if self
+                    .path
+                    .symlink_metadata()
+                    .map_or(false, |x| x.file_type().is_symlink())
+                {
+                    fs::remove_file(&self.path)?;
+                }
+                fs::write(&self.path, content)?;
+            }
+            Some(Content::Symlink(target)) => {
+                if self.path.symlink_metadata().is_ok() {
+                    fs::remove_file(&self.path)?;
+                }
+                std::os::unix::fs::symlink(target, &self.path)?;
+            }
+            None => fs::remove_file(&self.path)?,
+        }
+
+        Ok(())
+    }
+
+    /// Print the change with the diff, `root` is stripped from the path
+    pub fn print(&self, root: &Path) {
+        let path = self.path.strip_prefix(root).unwrap_or(&self.path);
+        let kind = match self.kind {
+            ChangeKind::Create => style("create").green(),
+            ChangeKind::Modify => style("modify").yellow(),
+            ChangeKind::Remove => style("remove").red(),
+        };
+        println!("{} /{}", kind.bold(), style(path.display()).bold());
+        print_diff(&self.diff);
+    }
+}
+
+/// Print the unified diff, colored by the kind of the lines
+pub fn print_diff(diff: &str) {
+    for line in diff.lines() {
+        if line.starts_with("@@") {
+            println!("{}", style(line).cyan());
+        } else if line.starts_with('+') {
+            println!("{}", style(line).green());
+        } else if line.starts_with('-') {
+            println!("{}", style(line).red());
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Generate the unified diff between the two texts
+pub fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+    // longest common subsequence table, lcs[i][j] is the length for old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            ops.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            ops.push(('-', old[i]));
+            i += 1;
+        } else {
+            ops.push(('+', new[j]));
+            j += 1;
+        }
+    }
+
+    let changes = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| op.0 != ' ')
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    if changes.is_empty() {
+        return String::new();
+    }
+    let mut output = format!("--- {}\n+++ {}\n", old_name, new_name);
+    let mut index = 0;
+    while index < changes.len() {
+        // merge the changes whose contexts overlap into the same hunk
+        let mut last = index;
+        while last + 1 < changes.len() && changes[last + 1] - changes[last] <= DIFF_CONTEXT * 2 {
+            last += 1;
+        }
+        let start = changes[index].saturating_sub(DIFF_CONTEXT);
+        let end = (changes[last] + DIFF_CONTEXT + 1).min(ops.len());
+        let old_start = ops[..start].iter().filter(|x| x.0 != '+').count();
+        let new_start = ops[..start].iter().filter(|x| x.0 != '-').count();
+        let old_count = ops[start..end].iter().filter(|x| x.0 != '+').count();
+        let new_count = ops[start..end].iter().filter(|x| x.0 != '-').count();
+        output.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            if old_count > 0 {
+                old_start + 1
+            } else {
+                old_start
+            },
+            old_count,
+            if new_count > 0 {
+                new_start + 1
+            } else {
+                new_start
+            },
+            new_count
+        ));
+        for (op, line) in &ops[start..end] {
+            output.push(*op);
+            output.push_str(line);
+            output.push('\n');
+        }
+        index = last + 1;
+    }
+
+    output
+}
+
+#[test]
+fn test_unified_diff() {
+    assert_eq!(unified_diff("a\nb\n", "a\nb\n", "a/x", "b/x"), "");
+    assert_eq!(
+        unified_diff("", "LANG=C\n", "/dev/null", "b/x"),
+        "--- /dev/null\n+++ b/x\n@@ -0,0 +1,1 @@\n+LANG=C\n"
+    );
+    assert_eq!(
+        unified_diff(
+            "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n",
+            "1\n2\nthree\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n",
+            "a/x",
+            "b/x"
+        ),
+        "--- a/x\n+++ b/x\n@@ -1,6 +1,6 @@\n 1\n 2\n-3\n+three\n 4\n 5\n 6\n@@ -10,3 +10,4 @@\n 10\n 11\n 12\n+13\n"
+    );
+}
@@ -0,0 +1,186 @@
+//! Detection of unknown keys in the configuration file
+
+use toml::{value::Table, Value};
+
+/// Keys that may appear at the top level of the configuration file
+pub(super) const CONFIG_FILE_KEYS: &[&str] = &[
+    "version",
+    "maintainer",
+    "active-maintainer",
+    "dnssec",
+    "dns-servers",
+    "apt_sources",
+    "http_proxy",
+    "https_proxy",
+    "no_proxy",
+    "ca-bundle",
+    "local_repo",
+    "local-repo-priority",
+    "isolate-network",
+    "local_sources",
+    "nspawn-extra-options",
+    "branch-exclusive-output",
+    "output-dir-pattern",
+    "output-dir",
+    "volatile-mount",
+    "clear-machine-id",
+    "shared-apt-cache",
+    "persist-home",
+    "auto-stop-after",
+    "boot-timeout",
+    "stop-timeout",
+    "network-timeout",
+    "memory-max",
+    "cpu-quota",
+    "tasks-max",
+    "log-dir",
+    "trash-max-age",
+    "trash-max-size",
+    "sources-format",
+    "manage-sources-list",
+    "extra-mounts",
+    "use-ccache",
+    "ccache-dir",
+    "parallelism",
+    "timezone",
+    "locale",
+    "tree",
+    "rootfs-url",
+    "rootfs-arch",
+    "rootfs-keyring",
+    "download-retries",
+    "download-connections",
+    "limit-rate",
+    "fastest-mirror",
+    "update-full-upgrade",
+    "update-autoremove",
+    "update-clean",
+    "package-manager",
+    "network",
+    "private-users",
+    "editor",
+    "build-env",
+    "hooks",
+    "instance",
+];
+/// Keys of the `[instance.<name>]` tables
+const INSTANCE_FILE_KEYS: &[&str] = &[
+    "nspawn-extra-options",
+    "branch-exclusive-output",
+    "volatile-mount",
+    "persist-home",
+    "apt_sources",
+    "hostname",
+    "read-only",
+    "private-users",
+];
+/// Keys of the `[[tree]]` tables
+const TREE_FILE_KEYS: &[&str] = &["name", "source", "location", "priority"];
+/// Keys of the `[[extra-mounts]]` tables
+const MOUNT_FILE_KEYS: &[&str] = &["host", "container", "read-only"];
+/// Keys of the `[hooks]` table
+const HOOKS_FILE_KEYS: &[&str] = &["pre-build", "post-build", "pre-update-os", "post-update-os"];
+
+/// Levenshtein distance between the two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                1 + prev.min(row[j]).min(current)
+            };
+            prev = current;
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Returns the known key that looks the most similar to `key`
+fn suggest<'a>(key: &str, known: &[&'a str]) -> Option<&'a str> {
+    known
+        .iter()
+        .map(|x| (edit_distance(key, x), *x))
+        .filter(|(distance, _)| *distance <= 3 && *distance < key.len())
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, x)| x)
+}
+
+/// Remove the unknown keys from the table, returns the warnings about them
+fn strip_table(table: &mut Table, known: &[&str], prefix: &str, warnings: &mut Vec<String>) {
+    let unknown = table
+        .keys()
+        .filter(|x| !known.contains(&x.as_str()))
+        .cloned()
+        .collect::<Vec<_>>();
+    for key in unknown {
+        table.remove(&key);
+        let mut warning = format!("Unknown configuration key `{}{}`", prefix, key);
+        if let Some(suggestion) = suggest(&key, known) {
+            warning.push_str(&format!(", did you mean `{}{}`?", prefix, suggestion));
+        } else {
+            warning.push_str(", it is ignored.");
+        }
+        warnings.push(warning);
+    }
+}
+
+/// Remove the unknown keys from the tables in the array
+fn strip_array(value: Option<&mut Value>, known: &[&str], name: &str, warnings: &mut Vec<String>) {
+    if let Some(Value::Array(array)) = value {
+        for (index, item) in array.iter_mut().enumerate() {
+            if let Value::Table(table) = item {
+                strip_table(table, known, &format!("{}[{}].", name, index), warnings);
+            }
+        }
+    }
+}
+
+/// Remove all the unknown keys in the configuration, returns the warnings about them
+pub(super) fn strip_unknown_keys(config: &mut Table) -> Vec<String> {
+    let mut warnings = Vec::new();
+    strip_table(config, CONFIG_FILE_KEYS, "", &mut warnings);
+    strip_array(
+        config.get_mut("tree"),
+        TREE_FILE_KEYS,
+        "tree",
+        &mut warnings,
+    );
+    strip_array(
+        config.get_mut("extra-mounts"),
+        MOUNT_FILE_KEYS,
+        "extra-mounts",
+        &mut warnings,
+    );
+    if let Some(Value::Table(hooks)) = config.get_mut("hooks") {
+        strip_table(hooks, HOOKS_FILE_KEYS, "hooks.", &mut warnings);
+    }
+    if let Some(Value::Table(instances)) = config.get_mut("instance") {
+        for (name, instance) in instances.iter_mut() {
+            if let Value::Table(instance) = instance {
+                let prefix = format!("instance.{}.", name);
+                strip_table(instance, INSTANCE_FILE_KEYS, &prefix, &mut warnings);
+            }
+        }
+    }
+
+    warnings
+}
+
+#[test]
+fn test_edit_distance() {
+    assert_eq!(edit_distance("volatile-muont", "volatile-mount"), 2);
+    assert_eq!(edit_distance("", "abc"), 3);
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+    assert_eq!(
+        suggest("volatile-muont", CONFIG_FILE_KEYS),
+        Some("volatile-mount")
+    );
+    assert_eq!(suggest("frobnicate-everything", CONFIG_FILE_KEYS), None);
+}
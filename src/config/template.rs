@@ -0,0 +1,92 @@
+//! Configuration templates, used to pre-populate the configuration of a new workspace
+
+use super::{strict::CONFIG_FILE_KEYS, user::config_home, CielConfig};
+use anyhow::{anyhow, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use toml::{value::Table, Value};
+
+/// Returns the directory containing the user's templates (`~/.config/ciel/templates`)
+fn templates_dir() -> Option<PathBuf> {
+    Some(config_home()?.join("ciel/templates"))
+}
+
+/// Locate the template, `name` could either be a path or the name of a template
+/// in the templates directory (with or without the `.toml` suffix)
+pub fn find_template(name: &str) -> Result<PathBuf> {
+    let path = Path::new(name);
+    if path.is_file() {
+        return Ok(path.to_owned());
+    }
+    if let Some(dir) = templates_dir() {
+        for candidate in [dir.join(name), dir.join(format!("{}.toml", name))] {
+            if candidate.is_file() {
+                return Ok(candidate);
+            }
+        }
+    }
+
+    Err(anyhow!(
+        "Template `{}` not found. Please specify a path or put it under ~/.config/ciel/templates/.",
+        name
+    ))
+}
+
+/// Apply the values in the template on top of the default configuration
+fn merge_template(template: Table) -> Result<CielConfig> {
+    let unknown = template
+        .keys()
+        .filter(|x| !CONFIG_FILE_KEYS.contains(&x.as_str()))
+        .map(|x| format!("`{}`", x))
+        .collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        return Err(anyhow!("Unknown keys: {}", unknown.join(", ")));
+    }
+    let mut table = match Value::try_from(CielConfig::default())? {
+        Value::Table(table) => table,
+        _ => unreachable!(),
+    };
+    table.extend(template);
+
+    CielConfig::load_config(&toml::to_string(&table)?)
+}
+
+/// Load the configuration template, keys not specified in the template use the default values
+pub fn load_template<P: AsRef<Path>>(path: P) -> Result<CielConfig> {
+    let path = path.as_ref();
+    let data = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Unable to read template {}: {}", path.display(), e))?;
+    let template: Table =
+        toml::from_str(&data).map_err(|e| anyhow!("Invalid template {}: {}", path.display(), e))?;
+
+    merge_template(template).map_err(|e| anyhow!("Invalid template {}: {}", path.display(), e))
+}
+
+#[test]
+fn test_load_template() {
+    let template: Table = toml::from_str(
+        "apt_sources = \"deb https://repo.aosc.io/debs/ testing main\"\n\
+         branch-exclusive-output = false\n\
+         nspawn-extra-options = [\"--capability=CAP_IPC_LOCK\"]\n",
+    )
+    .unwrap();
+    let config = merge_template(template).unwrap();
+    assert!(!config.sep_mount);
+    assert_eq!(config.extra_options, vec!["--capability=CAP_IPC_LOCK"]);
+    assert_eq!(
+        config.apt_sources,
+        "deb https://repo.aosc.io/debs/ testing main"
+    );
+    assert_eq!(
+        config.primary_maintainer(),
+        CielConfig::default().primary_maintainer()
+    );
+
+    let template: Table = toml::from_str("sep-mount = true\nvolatile-mount = true\n").unwrap();
+    assert_eq!(
+        merge_template(template).unwrap_err().to_string(),
+        "Unknown keys: `sep-mount`"
+    );
+}
@@ -0,0 +1,69 @@
+//! User-level configuration (`~/.config/ciel/config.toml`), shared by all the workspaces
+
+use crate::common::parse_size;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Default size limit of the OS tarball cache
+const DEFAULT_TARBALL_CACHE_SIZE: u64 = 4 << 30;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct UserConfig {
+    /// Maximum total size of the cached OS tarballs (e.g. `8GiB`), `0` disables the cache
+    #[serde(default)]
+    pub tarball_cache_size: Option<String>,
+}
+
+impl UserConfig {
+    /// Returns the size limit of the OS tarball cache in bytes
+    pub fn tarball_cache_size(&self) -> Result<u64> {
+        match &self.tarball_cache_size {
+            Some(size) => {
+                parse_size(size).ok_or_else(|| anyhow!("Invalid tarball-cache-size `{}`", size))
+            }
+            None => Ok(DEFAULT_TARBALL_CACHE_SIZE),
+        }
+    }
+}
+
+/// Returns the user's configuration directory (`$XDG_CONFIG_HOME` or `~/.config`)
+pub fn config_home() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|x| Path::new(&x).join(".config")))
+}
+
+/// Read the user-level configuration, the default values are used if it does not exist
+pub fn read_user_config() -> Result<UserConfig> {
+    let path = match config_home() {
+        Some(home) => home.join("ciel/config.toml"),
+        None => return Ok(UserConfig::default()),
+    };
+    if !path.is_file() {
+        return Ok(UserConfig::default());
+    }
+
+    toml::from_str(&fs::read_to_string(&path)?)
+        .map_err(|e| anyhow!("Invalid user configuration {}: {}", path.display(), e))
+}
+
+#[test]
+fn test_tarball_cache_size() {
+    assert_eq!(
+        UserConfig {
+            tarball_cache_size: Some("1K".to_owned())
+        }
+        .tarball_cache_size()
+        .unwrap(),
+        1024
+    );
+    assert_eq!(
+        UserConfig::default().tarball_cache_size().unwrap(),
+        DEFAULT_TARBALL_CACHE_SIZE
+    );
+}
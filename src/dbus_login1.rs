@@ -0,0 +1,22 @@
+//! # DBus interface proxy for: `org.freedesktop.login1.Manager`
+//!
+//! Only the methods used by ciel are included, adapted from the output of `zbus-xmlgen`
+//! for `org.freedesktop.login1.xml`.
+
+use zbus::dbus_proxy;
+
+#[dbus_proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1"
+)]
+trait Manager {
+    /// Inhibit method
+    fn inhibit(
+        &self,
+        what: &str,
+        who: &str,
+        why: &str,
+        mode: &str,
+    ) -> zbus::Result<zbus::zvariant::OwnedFd>;
+}
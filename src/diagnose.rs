@@ -1,31 +1,75 @@
 use anyhow::{anyhow, Result};
-use console::style;
+use console::{style, user_attended};
+use dialoguer::{theme::ColorfulTheme, Confirm};
 use fs3::statvfs;
 use indicatif::HumanBytes;
+use nix::mount::{umount2, MntFlags};
 use std::sync::mpsc::channel;
-use std::{fs::File, io::BufRead, time::Duration};
 use std::{
+    fs,
     io::{BufReader, Write},
+    path::{Path, PathBuf},
+    process::Command,
     thread,
 };
+use std::{fs::File, io::BufRead, time::Duration};
 use tempfile::tempfile_in;
 use which::which;
 use zbus::blocking::Connection;
 use zbus::dbus_proxy;
 
-use crate::error;
+use crate::common::{
+    is_instance_exists, is_legacy_workspace, parse_mountinfo, CIEL_DIST_DIR, CIEL_INST_DIR,
+    SKELETON_DIRS,
+};
+use crate::dbus_machine1::ManagerProxyBlocking;
+use crate::{actions, config, error, info, machine, warn, workspace};
 
 const TEST_TEXT: &[u8] = b"An-An was born a rabbit, but found herself a girl with bunny ears and tails when she woke up one day. She couldn't seem to remember why.";
 const TEST_PROGRAMS: &[&str] = &["systemd-nspawn", "systemd-run"];
-const TEST_CASES: &[&dyn Fn() -> Result<String>] = &[
-    &test_sd_bus,
-    &test_io_simple,
-    &test_required_binaries,
-    &test_fs_support,
-    &test_vm_container,
-    &test_disk_io,
-    &test_disk_space,
+/// systemd-nspawn supports `--volatile` since this version
+const MIN_NSPAWN_VERSION: u32 = 216;
+/// Free space needed to do something meaningful
+const MIN_FREE_SPACE: u64 = 10 * 1024 * 1024 * 1024;
+const HOST_CHECKS: &[Check] = &[
+    test_sd_bus,
+    test_machined,
+    test_io_simple,
+    test_required_binaries,
+    test_nspawn_version,
+    test_fs_support,
+    test_overlay_features,
+    test_vm_container,
+    test_disk_io,
+    test_disk_space,
 ];
+const WORKSPACE_CHECKS: &[Check] = &[
+    test_workspace_layout,
+    test_workspace_location,
+    test_config,
+    test_stale_mounts,
+];
+
+/// Result of a diagnostic check
+enum Outcome {
+    Pass(String),
+    /// A problem that does not prevent ciel from working, along with the remediation hint
+    Warn(String, String),
+    /// A problem that needs to be fixed, along with the remediation hint
+    Fail(String, String),
+}
+
+use Outcome::*;
+
+/// Environment of the diagnostic checks
+struct Doctor {
+    /// Whether the current directory is a ciel workspace
+    workspace: bool,
+    /// Whether to repair the problems that can be safely fixed
+    fix: bool,
+}
+
+type Check = fn(&Doctor) -> Outcome;
 
 #[dbus_proxy(
     interface = "org.freedesktop.systemd1.Manager",
@@ -42,31 +86,105 @@ trait Systemd1Manager {
     fn virtualization(&self) -> zbus::Result<String>;
 }
 
-fn test_sd_bus() -> Result<String> {
+fn systemd_version() -> Result<String> {
     let conn = Connection::system()?;
     let proxy = Systemd1ManagerProxyBlocking::new(&conn)?;
-    let version: String = proxy.version()?;
-    Ok(format!(
-        "Systemd D-Bus (systemd {}) seems to be working",
-        version
-    ))
+
+    Ok(proxy.version()?)
+}
+
+fn test_sd_bus(_: &Doctor) -> Outcome {
+    match systemd_version() {
+        Ok(version) => Pass(format!(
+            "Systemd D-Bus (systemd {}) seems to be working",
+            version
+        )),
+        Err(e) => Warn(
+            format!("Unable to talk to systemd over D-Bus: {}", e),
+            "Make sure the system is booted with systemd and the D-Bus system bus is running, or else systemd-machined is unavailable as well"
+                .to_string(),
+        ),
+    }
 }
 
-fn test_io_simple() -> Result<String> {
-    File::open("/proc/1/cmdline")?;
-    Ok("Basic I/O operations seem to be working".to_string())
+fn test_machined(_: &Doctor) -> Outcome {
+    let pool = Connection::system().and_then(|conn| ManagerProxyBlocking::new(&conn)?.pool_path());
+    match pool {
+        Ok(_) => Pass("systemd-machined is reachable".to_string()),
+        // the instances are still usable, see crate::fallback
+        Err(e) => Warn(
+            format!(
+                "Unable to reach systemd-machined, the instances are run without registering them: {}",
+                e
+            ),
+            "Install systemd-container and make sure `systemctl start systemd-machined` works. Until then, the resource limits, mounting into the running instances (`--mount`), and the state, addresses and unit of the instances (`ciel status -i`, `ciel list -v`) are unavailable"
+                .to_string(),
+        ),
+    }
 }
 
-fn test_required_binaries() -> Result<String> {
+fn test_io_simple(_: &Doctor) -> Outcome {
+    match File::open("/proc/1/cmdline") {
+        Ok(_) => Pass("Basic I/O operations seem to be working".to_string()),
+        Err(e) => Fail(
+            format!("Unable to read /proc/1/cmdline: {}", e),
+            "Make sure /proc is mounted".to_string(),
+        ),
+    }
+}
+
+fn test_required_binaries(_: &Doctor) -> Outcome {
     for binary in TEST_PROGRAMS {
         if which(binary).is_err() {
-            return Err(anyhow!("Required program `{}` is not found", binary));
+            return Fail(
+                format!("Required program `{}` is not found", binary),
+                format!(
+                    "Install systemd-container (or the package providing `{}`)",
+                    binary
+                ),
+            );
         }
     }
-    Ok("Required binaries are correctly installed".to_string())
+    Pass("Required binaries are correctly installed".to_string())
+}
+
+/// Parse the version from the output of `systemd-nspawn --version` (e.g. `systemd 254 (254.5)`)
+fn parse_systemd_version(output: &str) -> Option<u32> {
+    output
+        .split_whitespace()
+        .nth(1)?
+        .split(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn test_nspawn_version(_: &Doctor) -> Outcome {
+    let version = Command::new("systemd-nspawn")
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|x| parse_systemd_version(&String::from_utf8_lossy(&x.stdout)));
+    match version {
+        Some(version) if version >= MIN_NSPAWN_VERSION => Pass(format!(
+            "systemd-nspawn (version {}) is recent enough",
+            version
+        )),
+        Some(version) => Fail(
+            format!(
+                "systemd-nspawn (version {}) does not support `--volatile`",
+                version
+            ),
+            format!("Upgrade systemd to version {} or later", MIN_NSPAWN_VERSION),
+        ),
+        None => Warn(
+            "Unable to determine the version of systemd-nspawn".to_string(),
+            "Make sure `systemd-nspawn --version` works".to_string(),
+        ),
+    }
 }
 
-fn test_fs_support() -> Result<String> {
+fn is_overlay_supported() -> Result<bool> {
     let f = File::open("/proc/filesystems")?;
     let reader = BufReader::new(f);
     for line in reader.lines() {
@@ -74,32 +192,76 @@ fn test_fs_support() -> Result<String> {
         let mut fs_type = line.splitn(2, '\t');
         if let Some(fs_type) = fs_type.nth(1) {
             if fs_type == "overlay" {
-                return Ok("Filesystem support seems to be sufficient".to_string());
+                return Ok(true);
             }
         }
     }
 
-    Err(anyhow!(
-        "Kernel does not support overlayfs, try `modprobe overlay`"
-    ))
+    Ok(false)
 }
 
-fn test_vm_container() -> Result<String> {
-    let conn = Connection::system()?;
-    let proxy = Systemd1ManagerProxyBlocking::new(&conn)?;
-    let virt: String = proxy.virtualization()?;
+fn test_fs_support(doctor: &Doctor) -> Outcome {
+    let mut supported = is_overlay_supported();
+    if doctor.fix && matches!(supported, Ok(false)) {
+        Command::new("modprobe").arg("overlay").status().ok();
+        supported = is_overlay_supported();
+    }
+    match supported {
+        Ok(true) => Pass("Filesystem support seems to be sufficient".to_string()),
+        Ok(false) => Fail(
+            "Kernel does not support overlayfs".to_string(),
+            "Load the module with `modprobe overlay` (or run `ciel doctor --fix`)".to_string(),
+        ),
+        Err(e) => Fail(
+            format!("Unable to read /proc/filesystems: {}", e),
+            "Make sure /proc is mounted".to_string(),
+        ),
+    }
+}
+
+fn test_overlay_features(_: &Doctor) -> Outcome {
+    let parameters = Path::new("/sys/module/overlay/parameters");
+    let missing = ["metacopy", "redirect_dir"]
+        .into_iter()
+        .filter(|x| !parameters.join(x).exists())
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        Pass("overlayfs supports metacopy and redirect_dir".to_string())
+    } else {
+        Warn(
+            format!("overlayfs does not support {}", missing.join(" and ")),
+            "Use Linux 4.19 or later (with the overlay module loaded), or some changes may not be committed correctly".to_string(),
+        )
+    }
+}
+
+fn test_vm_container(_: &Doctor) -> Outcome {
+    let virt = Connection::system()
+        .and_then(|conn| Systemd1ManagerProxyBlocking::new(&conn)?.virtualization());
+    let virt = match virt {
+        Ok(virt) => virt,
+        Err(e) => {
+            return Warn(
+                format!("Unable to detect the virtualization environment: {}", e),
+                "Make sure systemd is running".to_string(),
+            )
+        }
+    };
     if virt == "wsl" {
-        return Ok("!WSL is not supported".to_string());
+        return Warn(
+            "WSL is not supported".to_string(),
+            "Use a virtual machine or a native Linux installation instead".to_string(),
+        );
     }
     let virt_msg = if virt.is_empty() {
         String::new()
     } else {
         format!("(running in {})", virt)
     };
-    Ok(format!("Environment seems sane {}", virt_msg))
+    Pass(format!("Environment seems sane {}", virt_msg))
 }
 
-fn test_disk_io() -> Result<String> {
+fn test_disk_io(_: &Doctor) -> Outcome {
     let (tx, rx) = channel();
     thread::spawn(move || {
         let f = tempfile_in("./");
@@ -111,63 +273,533 @@ fn test_disk_io() -> Result<String> {
     });
 
     if rx.recv_timeout(Duration::from_secs(10)).is_ok() {
-        return Ok("Disk I/O seems ok".to_string());
+        return Pass("Disk I/O seems ok".to_string());
     }
 
     error!("The test file is taking too long to write, suspecting I/O stuck.");
 
-    Err(anyhow!("Disk I/O is not working correctly"))
+    Fail(
+        "Disk I/O is not working correctly".to_string(),
+        "Check the health of the disk and the kernel log (`dmesg`)".to_string(),
+    )
 }
 
-fn test_disk_space() -> Result<String> {
-    let stats = statvfs(std::fs::canonicalize(".")?)?;
-    if stats.available_space() < (10 * 1024 * 1024 * 1024) {
-        // 10 GB
-        Err(anyhow!("Disk space insufficient. Need at least 10 GB of free space to do something meaningful (You have {}).", HumanBytes(stats.available_space())))
+/// Returns the closest existing ancestor of the path, the path itself may not exist yet
+fn existing_ancestor(path: &Path) -> PathBuf {
+    path.ancestors()
+        .find(|x| x.exists())
+        .unwrap_or_else(|| Path::new("."))
+        .to_path_buf()
+}
+
+fn test_disk_space(doctor: &Doctor) -> Outcome {
+    let paths = if doctor.workspace {
+        let output = config::read_config()
+            .map(|c| actions::get_output_directory(&c, c.sep_mount))
+            .unwrap_or_else(|_| PathBuf::from("OUTPUT"));
+        vec![
+            ("base system", PathBuf::from(CIEL_DIST_DIR)),
+            ("output", output),
+        ]
     } else {
-        Ok(format!(
-            "Disk space is sufficient ({} free of {}).",
+        vec![("current directory", PathBuf::from("."))]
+    };
+    let mut messages = Vec::new();
+    let mut insufficient = Vec::new();
+    for (name, path) in paths {
+        let stats = match statvfs(existing_ancestor(&path)) {
+            Ok(stats) => stats,
+            Err(e) => {
+                return Fail(
+                    format!(
+                        "Unable to query the free space of {}: {}",
+                        path.display(),
+                        e
+                    ),
+                    "Make sure the path is accessible".to_string(),
+                )
+            }
+        };
+        let message = format!(
+            "{} free of {} for the {}",
             HumanBytes(stats.available_space()),
-            HumanBytes(stats.total_space())
+            HumanBytes(stats.total_space()),
+            name
+        );
+        if stats.available_space() < MIN_FREE_SPACE {
+            insufficient.push(message);
+        } else {
+            messages.push(message);
+        }
+    }
+
+    if insufficient.is_empty() {
+        Pass(format!(
+            "Disk space is sufficient ({}).",
+            messages.join(", ")
         ))
+    } else {
+        Fail(
+            format!("Disk space insufficient ({}).", insufficient.join(", ")),
+            format!(
+                "Free up some space, at least {} is needed to do something meaningful",
+                HumanBytes(MIN_FREE_SPACE)
+            ),
+        )
     }
 }
 
-/// Carry out the diagnostic tests
-pub fn run_diagnose() -> Result<()> {
-    let mut lines = vec![];
-    let mut has_error = false;
-    for test in TEST_CASES {
-        match test() {
-            Ok(msg) => {
-                if msg.starts_with('!') {
-                    lines.push(format!(
-                        "{} {}",
-                        style("!").yellow(),
-                        style(msg.strip_prefix('!').unwrap()).yellow().bold()
-                    ));
-                    continue;
-                }
-                lines.push(format!(
-                    "{} {}",
-                    style("✓").green(),
-                    style(msg).green().bold()
-                ))
+fn test_workspace_layout(doctor: &Doctor) -> Outcome {
+    let mut missing = SKELETON_DIRS
+        .iter()
+        .filter(|x| !Path::new(x).is_dir())
+        .collect::<Vec<_>>();
+    if doctor.fix {
+        missing.retain(|x| fs::create_dir_all(x).is_err());
+    }
+    if !missing.is_empty() {
+        return Fail(
+            format!(
+                "Workspace layout is incomplete, missing {}",
+                missing
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            "Run `ciel doctor --fix` to create the missing directories".to_string(),
+        );
+    }
+    if !Path::new(".ciel/version").is_file() {
+        return Fail(
+            "Workspace version file (.ciel/version) is missing".to_string(),
+            "Re-create the workspace with `ciel init`".to_string(),
+        );
+    }
+    let has_base = fs::read_dir(CIEL_DIST_DIR)
+        .map(|mut x| x.next().is_some())
+        .unwrap_or(false);
+    if !has_base {
+        return Warn(
+            "Base system is not loaded".to_string(),
+            "Run `ciel load-os` to load the base system".to_string(),
+        );
+    }
+
+    Pass("Workspace layout is complete".to_string())
+}
+
+fn test_workspace_location(_: &Doctor) -> Outcome {
+    match workspace::moved_from() {
+        Some(old_root) => Fail(
+            format!("Workspace has been moved from {}", old_root.display()),
+            "Run `ciel doctor --relocate` to clean up the instances started at the old location"
+                .to_string(),
+        ),
+        None => Pass("Workspace has not been moved".to_string()),
+    }
+}
+
+fn test_config(_: &Doctor) -> Outcome {
+    let config = match config::read_config_raw() {
+        Ok(config) => config,
+        Err(e) => return Fail(
+            format!("Configuration is invalid: {}", e),
+            "Fix it with `ciel config -g`, or restore the previous one with `ciel config restore`"
+                .to_string(),
+        ),
+    };
+    let mut warnings = config::config_warnings();
+    if let Err(errors) = config::validate_nspawn_options(&config.extra_options) {
+        warnings.extend(errors);
+    }
+    if warnings.is_empty() {
+        Pass("Configuration is valid".to_string())
+    } else {
+        Warn(
+            format!("Configuration has problems: {}", warnings.join("; ")),
+            "Correct them with `ciel config set` or `ciel config -g`".to_string(),
+        )
+    }
+}
+
+/// Find the stale overlay mounts in the workspace: mounts of the instances that no longer exist,
+/// and the extra mounts stacked on the same instance
+fn find_stale_mounts(mounts: &[(PathBuf, String)], workspace: &Path) -> Vec<PathBuf> {
+    let mut seen = Vec::new();
+    let mut stale = Vec::new();
+    for (mount_point, fs_type) in mounts {
+        if fs_type != "overlay" || mount_point.parent() != Some(workspace) {
+            continue;
+        }
+        let name = mount_point
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        if !is_instance_exists(&name) || seen.contains(&mount_point) {
+            stale.push(mount_point.clone());
+        } else {
+            seen.push(mount_point);
+        }
+    }
+
+    stale
+}
+
+fn test_stale_mounts(doctor: &Doctor) -> Outcome {
+    let mounts = fs::read_to_string("/proc/self/mountinfo").map(|x| parse_mountinfo(&x));
+    let (mounts, workspace) = match (mounts, std::env::current_dir()) {
+        (Ok(mounts), Ok(workspace)) => (mounts, workspace),
+        (Err(e), _) | (_, Err(e)) => {
+            return Fail(
+                format!("Unable to inspect the mounts: {}", e),
+                "Make sure /proc is mounted".to_string(),
+            )
+        }
+    };
+    let mut stale = find_stale_mounts(&mounts, &workspace);
+    if stale.is_empty() {
+        return Pass("No stale mounts found".to_string());
+    }
+    if doctor.fix {
+        // the stacked mounts are unmounted from the top, one at a time
+        stale.retain(|x| umount2(x, MntFlags::MNT_DETACH).is_err());
+        if stale.is_empty() {
+            return Pass("Stale mounts have been unmounted".to_string());
+        }
+    }
+
+    Fail(
+        format!(
+            "Stale mounts found: {}",
+            stale
+                .iter()
+                .map(|x| x.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        "Run `ciel doctor --fix` to unmount them".to_string(),
+    )
+}
+
+/// Layers of an instance, recreated if the instance directory was partially removed
+const INSTANCE_LAYERS: &[&str] = &["layers/diff", "layers/diff.tmp", "layers/local"];
+
+/// An inconsistency between the instances, the mounts in the workspace and the machines
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InstanceIssue {
+    /// A machine whose root directory (or the instance it belongs to) no longer exists
+    StaleMachine { name: String, root: PathBuf },
+    /// An overlay mounted without an instance, or stacked on another one
+    OrphanedMount(PathBuf),
+    /// An instance whose layers were partially removed
+    MissingLayers {
+        instance: String,
+        layers: Vec<&'static str>,
+    },
+}
+
+impl std::fmt::Display for InstanceIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InstanceIssue::StaleMachine { name, root } => write!(
+                f,
+                "machine {} is registered, but its root {} is gone",
+                name,
+                root.display()
+            ),
+            InstanceIssue::OrphanedMount(path) => {
+                write!(f, "stale overlay mounted at {}", path.display())
             }
-            Err(err) => {
-                has_error = true;
-                lines.push(format!("{} {}", style("x").red(), style(err).red().bold()));
-                break;
+            InstanceIssue::MissingLayers { instance, layers } => {
+                write!(f, "instance {} is missing {}", instance, layers.join(", "))
             }
         }
     }
+}
 
-    for line in lines {
-        println!("{}", line);
+impl InstanceIssue {
+    /// Returns the description of the repair action
+    pub fn action(&self) -> String {
+        match self {
+            InstanceIssue::StaleMachine { name, .. } => {
+                format!("terminate and unregister machine {}", name)
+            }
+            InstanceIssue::OrphanedMount(path) => format!("unmount {}", path.display()),
+            InstanceIssue::MissingLayers { instance, layers } => {
+                format!("recreate {} of instance {}", layers.join(", "), instance)
+            }
+        }
     }
-    if has_error {
-        return Err(anyhow!("Test error detected"));
+
+    fn repair(&self) -> Result<()> {
+        match self {
+            InstanceIssue::StaleMachine { name, .. } => machine::unregister_machine(name),
+            InstanceIssue::OrphanedMount(path) => {
+                umount2(path, MntFlags::MNT_DETACH)?;
+                Ok(())
+            }
+            InstanceIssue::MissingLayers { instance, layers } => {
+                let inst = Path::new(CIEL_INST_DIR).join(instance);
+                for layer in layers {
+                    fs::create_dir_all(inst.join(layer))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Find the machines whose root directory is not a mounted instance any more
+fn find_stale_machines(
+    machines: &[(String, PathBuf)],
+    mounts: &[(PathBuf, String)],
+    instances: &[String],
+) -> Vec<InstanceIssue> {
+    machines
+        .iter()
+        .filter(|(_, root)| {
+            let name = root.file_name().unwrap_or_default().to_string_lossy();
+            !instances.iter().any(|x| *x == name)
+                || !mounts
+                    .iter()
+                    .any(|(mount, fs_type)| mount == root && fs_type == "overlay")
+        })
+        .map(|(name, root)| InstanceIssue::StaleMachine {
+            name: name.clone(),
+            root: root.clone(),
+        })
+        .collect()
+}
+
+/// Find the layers missing from the instance, the layers of an instance never mounted
+/// (and thus without the `layers` directory) are created on the first mount
+fn find_missing_layers(inst_dir: &Path, instance: &str, mounted: bool) -> Option<InstanceIssue> {
+    let inst = inst_dir.join(instance);
+    if !mounted && !inst.join("layers").is_dir() {
+        return None;
+    }
+    let layers = INSTANCE_LAYERS
+        .iter()
+        .filter(|x| !inst.join(x).is_dir())
+        .copied()
+        .collect::<Vec<_>>();
+    if layers.is_empty() {
+        return None;
+    }
+
+    Some(InstanceIssue::MissingLayers {
+        instance: instance.to_owned(),
+        layers,
+    })
+}
+
+/// Cross-reference the mounts, the instance directories and the machines registered in
+/// systemd-machined, returns the inconsistencies in the order they should be repaired
+pub fn detect_instance_issues() -> Result<Vec<InstanceIssue>> {
+    let workspace = std::env::current_dir()?;
+    let mounts = parse_mountinfo(&fs::read_to_string("/proc/self/mountinfo")?);
+    let mut instances = machine::list_instances_simple()?;
+    instances.sort();
+    // the machines are stopped before their root is unmounted
+    let mut issues = find_stale_machines(
+        &machine::list_workspace_machine_roots(&workspace)?,
+        &mounts,
+        &instances,
+    );
+    issues.extend(
+        find_stale_mounts(&mounts, &workspace)
+            .into_iter()
+            .map(InstanceIssue::OrphanedMount),
+    );
+    // the instances of ciel 2.x keep their layers elsewhere
+    if !is_legacy_workspace()? {
+        for instance in &instances {
+            let mounted = mounts
+                .iter()
+                .any(|(mount, fs_type)| fs_type == "overlay" && *mount == workspace.join(instance));
+            issues.extend(find_missing_layers(
+                Path::new(CIEL_INST_DIR),
+                instance,
+                mounted,
+            ));
+        }
+    }
+
+    Ok(issues)
+}
+
+/// Warn about the inconsistencies of the instances, if any
+pub fn warn_instance_issues() {
+    let issues = match detect_instance_issues() {
+        Ok(issues) => issues,
+        // the callers report the errors of listing the instances by themselves
+        Err(_) => return,
+    };
+    if issues.is_empty() {
+        return;
+    }
+    for issue in &issues {
+        warn!("Inconsistency: {}", issue);
+    }
+    warn!("Run `ciel doctor --repair-instances` to repair them.");
+}
+
+/// Repair the inconsistencies of the instances (see [detect_instance_issues]),
+/// each action is printed and confirmed unless `yes` is set
+pub fn repair_instances(yes: bool) -> Result<()> {
+    let issues = detect_instance_issues()?;
+    if issues.is_empty() {
+        info!("No inconsistencies found in the instances.");
+        return Ok(());
+    }
+    if !yes && !user_attended() {
+        for issue in &issues {
+            warn!("{}: would {}", issue, issue.action());
+        }
+        return Err(anyhow!(
+            "Not controlled by an user, please pass `--yes` to repair the instances."
+        ));
+    }
+    let theme = ColorfulTheme::default();
+    let mut failures = 0;
+    for issue in &issues {
+        info!("{}: {}...", issue, issue.action());
+        if !yes
+            && !Confirm::with_theme(&theme)
+                .with_prompt(format!("{}?", issue.action()))
+                .default(true)
+                .interact()?
+        {
+            info!("Skipped.");
+            continue;
+        }
+        if let Err(e) = issue.repair() {
+            error!("Unable to {}: {:#}", issue.action(), e);
+            failures += 1;
+        }
+    }
+    machine::clean_child_process();
+    if failures > 0 {
+        return Err(anyhow!("{} repair(s) failed", failures));
+    }
+
+    Ok(())
+}
+
+/// Carry out the diagnostic tests, the problems that can be safely fixed are repaired if `fix` is set
+pub fn run_diagnose(fix: bool) -> Result<()> {
+    let doctor = Doctor {
+        workspace: Path::new("./.ciel").is_dir(),
+        fix,
+    };
+    let mut checks = HOST_CHECKS.to_vec();
+    if doctor.workspace {
+        checks.extend(WORKSPACE_CHECKS);
+    }
+    let mut failures = 0;
+    for check in checks {
+        match check(&doctor) {
+            Pass(msg) => println!("{} {}", style("✓").green(), style(msg).green().bold()),
+            Warn(msg, hint) => {
+                println!("{} {}", style("!").yellow(), style(msg).yellow().bold());
+                println!("  {}", style(hint).dim());
+            }
+            Fail(msg, hint) => {
+                failures += 1;
+                println!("{} {}", style("x").red(), style(msg).red().bold());
+                println!("  {}", style(hint).dim());
+            }
+        }
+    }
+    if !doctor.workspace {
+        println!(
+            "{} {}",
+            style("!").yellow(),
+            style("Not in a workspace, the workspace checks are skipped").yellow()
+        );
+    }
+    if failures > 0 {
+        return Err(anyhow!("{} check(s) failed", failures));
     }
 
     Ok(())
 }
+
+#[test]
+fn test_parse_mountinfo() {
+    let mountinfo = "22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw\n\
+        120 22 0:45 / /work/ciel/main rw,relatime shared:60 - overlay overlay rw,lowerdir=a,upperdir=b,workdir=c\n\
+        121 120 0:46 / /work/ciel/main rw,relatime - overlay overlay rw\n\
+        122 22 0:47 / /work/ciel/old\\040one rw,relatime - overlay overlay rw\n\
+        123 22 0:48 / /work/ciel/OUTPUT rw - ext4 /dev/sda1 rw\n";
+    let mounts = parse_mountinfo(mountinfo);
+    assert_eq!(mounts.len(), 5);
+    assert_eq!(mounts[3].0, Path::new("/work/ciel/old one"));
+    assert_eq!(mounts[4].1, "ext4");
+    assert_eq!(
+        parse_systemd_version("systemd 254 (254.5-1)\n+PAM"),
+        Some(254)
+    );
+    assert_eq!(parse_systemd_version("systemd 219\n"), Some(219));
+    assert_eq!(parse_systemd_version(""), None);
+    // none of the instances exist in the current directory
+    assert_eq!(
+        find_stale_mounts(&mounts, Path::new("/work/ciel")),
+        vec![
+            PathBuf::from("/work/ciel/main"),
+            PathBuf::from("/work/ciel/main"),
+            PathBuf::from("/work/ciel/old one"),
+        ]
+    );
+}
+
+#[test]
+fn test_instance_issues() {
+    let mounts = vec![
+        (PathBuf::from("/work/ciel/main"), "overlay".to_owned()),
+        (PathBuf::from("/work/ciel/gone"), "overlay".to_owned()),
+    ];
+    let machines = vec![
+        ("main-1".to_owned(), PathBuf::from("/work/ciel/main")),
+        ("gone-1".to_owned(), PathBuf::from("/work/ciel/gone")),
+        ("test-1".to_owned(), PathBuf::from("/work/ciel/test")),
+    ];
+    let instances = vec!["main".to_owned(), "test".to_owned()];
+    assert_eq!(
+        find_stale_machines(&machines, &mounts, &instances),
+        vec![
+            InstanceIssue::StaleMachine {
+                name: "gone-1".to_owned(),
+                root: PathBuf::from("/work/ciel/gone"),
+            },
+            InstanceIssue::StaleMachine {
+                name: "test-1".to_owned(),
+                root: PathBuf::from("/work/ciel/test"),
+            },
+        ]
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    for path in ["new", "partial/layers/diff", "intact/layers"] {
+        fs::create_dir_all(dir.path().join(path)).unwrap();
+    }
+    for layer in INSTANCE_LAYERS {
+        fs::create_dir_all(dir.path().join("intact").join(layer)).unwrap();
+    }
+    assert_eq!(find_missing_layers(dir.path(), "new", false), None);
+    assert_eq!(find_missing_layers(dir.path(), "intact", true), None);
+    assert_eq!(
+        find_missing_layers(dir.path(), "new", true)
+            .unwrap()
+            .action(),
+        "recreate layers/diff, layers/diff.tmp, layers/local of instance new"
+    );
+    assert_eq!(
+        find_missing_layers(dir.path(), "partial", false),
+        Some(InstanceIssue::MissingLayers {
+            instance: "partial".to_owned(),
+            layers: vec!["layers/diff.tmp", "layers/local"],
+        })
+    );
+}
@@ -0,0 +1,222 @@
+//! Inspection of the dpkg and APT state in the root filesystems, so that the state left by an
+//! interrupted package manager is detected before it breaks the next operation
+
+use anyhow::Result;
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, flock, FcntlArg, FlockArg},
+};
+use std::{
+    fmt, fs,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+/// The lock files of dpkg and APT, relative to the root
+const LOCK_FILES: &[&str] = &[
+    "var/lib/dpkg/lock-frontend",
+    "var/lib/dpkg/lock",
+    "var/lib/apt/lists/lock",
+    "var/cache/apt/archives/lock",
+];
+/// The journal of dpkg, it is not empty if dpkg has been interrupted
+const DPKG_UPDATES_DIR: &str = "var/lib/dpkg/updates";
+const DPKG_STATUS_FILE: &str = "var/lib/dpkg/status";
+/// The package states left by an interrupted dpkg
+const INTERRUPTED_STATES: &[&str] = &[
+    "half-installed",
+    "half-configured",
+    "unpacked",
+    "triggers-awaited",
+    "triggers-pending",
+];
+/// The command finishing the interrupted dpkg operations
+pub const REPAIR_COMMAND: &str =
+    "DEBIAN_FRONTEND=noninteractive dpkg --force-confnew --configure -a";
+/// Exit code of ciel if the package manager state needs to be repaired (see [RepairNeeded])
+pub const REPAIR_NEEDED_EXIT_CODE: i32 = 3;
+
+/// The dpkg and APT state of a root filesystem
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DpkgState {
+    /// The journal of dpkg is not empty
+    pub interrupted: bool,
+    /// Packages left in the interrupted states
+    pub broken_packages: Vec<String>,
+    /// Lock files not held by any process
+    pub stale_locks: Vec<PathBuf>,
+    /// Lock files held by a running package manager
+    pub held_locks: Vec<PathBuf>,
+}
+
+impl DpkgState {
+    /// Returns whether `dpkg --configure -a` is needed
+    pub fn needs_repair(&self) -> bool {
+        self.interrupted || !self.broken_packages.is_empty()
+    }
+}
+
+/// Error returned if the package manager state needs to be repaired but the repair was declined,
+/// ciel exits with [REPAIR_NEEDED_EXIT_CODE]
+#[derive(Debug)]
+pub struct RepairNeeded {
+    pub root: PathBuf,
+    pub state: DpkgState,
+}
+
+impl fmt::Display for RepairNeeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The package manager in {} was interrupted",
+            self.root.display()
+        )?;
+        if !self.state.broken_packages.is_empty() {
+            write!(
+                f,
+                " ({} packages are not fully installed: {})",
+                self.state.broken_packages.len(),
+                self.state.broken_packages.join(", ")
+            )?;
+        }
+
+        write!(f, ", please repair it with `--auto-repair`.")
+    }
+}
+
+impl std::error::Error for RepairNeeded {}
+
+/// Returns the packages in the interrupted states in the dpkg status file
+fn broken_packages(status: &str) -> Vec<String> {
+    let mut packages = Vec::new();
+    for paragraph in status.split("\n\n") {
+        let mut name = None;
+        let mut state = None;
+        for line in paragraph.lines() {
+            if let Some(value) = line.strip_prefix("Package:") {
+                name = Some(value.trim());
+            } else if let Some(value) = line.strip_prefix("Status:") {
+                // want, error flag and state, e.g. `install ok half-configured`
+                state = value.split_whitespace().nth(2);
+            }
+        }
+        if let (Some(name), Some(state)) = (name, state) {
+            if INTERRUPTED_STATES.contains(&state) {
+                packages.push(name.to_owned());
+            }
+        }
+    }
+
+    packages
+}
+
+/// Returns whether the journal of dpkg has any pending entries (named with digits only)
+fn has_journal(dir: &Path) -> Result<bool> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if !name.is_empty() && name.bytes().all(|x| x.is_ascii_digit()) {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Returns whether any process holds the lock file, probing both the record locks
+/// (used by dpkg and APT) and the `flock` locks without taking them
+fn is_lock_held(path: &Path) -> Result<bool> {
+    let file = fs::File::open(path)?;
+    let fd = file.as_raw_fd();
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    fcntl(fd, FcntlArg::F_GETLK(&mut lock))?;
+    if lock.l_type != libc::F_UNLCK as libc::c_short {
+        return Ok(true);
+    }
+    match flock(fd, FlockArg::LockExclusiveNonblock) {
+        Ok(()) => {
+            flock(fd, FlockArg::Unlock)?;
+            Ok(false)
+        }
+        Err(Errno::EWOULDBLOCK) => Ok(true),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Inspect the dpkg and APT state in the root filesystem
+pub fn inspect(root: &Path) -> Result<DpkgState> {
+    let mut state = DpkgState {
+        interrupted: has_journal(&root.join(DPKG_UPDATES_DIR))?,
+        broken_packages: broken_packages(
+            &fs::read_to_string(root.join(DPKG_STATUS_FILE)).unwrap_or_default(),
+        ),
+        ..Default::default()
+    };
+    for lock in LOCK_FILES {
+        let path = root.join(lock);
+        if !path.is_file() {
+            continue;
+        }
+        if is_lock_held(&path)? {
+            state.held_locks.push(path);
+        } else {
+            state.stale_locks.push(path);
+        }
+    }
+
+    Ok(state)
+}
+
+/// Remove the lock files not held by any process, they are created again when needed
+pub fn clear_stale_locks(state: &DpkgState) -> Result<()> {
+    for lock in &state.stale_locks {
+        match fs::remove_file(lock) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_inspect() {
+    let root = tempfile::tempdir().unwrap();
+    assert_eq!(inspect(root.path()).unwrap(), DpkgState::default());
+
+    let dpkg = root.path().join("var/lib/dpkg");
+    fs::create_dir_all(dpkg.join("updates")).unwrap();
+    fs::write(dpkg.join("updates/tmp.i"), b"").unwrap();
+    fs::write(
+        dpkg.join("status"),
+        "Package: bash\nStatus: install ok installed\nVersion: 5.2\n\n\
+         Package: gcc\nStatus: install ok half-configured\nVersion: 13.2\n\n\
+         Package: glibc\nStatus: install ok unpacked\n",
+    )
+    .unwrap();
+    fs::write(dpkg.join("lock"), b"").unwrap();
+    let state = inspect(root.path()).unwrap();
+    assert!(!state.interrupted);
+    assert_eq!(state.broken_packages, vec!["gcc", "glibc"]);
+    assert_eq!(state.stale_locks, vec![dpkg.join("lock")]);
+    assert!(state.held_locks.is_empty());
+    assert!(state.needs_repair());
+
+    fs::write(dpkg.join("updates/0001"), b"").unwrap();
+    let held = fs::File::open(dpkg.join("lock")).unwrap();
+    flock(held.as_raw_fd(), FlockArg::LockExclusiveNonblock).unwrap();
+    let state = inspect(root.path()).unwrap();
+    assert!(state.interrupted);
+    assert_eq!(state.held_locks, vec![dpkg.join("lock")]);
+    drop(held);
+
+    clear_stale_locks(&inspect(root.path()).unwrap()).unwrap();
+    assert!(!dpkg.join("lock").exists());
+}
@@ -0,0 +1,288 @@
+//! Running the containers without systemd-machined (e.g. on the minimal hosts and in the
+//! containers with a limited systemd): systemd-nspawn is spawned with `--register=no`, and
+//! tracked by a PID file instead of the machine registration
+
+use anyhow::{anyhow, Result};
+use console::style;
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    thread::sleep,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    machine::{StopLevel, StopOptions},
+    warn,
+};
+
+/// Where the PID files of the containers are kept, cleared when the host reboots
+const PID_DIR: &str = "/run/ciel/machines";
+/// Exists in the container once systemd (PID 1) is listening for connections
+const SYSTEMD_READY_PATH: &str = "run/systemd/private";
+/// Environment of the commands run in the container, systemd-run starts them with the same
+const DEFAULT_ENV: &[&str] = &[
+    "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+    "HOME=/root",
+];
+/// Changes to the working directory (`$0`) before running the command
+const WORKDIR_WRAPPER: &[&str] = &["/bin/sh", "-c", "cd -- \"$0\" && exec \"$@\""];
+/// Interval of checking whether the container is stopped
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A container spawned without systemd-machined, as recorded in its PID file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Container {
+    /// PID of systemd-nspawn
+    pub pid: i32,
+    /// Root directory of the container
+    pub root: PathBuf,
+    /// When the container was spawned, in seconds since the epoch
+    pub since: u64,
+}
+
+fn pid_file(ns_name: &str) -> PathBuf {
+    Path::new(PID_DIR).join(format!("{}.pid", ns_name))
+}
+
+/// Parse the PID file: the PID of systemd-nspawn, the root directory and the spawn time,
+/// one per line
+fn parse_pid_file(content: &str) -> Option<Container> {
+    let mut lines = content.lines();
+
+    Some(Container {
+        pid: lines.next()?.trim().parse().ok()?,
+        root: PathBuf::from(lines.next()?),
+        since: lines.next()?.trim().parse().ok()?,
+    })
+}
+
+/// Record the systemd-nspawn process of the container just spawned
+pub fn record(ns_name: &str, pid: u32, root: &Path) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    fs::create_dir_all(PID_DIR)?;
+    fs::write(
+        pid_file(ns_name),
+        format!("{}\n{}\n{}\n", pid, root.display(), now),
+    )?;
+
+    Ok(())
+}
+
+/// Returns if the process is a running systemd-nspawn, and not another process reusing the PID.
+/// systemd-nspawn stays a zombie until the invocation that spawned it reaps it
+fn is_nspawn(pid: i32) -> bool {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+    match (stat.find('('), stat.rfind(')')) {
+        (Some(start), Some(end)) if start < end => {
+            &stat[start + 1..end] == "systemd-nspawn"
+                && !stat[end + 1..].trim_start().starts_with('Z')
+        }
+        _ => false,
+    }
+}
+
+/// Returns if the process is the init process of its PID namespace, but not of the host
+fn is_namespace_init(pid: i32) -> bool {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+    status
+        .lines()
+        .find_map(|x| x.strip_prefix("NSpid:"))
+        .map_or(false, |x| {
+            let pids = x.split_whitespace().collect::<Vec<_>>();
+            pids.len() > 1 && pids.last() == Some(&"1")
+        })
+}
+
+/// Returns the container if it is running, the stale PID file is removed
+pub fn find(ns_name: &str) -> Option<Container> {
+    let path = pid_file(ns_name);
+    let container = parse_pid_file(&fs::read_to_string(&path).ok()?)?;
+    if is_nspawn(container.pid) {
+        return Some(container);
+    }
+    fs::remove_file(&path).ok();
+
+    None
+}
+
+/// Returns the names and the root directories of the running containers whose root directory
+/// is in the workspace
+pub fn list(workspace: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let entries = match fs::read_dir(PID_DIR) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut containers = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension() != Some(OsStr::new("pid")) {
+            continue;
+        }
+        let ns_name = match path.file_stem().and_then(|x| x.to_str()) {
+            Some(ns_name) => ns_name.to_owned(),
+            None => continue,
+        };
+        if let Some(container) = find(&ns_name) {
+            if container.root.starts_with(workspace) {
+                containers.push((ns_name, container.root));
+            }
+        }
+    }
+
+    Ok(containers)
+}
+
+impl Container {
+    /// Returns the PID (on the host) of the init process in the container
+    pub fn leader(&self) -> Option<i32> {
+        fs::read_dir("/proc")
+            .ok()?
+            .flatten()
+            .filter_map(|x| x.file_name().to_str()?.parse::<i32>().ok())
+            .find(|pid| {
+                fs::read_link(format!("/proc/{}/root", pid)).ok().as_ref() == Some(&self.root)
+                    && is_namespace_init(*pid)
+            })
+    }
+
+    /// Returns if systemd in the container is up
+    pub fn is_booted(&self) -> bool {
+        self.leader().map_or(false, |leader| {
+            Path::new(&format!("/proc/{}/root", leader))
+                .join(SYSTEMD_READY_PATH)
+                .exists()
+        })
+    }
+
+    /// Returns the root directory of the container as seen from the host, `None` if
+    /// the container is not up yet
+    pub fn root_on_host(&self) -> Option<PathBuf> {
+        Some(PathBuf::from(format!("/proc/{}/root", self.leader()?)))
+    }
+
+    /// Wait for systemd-nspawn to exit, returns false if it is still running after the timeout
+    fn wait_for_exit(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            crate::machine::clean_child_process();
+            if !is_nspawn(self.pid) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            sleep(STOP_POLL_INTERVAL);
+        }
+    }
+}
+
+/// Returns the running container, or an error if it is not running
+fn running(ns_name: &str) -> Result<Container> {
+    find(ns_name).ok_or_else(|| anyhow!("Container {} is not running.", ns_name))
+}
+
+/// Returns the command running the program in the namespaces of the container with `nsenter`,
+/// in place of `systemd-run -M`. The environment is reset to `env` in addition to the defaults
+pub fn command(
+    ns_name: &str,
+    env: &[String],
+    workdir: Option<&str>,
+    args: &[&OsStr],
+) -> Result<Command> {
+    let leader = running(ns_name)?
+        .leader()
+        .ok_or_else(|| anyhow!("Container {} is not up yet.", ns_name))?;
+    let mut command = Command::new("nsenter");
+    command
+        .args(&["--target", &leader.to_string()])
+        .args(&[
+            "--mount", "--uts", "--ipc", "--net", "--pid", "--root", "--wd", "--",
+        ])
+        .args(&["/usr/bin/env", "-i"])
+        .args(DEFAULT_ENV);
+    if let Ok(term) = std::env::var("TERM") {
+        command.arg(format!("TERM={}", term));
+    }
+    command.args(env);
+    if let Some(workdir) = workdir {
+        command.args(WORKDIR_WRAPPER).arg(workdir);
+    }
+    command.args(args);
+
+    Ok(command)
+}
+
+/// Send the signal to the process, the process may have gone away in the meantime
+fn signal(pid: i32, signal: libc::c_int) {
+    unsafe { libc::kill(pid, signal) };
+}
+
+/// Stop the container like [crate::machine::stop_machine]: powering it off through systemd in
+/// the container, then SIGTERM to systemd-nspawn and at last SIGKILL to all the processes
+/// in the container. Returns the step that stopped the container
+pub fn stop(ns_name: &str, options: &StopOptions) -> Result<StopLevel> {
+    let container = running(ns_name)?;
+    let grace_period = options.grace_period;
+    let leader = container.leader();
+    let stopped = |level| -> Result<StopLevel> {
+        fs::remove_file(pid_file(ns_name)).ok();
+        Ok(level)
+    };
+    if !options.force {
+        match leader {
+            Some(leader) => {
+                // systemd powers off the system on SIGRTMIN+4
+                signal(leader, libc::SIGRTMIN() + 4);
+                if container.wait_for_exit(grace_period) {
+                    return stopped(StopLevel::Poweroff);
+                }
+                warn!("{}: the container did not power off in time...", ns_name);
+            }
+            None => warn!("{}: the container is not up yet...", ns_name),
+        }
+        warn!("{}: sending SIGTERM to the container...", ns_name);
+        signal(container.pid, libc::SIGTERM);
+        if container.wait_for_exit(grace_period) {
+            return stopped(StopLevel::Terminate);
+        }
+    }
+    warn!("{}: killing the container with SIGKILL...", ns_name);
+    // killing the init process of the PID namespace kills all the processes in it
+    if let Some(leader) = container.leader() {
+        signal(leader, libc::SIGKILL);
+    }
+    signal(container.pid, libc::SIGKILL);
+    if container.wait_for_exit(grace_period) {
+        return stopped(StopLevel::Kill);
+    }
+
+    Err(anyhow!("Failed to kill the container! This may indicate a problem with your I/O, see dmesg or journalctl for more details."))
+}
+
+/// Report that the containers are run without systemd-machined, and what does not work then
+pub fn report(e: &anyhow::Error) {
+    warn!("systemd-machined is unavailable: {:#}", e);
+    warn!(
+        "The instances are run without registering them (see {}), resource limits, mounting into the running instances and the machine status are not available.",
+        style("ciel doctor").cyan()
+    );
+}
+
+#[test]
+fn test_parse_pid_file() {
+    assert_eq!(
+        parse_pid_file("1234\n/var/lib/ciel/main\n1700000000\n"),
+        Some(Container {
+            pid: 1234,
+            root: PathBuf::from("/var/lib/ciel/main"),
+            since: 1700000000,
+        })
+    );
+    assert_eq!(parse_pid_file("1234\n/var/lib/ciel/main\n"), None);
+    assert_eq!(parse_pid_file("pid\n/var/lib/ciel/main\n0\n"), None);
+    assert_eq!(parse_pid_file(""), None);
+}
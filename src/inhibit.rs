@@ -0,0 +1,42 @@
+//! Keeping the host from sleeping (and shutting down right away) during the long operations
+//! that leave the workspace in a mess if interrupted, with the inhibitor locks of systemd-logind
+
+use anyhow::Result;
+use console::style;
+use zbus::{blocking::Connection, zvariant::OwnedFd};
+
+use crate::{dbus_login1::ManagerProxyBlocking, debug};
+
+/// The inhibitor locks taken (what is inhibited and how), see systemd-inhibit(1)
+const LOCKS: &[(&str, &str)] = &[("sleep", "block"), ("shutdown", "delay")];
+
+/// Inhibitor locks held until this is dropped (or ciel exits)
+pub struct Inhibitor {
+    _locks: Vec<OwnedFd>,
+}
+
+fn take_locks(operation: &str) -> Result<Vec<OwnedFd>> {
+    let conn = Connection::system()?;
+    let manager = ManagerProxyBlocking::new(&conn)?;
+    let why = format!("{} ({})", operation, std::env::current_dir()?.display());
+
+    LOCKS
+        .iter()
+        .map(|(what, mode)| Ok(manager.inhibit(what, "ciel", &why, mode)?))
+        .collect()
+}
+
+/// Take the inhibitor locks for the operation (e.g. `Building packages`), unless `disabled`
+/// is set. Nothing is taken if systemd-logind is unavailable
+pub fn inhibit(operation: &str, disabled: bool) -> Option<Inhibitor> {
+    if disabled {
+        return None;
+    }
+    match take_locks(operation) {
+        Ok(locks) => Some(Inhibitor { _locks: locks }),
+        Err(e) => {
+            debug!("Unable to keep the host from sleeping: {:#}", e);
+            None
+        }
+    }
+}
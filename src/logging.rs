@@ -22,6 +22,17 @@ macro_rules! error {
     };
 }
 
+/// Only printed if `CIEL_DEBUG` is set
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        if std::env::var_os("CIEL_DEBUG").is_some() {
+            eprint!("{} ", style("debug:").dim().bold());
+            eprintln!($($arg)+);
+        }
+    };
+}
+
 #[inline]
 pub fn color_bool(pred: bool) -> &'static str {
     if pred {
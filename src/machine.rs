@@ -1,51 +1,234 @@
 //! This module contains systemd machined related APIs
 
-use crate::common::{is_legacy_workspace, CIEL_INST_DIR};
+use crate::command_log::{tee, CommandLog};
+use crate::common::{
+    disk_usage, format_duration, global_options, is_legacy_workspace, CIEL_INST_DIR,
+};
+use crate::config::MountSpec;
 use crate::dbus_machine1::ManagerProxyBlocking;
 use crate::dbus_machine1_machine::MachineProxyBlocking;
-use crate::overlayfs::is_mounted;
-use crate::{info, overlayfs::LayerManager, warn};
+use crate::fallback;
+use crate::metadata::{read_adhoc_mounts, read_machine_name, read_metadata, InstanceMetadata};
+use crate::overlayfs::{self, is_mounted};
+use crate::{info, overlayfs::LayerManager, warn, workspace};
 use adler32::adler32;
 use anyhow::{anyhow, Result};
 use console::style;
+use indicatif::HumanBytes;
+use lazy_static::lazy_static;
 use libc::{c_char, ftok, waitpid, WNOHANG};
 use libsystemd_sys::bus::{sd_bus_flush_close_unref, sd_bus_open_system_machine};
+use serde::{Deserialize, Serialize};
 use std::{
+    convert::TryFrom,
     ffi::{CString, OsStr},
+    fmt,
+    io::{Read, Seek, SeekFrom, Write},
     mem::MaybeUninit,
-    process::Command,
+    process::{Command, ExitStatus},
+    time::Instant,
 };
 use std::{fs, time::Duration};
-use std::{os::unix::ffi::OsStrExt, process::Child};
-use std::{path::Path, process::Stdio, thread::sleep};
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::Stdio,
+    thread::sleep,
+};
+use std::{
+    os::unix::{ffi::OsStrExt, process::ExitStatusExt},
+    process::Child,
+    sync::{Arc, Mutex},
+};
 use zbus::blocking::Connection;
 
+/// How long to wait for the container to boot if not configured
+pub const DEFAULT_BOOT_TIMEOUT: Duration = Duration::from_secs(20);
+/// Where the console output of the container is saved, in the instance directory
+const CONSOLE_LOG: &str = "console.log";
+/// Number of the lines of the console output shown when the container fails to boot
+const CONSOLE_TAIL_LINES: usize = 50;
+/// Where systemd-machined keeps the state of each registered machine
+const MACHINED_STATE_DIR: &str = "/run/systemd/machines";
+/// Reports the death of the command by a signal in the exit code, see [wrap_command]
+const EXIT_STATUS_WRAPPER: &[&str] = &["/bin/sh", "-c", "\"$@\"; exit $?", "sh"];
+/// How long to wait for the network in the container if not configured (`network-timeout`)
+pub const DEFAULT_NETWORK_TIMEOUT: Duration = Duration::from_secs(30);
+/// Interval of checking whether the network in the container is ready
+const NETWORK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait at each step of stopping the container if not configured
+pub const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Interval of checking whether the container is stopped
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Interval of checking for the new console output of the container, see [follow_console]
+const CONSOLE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// How many times to try reconnecting to the system bus when the connection is dropped
+const RECONNECT_ATTEMPTS: u32 = 5;
+/// Interval between the attempts to reconnect to the system bus
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
 const DEFAULT_NSPAWN_OPTIONS: &[&str] = &[
-    "-qb",
+    "--quiet",
     "--capability=CAP_IPC_LOCK",
     "--system-call-filter=swapcontext",
 ];
 
+lazy_static! {
+    /// The connection to systemd-machined, established on the first use and replaced when it is
+    /// re-established (see [call_machined]). `None` if systemd-machined is unavailable, the
+    /// containers are then run without it (see [crate::fallback])
+    static ref MACHINED: Mutex<Option<Connection>> = Mutex::new(match probe_machined() {
+        Ok(conn) => Some(conn),
+        Err(e) => {
+            fallback::report(&e);
+            None
+        }
+    });
+}
+
+/// Connect to the system bus, and make sure that systemd-machined answers on it
+fn probe_machined() -> Result<Connection> {
+    let conn = Connection::system()?;
+    ManagerProxyBlocking::new(&conn)?.list_machines()?;
+
+    Ok(conn)
+}
+
+/// Returns the connection to systemd-machined, `None` if it is unavailable (reported once)
+pub fn machined() -> Option<Connection> {
+    MACHINED.lock().ok()?.clone()
+}
+
+/// Returns the connection to systemd-machined, or an error if the operation cannot be done
+/// without it
+fn require_machined(operation: &str) -> Result<Connection> {
+    machined().ok_or_else(|| {
+        anyhow!(
+            "{} requires systemd-machined, which is unavailable (see `ciel doctor`).",
+            operation
+        )
+    })
+}
+
+/// Returns if the error means that the connection to the bus is dropped (e.g. the bus is
+/// restarted), rather than that the call failed
+fn is_disconnected(e: &anyhow::Error) -> bool {
+    fn is_bus_error(e: &zbus::Error) -> bool {
+        match e {
+            zbus::Error::InputOutput(_) => true,
+            zbus::Error::MethodError(name, _, _) => {
+                name.as_ref() == "org.freedesktop.DBus.Error.Disconnected"
+            }
+            zbus::Error::FDO(e) => is_fdo_error(e),
+            _ => false,
+        }
+    }
+    fn is_fdo_error(e: &zbus::fdo::Error) -> bool {
+        match e {
+            zbus::fdo::Error::ZBus(e) => is_bus_error(e),
+            zbus::fdo::Error::Disconnected(_) => true,
+            _ => false,
+        }
+    }
+    // the standard interfaces (e.g. `org.freedesktop.DBus`) return the `fdo` errors
+    match e.downcast_ref::<zbus::fdo::Error>() {
+        Some(e) => is_fdo_error(e),
+        None => e.downcast_ref::<zbus::Error>().map_or(false, is_bus_error),
+    }
+}
+
+/// Run the D-Bus calls, and if the connection is dropped, connect again with `connect` and run
+/// them again, up to [RECONNECT_ATTEMPTS] times. The calls should look up the objects (e.g. the
+/// path of the machine) by themselves, so that they are resolved again after reconnecting
+fn with_reconnect<T, C, F>(conn: &mut Connection, connect: C, call: F) -> Result<T>
+where
+    C: Fn() -> Result<Connection>,
+    F: Fn(&Connection) -> Result<T>,
+{
+    let mut error = match call(conn) {
+        Err(e) if is_disconnected(&e) => e,
+        result => return result,
+    };
+    warn!(
+        "Lost the connection to the system bus ({:#}), reconnecting...",
+        error
+    );
+    for _ in 0..RECONNECT_ATTEMPTS {
+        // give the bus some time to come back
+        sleep(RECONNECT_INTERVAL);
+        error = match connect() {
+            Ok(new) => {
+                *conn = new;
+                match call(conn) {
+                    Err(e) if is_disconnected(&e) => e,
+                    result => return result,
+                }
+            }
+            Err(e) => e,
+        };
+    }
+
+    Err(error.context("Unable to reconnect to the system bus"))
+}
+
+/// Run the D-Bus calls to systemd-machined, reconnecting if the connection is dropped (see
+/// [with_reconnect]). The connection is replaced for the later calls as well
+fn call_machined<T, F: Fn(&Connection) -> Result<T>>(conn: &Connection, call: F) -> Result<T> {
+    let mut conn = conn.clone();
+    let result = with_reconnect(&mut conn, probe_machined, call);
+    if let Ok(mut machined) = MACHINED.lock() {
+        if machined.is_some() {
+            *machined = Some(conn);
+        }
+    }
+
+    result
+}
+
 /// Instance status information
 #[derive(Debug)]
 pub struct CielInstance {
-    name: String,
+    pub name: String,
     // namespace name (in the form of `$name-$id`)
     pub ns_name: String,
     pub mounted: bool,
-    running: bool,
+    pub running: bool,
     pub started: bool,
-    booted: Option<bool>,
+    pub booted: Option<bool>,
+    /// When the machine was registered, in seconds since the epoch
+    pub boot_time: Option<u64>,
+}
+
+/// Instance information printed by `ciel list --json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstanceInfo {
+    pub name: String,
+    /// Name of the machine in systemd-machined
+    pub machine_name: String,
+    pub mounted: bool,
+    pub running: bool,
+    /// `None` if the instance is not started
+    pub booted: Option<bool>,
+    pub volatile: bool,
+    /// Mounted in the read-only mode, see `ciel boot --read-only`
+    pub read_only: bool,
+    /// Disk space used by the changes in the instance, `None` if skipped (`--fast`)
+    pub disk_usage: Option<u64>,
+    /// Time when the instance was started, in seconds since the epoch
+    pub boot_time: Option<u64>,
+    #[serde(flatten)]
+    pub metadata: InstanceMetadata,
 }
 
 /// Used for getting the instance name from Ciel 1/2
 fn legacy_container_name(path: &Path) -> Result<String> {
     let key_id;
-    let current_dir = std::env::current_dir()?;
+    let workspace = path
+        .parent()
+        .ok_or_else(|| anyhow!("Invalid container path: {:?}", path))?;
     let name = path
         .file_name()
         .ok_or_else(|| anyhow!("Invalid container path: {:?}", path))?;
-    let mut path = current_dir.as_os_str().as_bytes().to_owned();
+    let mut path = workspace.as_os_str().as_bytes().to_owned();
     path.push(0); // add trailing null terminator
     unsafe {
         // unsafe because of the `ftok` invocation
@@ -63,8 +246,8 @@ fn legacy_container_name(path: &Path) -> Result<String> {
     ))
 }
 
-/// Used for getting the instance name from Ciel 3+
-fn new_container_name(path: &Path) -> Result<String> {
+/// Used for getting the instance name from Ciel 3 before the workspace ID is introduced
+fn path_container_name(path: &Path) -> Result<String> {
     // New container name is calculated using the following formula:
     // $name-adler32($PWD)
     let hash = adler32(path.as_os_str().as_bytes())?;
@@ -99,80 +282,452 @@ fn try_open_container_bus(ns_name: &str) -> Result<()> {
     Err(anyhow!("Could not open container bus"))
 }
 
-fn wait_for_container(child: &mut Child, ns_name: &str, retry: usize) -> Result<()> {
-    for i in 0..retry {
+/// Error returned when the container fails to boot, along with the diagnostics
+#[derive(Debug)]
+pub enum BootError {
+    /// systemd-nspawn exited before the container was up
+    Exited {
+        ns_name: String,
+        status: ExitStatus,
+        diagnostics: String,
+    },
+    /// systemd-nspawn is running, but systemd in the container never became reachable
+    Timeout {
+        ns_name: String,
+        timeout: Duration,
+        diagnostics: String,
+    },
+}
+
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootError::Exited {
+                ns_name,
+                status,
+                diagnostics,
+            } => write!(
+                f,
+                "{}: nspawn exited too early ({})\n{}",
+                ns_name, status, diagnostics
+            ),
+            BootError::Timeout {
+                ns_name,
+                timeout,
+                diagnostics,
+            } => write!(
+                f,
+                "{}: the container did not boot in {} seconds, it is left running for inspection (stop it with `ciel stop`)\n{}",
+                ns_name,
+                timeout.as_secs(),
+                diagnostics
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BootError {}
+
+/// Returns the timeout of booting the containers
+pub fn boot_timeout() -> Duration {
+    global_options()
+        .boot_timeout
+        .or_else(|| {
+            crate::config::read_config()
+                .ok()
+                .and_then(|c| c.boot_timeout)
+        })
+        .unwrap_or(DEFAULT_BOOT_TIMEOUT)
+}
+
+fn console_log_path(instance: &str) -> PathBuf {
+    Path::new(CIEL_INST_DIR).join(instance).join(CONSOLE_LOG)
+}
+
+/// Returns the last lines of the content
+fn tail_lines(content: &str, count: usize) -> &str {
+    let content = content.trim_end();
+    match content.rmatch_indices('\n').nth(count.saturating_sub(1)) {
+        Some((pos, _)) => &content[pos + 1..],
+        None => content,
+    }
+}
+
+/// Collect the console output of the container and the hints about the common causes
+fn boot_diagnostics(ns_name: &str, instance: &str, console: &str) -> String {
+    let mut diagnostics = String::new();
+    let console = tail_lines(console, CONSOLE_TAIL_LINES);
+    if console.is_empty() {
+        diagnostics.push_str("The container printed nothing.\n");
+    } else {
+        diagnostics.push_str(&format!(
+            "Last lines of the console output ({}):\n{}\n",
+            console_log_path(instance).display(),
+            console
+        ));
+    }
+    let rootfs = std::env::current_dir()
+        .map(|x| x.join(instance))
+        .unwrap_or_else(|_| Path::new(instance).to_owned());
+    if !rootfs.join("usr/lib/systemd/systemd").exists() {
+        diagnostics.push_str(
+            "Hint: systemd is missing from the container (usr/lib/systemd/systemd), please make sure the OS is loaded properly (`ciel load-os`).\n",
+        );
+    }
+    if console.contains("already exists") || console.contains("File exists") {
+        diagnostics.push_str(&format!(
+            "Hint: the machine name {} may be used by a stale registration, try `machinectl terminate {}`.\n",
+            ns_name, ns_name
+        ));
+    }
+    if console.contains("Failed to parse") || console.contains("unrecognized option") {
+        diagnostics.push_str(
+            "Hint: an option of systemd-nspawn seems invalid, please check nspawn-extra-options (`ciel config get nspawn-extra-options`).\n",
+        );
+    }
+
+    diagnostics
+}
+
+/// Wait until `is_up` tells that systemd in the container is up
+fn wait_for_container<F: Fn() -> bool>(
+    child: &mut Child,
+    ns_name: &str,
+    instance: &str,
+    timeout: Duration,
+    is_up: F,
+) -> Result<()> {
+    let start = Instant::now();
+    let read_console = || fs::read_to_string(console_log_path(instance)).unwrap_or_default();
+    for i in 1.. {
         let exited = child.try_wait()?;
         if let Some(status) = exited {
-            return Err(anyhow!("nspawn exited too early! (Status: {})", status));
+            return Err(BootError::Exited {
+                ns_name: ns_name.to_owned(),
+                status,
+                diagnostics: boot_diagnostics(ns_name, instance, &read_console()),
+            }
+            .into());
         }
         // why this is used: because PTY spawning can happen before the systemd in the container
         // is fully initialized. To spawn a new process in the container, we need the systemd
         // in the container to be fully initialized and listening for connections.
         // One way to resolve this issue is to test the connection to the container's systemd.
-        if try_open_container_bus(ns_name).is_ok() {
+        if is_up() {
             return Ok(());
         }
+        let remaining = match timeout.checked_sub(start.elapsed()) {
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => break,
+        };
         // wait for a while, sleep time follows a natural-logarithm distribution
-        sleep(Duration::from_secs_f32(((i + 1) as f32).ln().ceil()));
+        sleep(Duration::from_secs_f32((i as f32).ln().ceil()).min(remaining));
+    }
+
+    Err(BootError::Timeout {
+        ns_name: ns_name.to_owned(),
+        timeout,
+        diagnostics: boot_diagnostics(ns_name, instance, &read_console()),
+    }
+    .into())
+}
+
+/// Print the last lines of the console output of the container, and then the output appended
+/// to it as it comes (like `tail -f`) until the container exits. Only the saved output is read,
+/// so nothing in the container is affected when this is interrupted
+pub fn follow_console(instance: &str, ns_name: &str) -> Result<()> {
+    let mut console = fs::File::open(console_log_path(instance))?;
+    let mut content = Vec::new();
+    console.read_to_end(&mut content)?;
+    let mut stdout = std::io::stdout();
+    let tail = tail_lines(&String::from_utf8_lossy(&content), CONSOLE_TAIL_LINES).to_owned();
+    if !tail.is_empty() {
+        writeln!(stdout, "{}", tail)?;
+    }
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = console.read(&mut buf)?;
+        if n > 0 {
+            stdout.write_all(&buf[..n])?;
+            stdout.flush()?;
+            continue;
+        }
+        if !inspect_instance(instance, ns_name)?.started {
+            info!("{}: container exited.", ns_name);
+            return Ok(());
+        }
+        // the log is truncated when the container is started again
+        if console.metadata()?.len() < console.stream_position()? {
+            console.seek(SeekFrom::Start(0))?;
+        }
+        sleep(CONSOLE_POLL_INTERVAL);
     }
+}
+
+/// Open an interactive shell in the container with `machinectl shell` (OpenMachineShell of
+/// systemd-machined), which detaches when ^] is pressed three times, or with `nsenter` if
+/// systemd-machined is unavailable. Leaving the shell does not affect the other processes in
+/// the container. Returns the exit code of the shell
+pub fn attach_shell(ns_name: &str) -> Result<i32> {
+    let mut command = if machined().is_some() {
+        let mut command = Command::new("machinectl");
+        command.args(&["shell", &format!("root@{}", ns_name)]);
+        command
+    } else {
+        info!("Press Ctrl-D or type `exit` to detach.");
+        fallback::command(
+            ns_name,
+            &[],
+            None,
+            &[OsStr::new("/bin/bash"), OsStr::new("-l")],
+        )?
+    };
+
+    Ok(exit_code(command.status()?))
+}
 
-    Err(anyhow!("Timeout waiting for container {}", ns_name))
+/// Returns how long to wait for the network in the containers, zero if the wait is disabled
+pub fn network_timeout() -> Duration {
+    crate::config::read_config()
+        .ok()
+        .and_then(|c| c.network_timeout)
+        .unwrap_or(DEFAULT_NETWORK_TIMEOUT)
+}
+
+/// Returns the command running the program in the container with the output discarded,
+/// to check something in the container by the exit status
+fn probe_command(ns_name: &str, args: &[&str]) -> Result<Command> {
+    let args = args.iter().map(OsStr::new).collect::<Vec<_>>();
+    let mut command = if machined().is_some() {
+        let mut command = Command::new("systemd-run");
+        command
+            .args(&["-M", ns_name, "-q", "--pipe", "--"])
+            .args(args);
+        command
+    } else {
+        fallback::command(ns_name, &[], None, &args)?
+    };
+    command.stdin(Stdio::null()).stdout(Stdio::null());
+
+    Ok(command)
+}
+
+/// Returns if the host name can be resolved in the container
+fn resolves_in_container(ns_name: &str, host: &str) -> Result<bool> {
+    let status = probe_command(ns_name, &["/usr/bin/getent", "hosts", host])?
+        .stderr(Stdio::null())
+        .status()?;
+
+    Ok(status.success())
+}
+
+/// Wait until the host name can be resolved in the booted container. Right after the boot,
+/// systemd-networkd and systemd-resolved in the container may not be up yet, and the commands
+/// accessing the network would fail with confusing resolution errors
+pub fn wait_for_network(ns_name: &str, host: &str, timeout: Duration) -> Result<()> {
+    let start = Instant::now();
+    for i in 0.. {
+        if resolves_in_container(ns_name, host)? {
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            break;
+        }
+        if i == 0 {
+            info!("Waiting for the network in the container...");
+        }
+        sleep(NETWORK_POLL_INTERVAL);
+    }
+
+    Err(anyhow!(
+        "The network is not ready after {}: unable to resolve {} in the container (`getent hosts {}`). Check the network of the instance, or set network-timeout to 0 to skip this check.",
+        format_duration(timeout),
+        host,
+        host
+    ))
 }
 
 /// Setting up cross-namespace bind-mounts for the container using systemd
-fn setup_bind_mounts(ns_name: &str, mounts: &[(String, &str)]) -> Result<()> {
-    let conn = Connection::system()?;
-    let proxy = ManagerProxyBlocking::new(&conn)?;
-    for mount in mounts {
-        fs::create_dir_all(&mount.0)?;
-        let source_path = fs::canonicalize(&mount.0)?;
-        proxy.bind_mount_machine(
+/// Bind mount the host path into the running container
+pub fn bind_mount(ns_name: &str, mount: &MountSpec) -> Result<()> {
+    let conn = require_machined("Mounting into the running instance")?;
+    call_machined(&conn, |conn| {
+        ManagerProxyBlocking::new(conn)?.bind_mount_machine(
             ns_name,
-            &source_path.to_string_lossy(),
-            mount.1,
-            false,
+            &mount.host.to_string_lossy(),
+            &mount.container.to_string_lossy(),
+            mount.read_only,
             true,
         )?;
+
+        Ok(())
+    })
+}
+
+/// Copy the file or the directory on the host into the running container
+pub fn copy_to_machine(ns_name: &str, source: &Path, destination: &Path) -> Result<()> {
+    let conn = require_machined("Copying into the running instance")?;
+    call_machined(&conn, |conn| {
+        ManagerProxyBlocking::new(conn)?.copy_to_machine(
+            ns_name,
+            &source.to_string_lossy(),
+            &destination.to_string_lossy(),
+        )?;
+
+        Ok(())
+    })
+}
+
+/// Copy the file or the directory in the running container to the host
+pub fn copy_from_machine(ns_name: &str, source: &Path, destination: &Path) -> Result<()> {
+    let conn = require_machined("Copying from the running instance")?;
+    call_machined(&conn, |conn| {
+        ManagerProxyBlocking::new(conn)?.copy_from_machine(
+            ns_name,
+            &source.to_string_lossy(),
+            &destination.to_string_lossy(),
+        )?;
+
+        Ok(())
+    })
+}
+
+fn setup_bind_mounts(ns_name: &str, mounts: &[(String, String)]) -> Result<()> {
+    let conn = require_machined("Mounting into the running instance")?;
+    for mount in mounts {
+        fs::create_dir_all(&mount.0)?;
+        let source_path = fs::canonicalize(&mount.0)?;
+        call_machined(&conn, |conn| {
+            ManagerProxyBlocking::new(conn)?.bind_mount_machine(
+                ns_name,
+                &source_path.to_string_lossy(),
+                &mount.1,
+                false,
+                true,
+            )?;
+
+            Ok(())
+        })?;
     }
 
     Ok(())
 }
 
-/// Get the container name (ns_name) of the instance
-pub fn get_container_ns_name<P: AsRef<Path>>(path: P, legacy: bool) -> Result<String> {
-    let current_dir = std::env::current_dir()?;
-    let path = current_dir.join(path);
+/// Get the container name (ns_name) of the instance in the current workspace
+pub fn get_container_ns_name(instance: &str, legacy: bool) -> Result<String> {
+    get_container_ns_name_at(&std::env::current_dir()?, instance, legacy)
+}
+
+/// Get the container name (ns_name) of the instance in the workspace at `root` (e.g. the old
+/// location of the workspace): the name it is started as if recorded, the name derived from the
+/// path if the instance is still running as started by an older version of ciel, or else the
+/// name it is going to be started as
+pub fn get_container_ns_name_at(root: &Path, instance: &str, legacy: bool) -> Result<String> {
+    let path = root.join(instance);
     if legacy {
         warn!("You are working in a legacy workspace. Use `ciel init --upgrade` to upgrade.");
         warn!("Please make sure to save your work before upgrading.");
         return legacy_container_name(&path);
     }
+    if let Some(name) = read_machine_name(instance) {
+        return Ok(name);
+    }
+    // started by an older version of ciel, the machine name was derived from the path
+    let name = path_container_name(&path)?;
+    if is_machine_registered(&name) {
+        return Ok(name);
+    }
+
+    workspace_machine_name(instance)
+}
+
+/// Returns the name of the machine the instance is started as: `$name-$id`, where `$id` is the
+/// ID of the workspace, so that the instances with the same name in different workspaces do not
+/// collide in systemd-machined
+pub fn workspace_machine_name(instance: &str) -> Result<String> {
+    Ok(format!("{}-{}", instance, workspace::workspace_id()?))
+}
+
+/// Returns if the machine is registered in systemd-machined, without connecting to it
+fn is_machine_registered(ns_name: &str) -> bool {
+    Path::new(MACHINED_STATE_DIR).join(ns_name).exists()
+}
+
+/// Returns the options of systemd-nspawn to run the container without systemd-machined: the
+/// machine is not registered, so the unit properties (the resource limits) cannot be applied,
+/// and the bind mounts are set up by systemd-nspawn
+fn unregistered_options(
+    ns_name: &str,
+    extra_options: &[String],
+    mounts: &[(String, String)],
+) -> Result<Vec<String>> {
+    let mut options = vec!["--register=no".to_owned(), "--keep-unit".to_owned()];
+    let (properties, extra_options): (Vec<_>, Vec<_>) = extra_options
+        .iter()
+        .cloned()
+        .partition(|x| x.starts_with("--property="));
+    if !properties.is_empty() {
+        warn!(
+            "{}: the resource limits are not applied without systemd-machined.",
+            ns_name
+        );
+    }
+    options.extend(extra_options);
+    for (source, target) in mounts {
+        fs::create_dir_all(source)?;
+        let source = fs::canonicalize(source)?;
+        options.push(format!("--bind={}:{}", source.display(), target));
+    }
 
-    new_container_name(&path)
+    Ok(options)
 }
 
-/// Spawn a new container using nspawn
+/// Spawn a new container using nspawn, the console output is saved in the instance directory.
+/// Returns as soon as nspawn is spawned if `wait` is false, the bind mounts are not set up then
+/// (unless systemd-machined is unavailable, then they are set up by nspawn)
 pub fn spawn_container<P: AsRef<Path>>(
     ns_name: &str,
     path: P,
     extra_options: &[String],
-    mounts: &[(String, &str)],
+    mounts: &[(String, String)],
+    wait: bool,
 ) -> Result<()> {
     let path = path
         .as_ref()
         .to_str()
         .ok_or_else(|| anyhow!("Path contains invalid Unicode characters."))?;
+    let registered = machined().is_some();
+    let options = if registered {
+        extra_options.to_vec()
+    } else {
+        unregistered_options(ns_name, extra_options, mounts)?
+    };
+    let console = fs::File::create(console_log_path(path))?;
     let mut child = Command::new("systemd-nspawn")
+        .arg("--boot")
         .args(DEFAULT_NSPAWN_OPTIONS)
-        .args(extra_options)
+        .args(options)
         .args(&["-D", path, "-M", ns_name, "--"])
         .env("SYSTEMD_NSPAWN_TMPFS_TMP", "0")
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
+        .stdout(console.try_clone()?)
+        .stderr(console)
         .spawn()?;
+    if !registered {
+        fallback::record(ns_name, child.id(), &fs::canonicalize(path)?)?;
+    }
+    if !wait {
+        info!("{}: container spawned.", ns_name);
+        return Ok(());
+    }
 
     info!("{}: waiting for container to start...", ns_name);
-    wait_for_container(&mut child, ns_name, 10)?;
+    if !registered {
+        let is_up = || fallback::find(ns_name).map_or(false, |x| x.is_booted());
+        return wait_for_container(&mut child, ns_name, path, boot_timeout(), is_up);
+    }
+    // a new connection is opened for each check, so the wait survives the restart of the bus
+    let is_up = || try_open_container_bus(ns_name).is_ok();
+    wait_for_container(&mut child, ns_name, path, boot_timeout(), is_up)?;
     info!("{}: setting up mounts...", ns_name);
     if let Err(e) = setup_bind_mounts(ns_name, mounts) {
         warn!("Failed to setup bind mounts: {:?}", e);
@@ -181,21 +736,256 @@ pub fn spawn_container<P: AsRef<Path>>(
     Ok(())
 }
 
-/// Execute a command in the container
-pub fn execute_container_command<S: AsRef<OsStr>>(ns_name: &str, args: &[S]) -> Result<i32> {
-    let mut extra_options = Vec::new();
+/// Whether a pseudo terminal is allocated for the command run in the container
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtyMode {
+    /// Allocate one if both stdin and stdout are terminals
+    Auto,
+    Always,
+    /// Connect the standard streams directly, so that stdout and stderr stay separated
+    Never,
+}
+
+impl Default for TtyMode {
+    fn default() -> Self {
+        TtyMode::Auto
+    }
+}
+
+impl TtyMode {
+    fn use_tty(self) -> bool {
+        match self {
+            TtyMode::Auto => unsafe { libc::isatty(0) == 1 && libc::isatty(1) == 1 },
+            TtyMode::Always => true,
+            TtyMode::Never => false,
+        }
+    }
+}
+
+/// Options of the command run in the container
+#[derive(Debug, Default, Clone)]
+pub struct CommandOptions {
+    pub tty: TtyMode,
+    /// Environment variables of the command, override the ones in the configuration
+    pub env: Vec<(String, String)>,
+    /// Working directory of the command in the container, the root directory if not set
+    pub workdir: Option<String>,
+    /// Where to save the output of the command, see [crate::command_log]
+    pub log: Option<PathBuf>,
+    /// Bind mounts for this command only (`--mount`), added to the running instance if needed
+    pub mounts: Vec<MountSpec>,
+    /// Run the command without booting the instance if it is not started (`--quick`)
+    pub quick: bool,
+}
+
+/// Returns the exit code of the process, or 128 + the signal number (like the shells do)
+/// if it is killed by a signal
+pub fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| status.signal().map(|x| 128 + x))
+        .unwrap_or(127)
+}
+
+/// Wrap the command so that its death by a signal is reported as 128 + the signal number,
+/// since systemd-run does not tell it apart from failing to run the command
+fn wrap_command<S: AsRef<OsStr>>(args: &[S]) -> Vec<&OsStr> {
+    EXIT_STATUS_WRAPPER
+        .iter()
+        .map(OsStr::new)
+        .chain(args.iter().map(|x| x.as_ref()))
+        .collect()
+}
+
+/// Execute a command in the container with the specified environment variables
+pub fn execute_container_command<S: AsRef<OsStr>>(
+    ns_name: &str,
+    args: &[S],
+    env: &[(String, String)],
+    options: &CommandOptions,
+) -> Result<i32> {
+    let command = container_command(ns_name, args, env, options)?;
+    let log = match &options.log {
+        Some(path) => CommandLog::open(path)?,
+        None => return Ok(exit_code(command.spawn()?.wait()?)),
+    };
+    let (exit_code, _) = execute_teed(command, Some(log), false)?;
+
+    Ok(exit_code)
+}
+
+/// Execute the command in the container like [execute_container_command], the output is
+/// shown and also captured. Returns the exit code and the output
+pub fn execute_container_command_captured<S: AsRef<OsStr>>(
+    ns_name: &str,
+    args: &[S],
+    env: &[(String, String)],
+    options: &CommandOptions,
+) -> Result<(i32, String)> {
+    let log = options.log.as_deref().map(CommandLog::open).transpose()?;
+    let (exit_code, output) =
+        execute_teed(container_command(ns_name, args, env, options)?, log, true)?;
+
+    Ok((exit_code, String::from_utf8_lossy(&output).into_owned()))
+}
+
+/// Run the command with the output shown and saved in the log, stdout is also captured
+/// if `capture` is set. Returns the exit code and the captured output
+fn execute_teed(
+    mut command: Command,
+    log: Option<CommandLog>,
+    capture: bool,
+) -> Result<(i32, Vec<u8>)> {
+    let log = log.map(Arc::new);
+    command.stdout(Stdio::piped());
+    if log.is_some() {
+        command.stderr(Stdio::piped());
+    }
+    let mut child = command.spawn()?;
+    let stderr = child.stderr.take().map(|stderr| {
+        let log = log.clone();
+        std::thread::spawn(move || tee(stderr, std::io::stderr(), log.as_deref(), false))
+    });
+    let output = tee(
+        child.stdout.take().unwrap(),
+        std::io::stdout(),
+        log.as_deref(),
+        capture,
+    );
+    let exit_code = exit_code(child.wait()?);
+    if let Some(stderr) = stderr {
+        stderr
+            .join()
+            .map_err(|_| anyhow!("Unable to forward the error output"))??;
+    }
+
+    Ok((exit_code, output?))
+}
+
+/// Returns if the path is a directory in the container
+pub fn is_dir_in_container(ns_name: &str, path: &str) -> Result<bool> {
+    let status = probe_command(ns_name, &["/usr/bin/test", "-d", path])?.status()?;
+
+    Ok(status.success())
+}
+
+/// Returns the environment variables with the ones in `overrides` taking precedence
+fn merge_environment<'a>(
+    env: &'a [(String, String)],
+    overrides: &'a [(String, String)],
+) -> Vec<&'a (String, String)> {
+    let mut merged = env
+        .iter()
+        .filter(|(name, _)| !overrides.iter().any(|(x, _)| x == name))
+        .collect::<Vec<_>>();
+    // the last one wins if a variable is given more than once
+    for (i, var) in overrides.iter().enumerate() {
+        if !overrides[i + 1..].iter().any(|(x, _)| *x == var.0) {
+            merged.push(var);
+        }
+    }
+
+    merged
+}
+
+/// Returns the `systemd-run` command executing the command in the container, or the `nsenter`
+/// one if systemd-machined is unavailable
+fn container_command<S: AsRef<OsStr>>(
+    ns_name: &str,
+    args: &[S],
+    env: &[(String, String)],
+    options: &CommandOptions,
+) -> Result<Command> {
+    let mut variables = Vec::new();
     if std::env::var("CIEL_STAGE2").is_ok() {
-        extra_options.push("--setenv=ABSTAGE2=1".to_string());
+        variables.push("ABSTAGE2=1".to_string());
+    }
+    for (name, value) in merge_environment(env, &options.env) {
+        variables.push(format!("{}={}", name, value));
+    }
+    if machined().is_none() {
+        return fallback::command(
+            ns_name,
+            &variables,
+            options.workdir.as_deref(),
+            &wrap_command(args),
+        );
+    }
+    let mut extra_options = variables
+        .iter()
+        .map(|x| format!("--setenv={}", x))
+        .collect::<Vec<_>>();
+    if let Some(workdir) = &options.workdir {
+        extra_options.push(format!("--working-directory={}", workdir));
     }
     // TODO: maybe replace with systemd API cross-namespace call?
-    let exit_code = Command::new("systemd-run")
+    // without a terminal, the standard streams are passed to the command as they are
+    let console = if options.tty.use_tty() {
+        "--pty"
+    } else {
+        "--pipe"
+    };
+    let mut command = Command::new("systemd-run");
+    command
         .args(extra_options)
-        .args(&["-M", ns_name, "-qt", "--"])
-        .args(args)
-        .spawn()?
-        .wait()?
-        .code()
-        .unwrap_or(127);
+        .args(&["-M", ns_name, "-q", console, "--"])
+        .args(wrap_command(args));
+
+    Ok(command)
+}
+
+/// Returns the `systemd-nspawn` command running the command in the root directory as PID 2
+/// (without booting the system and registering the machine)
+fn quick_command<S: AsRef<OsStr>>(
+    root: &Path,
+    nspawn_options: &[String],
+    args: &[S],
+    env: &[(String, String)],
+    options: &CommandOptions,
+) -> Command {
+    let mut extra_options = nspawn_options.to_vec();
+    if std::env::var("CIEL_STAGE2").is_ok() {
+        extra_options.push("--setenv=ABSTAGE2=1".to_string());
+    }
+    for (name, value) in merge_environment(env, &options.env) {
+        extra_options.push(format!("--setenv={}={}", name, value));
+    }
+    if let Some(workdir) = &options.workdir {
+        extra_options.push(format!("--chdir={}", workdir));
+    }
+    let console = if options.tty.use_tty() && options.log.is_none() {
+        "--console=interactive"
+    } else {
+        "--console=pipe"
+    };
+    let mut command = Command::new("systemd-nspawn");
+    command
+        .args(DEFAULT_NSPAWN_OPTIONS)
+        .args(&["--as-pid2", "--register=no", console])
+        .args(extra_options)
+        .arg("-D")
+        .arg(root)
+        .arg("--")
+        .args(wrap_command(args))
+        .env("SYSTEMD_NSPAWN_TMPFS_TMP", "0");
+
+    command
+}
+
+/// Execute the command in the root directory without booting it, see [quick_command]
+pub fn execute_quick_command<S: AsRef<OsStr>>(
+    root: &Path,
+    nspawn_options: &[String],
+    args: &[S],
+    env: &[(String, String)],
+    options: &CommandOptions,
+) -> Result<i32> {
+    let command = quick_command(root, nspawn_options, args, env, options);
+    let log = match &options.log {
+        Some(path) => CommandLog::open(path)?,
+        None => return Ok(exit_code(command.spawn()?.wait()?)),
+    };
+    let (exit_code, _) = execute_teed(command, Some(log), false)?;
 
     Ok(exit_code)
 }
@@ -206,13 +996,6 @@ pub(crate) fn clean_child_process() {
     unsafe { waitpid(-1, &mut status, WNOHANG) };
 }
 
-fn kill_container(proxy: &MachineProxyBlocking) -> Result<()> {
-    proxy.kill("all", libc::SIGKILL)?;
-    proxy.terminate()?;
-
-    Ok(())
-}
-
 fn execute_poweroff(ns_name: &str) -> Result<()> {
     // TODO: maybe replace with systemd API cross-namespace call?
     let exit_code = Command::new("systemd-run")
@@ -229,19 +1012,31 @@ fn execute_poweroff(ns_name: &str) -> Result<()> {
     }
 }
 
-fn wait_for_poweroff(proxy: &MachineProxyBlocking) -> Result<()> {
-    let ns_name = proxy.name()?;
-    let conn = proxy.connection();
-    let proxy = ManagerProxyBlocking::new(conn)?;
-    for _ in 0..10 {
-        if proxy.get_machine(&ns_name).is_err() {
-            // machine object no longer exists
-            return Ok(());
+/// Wait for the machine to go away, returns false if it is still registered after the timeout.
+/// Each check goes through [call_machined], so that the wait goes on after reconnecting if the
+/// connection is dropped in the meantime
+fn wait_for_poweroff(conn: &Connection, ns_name: &str, timeout: Duration) -> Result<bool> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if !is_machine_alive(conn, ns_name)? {
+            return Ok(true);
+        }
+        if Instant::now() >= deadline {
+            return Ok(false);
         }
-        sleep(Duration::from_secs(1));
+        sleep(STOP_POLL_INTERVAL);
     }
+}
 
-    Err(anyhow!("shutdown failed"))
+/// Returns if the machine is still registered in systemd-machined
+fn is_machine_alive(conn: &Connection, ns_name: &str) -> Result<bool> {
+    call_machined(conn, |conn| {
+        match ManagerProxyBlocking::new(conn)?.get_machine(ns_name) {
+            Ok(_) => Ok(true),
+            Err(e) if is_no_such_machine(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    })
 }
 
 fn is_booted(proxy: &MachineProxyBlocking) -> Result<bool> {
@@ -264,26 +1059,106 @@ fn is_booted(proxy: &MachineProxyBlocking) -> Result<bool> {
     Ok(false)
 }
 
-fn terminate_container(proxy: &MachineProxyBlocking) -> Result<()> {
-    let ns_name = proxy.name()?;
-    let _ = proxy.receive_state_changed();
-    if execute_poweroff(&ns_name).is_ok() {
-        // Successfully passed poweroff command to the container, wait for it
-        if wait_for_poweroff(proxy).is_ok() {
-            return Ok(());
+/// How far [stop_machine] had to go to stop the machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StopLevel {
+    /// Powered off cleanly
+    Poweroff,
+    /// The leader of the container is terminated with SIGTERM
+    Terminate,
+    /// All the processes in the container are killed with SIGKILL
+    Kill,
+    /// The registration of the machine is terminated forcefully
+    Unregister,
+}
+
+impl fmt::Display for StopLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StopLevel::Poweroff => "powered off",
+            StopLevel::Terminate => "terminated with SIGTERM",
+            StopLevel::Kill => "killed with SIGKILL",
+            StopLevel::Unregister => "unregistered forcefully",
+        })
+    }
+}
+
+/// Options of [stop_machine]
+#[derive(Debug, Clone, Copy)]
+pub struct StopOptions {
+    /// Skip the poweroff and SIGTERM, and kill the container right away
+    pub force: bool,
+    /// How long to wait at each step before going further
+    pub grace_period: Duration,
+}
+
+impl Default for StopOptions {
+    fn default() -> Self {
+        Self {
+            force: false,
+            grace_period: crate::config::read_config()
+                .ok()
+                .and_then(|c| c.stop_timeout)
+                .unwrap_or(DEFAULT_STOP_TIMEOUT),
         }
-        // still did not poweroff?
-        warn!("Container did not respond to the poweroff command correctly...");
-        warn!("Killing the container by sending SIGKILL...");
-        // fall back to nuke
     }
+}
 
-    // violently kill everything inside the container
-    kill_container(proxy)?;
-    proxy.terminate().ok();
-    // status re-check, in the event of I/O problems, the container may still be running (stuck)
-    if wait_for_poweroff(proxy).is_ok() {
-        return Ok(());
+/// Stop the machine, escalating from a clean poweroff to SIGTERM, SIGKILL and at last
+/// terminating the registration if the machine is still there after the grace period.
+/// Returns the step that stopped the machine
+pub fn stop_machine(ns_name: &str, options: &StopOptions) -> Result<StopLevel> {
+    let conn = match machined() {
+        Some(conn) => conn,
+        None => return fallback::stop(ns_name, options),
+    };
+    call_machined(&conn, |conn| {
+        ManagerProxyBlocking::new(conn)?.get_machine(ns_name)?;
+
+        Ok(())
+    })?;
+    // the machine may have gone away in the meantime, the errors are ignored
+    let kill = |who: &str, signal: i32| {
+        call_machined(&conn, |conn| {
+            Ok(ManagerProxyBlocking::new(conn)?.kill_machine(ns_name, who, signal)?)
+        })
+        .ok()
+    };
+    let grace_period = options.grace_period;
+    if !options.force {
+        match execute_poweroff(ns_name) {
+            Ok(()) if wait_for_poweroff(&conn, ns_name, grace_period)? => {
+                return Ok(StopLevel::Poweroff)
+            }
+            Ok(()) => warn!("{}: the container did not power off in time...", ns_name),
+            Err(e) => warn!("{}: unable to power off the container: {}", ns_name, e),
+        }
+        warn!("{}: sending SIGTERM to the container...", ns_name);
+        kill("leader", libc::SIGTERM);
+        if wait_for_poweroff(&conn, ns_name, grace_period)? {
+            return Ok(StopLevel::Terminate);
+        }
+    }
+    warn!("{}: killing the container with SIGKILL...", ns_name);
+    kill("all", libc::SIGKILL);
+    if wait_for_poweroff(&conn, ns_name, grace_period)? {
+        return Ok(StopLevel::Kill);
+    }
+    warn!("{}: terminating the machine registration...", ns_name);
+    call_machined(&conn, |conn| {
+        Ok(ManagerProxyBlocking::new(conn)?.terminate_machine(ns_name)?)
+    })
+    .ok();
+    if wait_for_poweroff(&conn, ns_name, grace_period)? {
+        return Ok(StopLevel::Unregister);
+    }
+    call_machined(&conn, |conn| {
+        Ok(ManagerProxyBlocking::new(conn)?.unregister_machine(ns_name)?)
+    })
+    .ok();
+    // in the event of I/O problems, the container may still be running (stuck)
+    if wait_for_poweroff(&conn, ns_name, STOP_POLL_INTERVAL)? {
+        return Ok(StopLevel::Unregister);
     }
 
     Err(anyhow!("Failed to kill the container! This may indicate a problem with your I/O, see dmesg or journalctl for more details."))
@@ -291,12 +1166,186 @@ fn terminate_container(proxy: &MachineProxyBlocking) -> Result<()> {
 
 /// Terminate the container (Use graceful method if possible)
 pub fn terminate_container_by_name(ns_name: &str) -> Result<()> {
-    let conn = Connection::system()?;
-    let proxy = ManagerProxyBlocking::new(&conn)?;
-    let path = proxy.get_machine(ns_name)?;
-    let proxy = MachineProxyBlocking::builder(&conn).path(&path)?.build()?;
+    stop_machine(ns_name, &StopOptions::default())?;
+
+    Ok(())
+}
+
+/// Returns the names and the root directories of the machines registered in systemd-machined
+/// (or the containers run without it) whose root directory is in the workspace, whether or
+/// not ciel knows about them
+pub fn list_workspace_machine_roots(workspace: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let conn = match machined() {
+        Some(conn) => conn,
+        None => return fallback::list(workspace),
+    };
+    call_machined(&conn, |conn| {
+        let proxy = ManagerProxyBlocking::new(conn)?;
+        let mut machines = Vec::new();
+        for (name, _, _, path) in proxy.list_machines()? {
+            let machine = MachineProxyBlocking::builder(conn).path(&path)?.build()?;
+            // the machine may have gone away in the meantime
+            if let Ok(root) = machine.root_directory() {
+                if Path::new(&root).starts_with(workspace) {
+                    machines.push((name, PathBuf::from(root)));
+                }
+            }
+        }
+
+        Ok(machines)
+    })
+}
+
+/// Returns the names of the machines registered in systemd-machined whose root directory is in
+/// the workspace, whether or not ciel knows about them
+pub fn list_workspace_machines(workspace: &Path) -> Result<Vec<String>> {
+    Ok(list_workspace_machine_roots(workspace)?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect())
+}
+
+/// Returns the systemd unit (the scope) of the machine
+fn machine_unit(conn: &Connection, ns_name: &str) -> Result<String> {
+    call_machined(conn, |conn| {
+        let path = ManagerProxyBlocking::new(conn)?.get_machine(ns_name)?;
+        let proxy = MachineProxyBlocking::builder(conn).path(&path)?.build()?;
+
+        Ok(proxy.unit()?)
+    })
+}
+
+/// Set the properties (e.g. the resource limits) on the unit of the running machine,
+/// until it is stopped
+pub fn set_machine_properties(ns_name: &str, properties: &[String]) -> Result<()> {
+    let unit = machine_unit(&require_machined("Changing the resource limits")?, ns_name)?;
+    let output = Command::new("systemctl")
+        .args(&["set-property", "--runtime", &unit])
+        .args(properties)
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "systemctl set-property failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Format the resource limits in the output of `systemctl show`, the absent limits are omitted
+fn format_limits(show: &str) -> String {
+    let mut limits = Vec::new();
+    for line in show.lines() {
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) if !value.is_empty() && value != "infinity" => (key, value),
+            _ => continue,
+        };
+        let limit = match key {
+            "MemoryMax" => value
+                .parse::<u64>()
+                .ok()
+                .map(|x| format!("memory={}", HumanBytes(x))),
+            // the quota is shown as the CPU time per second, e.g. `1.500s` or `500ms`
+            "CPUQuotaPerSecUSec" => parse_duration_usec(value)
+                .map(|x| format!("cpu={}%", (x as f64 / 10_000.0).round())),
+            "TasksMax" => Some(format!("tasks={}", value)),
+            _ => None,
+        };
+        limits.extend(limit);
+    }
+
+    limits.join(",")
+}
+
+/// Parse a time span printed by systemd (only the units used for the CPU quota)
+fn parse_duration_usec(value: &str) -> Option<u64> {
+    let (number, scale) = if let Some(x) = value.strip_suffix("us") {
+        (x, 1.0)
+    } else if let Some(x) = value.strip_suffix("ms") {
+        (x, 1_000.0)
+    } else {
+        (value.strip_suffix('s')?, 1_000_000.0)
+    };
+
+    Some((number.parse::<f64>().ok()? * scale).round() as u64)
+}
+
+/// Returns the resource limits in effect on the running machine, e.g. `memory=16.00 GiB,cpu=800%`
+pub fn machine_limits(conn: &Connection, ns_name: &str) -> Result<String> {
+    let unit = machine_unit(conn, ns_name)?;
+    let output = Command::new("systemctl")
+        .args(&[
+            "show",
+            &unit,
+            "-p",
+            "MemoryMax",
+            "-p",
+            "CPUQuotaPerSecUSec",
+            "-p",
+            "TasksMax",
+        ])
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "systemctl show failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(format_limits(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Convert the addresses returned by machined (address family and bytes), the link-local
+/// IPv6 addresses are omitted
+fn parse_addresses(addresses: &[(i32, Vec<u8>)]) -> Vec<IpAddr> {
+    addresses
+        .iter()
+        .filter_map(|(family, bytes)| match *family {
+            libc::AF_INET => <[u8; 4]>::try_from(bytes.as_slice()).ok().map(IpAddr::from),
+            libc::AF_INET6 => <[u8; 16]>::try_from(bytes.as_slice())
+                .ok()
+                .map(IpAddr::from),
+            _ => None,
+        })
+        .filter(|x| match x {
+            IpAddr::V6(x) => (x.segments()[0] & 0xffc0) != 0xfe80,
+            IpAddr::V4(_) => true,
+        })
+        .collect()
+}
+
+/// Returns the addresses of the running machine (only available with a private network)
+pub fn machine_addresses(conn: &Connection, ns_name: &str) -> Result<Vec<IpAddr>> {
+    call_machined(conn, |conn| {
+        let proxy = ManagerProxyBlocking::new(conn)?;
+
+        Ok(parse_addresses(&proxy.get_machine_addresses(ns_name)?))
+    })
+}
+
+/// Stop the machine (gracefully if possible) and make sure that it is unregistered from
+/// systemd-machined, does nothing if the machine is not registered (or not running if
+/// systemd-machined is unavailable)
+pub fn unregister_machine(ns_name: &str) -> Result<()> {
+    let conn = match machined() {
+        Some(conn) => conn,
+        None if fallback::find(ns_name).is_some() => return terminate_container_by_name(ns_name),
+        None => return Ok(()),
+    };
+    if !is_machine_alive(&conn, ns_name)? {
+        return Ok(());
+    }
+    if let Err(e) = terminate_container_by_name(ns_name) {
+        warn!("{}: {}, unregistering it forcefully...", ns_name, e);
+    }
+    if is_machine_alive(&conn, ns_name)? {
+        call_machined(&conn, |conn| {
+            Ok(ManagerProxyBlocking::new(conn)?.terminate_machine(ns_name)?)
+        })?;
+    }
 
-    terminate_container(&proxy)
+    Ok(())
 }
 
 /// Mount the filesystem layers using the specified layer manager and the instance name
@@ -310,35 +1359,72 @@ pub fn mount_layers(manager: &mut dyn LayerManager, name: &str) -> Result<()> {
     Ok(())
 }
 
+/// Returns if the error means that the machine is not registered
+fn is_no_such_machine(e: &zbus::Error) -> bool {
+    matches!(e, zbus::Error::MethodError(name, _, _) if name.as_ref() == "org.freedesktop.machine1.NoSuchMachine")
+}
+
 /// Get the information of the container specified
 pub fn inspect_instance(name: &str, ns_name: &str) -> Result<CielInstance> {
+    inspect_instance_with(machined().as_ref(), name, ns_name)
+}
+
+/// Same as [inspect_instance], using the connection to systemd-machined if available
+fn inspect_instance_with(
+    conn: Option<&Connection>,
+    name: &str,
+    ns_name: &str,
+) -> Result<CielInstance> {
     let full_path = std::env::current_dir()?.join(name);
     let mounted = is_mounted(&full_path, OsStr::new("overlay"))?;
-    let conn = Connection::system()?;
-    let proxy = ManagerProxyBlocking::new(&conn)?;
-    let path = proxy.get_machine(ns_name);
-    if let Err(e) = path {
-        if let zbus::Error::MethodError(ref err_name, _, _) = e {
-            if err_name.as_ref() == "org.freedesktop.machine1.NoSuchMachine" {
-                return Ok(CielInstance {
-                    name: name.to_owned(),
-                    ns_name: ns_name.to_owned(),
-                    started: false,
-                    running: false,
-                    mounted,
-                    booted: None,
-                });
-            }
+    let conn = match conn {
+        Some(conn) => conn,
+        None => {
+            let container = fallback::find(ns_name);
+            return Ok(CielInstance {
+                name: name.to_owned(),
+                ns_name: ns_name.to_owned(),
+                started: container.is_some(),
+                running: container.as_ref().map_or(false, |x| x.is_booted()),
+                mounted,
+                // always spawned with `--boot`
+                booted: container.as_ref().map(|_| true),
+                boot_time: container.map(|x| x.since),
+            });
         }
-        // For all other errors, just return the original error object
-        return Err(anyhow!("{}", e));
-    }
-    let path = path?;
-    let proxy = MachineProxyBlocking::builder(&conn).path(&path)?.build()?;
-    let state = proxy.state()?;
-    // Sometimes the system in the container is misconfigured, so we also accept "degraded" status as "running"
-    let running = state == "running" || state == "degraded";
-    let booted = is_booted(&proxy)?;
+    };
+    // `None` if the machine is not registered
+    let machine = call_machined(conn, |conn| {
+        let path = match ManagerProxyBlocking::new(conn)?.get_machine(ns_name) {
+            Ok(path) => path,
+            Err(e) if is_no_such_machine(&e) => return Ok(None),
+            // For all other errors, just return the original error object
+            Err(e) => return Err(e.into()),
+        };
+        let proxy = MachineProxyBlocking::builder(conn).path(&path)?.build()?;
+        let state = proxy.state()?;
+        // Sometimes the system in the container is misconfigured, so we also accept "degraded" status as "running"
+        let running = state == "running" || state == "degraded";
+        let booted = is_booted(&proxy)?;
+        // in microseconds
+        let boot_time = proxy.timestamp().ok().map(|x| x / 1_000_000);
+
+        Ok(Some((running, booted, boot_time)))
+    })?;
+    let (running, booted, boot_time) = match machine {
+        Some(machine) => machine,
+        None => {
+            return Ok(CielInstance {
+                name: name.to_owned(),
+                ns_name: ns_name.to_owned(),
+                started: false,
+                running: false,
+                mounted,
+                booted: None,
+                boot_time: None,
+            })
+        }
+    };
 
     Ok(CielInstance {
         name: name.to_owned(),
@@ -347,18 +1433,26 @@ pub fn inspect_instance(name: &str, ns_name: &str) -> Result<CielInstance> {
         running,
         mounted,
         booted: Some(booted),
+        boot_time,
     })
 }
 
 /// List all the instances under the current directory
 pub fn list_instances() -> Result<Vec<CielInstance>> {
+    list_instances_with(machined().as_ref())
+}
+
+/// Same as [list_instances], all the instances are inspected through the same connection
+fn list_instances_with(conn: Option<&Connection>) -> Result<Vec<CielInstance>> {
     let legacy = is_legacy_workspace()?;
     let mut instances: Vec<CielInstance> = Vec::new();
     for entry in (fs::read_dir(CIEL_INST_DIR)?).flatten() {
         if entry.file_type().map(|e| e.is_dir())? {
-            instances.push(inspect_instance(
-                &entry.file_name().to_string_lossy(),
-                &get_container_ns_name(&entry.file_name(), legacy)?,
+            let name = entry.file_name().to_string_lossy().into_owned();
+            instances.push(inspect_instance_with(
+                conn,
+                &name,
+                &get_container_ns_name(&name, legacy)?,
             )?);
         }
     }
@@ -366,6 +1460,101 @@ pub fn list_instances() -> Result<Vec<CielInstance>> {
     Ok(instances)
 }
 
+/// Runtime status of an instance reported by systemd-machined (what `machinectl status` shows)
+#[derive(Debug, Clone, Serialize)]
+pub struct InstanceStatus {
+    pub name: String,
+    /// Name of the machine in systemd-machined
+    pub machine_name: String,
+    pub mounted: bool,
+    /// Whether the machine is registered
+    pub started: bool,
+    pub booted: bool,
+    /// State of the machine (`opening`, `running`, `degraded` or `closing`)
+    pub state: Option<String>,
+    /// PID (on the host) of the init process of the container
+    pub leader: Option<u32>,
+    /// When the machine was registered, in seconds since the epoch
+    pub since: Option<u64>,
+    /// Seconds since the machine was registered
+    pub uptime: Option<u64>,
+    pub addresses: Vec<IpAddr>,
+    /// The systemd unit (scope) of the machine
+    pub unit: Option<String>,
+}
+
+/// Returns the status of the instance, an instance not started is not booted
+pub fn instance_status(name: &str) -> Result<InstanceStatus> {
+    if !Path::new(CIEL_INST_DIR).join(name).is_dir() {
+        return Err(anyhow!("Instance `{}` does not exist.", name));
+    }
+    let ns_name = get_container_ns_name(name, is_legacy_workspace()?)?;
+
+    instance_status_with(machined().as_ref(), name, &ns_name)
+}
+
+fn instance_status_with(
+    conn: Option<&Connection>,
+    name: &str,
+    ns_name: &str,
+) -> Result<InstanceStatus> {
+    let mounted = is_mounted(&std::env::current_dir()?.join(name), OsStr::new("overlay"))?;
+    let mut status = InstanceStatus {
+        name: name.to_owned(),
+        machine_name: ns_name.to_owned(),
+        mounted,
+        started: false,
+        booted: false,
+        state: None,
+        leader: None,
+        since: None,
+        uptime: None,
+        addresses: Vec::new(),
+        unit: None,
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let conn = match conn {
+        Some(conn) => conn,
+        None => {
+            if let Some(container) = fallback::find(ns_name) {
+                status.started = true;
+                status.booted = container.is_booted();
+                status.state = Some(if status.booted { "running" } else { "opening" }.to_owned());
+                status.leader = container.leader().map(|x| x as u32);
+                status.since = Some(container.since);
+                status.uptime = Some(now.saturating_sub(container.since));
+            }
+            return Ok(status);
+        }
+    };
+    call_machined(conn, |conn| {
+        let mut status = status.clone();
+        let manager = ManagerProxyBlocking::new(conn)?;
+        let path = match manager.get_machine(ns_name) {
+            Ok(path) => path,
+            Err(e) if is_no_such_machine(&e) => return Ok(status),
+            Err(e) => return Err(e.into()),
+        };
+        let proxy = MachineProxyBlocking::builder(conn).path(&path)?.build()?;
+        status.started = true;
+        status.booted = is_booted(&proxy).unwrap_or(false);
+        status.state = proxy.state().ok();
+        status.leader = proxy.leader().ok();
+        // in microseconds
+        status.since = proxy.timestamp().ok().map(|x| x / 1_000_000);
+        status.uptime = status.since.map(|x| now.saturating_sub(x));
+        status.addresses = manager
+            .get_machine_addresses(ns_name)
+            .map(|x| parse_addresses(&x))
+            .unwrap_or_default();
+        status.unit = proxy.unit().ok();
+
+        Ok(status)
+    })
+}
+
 /// List all the instances under the current directory, returns only instance names
 pub fn list_instances_simple() -> Result<Vec<String>> {
     let mut instances: Vec<String> = Vec::new();
@@ -378,16 +1567,80 @@ pub fn list_instances_simple() -> Result<Vec<String>> {
     Ok(instances)
 }
 
-/// Print all the instances under the current directory
-pub fn print_instances() -> Result<()> {
+/// Collect the information of the instances (with all the labels) under the current directory,
+/// the disk usage is skipped if `fast` is set
+pub fn list_instances_info(fast: bool, labels: &[(String, String)]) -> Result<Vec<InstanceInfo>> {
+    let config = crate::config::read_config().unwrap_or_default();
+    let mut instances = Vec::new();
+    for instance in list_instances()? {
+        let metadata = read_metadata(&instance.name)?;
+        if metadata.matches(labels) {
+            instances.push((instance, metadata));
+        }
+    }
+    instances.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+    let instances = instances
+        .into_iter()
+        .map(|(x, metadata)| InstanceInfo {
+            disk_usage: if fast {
+                None
+            } else {
+                Some(disk_usage(
+                    &Path::new(CIEL_INST_DIR).join(&x.name).join("layers/diff"),
+                ))
+            },
+            volatile: config.for_instance(&x.name).volatile_mount,
+            read_only: overlayfs::is_read_only(&x.name).unwrap_or(false),
+            machine_name: x.ns_name,
+            mounted: x.mounted,
+            running: x.running,
+            booted: x.booted,
+            boot_time: x.boot_time,
+            name: x.name,
+            metadata,
+        })
+        .collect();
+
+    Ok(instances)
+}
+
+/// Print the instances (with all the labels) under the current directory in JSON
+pub fn print_instances_json(fast: bool, labels: &[(String, String)]) -> Result<()> {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&list_instances_info(fast, labels)?)?
+    );
+
+    Ok(())
+}
+
+/// Print the instances (with all the labels) under the current directory
+pub fn print_instances(verbose: bool, labels: &[(String, String)]) -> Result<()> {
     use crate::logging::color_bool;
-    use std::io::Write;
     use tabwriter::TabWriter;
 
-    let instances = list_instances()?;
+    let conn = machined();
+    let conn = conn.as_ref();
+    let instances = list_instances_with(conn)?;
+    let config = crate::config::read_config().ok();
     let mut formatter = TabWriter::new(std::io::stderr());
-    writeln!(&mut formatter, "NAME\tMOUNTED\tRUNNING\tBOOTED")?;
+    write!(
+        &mut formatter,
+        "NAME\tMOUNTED\tRUNNING\tBOOTED\tVOLATILE\tREAD-ONLY"
+    )?;
+    if verbose {
+        write!(
+            &mut formatter,
+            "\tMACHINE\tOPTIONS\tNETWORK\tLIMITS\tMOUNTS\tLABELS\tDESCRIPTION"
+        )?;
+    }
+    writeln!(&mut formatter)?;
     for instance in instances {
+        // an invalid metadata file should not break the listing
+        let metadata = read_metadata(&instance.name).unwrap_or_default();
+        if !metadata.matches(labels) {
+            continue;
+        }
         let mounted = color_bool(instance.mounted);
         let running = color_bool(instance.running);
         let booted = {
@@ -398,17 +1651,151 @@ pub fn print_instances() -> Result<()> {
                 "\x1b[2m-\x1b[0m"
             }
         };
-        writeln!(
+        let volatile = match &config {
+            Some(config) => color_bool(config.for_instance(&instance.name).volatile_mount),
+            None => "\x1b[2m-\x1b[0m",
+        };
+        let read_only = color_bool(overlayfs::is_read_only(&instance.name).unwrap_or(false));
+        write!(
             &mut formatter,
-            "{}\t{}\t{}\t{}",
-            instance.name, mounted, running, booted
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            instance.name, mounted, running, booted, volatile, read_only
         )?;
+        if verbose {
+            let inst_config = config.as_ref().map(|c| c.for_instance(&instance.name));
+            let options = inst_config
+                .as_ref()
+                .map(|c| c.extra_options.join(" "))
+                .unwrap_or_default();
+            let mut network = inst_config
+                .map(|c| c.network.to_string())
+                .unwrap_or_default();
+            if let (true, Some(conn)) = (instance.started, conn) {
+                for address in machine_addresses(conn, &instance.ns_name).unwrap_or_default() {
+                    network.push_str(&format!(" {}", address));
+                }
+            }
+            // read back from systemd, so that the one-shot limits are shown as well
+            let (limits, mounts) = if instance.started {
+                (
+                    conn.and_then(|c| machine_limits(c, &instance.ns_name).ok())
+                        .unwrap_or_else(|| "?".to_owned()),
+                    read_adhoc_mounts(&instance.name)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|x| x.spec)
+                        .collect::<Vec<_>>()
+                        .join(","),
+                )
+            } else {
+                Default::default()
+            };
+            write!(
+                &mut formatter,
+                "\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                instance.ns_name,
+                options,
+                network,
+                limits,
+                mounts,
+                metadata.labels_string(),
+                metadata.description.as_deref().unwrap_or("")
+            )?;
+        }
+        writeln!(&mut formatter)?;
     }
     formatter.flush()?;
+    crate::diagnose::warn_instance_issues();
 
     Ok(())
 }
 
+#[test]
+fn test_probe_machined() {
+    let address = std::env::var_os("DBUS_SYSTEM_BUS_ADDRESS");
+    std::env::set_var(
+        "DBUS_SYSTEM_BUS_ADDRESS",
+        "unix:path=/nonexistent/system_bus_socket",
+    );
+    let probe = probe_machined();
+    match address {
+        Some(address) => std::env::set_var("DBUS_SYSTEM_BUS_ADDRESS", address),
+        None => std::env::remove_var("DBUS_SYSTEM_BUS_ADDRESS"),
+    }
+    assert!(probe.is_err());
+}
+
+#[test]
+fn test_reconnect() {
+    let dbus_daemon = match which::which("dbus-daemon") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    // a bus of our own, killed and restarted between the calls
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("bus");
+    let address = format!("unix:path={}", socket.display());
+    let config = dir.path().join("bus.conf");
+    fs::write(
+        &config,
+        format!(
+            "<busconfig><type>session</type><listen>{}</listen><auth>EXTERNAL</auth>\
+             <policy context=\"default\"><allow send_destination=\"*\"/>\
+             <allow receive_sender=\"*\"/><allow own=\"*\"/></policy></busconfig>",
+            address
+        ),
+    )
+    .unwrap();
+    let start = || {
+        fs::remove_file(&socket).ok();
+        let bus = Command::new(&dbus_daemon)
+            .arg(format!("--config-file={}", config.display()))
+            .arg("--nofork")
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        for _ in 0..500 {
+            if std::os::unix::net::UnixStream::connect(&socket).is_ok() {
+                break;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        bus
+    };
+    let stop = |mut bus: Child| {
+        bus.kill().unwrap();
+        bus.wait().unwrap();
+    };
+    let connect = || -> Result<Connection> {
+        Ok(zbus::blocking::ConnectionBuilder::address(address.as_str())?.build()?)
+    };
+    // differs between the instances of the bus
+    let bus_id = |conn: &Connection| -> Result<String> {
+        Ok(zbus::blocking::fdo::DBusProxy::new(conn)?
+            .get_id()?
+            .to_string())
+    };
+
+    let bus = start();
+    let mut conn = connect().unwrap();
+    let first = with_reconnect(&mut conn, connect, bus_id).unwrap();
+    assert_eq!(with_reconnect(&mut conn, connect, bus_id).unwrap(), first);
+    stop(bus);
+    let bus = start();
+    let second = with_reconnect(&mut conn, connect, bus_id).unwrap();
+    assert_ne!(first, second);
+    stop(bus);
+    // the bus is gone for good
+    assert!(with_reconnect(&mut conn, connect, bus_id).is_err());
+}
+
+#[test]
+fn test_tail_lines() {
+    assert_eq!(tail_lines("a\nb\nc\n", 2), "b\nc");
+    assert_eq!(tail_lines("a\nb\nc", 5), "a\nb\nc");
+    assert_eq!(tail_lines("", 5), "");
+}
+
 #[test]
 fn test_inspect_instance() {
     println!("{:#?}", inspect_instance("alpine", "alpine"));
@@ -417,11 +1804,94 @@ fn test_inspect_instance() {
 #[test]
 fn test_container_name() {
     assert_eq!(
-        get_container_ns_name(Path::new("/tmp/"), false).unwrap(),
+        path_container_name(Path::new("/tmp/")).unwrap(),
         "tmp-51601b0".to_string()
     );
-    println!(
-        "{:#?}",
-        get_container_ns_name(Path::new("/tmp/"), true).unwrap()
+    println!("{:#?}", legacy_container_name(Path::new("/tmp/")).unwrap());
+}
+
+#[test]
+fn test_command_stdio() {
+    let run = |args: &[&str]| {
+        let command = wrap_command(args);
+        Command::new(command[0])
+            .args(&command[1..])
+            .stdin(Stdio::null())
+            .output()
+            .unwrap()
+    };
+    let output = run(&["sh", "-c", "echo out; echo err >&2; exit 3"]);
+    assert_eq!(output.stdout, b"out\n");
+    assert_eq!(output.stderr, b"err\n");
+    assert_eq!(exit_code(output.status), 3);
+    assert_eq!(exit_code(run(&["true"]).status), 0);
+    // the signal deaths are reported by the wrapper as exit codes
+    let output = run(&["sh", "-c", "kill -TERM $$"]);
+    assert_eq!(output.status.code(), Some(143));
+    let status = Command::new("sh")
+        .args(&["-c", "kill -KILL $$"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), None);
+    assert_eq!(exit_code(status), 137);
+}
+
+#[test]
+fn test_merge_environment() {
+    let var = |name: &str, value: &str| (name.to_owned(), value.to_owned());
+    let env = vec![var("http_proxy", "a"), var("ABBS_NO_STRIP", "0")];
+    let overrides = vec![
+        var("ABBS_NO_STRIP", "1"),
+        var("LANG", "C"),
+        var("LANG", "C.UTF-8"),
+    ];
+    assert_eq!(
+        merge_environment(&env, &overrides),
+        vec![
+            &var("http_proxy", "a"),
+            &var("ABBS_NO_STRIP", "1"),
+            &var("LANG", "C.UTF-8"),
+        ]
+    );
+    assert_eq!(merge_environment(&env, &[]), env.iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn test_format_limits() {
+    assert_eq!(
+        format_limits("MemoryMax=17179869184\nCPUQuotaPerSecUSec=8s\nTasksMax=4096\n"),
+        "memory=16.00 GiB,cpu=800%,tasks=4096"
+    );
+    assert_eq!(
+        format_limits("MemoryMax=infinity\nCPUQuotaPerSecUSec=1.500s\nTasksMax=infinity\n"),
+        "cpu=150%"
+    );
+    assert_eq!(format_limits("CPUQuotaPerSecUSec=500ms\n"), "cpu=50%");
+    assert_eq!(
+        format_limits("MemoryMax=infinity\nCPUQuotaPerSecUSec=infinity\n"),
+        ""
+    );
+}
+
+#[test]
+fn test_parse_addresses() {
+    let addresses = vec![
+        (libc::AF_INET, vec![10, 0, 0, 2]),
+        (
+            libc::AF_INET6,
+            vec![0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+        ),
+        (
+            libc::AF_INET6,
+            vec![0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+        ),
+        (libc::AF_INET, vec![1, 2, 3]),
+    ];
+    assert_eq!(
+        parse_addresses(&addresses)
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>(),
+        vec!["10.0.0.2", "fd00::1"]
     );
 }
@@ -1,30 +1,40 @@
 mod actions;
+mod cache;
 mod cli;
+mod command_log;
 mod common;
 mod config;
+mod dbus_login1;
 mod dbus_machine1;
 mod dbus_machine1_machine;
 mod diagnose;
+mod dpkg;
+mod fallback;
+mod inhibit;
 mod logging;
 mod machine;
+mod metadata;
 mod network;
 mod overlayfs;
 mod repo;
+mod workspace;
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, bail, Result};
 use clap::ArgMatches;
 use console::style;
 use dotenv::dotenv;
 use std::process;
-use std::{path::Path, process::Command};
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
 
 use crate::actions::BuildSettings;
 
 macro_rules! print_error {
     ($input:block) => {
         if let Err(e) = $input {
-            error!("{:?}", e);
-            process::exit(1);
+            exit_with_error(e);
         }
     };
 }
@@ -39,11 +49,18 @@ macro_rules! one_or_all_instance {
     }};
 }
 
-fn get_output_dir() -> String {
+/// Returns `--jobs` of the operations on all the instances
+fn jobs_option(args: &ArgMatches) -> usize {
+    args.get_one::<usize>("jobs")
+        .copied()
+        .unwrap_or(actions::DEFAULT_BULK_JOBS)
+}
+
+fn get_output_dir() -> PathBuf {
     if let Ok(c) = config::read_config() {
-        return actions::get_output_directory(c.sep_mount);
+        return actions::get_output_directory(&c, c.sep_mount);
     }
-    "OUTPUT".to_string()
+    PathBuf::from("OUTPUT")
 }
 
 #[inline]
@@ -56,11 +73,185 @@ fn get_instance_option(args: &ArgMatches) -> Result<String> {
     Ok(option_instance.expect("Internal error").to_string())
 }
 
+#[inline]
+fn is_config_show(subcmd: Option<(&str, &ArgMatches)>) -> bool {
+    matches!(subcmd, Some(("config", args)) if args.subcommand_name() == Some("show"))
+}
+
+#[inline]
+fn is_json_output(subcmd: Option<(&str, &ArgMatches)>) -> bool {
+    if let Some(("config", args)) = subcmd {
+        if let Some(("show", args)) = args.subcommand() {
+            return args.get_flag("json");
+        }
+    }
+
+    false
+}
+
+/// Print the error and exit, a distinct exit code is used if the package manager needs to be
+/// repaired so that the scripts can tell it from the other failures
+fn exit_with_error(e: anyhow::Error) -> ! {
+    error!("{:?}", e);
+    if e.downcast_ref::<dpkg::RepairNeeded>().is_some() {
+        process::exit(dpkg::REPAIR_NEEDED_EXIT_CODE);
+    }
+    process::exit(1);
+}
+
+/// Exit with the status of the build, ringing the bell if `bell` is set. The configured
+/// resource limits are put back onto the running instance first if the build overrode them
+/// (`--memory` and `--cpus`)
+fn exit_build(instance: &str, status: Result<i32>, bell: bool) -> ! {
+    if config::ResourceLimits::one_shot() != config::ResourceLimits::default() {
+        if let Err(e) = actions::restore_resource_limits(instance) {
+            warn!(
+                "{}: unable to restore the resource limits: {:#}",
+                instance, e
+            );
+        }
+    }
+    let status = status.unwrap_or_else(|e| exit_with_error(e));
+    if bell {
+        println!("\x07"); // bell character
+    }
+    process::exit(status);
+}
+
 #[inline]
 fn is_root() -> bool {
     nix::unistd::geteuid().is_root()
 }
 
+#[inline]
+fn clone_options(args: &ArgMatches) -> network::CloneOptions {
+    network::CloneOptions {
+        depth: args.get_one::<u32>("depth").copied(),
+        branch: args.get_one::<String>("branch").cloned(),
+    }
+}
+
+/// Parse the `--label` options
+fn labels_option(args: &ArgMatches) -> Result<Vec<(String, String)>> {
+    args.get_many::<String>("label")
+        .into_iter()
+        .flatten()
+        .map(|x| metadata::parse_label(x))
+        .collect()
+}
+
+/// Collect the options of the command run in the container, the relative paths are resolved
+/// against the directory ciel is invoked in
+fn command_options(args: &ArgMatches, invocation_dir: &Path) -> Result<machine::CommandOptions> {
+    let tty = if args.get_flag("tty") {
+        machine::TtyMode::Always
+    } else if args.get_flag("no-tty") {
+        machine::TtyMode::Never
+    } else {
+        machine::TtyMode::Auto
+    };
+    // the files first, so that `--env` overrides them
+    let mut env = Vec::new();
+    for path in args.get_many::<String>("env-file").into_iter().flatten() {
+        let content = std::fs::read_to_string(invocation_dir.join(path))
+            .map_err(|e| anyhow!("Unable to read {}: {}", path, e))?;
+        env.extend(config::parse_env_file(&content).map_err(|e| anyhow!("{}: {}", path, e))?);
+    }
+    for var in args.get_many::<String>("env").into_iter().flatten() {
+        match config::parse_env_var(var, |x| std::env::var(x).ok())? {
+            Some(var) => env.push(var),
+            None => warn!("Environment variable `{}` is not set, not passing it.", var),
+        }
+    }
+
+    let workdir = if args.get_flag("here") {
+        let workspace = std::env::current_dir()?;
+        let config = config::read_config().unwrap_or_default();
+        let workdir = config
+            .trees
+            .iter()
+            .find_map(|x| x.container_path(&workspace, invocation_dir))
+            .ok_or_else(|| {
+                anyhow!("--here can only be used in the tree of the workspace (e.g. TREE)")
+            })?;
+        Some(workdir.display().to_string())
+    } else {
+        args.get_one::<String>("workdir").cloned()
+    };
+    if let Some(workdir) = &workdir {
+        if !workdir.starts_with('/') {
+            bail!(
+                "Working directory `{}` should be an absolute path in the container.",
+                workdir
+            );
+        }
+    }
+
+    let mut mounts = Vec::new();
+    for spec in args.get_many::<String>("mount").into_iter().flatten() {
+        let mut mount = config::MountSpec::parse(spec)?;
+        mount.host = mount
+            .host
+            .canonicalize()
+            .map_err(|e| anyhow!("Unable to mount {}: {}", mount.host.display(), e))?;
+        mounts.push(mount);
+    }
+
+    Ok(machine::CommandOptions {
+        tty,
+        env,
+        workdir,
+        log: None,
+        mounts,
+        quick: args.get_flag("quick"),
+    })
+}
+
+/// Collect the answers of the onboarding questions given as flags of `ciel new`
+fn onboarding_options(args: &ArgMatches) -> actions::OnboardingOptions {
+    let mut settings = Vec::new();
+    if let Some(maintainer) = args.get_one::<String>("maintainer") {
+        settings.push(("maintainer", maintainer.clone()));
+    }
+    if let Some(sources) = args.get_many::<String>("apt-source") {
+        settings.push((
+            "apt-sources",
+            sources.fold(String::new(), |acc, x| acc + x + "\n"),
+        ));
+    }
+    for (flag, key) in [
+        ("dnssec", "dnssec"),
+        ("local-repo", "local-repo"),
+        ("local-sources", "local-sources"),
+        ("sep-mount", "branch-exclusive-output"),
+        ("volatile", "volatile-mount"),
+    ] {
+        if let Some(value) = args.get_one::<bool>(flag) {
+            settings.push((key, value.to_string()));
+        }
+    }
+
+    actions::OnboardingOptions {
+        settings,
+        tree_url: args.get_one::<String>("tree").cloned(),
+        tree: clone_options(args),
+        instances: args
+            .get_many::<String>("instance")
+            .map(|x| x.cloned().collect())
+            .unwrap_or_default(),
+    }
+}
+
+/// Clone the tree and record the chosen branch
+fn load_tree(url: &str, options: &network::CloneOptions) -> Result<()> {
+    network::download_git(url, Path::new("TREE"), options)?;
+    if let Some(branch) = &options.branch {
+        workspace::record_branch(branch)?;
+    }
+
+    Ok(())
+}
+
 fn update_tree(path: &Path, branch: Option<&String>, rebase_from: Option<&String>) -> Result<()> {
     let mut repo = network::fetch_repo(path)?;
     if let Some(branch) = branch {
@@ -73,6 +264,7 @@ fn update_tree(path: &Path, branch: Option<&String>, rebase_from: Option<&String
         if let Err(e) = result {
             bail!("Failed to switch branches: {}\nNote that you can still use `git stash pop` to retrieve your previous changes.`", e);
         }
+        workspace::record_branch(branch)?;
         info!("Successfully updated the tree and switched to {}.", branch);
     } else {
         if rebase_from.is_some() {
@@ -95,23 +287,87 @@ fn main() -> Result<()> {
         println!("Please run me as root!");
         process::exit(1);
     }
+    let mut options = common::GlobalOptions {
+        proxy: args.get_one::<String>("proxy").cloned(),
+        // only the probing on the host, the builds from the fetched sources are not affected
+        offline: args.get_flag("offline"),
+        no_probe: args.get_flag("no-probe"),
+        wait_lock: args.get_flag("wait"),
+        ..Default::default()
+    };
+    if let Some(timeout) = args.get_one::<String>("boot-timeout") {
+        match common::parse_duration(timeout).filter(|x| !x.is_zero()) {
+            Some(timeout) => options.boot_timeout = Some(timeout),
+            None => {
+                error!("Invalid --boot-timeout: {}", timeout);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(rate) = args.get_one::<String>("limit-rate") {
+        match network::parse_rate_limit(rate) {
+            Ok(rate) => options.limit_rate = Some(rate),
+            Err(e) => {
+                error!("Invalid --limit-rate: {}", e);
+                process::exit(1);
+            }
+        }
+    }
     let mut directory = Path::new(args.get_one::<String>("C").unwrap()).to_path_buf();
+    // where the relative paths given to the commands run in the containers are resolved
+    let invocation_dir = std::env::current_dir()?;
+    options.log_file = args
+        .get_one::<String>("log")
+        .map(|log| invocation_dir.join(log));
+    common::set_global_options(options);
     // Switch to the target directory
     std::env::set_current_dir(&directory).unwrap();
     // get subcommands from command line parser
     let subcmd = args.subcommand();
+    // the tarball cache is shared by all the workspaces
+    if let Some(("clean", args)) = subcmd {
+        if args.get_flag("tarball-cache") {
+            print_error!({ cache::clean_tarball_cache() });
+            return Ok(());
+        }
+    }
     // check if the workspace exists, except when the command is `init` or `new`
     match subcmd {
         Some(("init", _)) | Some(("new", _)) | Some(("version", _)) => (),
+        _ if directory == Path::new(".")
+            && (std::env::var_os("CIEL_DIR").is_some() || !Path::new("./.ciel").is_dir()) =>
+        {
+            let found = workspace::find_root();
+            // `config show` is allowed outside of a workspace
+            if found.is_err() && is_config_show(subcmd) {
+                print_error!({ config::show(is_json_output(subcmd)) });
+                return Ok(());
+            }
+            // so is `doctor`, only the host is checked then
+            if let (Err(_), Some(("doctor", args))) = (&found, subcmd) {
+                print_error!({ diagnose::run_diagnose(args.get_flag("fix")) });
+                return Ok(());
+            }
+            directory = match found {
+                Ok(directory) => directory,
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            };
+            info!(
+                "Selected Ciel directory: {}",
+                style(directory.display()).cyan()
+            );
+            std::env::set_current_dir(&directory).unwrap();
+        }
         _ if !Path::new("./.ciel").is_dir() => {
-            if directory == Path::new(".") {
-                directory =
-                    common::find_ciel_dir(".").context("Error finding ciel workspace directory")?;
-                info!(
-                    "Selected Ciel directory: {}",
-                    style(directory.canonicalize()?.display()).cyan()
-                );
-                std::env::set_current_dir(&directory).unwrap();
+            if is_config_show(subcmd) {
+                print_error!({ config::show(is_json_output(subcmd)) });
+                return Ok(());
+            } else if let Some(("doctor", args)) = subcmd {
+                print_error!({ diagnose::run_diagnose(args.get_flag("fix")) });
+                return Ok(());
             } else {
                 error!("This directory does not look like a Ciel workspace");
                 process::exit(1);
@@ -119,82 +375,234 @@ fn main() -> Result<()> {
         }
         _ => (),
     }
+    // clean up if the workspace has been moved since it was last used
+    match subcmd {
+        Some(("init", _)) | Some(("new", _)) | Some(("version", _)) | Some(("doctor", _)) => (),
+        _ => {
+            if let Some(old_root) = workspace::moved_from() {
+                warn!(
+                    "This workspace has been moved from {}, relocating...",
+                    old_root.display()
+                );
+                print_error!({ actions::relocate_workspace(&old_root) });
+            }
+        }
+    }
+    // offer to migrate the workspace created by ciel 2.x,
+    // or to upgrade the configuration file created by an older ciel
+    match subcmd {
+        Some(("init", _))
+        | Some(("new", _))
+        | Some(("version", _))
+        | Some(("farewell", _))
+        | Some(("doctor", _)) => (),
+        _ => match actions::offer_migration() {
+            // the configuration file is converted along with the workspace
+            Ok(true) => (),
+            Ok(false) => print_error!({ config::upgrade_config(true) }),
+            Err(e) => exit_with_error(e),
+        },
+    }
+    // `config show` and `doctor` print the warnings by themselves
+    match subcmd {
+        Some(("init", _)) | Some(("new", _)) | Some(("version", _)) | Some(("doctor", _)) => (),
+        _ if is_config_show(subcmd) => (),
+        _ => config::print_config_warnings(),
+    }
     // list instances if no command is specified
     if subcmd.is_none() {
-        machine::print_instances()?;
+        machine::print_instances(false, &[])?;
         return Ok(());
     }
     let subcmd = subcmd.unwrap();
     // Switch table
     match subcmd {
-        ("farewell", _) => {
-            actions::farewell(&directory).unwrap();
+        ("farewell", args) => {
+            let options = actions::FarewellOptions {
+                dry_run: args.get_flag("dry-run"),
+                remove_output: args.get_flag("output"),
+            };
+            print_error!({ actions::farewell(&directory, &options) });
         }
         ("init", args) => {
             if args.get_flag("upgrade") {
                 info!("Upgrading workspace...");
-                info!("First, shutting down all the instances...");
-                print_error!({ actions::for_each_instance(&actions::container_down) });
-            } else {
-                warn!("Please do not use this command manually ...");
-                warn!("... try `ciel new` instead.");
+                print_error!({ actions::migrate_workspace(args.get_flag("dry-run")) });
+                return Ok(());
             }
+            warn!("Please do not use this command manually ...");
+            warn!("... try `ciel new` instead.");
             print_error!({ common::ciel_init() });
             info!("Initialized working directory at {}", directory.display());
         }
         ("load-tree", args) => {
             info!("Cloning abbs tree...");
-            network::download_git(args.get_one::<String>("url").unwrap(), Path::new("TREE"))?;
+            let url = args.get_one::<String>("url").unwrap();
+            print_error!({ load_tree(url, &clone_options(args)) });
         }
+        ("tree", args) => match args.subcommand() {
+            Some(("clone", args)) => {
+                info!("Cloning abbs tree...");
+                let url = args.get_one::<String>("url").unwrap();
+                print_error!({ load_tree(url, &clone_options(args)) });
+            }
+            Some(("switch", args)) => {
+                let branch = args.get_one::<String>("BRANCH").unwrap();
+                print_error!({ actions::switch_tree(branch, args.get_flag("force")) });
+            }
+            _ => unreachable!(),
+        },
         ("update-tree", args) => {
             let tree = Path::new("TREE");
             info!("Updating tree...");
             print_error!({ update_tree(tree, args.get_one("branch"), args.get_one("rebase")) });
         }
         ("load-os", args) => {
-            let url = args.get_one::<String>("url");
-            if let Some(url) = url {
-                // load from network using specified url
-                if url.starts_with("https://") || url.starts_with("http://") {
-                    print_error!({ actions::load_os(url, None) });
+            let _inhibitor = inhibit::inhibit("Loading the OS", args.get_flag("no-inhibit"));
+            let mut global = common::global_options();
+            global.online = args.get_flag("online");
+            common::set_global_options(global);
+            let options = actions::LoadOsOptions {
+                online: args.get_flag("online"),
+                verify: !args.get_flag("no-verify"),
+                keep_tarball: args.get_flag("keep-tarball"),
+                use_cache: !args.get_flag("no-cache"),
+                fastest_mirror: args.get_flag("fastest-mirror"),
+            };
+            let downed = actions::take_down_instances("Loading the OS", args.get_flag("stop-all"))
+                .unwrap_or_else(|e| exit_with_error(e));
+            if let Some(url) = args.get_one::<String>("url") {
+                let sha256 = args.get_one::<String>("sha256").cloned();
+                print_error!({ actions::load_os_from(url, sha256, &options) });
+            } else {
+                info!("No URL specified. Ciel will automatically pick one.");
+                let selection = actions::TarballSelection {
+                    variant: args.get_one::<String>("variant").cloned(),
+                    arch: args.get_one::<String>("arch").cloned(),
+                    date: args.get_one::<String>("date").cloned(),
+                    interactive: console::user_attended(),
+                };
+                print_error!({ actions::load_os_auto(&options, &selection) });
+            }
+            print_error!({ actions::restore_instances(&downed, args.get_flag("restore")) });
+        }
+        ("update-os", args) => {
+            let _inhibitor = inhibit::inhibit("Updating the OS", args.get_flag("no-inhibit"));
+            let mut global = common::global_options();
+            global.online = args.get_flag("online");
+            common::set_global_options(global);
+            print_error!({ actions::ensure_network_allowed(args.get_flag("online")) });
+            let mut options =
+                actions::UpdateOptions::from_config(&config::read_config().unwrap_or_default());
+            if args.get_flag("full-upgrade") || args.get_flag("safe-upgrade") {
+                options.full_upgrade = args.get_flag("full-upgrade");
+            }
+            if args.get_flag("autoremove") || args.get_flag("no-autoremove") {
+                options.autoremove = args.get_flag("autoremove");
+            }
+            if args.get_flag("clean") || args.get_flag("no-clean") {
+                options.clean = args.get_flag("clean");
+            }
+            options.force = args.get_flag("force");
+            options.auto_repair = args.get_flag("auto-repair");
+            // with `--force`, the OS is updated underneath the running instances
+            let downed = if options.force {
+                actions::DownedInstances::default()
+            } else {
+                actions::take_down_instances("Updating the OS", args.get_flag("stop-all"))
+                    .unwrap_or_else(|e| exit_with_error(e))
+            };
+            match actions::update_os(&options) {
+                Ok(Some(summary)) => info!("Base OS updated: {}.", summary),
+                Ok(None) => info!("Base OS updated."),
+                Err(e) => exit_with_error(e),
+            }
+            print_error!({ actions::restore_instances(&downed, args.get_flag("restore")) });
+        }
+        ("config", args) => {
+            match args.subcommand() {
+                Some(("set", args)) => {
+                    let key = args.get_one::<String>("KEY").unwrap();
+                    let value = args.get_one::<String>("VALUE").unwrap();
+                    if args.get_flag("migrate") {
+                        if key != "output-dir" {
+                            error!("--migrate can only be used with output-dir");
+                            process::exit(1);
+                        }
+                        print_error!({ actions::migrate_output_dir(value) });
+                        return Ok(());
+                    }
+                    print_error!({ config::set_value(key, value, args.get_flag("strict")) });
+                    print_error!({ actions::propagate_config_value(key) });
                     return Ok(());
                 }
-                // load from file
-                let tarball = Path::new(url);
-                if !tarball.is_file() {
-                    error!("{:?} is not a file", url);
-                    process::exit(1);
+                Some(("apply", sub_args)) => {
+                    let instance = args.get_one::<String>("INSTANCE").map(|x| x.as_str());
+                    print_error!({
+                        actions::apply_config_os(instance, sub_args.get_flag("dry-run"))
+                    });
+                    return Ok(());
                 }
-                print_error!({
-                    common::extract_system_tarball(tarball, tarball.metadata()?.len())
-                });
-
-                return Ok(());
+                Some(("export", args)) => {
+                    let file = args.get_one::<String>("FILE").map(|x| x.as_str());
+                    print_error!({
+                        actions::export_config(file, args.get_flag("include-secrets"))
+                    });
+                    return Ok(());
+                }
+                Some(("import", args)) => {
+                    let file = args.get_one::<String>("FILE").unwrap();
+                    print_error!({ actions::import_config(file, args.get_flag("force")) });
+                    return Ok(());
+                }
+                Some(("restore", _)) => {
+                    print_error!({ config::restore_config() });
+                    return Ok(());
+                }
+                Some(("show", args)) => {
+                    print_error!({ config::show(args.get_flag("json")) });
+                    return Ok(());
+                }
+                Some(("set-maintainer", args)) => {
+                    let maintainer = args.get_one::<String>("MAINTAINER").unwrap();
+                    print_error!({ config::set_maintainer(maintainer) });
+                    print_error!({ actions::propagate_config_value("maintainer") });
+                    return Ok(());
+                }
+                Some(("get", args)) => {
+                    let key = args.get_one::<String>("KEY").unwrap();
+                    match config::get_value(key) {
+                        Ok(value) => println!("{}", value),
+                        Err(e) => {
+                            error!("{:?}", e);
+                            process::exit(1);
+                        }
+                    }
+                    return Ok(());
+                }
+                _ => (),
             }
-            // load from network using auto picked url
-            info!("No URL specified. Ciel will automatically pick one.");
-            let tarball = network::pick_latest_tarball();
-            if let Err(e) = tarball {
-                error!("Unable to determine the latest tarball: {}", e);
-                process::exit(1);
+            if args.get_flag("upgrade") {
+                print_error!({ actions::upgrade_config_os() });
+                return Ok(());
             }
-            let tarball = tarball.unwrap();
-            print_error!({
-                actions::load_os(
-                    &format!("https://releases.aosc.io/{}", tarball.path),
-                    Some(tarball.sha256sum),
-                )
-            });
-        }
-        ("update-os", _) => {
-            print_error!({ actions::update_os() });
-        }
-        ("config", args) => {
             if args.get_flag("g") {
                 print_error!({ actions::config_os(None) });
                 return Ok(());
             }
             let instance = get_instance_option(args)?;
+            if let Some(options) = args.get_one::<String>("add-option") {
+                print_error!({ config::add_instance_options(&instance, options) });
+                return Ok(());
+            }
+            if let Some(options) = args.get_one::<String>("remove-option") {
+                print_error!({ config::remove_instance_options(&instance, options) });
+                return Ok(());
+            }
+            if let Some(volatile) = args.get_one::<String>("volatile") {
+                print_error!({ actions::set_instance_volatile(&instance, volatile == "on") });
+                return Ok(());
+            }
             print_error!({ actions::config_os(Some(&instance)) });
         }
         ("mount", args) => {
@@ -202,65 +610,238 @@ fn main() -> Result<()> {
         }
         ("new", args) => {
             let tarball = args.get_one::<String>("tarball");
-            if let Err(e) = actions::onboarding(tarball) {
+            let template = args.get_one::<String>("template");
+            let interactive = !args.get_flag("no-interact");
+            if let Err(e) =
+                actions::onboarding(tarball, template, interactive, &onboarding_options(args))
+            {
                 error!("{}", e);
                 process::exit(1);
             }
         }
         ("run", args) => {
             let instance = get_instance_option(args)?;
+            let options =
+                command_options(args, &invocation_dir).unwrap_or_else(|e| exit_with_error(e));
             let args = args.get_many::<String>("COMMANDS").unwrap();
-            let status =
-                actions::run_in_container(&instance, &args.into_iter().collect::<Vec<_>>())?;
+            let status = actions::run_in_container_with(
+                &instance,
+                &args.into_iter().collect::<Vec<_>>(),
+                &options,
+            )?;
             process::exit(status);
         }
         ("shell", args) => {
             let instance = get_instance_option(args)?;
+            let options =
+                command_options(args, &invocation_dir).unwrap_or_else(|e| exit_with_error(e));
             if let Some(cmd) = args.get_many::<String>("COMMANDS") {
                 let command = cmd
                     .into_iter()
                     .fold(String::with_capacity(1024), |acc, x| acc + " " + x);
-                let status = actions::run_in_container(&instance, &["/bin/bash", "-ec", &command])?;
+                let status = actions::run_in_container_with(
+                    &instance,
+                    &["/bin/bash", "-ec", &command],
+                    &options,
+                )?;
                 process::exit(status);
             }
-            let status = actions::run_in_container(&instance, &["/bin/bash"])?;
+            let status = actions::run_in_container_with(&instance, &["/bin/bash"], &options)?;
+            process::exit(status);
+        }
+        ("ccache-stats", args) => {
+            let instance = get_instance_option(args)?;
+            print_error!({ actions::ccache_stats(&instance) });
+        }
+        ("start", args) => {
+            let instance = get_instance_option(args)?;
+            if args.get_flag("read-only") {
+                let mut options = common::global_options();
+                options.read_only = true;
+                common::set_global_options(options);
+            }
+            print_error!({ actions::boot_container(&instance, !args.get_flag("no-wait")) });
+        }
+        ("attach", args) => {
+            let instance = args.get_one::<String>("INSTANCE").unwrap();
+            let status = actions::attach_container(instance, args.get_flag("console"))
+                .unwrap_or_else(|e| exit_with_error(e));
             process::exit(status);
         }
+        ("cp", args) => {
+            let location = |name: &str| match actions::parse_copy_location(
+                args.get_one::<String>(name).unwrap(),
+            ) {
+                actions::CopyLocation::Host(path) => {
+                    actions::CopyLocation::Host(invocation_dir.join(path))
+                }
+                location => location,
+            };
+            let (source, destination) = (location("SOURCE"), location("DESTINATION"));
+            print_error!({ actions::copy_files(&source, &destination, args.get_flag("force")) });
+        }
         ("stop", args) => {
+            let mut options = machine::StopOptions {
+                force: args.get_flag("force"),
+                ..Default::default()
+            };
+            if let Some(timeout) = args.get_one::<String>("timeout") {
+                options.grace_period = common::parse_duration(timeout)
+                    .filter(|x| !x.is_zero())
+                    .unwrap_or_else(|| exit_with_error(anyhow!("Invalid --timeout: {}", timeout)));
+            }
+            if args.get_flag("all") {
+                print_error!({ actions::stop_all(&options, jobs_option(args)) });
+                return Ok(());
+            }
             let instance = get_instance_option(args)?;
-            print_error!({ actions::stop_container(&instance) });
+            print_error!({ actions::stop_container_with(&instance, &options) });
         }
         ("down", args) => {
+            if args.get_flag("all") {
+                print_error!({ actions::down_all(jobs_option(args)) });
+                return Ok(());
+            }
             print_error!({ one_or_all_instance!(args, &actions::container_down) });
         }
         ("commit", args) => {
             let instance = get_instance_option(args)?;
-            print_error!({ actions::commit_container(&instance) });
+            let _inhibitor = inhibit::inhibit(
+                &format!("Committing {}", instance),
+                args.get_flag("no-inhibit"),
+            );
+            print_error!({ actions::commit_container(&instance, !args.get_flag("no-progress")) });
         }
         ("rollback", args) => {
-            print_error!({ one_or_all_instance!(args, &actions::rollback_container) });
+            let purge = args.get_flag("purge");
+            if args.get_flag("all") {
+                print_error!({
+                    actions::rollback_all(args.get_flag("stop"), purge, jobs_option(args))
+                });
+                return Ok(());
+            }
+            print_error!({
+                one_or_all_instance!(args, &|x: &str| actions::rollback_container_with(x, purge))
+            });
         }
+        ("trash", args) => match args.subcommand() {
+            Some(("list", _)) => print_error!({ actions::list_trash() }),
+            Some(("restore", args)) => {
+                let id = args.get_one::<String>("ID").unwrap();
+                print_error!({ actions::restore_trash(id) });
+            }
+            Some(("empty", _)) => print_error!({ actions::empty_trash() }),
+            _ => unreachable!(),
+        },
         ("del", args) => {
+            if args.get_flag("all") {
+                print_error!({ actions::remove_all(args.get_flag("yes"), jobs_option(args)) });
+                return Ok(());
+            }
             let instance = args.get_one::<String>("INSTANCE").unwrap();
             print_error!({ actions::remove_instance(instance) });
         }
+        ("gc-instances", args) => {
+            let duration = |name: &str| -> Result<Option<std::time::Duration>> {
+                match args.get_one::<String>(name) {
+                    Some(value) => common::parse_duration(value)
+                        .map(Some)
+                        .ok_or_else(|| anyhow!("Invalid duration `{}` for --{}", value, name)),
+                    None => Ok(None),
+                }
+            };
+            let options = actions::GcOptions {
+                idle_after: duration("after").unwrap_or_else(|e| exit_with_error(e)),
+                dry_run: args.get_flag("dry-run"),
+            };
+            if args.get_flag("watch") {
+                let interval = duration("interval")
+                    .unwrap_or_else(|e| exit_with_error(e))
+                    .unwrap_or(actions::DEFAULT_WATCH_INTERVAL);
+                print_error!({ actions::watch_instances(&options, interval) });
+            } else {
+                print_error!({ actions::gc_instances(&options) });
+            }
+        }
+        ("rename", args) => {
+            let instance = args.get_one::<String>("INSTANCE").unwrap();
+            let new_name = args.get_one::<String>("NEW_NAME").unwrap();
+            print_error!({ actions::rename_instance(instance, new_name) });
+        }
+        ("clone", args) => {
+            let instance = args.get_one::<String>("INSTANCE").unwrap();
+            let new_name = args.get_one::<String>("NEW_NAME").unwrap();
+            print_error!({ actions::clone_instance(instance, new_name) });
+        }
         ("add", args) => {
             let instance = args.get_one::<String>("INSTANCE").unwrap();
-            print_error!({ actions::add_instance(instance) });
+            let labels = labels_option(args).unwrap_or_else(|e| exit_with_error(e));
+            let mut metadata = metadata::InstanceMetadata::new()?;
+            metadata.description = args.get_one::<String>("description").cloned();
+            metadata.labels.extend(labels);
+            let network = args.get_one::<String>("network").map(|x| x.as_str());
+            let ports = args
+                .get_many::<String>("port")
+                .map(|x| x.cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+            // checked before the instance is created
+            let network_config = if network.is_some() || !ports.is_empty() {
+                Some(
+                    config::with_instance_network(instance, network, &ports)
+                        .unwrap_or_else(|e| exit_with_error(e)),
+                )
+            } else {
+                None
+            };
+            print_error!({ actions::add_instance(instance, &metadata) });
+            if let Some(config) = network_config {
+                print_error!({ config::write_config(&config) });
+            }
+        }
+        ("describe", args) => {
+            let instance = args.get_one::<String>("INSTANCE").unwrap();
+            let labels = labels_option(args).unwrap_or_else(|e| exit_with_error(e));
+            let unlabel = args
+                .get_many::<String>("unlabel")
+                .map(|x| x.cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+            print_error!({
+                actions::describe_instance(
+                    instance,
+                    args.get_one::<String>("description").map(|x| x.as_str()),
+                    &labels,
+                    &unlabel,
+                )
+            });
         }
         ("build", args) => {
             let instance = get_instance_option(args)?;
+            let _inhibitor = inhibit::inhibit(
+                &format!("Building packages in {}", instance),
+                args.get_flag("no-inhibit"),
+            );
+            let mut options = common::global_options();
+            for (arg, key) in [("memory", "memory-max"), ("cpus", "cpu-quota")] {
+                if let Some(value) = args.get_one::<String>(arg) {
+                    config::limit_property(key, value)
+                        .map_err(|e| anyhow!("Invalid value for --{}: {}", arg, e))?;
+                }
+            }
+            options.memory_max = args.get_one::<String>("memory").cloned();
+            options.cpu_quota = args.get_one::<String>("cpus").cloned();
+            common::set_global_options(options);
             let settings = BuildSettings {
                 offline: args.get_flag("OFFLINE"),
                 stage2: args.get_flag("STAGE2"),
+                tree: args.get_one::<String>("TREE").cloned(),
+                auto_repair: args.get_flag("auto-repair"),
             };
             let mut state = None;
             if let Some(cont) = args.get_one::<String>("CONTINUE") {
                 state = Some(actions::load_build_checkpoint(cont)?);
                 let empty: Vec<&str> = Vec::new();
-                let status = actions::package_build(&instance, empty.into_iter(), state, settings)?;
-                println!("\x07"); // bell character
-                process::exit(status);
+                let status = actions::package_build(&instance, empty.into_iter(), state, settings);
+                exit_build(&instance, status, true);
             }
             let packages = args.get_many::<String>("PACKAGES");
             if packages.is_none() {
@@ -271,26 +852,66 @@ fn main() -> Result<()> {
             if args.contains_id("SELECT") {
                 let start_package = args.get_one::<String>("SELECT");
                 let status =
-                    actions::packages_stage_select(&instance, packages, settings, start_package)?;
-                process::exit(status);
+                    actions::packages_stage_select(&instance, packages, settings, start_package);
+                exit_build(&instance, status, false);
             }
             if args.get_flag("FETCH") {
                 let packages = packages.into_iter().collect::<Vec<_>>();
-                let status = actions::package_fetch(&instance, &packages)?;
-                process::exit(status);
+                let status = actions::package_fetch(&instance, &packages);
+                exit_build(&instance, status, false);
             }
-            let status = actions::package_build(&instance, packages, state, settings)?;
-            println!("\x07"); // bell character
-            process::exit(status);
+            let status = actions::package_build(&instance, packages, state, settings);
+            exit_build(&instance, status, true);
         }
         ("", _) => {
-            machine::print_instances()?;
+            machine::print_instances(false, &[])?;
+        }
+        ("list", args) => {
+            let labels = labels_option(args).unwrap_or_else(|e| exit_with_error(e));
+            if args.get_flag("json")
+                || args.get_one::<String>("format").map(|x| x.as_str()) == Some("json")
+            {
+                print_error!({ machine::print_instances_json(args.get_flag("fast"), &labels) });
+            } else {
+                machine::print_instances(args.get_flag("verbose"), &labels)?;
+            }
         }
-        ("list", _) => {
-            machine::print_instances()?;
+        ("status", args) => {
+            if let Some(instance) = args.get_one::<String>("INSTANCE") {
+                print_error!({ actions::show_instance_status(instance, args.get_flag("json")) });
+                return Ok(());
+            }
+            print_error!({ actions::show_status(args.get_flag("json")) });
         }
-        ("doctor", _) => {
-            print_error!({ diagnose::run_diagnose() });
+        ("generate-unit", args) => {
+            let instance = args.get_one::<String>("INSTANCE").unwrap();
+            let options = actions::UnitOptions {
+                command: args
+                    .get_many::<String>("command")
+                    .map(|x| x.cloned().collect())
+                    .unwrap_or_default(),
+                user: args.get_flag("user"),
+                install: args.get_flag("install"),
+                force: args.get_flag("force"),
+            };
+            print_error!({ actions::generate_unit(instance, &options) });
+        }
+        ("ps", args) => {
+            let instance = args.get_one::<String>("INSTANCE").unwrap();
+            print_error!({ actions::show_processes(instance, args.get_flag("json")) });
+        }
+        ("doctor", args) => {
+            if args.get_flag("relocate") {
+                match workspace::moved_from() {
+                    Some(old_root) => print_error!({ actions::relocate_workspace(&old_root) }),
+                    None => info!("This workspace has not been moved."),
+                }
+            }
+            if args.get_flag("repair-instances") {
+                print_error!({ diagnose::repair_instances(args.get_flag("yes")) });
+                return Ok(());
+            }
+            print_error!({ diagnose::run_diagnose(args.get_flag("fix")) });
         }
         ("repo", args) => match args.subcommand() {
             Some(("refresh", _)) => {
@@ -318,7 +939,11 @@ fn main() -> Result<()> {
             }
             _ => unreachable!(),
         },
-        ("clean", _) => {
+        ("clean", args) => {
+            if args.get_flag("apt-cache") {
+                print_error!({ actions::clean_apt_cache() });
+                return Ok(());
+            }
             print_error!({ actions::cleanup_outputs() });
         }
         ("version", _) => {
@@ -0,0 +1,491 @@
+//! Metadata of the instances (creation time, description and labels) and their activity,
+//! stored in the instance directory
+
+use anyhow::{anyhow, Result};
+use console::style;
+use lazy_static::lazy_static;
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+    sys::signal::kill,
+    unistd::Pid,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt, fs,
+    os::unix::{fs::FileExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    common::{format_time, global_options, CIEL_INST_DIR},
+    info,
+};
+
+const METADATA_FILE: &str = "metadata.toml";
+/// Time of the last command run in the instance, locked while a command is running
+const ACTIVITY_FILE: &str = "activity";
+/// Serializes the uses of the filesystem of the instance that cannot happen at the same time
+const LOCK_FILE: &str = "lock";
+/// Bind mounts added to the running instance by `--mount`, removed when it is stopped
+const ADHOC_MOUNTS_FILE: &str = "adhoc-mounts.toml";
+/// Name of the machine the instance is started as, removed when it is stopped
+const MACHINE_NAME_FILE: &str = "machine-name";
+/// Label of the instances never stopped automatically
+pub const KEEP_ALIVE_LABEL: &str = "keep-alive";
+
+lazy_static! {
+    /// Instances locked by this process, which locks them again without waiting for itself
+    /// (e.g. when the instance is booted for the build holding the lock)
+    static ref HELD_LOCKS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstanceMetadata {
+    /// Creation time in seconds since the epoch, unknown for the instances created by older ciel
+    #[serde(
+        rename = "created-at",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl InstanceMetadata {
+    /// Returns the metadata of a newly created instance
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            created_at: Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()),
+            ..Default::default()
+        })
+    }
+
+    /// Returns if the instance must never be stopped automatically (`keep-alive=true`)
+    pub fn keep_alive(&self) -> bool {
+        self.labels.get(KEEP_ALIVE_LABEL).map(|x| x.as_str()) == Some("true")
+    }
+
+    /// Returns if the instance has all the labels
+    pub fn matches(&self, labels: &[(String, String)]) -> bool {
+        labels
+            .iter()
+            .all(|(key, value)| self.labels.get(key) == Some(value))
+    }
+
+    /// Format the labels as `key=value` separated by commas
+    pub fn labels_string(&self) -> String {
+        self.labels
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+fn metadata_path(instance: &str) -> PathBuf {
+    Path::new(CIEL_INST_DIR).join(instance).join(METADATA_FILE)
+}
+
+/// Read the metadata of the instance, missing metadata (e.g. created by older ciel) is empty
+pub fn read_metadata(instance: &str) -> Result<InstanceMetadata> {
+    match fs::read_to_string(metadata_path(instance)) {
+        Ok(data) => toml::from_str(&data)
+            .map_err(|e| anyhow!("Invalid metadata of instance `{}`: {}", instance, e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(InstanceMetadata::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn write_metadata(instance: &str, metadata: &InstanceMetadata) -> Result<()> {
+    fs::write(metadata_path(instance), toml::to_string(metadata)?)?;
+
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs())
+}
+
+/// Marks the instance as busy until dropped, see [begin_activity]
+pub struct ActivityGuard {
+    file: fs::File,
+}
+
+impl ActivityGuard {
+    fn touch(&self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file
+            .write_all_at(format!("{}\n", now()).as_bytes(), 0)?;
+
+        Ok(())
+    }
+}
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        // the lock is released along with the file
+        self.touch().ok();
+    }
+}
+
+/// The operation holding the lock of the instance, recorded in the lock file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LockHolder {
+    pid: i32,
+    /// In seconds since the epoch
+    since: u64,
+    operation: String,
+}
+
+impl LockHolder {
+    fn parse(content: &str) -> Option<Self> {
+        let mut fields = content.trim().splitn(3, ' ');
+
+        Some(Self {
+            pid: fields.next()?.parse().ok()?,
+            since: fields.next()?.parse().ok()?,
+            operation: fields.next()?.to_owned(),
+        })
+    }
+
+    fn is_alive(&self) -> bool {
+        kill(Pid::from_raw(self.pid), None) != Err(Errno::ESRCH)
+    }
+}
+
+impl fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "held by PID {} since {} running {}",
+            self.pid,
+            format_time(self.since),
+            self.operation
+        )
+    }
+}
+
+/// Holds the instance exclusively until dropped, see [lock_instance]
+pub struct InstanceLock {
+    /// `None` if the instance was already locked by this process
+    file: Option<fs::File>,
+    instance: String,
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        if let Some(file) = &self.file {
+            // the lock itself is released along with the file
+            file.set_len(0).ok();
+            HELD_LOCKS.lock().map(|mut x| x.remove(&self.instance)).ok();
+        }
+    }
+}
+
+/// Lock the instance exclusively for the operation (e.g. `build`), so that the operations
+/// modifying the instance never run at the same time. Fails if another process holds it, unless
+/// `--wait` is given. The lock (flock) is released by the kernel once its holder exits, so a dead
+/// holder never stands in the way, and the holder it recorded is simply overwritten
+pub fn lock_instance(instance: &str, operation: &str) -> Result<InstanceLock> {
+    let dir = Path::new(CIEL_INST_DIR).join(instance);
+    if !dir.is_dir() {
+        return Err(anyhow!("Instance `{}` does not exist.", instance));
+    }
+    if HELD_LOCKS.lock().map_or(false, |x| x.contains(instance)) {
+        return Ok(InstanceLock {
+            file: None,
+            instance: instance.to_owned(),
+        });
+    }
+    let path = dir.join(LOCK_FILE);
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(&path)?;
+    match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => (),
+        Err(Errno::EWOULDBLOCK) => {
+            let holder = fs::read_to_string(&path)
+                .ok()
+                .and_then(|x| LockHolder::parse(&x))
+                // the lock outlives the holder only if a process it started inherited it
+                .filter(|x| x.is_alive())
+                .map_or_else(|| "held by another process".to_owned(), |x| x.to_string());
+            if !global_options().wait_lock {
+                return Err(anyhow!(
+                    "{}: instance busy: {}. Use `ciel --wait` to wait for it.",
+                    instance,
+                    holder
+                ));
+            }
+            info!("{}: instance busy: {}, waiting...", instance, holder);
+            flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+        }
+        Err(e) => return Err(e.into()),
+    }
+    let holder = format!("{} {} {}\n", std::process::id(), now(), operation);
+    file.set_len(0)?;
+    file.write_all_at(holder.as_bytes(), 0)?;
+    if let Ok(mut held) = HELD_LOCKS.lock() {
+        held.insert(instance.to_owned());
+    }
+
+    Ok(InstanceLock {
+        file: Some(file),
+        instance: instance.to_owned(),
+    })
+}
+
+/// Record that a command is being run in the instance,
+/// the instance is busy (see [is_busy]) until the guard is dropped
+pub fn begin_activity(instance: &str) -> Result<ActivityGuard> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(Path::new(CIEL_INST_DIR).join(instance).join(ACTIVITY_FILE))?;
+    // shared, since multiple commands may run in the instance at the same time
+    flock(file.as_raw_fd(), FlockArg::LockShared)?;
+    let guard = ActivityGuard { file };
+    guard.touch()?;
+
+    Ok(guard)
+}
+
+/// Returns the time (in seconds since the epoch) of the last command run in the instance
+pub fn last_activity(instance: &str) -> Option<u64> {
+    fs::read_to_string(Path::new(CIEL_INST_DIR).join(instance).join(ACTIVITY_FILE))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Returns if ciel is running a command in the instance
+pub fn is_busy(instance: &str) -> Result<bool> {
+    let file = match fs::File::open(Path::new(CIEL_INST_DIR).join(instance).join(ACTIVITY_FILE)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => Ok(false),
+        Err(Errno::EWOULDBLOCK) => Ok(true),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A bind mount added for a single invocation of `ciel run` or `ciel shell`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdhocMount {
+    /// In the form of `host:container[:ro]`
+    pub spec: String,
+    /// The mount point and its parents created in the instance for the mount (relative to the
+    /// root of the instance, the deepest first), so that they are never committed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub created: Vec<PathBuf>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AdhocMounts {
+    #[serde(default)]
+    mounts: Vec<AdhocMount>,
+}
+
+fn adhoc_mounts_path(instance: &str) -> PathBuf {
+    Path::new(CIEL_INST_DIR)
+        .join(instance)
+        .join(ADHOC_MOUNTS_FILE)
+}
+
+/// Returns the paths missing in the root for the mount point, relative to the root
+/// and the deepest first
+pub fn missing_paths(root: &Path, mount_point: &Path) -> Vec<PathBuf> {
+    let relative = mount_point.strip_prefix("/").unwrap_or(mount_point);
+
+    relative
+        .ancestors()
+        .take_while(|x| !x.as_os_str().is_empty() && root.join(x).symlink_metadata().is_err())
+        .map(|x| x.to_owned())
+        .collect()
+}
+
+/// Returns the ad-hoc mounts of the instance, empty if it is not running
+pub fn read_adhoc_mounts(instance: &str) -> Result<Vec<AdhocMount>> {
+    match fs::read_to_string(adhoc_mounts_path(instance)) {
+        Ok(data) => Ok(toml::from_str::<AdhocMounts>(&data)?.mounts),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Record the ad-hoc mount of the running instance, before it is mounted
+pub fn record_adhoc_mount(instance: &str, mount: AdhocMount) -> Result<()> {
+    let mut mounts = AdhocMounts {
+        mounts: read_adhoc_mounts(instance)?,
+    };
+    mounts.mounts.push(mount);
+    fs::write(adhoc_mounts_path(instance), toml::to_string(&mounts)?)?;
+
+    Ok(())
+}
+
+/// Forget the ad-hoc mounts of the stopped instance, the mount points created for them are
+/// removed from `root` (the filesystem of the instance) as long as they are empty
+pub fn clear_adhoc_mounts(instance: &str, root: &Path) -> Result<()> {
+    for mount in read_adhoc_mounts(instance)? {
+        for path in mount.created {
+            let path = root.join(path);
+            let removed = match path.symlink_metadata() {
+                Ok(meta) if meta.is_dir() => fs::remove_dir(&path).is_ok(),
+                // a file is created for the mount of a file
+                Ok(meta) if meta.len() == 0 => fs::remove_file(&path).is_ok(),
+                _ => false,
+            };
+            if !removed {
+                break;
+            }
+        }
+    }
+    match fs::remove_file(adhoc_mounts_path(instance)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn machine_name_path(instance: &str) -> PathBuf {
+    Path::new(CIEL_INST_DIR)
+        .join(instance)
+        .join(MACHINE_NAME_FILE)
+}
+
+/// Returns the name of the machine the instance is started as, if it is recorded
+pub fn read_machine_name(instance: &str) -> Option<String> {
+    fs::read_to_string(machine_name_path(instance))
+        .ok()
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+}
+
+/// Record the name of the machine the instance is going to be started as
+pub fn record_machine_name(instance: &str, name: &str) -> Result<()> {
+    fs::write(machine_name_path(instance), format!("{}\n", name))?;
+
+    Ok(())
+}
+
+/// Forget the name of the machine of the stopped instance
+pub fn clear_machine_name(instance: &str) -> Result<()> {
+    match fs::remove_file(machine_name_path(instance)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Parse a label in the form of `key=value`
+pub fn parse_label(label: &str) -> Result<(String, String)> {
+    let (key, value) = label
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Invalid label `{}`: expected `key=value`", label))?;
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        return Err(anyhow!(
+            "Invalid label `{}`: the key may only contain letters, digits, `-`, `_` and `.`",
+            label
+        ));
+    }
+    if value.chars().any(|c| c.is_control()) {
+        return Err(anyhow!(
+            "Invalid label `{}`: the value must not contain control characters",
+            label
+        ));
+    }
+
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+#[test]
+fn test_parse_label() {
+    assert_eq!(
+        parse_label("purpose=qa").unwrap(),
+        ("purpose".to_owned(), "qa".to_owned())
+    );
+    assert_eq!(
+        parse_label("note=a=b").unwrap(),
+        ("note".to_owned(), "a=b".to_owned())
+    );
+    assert_eq!(parse_label("empty=").unwrap().1, "");
+    assert!(parse_label("purpose").is_err());
+    assert!(parse_label("=qa").is_err());
+    assert!(parse_label("a b=c").is_err());
+    assert!(parse_label("a=b\nc").is_err());
+}
+
+#[test]
+fn test_metadata_format() {
+    let mut metadata = InstanceMetadata {
+        created_at: Some(1700000000),
+        description: Some("QA of the stable branch".to_owned()),
+        ..Default::default()
+    };
+    metadata
+        .labels
+        .insert("purpose".to_owned(), "qa".to_owned());
+    let data = toml::to_string(&metadata).unwrap();
+    assert_eq!(toml::from_str::<InstanceMetadata>(&data).unwrap(), metadata);
+    assert!(metadata.matches(&[("purpose".to_owned(), "qa".to_owned())]));
+    assert!(!metadata.matches(&[("purpose".to_owned(), "dev".to_owned())]));
+    assert!(metadata.matches(&[]));
+    assert_eq!(metadata.labels_string(), "purpose=qa");
+    assert_eq!(
+        toml::from_str::<InstanceMetadata>("").unwrap(),
+        InstanceMetadata::default()
+    );
+}
+
+#[test]
+fn test_lock_holder() {
+    let holder = LockHolder::parse("1234 1700000000 build\n").unwrap();
+    assert_eq!(holder.pid, 1234);
+    assert_eq!(holder.since, 1700000000);
+    assert_eq!(holder.operation, "build");
+    assert_eq!(
+        holder.to_string(),
+        "held by PID 1234 since 2023-11-14 22:13:20 UTC running build"
+    );
+    assert_eq!(
+        LockHolder::parse("1 2 quick run").unwrap().operation,
+        "quick run"
+    );
+    assert!(LockHolder::parse("").is_none());
+    assert!(LockHolder::parse("pid 2 build").is_none());
+    assert!(LockHolder::parse(&format!("{} 0 test", std::process::id()))
+        .unwrap()
+        .is_alive());
+}
+
+#[test]
+fn test_missing_paths() {
+    let root = tempfile::tempdir().unwrap();
+    fs::create_dir_all(root.path().join("mnt")).unwrap();
+    assert_eq!(
+        missing_paths(root.path(), Path::new("/mnt/data/logs")),
+        vec![PathBuf::from("mnt/data/logs"), PathBuf::from("mnt/data")]
+    );
+    assert!(missing_paths(root.path(), Path::new("/mnt")).is_empty());
+    assert_eq!(
+        missing_paths(root.path(), Path::new("/srv")),
+        vec![PathBuf::from("srv")]
+    );
+}
@@ -1,7 +1,10 @@
 use crate::common;
 use anyhow::{anyhow, bail, Context, Result};
 use libmount::{mountinfo::Parser, Overlay};
-use nix::mount::{umount2, MntFlags};
+use nix::{
+    mount::{mount, umount2, MntFlags, MsFlags},
+    unistd::{fchownat, FchownatFlags, Gid, Uid},
+};
 use std::fs;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
@@ -12,6 +15,12 @@ use std::{
     io::{BufRead, BufReader},
 };
 
+/// Location of the machine-id file, relative to the root of the filesystem
+const MACHINE_ID_PATH: &str = "etc/machine-id";
+const APT_ARCHIVES_PATH: &str = "var/cache/apt/archives";
+/// The tmpfs holding the upper layer in the read-only mode, relative to the instance directory
+const READ_ONLY_LAYER: &str = "layers/read-only";
+
 pub trait LayerManager {
     /// Return the name of the layer manager, e.g. "overlay".
     /// This name should be the same as the fs_type listed in the /proc/<>/mountinfo file
@@ -33,8 +42,24 @@ pub trait LayerManager {
     fn is_mounted(&self, target: &Path) -> Result<bool>;
     /// Rollback the filesystem to the distribution state
     fn rollback(&mut self) -> Result<()>;
+    /// Rollback like [LayerManager::rollback], but move the upper layer to `dest` (on the same
+    /// filesystem) instead of removing it
+    fn rollback_to(&mut self, dest: &Path) -> Result<()>;
+    /// Replace the upper layer with the one moved away by [LayerManager::rollback_to]
+    fn restore_from(&mut self, src: &Path) -> Result<()>;
+    /// Return if the upper layer has any changes to be committed, other than the directories
+    /// (e.g. the ones holding the machine-id of the instance)
+    fn has_changes(&self) -> Result<bool>;
     /// Commit the current state of the instance filesystem to the distribution state
-    fn commit(&mut self) -> Result<()>;
+    fn commit(&mut self) -> Result<()> {
+        self.commit_with_progress(None).map(|_| ())
+    }
+    /// Commit like [LayerManager::commit], reporting the progress to `progress` if it is set
+    /// (the upper layer is scanned for the number and the size of the files first then)
+    fn commit_with_progress(
+        &mut self,
+        progress: Option<&mut dyn CommitProgress>,
+    ) -> Result<CommitSummary>;
     /// Un-mount the filesystem
     fn unmount(&mut self, target: &Path) -> Result<()>;
     /// Return the directory where the configuration layer is located
@@ -44,10 +69,35 @@ pub trait LayerManager {
     fn get_base_layer(&mut self) -> Result<PathBuf>;
     /// Set the volatile state of the instance filesystem
     fn set_volatile(&mut self, volatile: bool) -> Result<()>;
+    /// Mount the filesystem in the read-only mode, in which the changes go to a temporary layer
+    /// on top of the instance and are discarded when it is un-mounted
+    fn set_read_only(&mut self, read_only: bool) -> Result<()>;
+    /// Return if the filesystem is mounted in the read-only mode
+    fn is_read_only(&self) -> Result<bool>;
+    /// Truncate the machine-id of the instance (without touching the base layer),
+    /// so that a transient one is generated when the container boots
+    fn clear_machine_id(&mut self) -> Result<()>;
     /// Destroy the filesystem of the current instance
     fn destroy(&mut self) -> Result<()>;
 }
 
+/// Receives the progress of [LayerManager::commit_with_progress]
+pub trait CommitProgress {
+    /// The upper layer is scanned, with the number and the total size of the files to be merged
+    fn scanned(&mut self, files: u64, bytes: u64);
+    /// A change is merged into the base layer, with the number and the size of the files in it
+    fn merged(&mut self, path: &Path, files: u64, bytes: u64);
+}
+
+/// What is done by [LayerManager::commit_with_progress]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CommitSummary {
+    /// The files, the symbolic links and the replaced or renamed directories merged
+    pub merged: u64,
+    /// The files and the directories removed from the base layer
+    pub whiteouts: u64,
+}
+
 struct OverlayFS {
     inst: PathBuf,
     base: PathBuf,
@@ -55,6 +105,7 @@ struct OverlayFS {
     upper: PathBuf,
     work: PathBuf,
     volatile: bool,
+    read_only: bool,
 }
 
 /// Create a new overlay filesystem on the host system
@@ -76,7 +127,60 @@ enum Diff {
     File(PathBuf),         // Simple modified or new file
 }
 
+impl Diff {
+    /// Returns the path (in the instance) of the change
+    fn path(&self) -> &Path {
+        match self {
+            Diff::Symlink(path)
+            | Diff::OverrideDir(path)
+            | Diff::RenamedDir(_, path)
+            | Diff::NewDir(path)
+            | Diff::ModifiedDir(path)
+            | Diff::WhiteoutFile(path)
+            | Diff::File(path) => path,
+        }
+    }
+}
+
+/// Returns if the path (in the instance) in the upper layer is never merged into the base layer
+fn is_excluded(rel_path: &Path) -> bool {
+    // the machine-id belongs to the instance, and the downloaded packages (or the leftovers of
+    // the shared APT cache) are not part of the base system
+    rel_path == Path::new(MACHINE_ID_PATH)
+        || (rel_path.starts_with(APT_ARCHIVES_PATH) && rel_path != Path::new(APT_ARCHIVES_PATH))
+}
+
+fn is_whiteout(meta: &fs::Metadata) -> bool {
+    meta.file_type().is_char_device() && meta.rdev() == 0
+}
+
+/// Returns the number and the total size of the files (and the symbolic links) at or under
+/// the path in the upper layer, the ones not merged into the base layer are left out
+fn count_files(upper: &Path, path: &Path) -> Result<(u64, u64)> {
+    let (mut files, mut bytes) = (0, 0);
+    let entries = walkdir::WalkDir::new(path)
+        .into_iter()
+        .filter_entry(|x| !x.path().strip_prefix(upper).map_or(false, is_excluded));
+    for entry in entries {
+        let meta = entry?.metadata()?;
+        if !meta.is_dir() && !is_whiteout(&meta) {
+            files += 1;
+            bytes += meta.len();
+        }
+    }
+
+    Ok((files, bytes))
+}
+
 impl OverlayFS {
+    /// Returns where the tmpfs of the read-only mode is mounted, as an absolute path
+    /// to be found in the mount table
+    fn read_only_layer(&self) -> Result<PathBuf> {
+        Ok(std::env::current_dir()?
+            .join(&self.inst)
+            .join(READ_ONLY_LAYER))
+    }
+
     /// Generate a list of changes made in the upper layer
     fn diff(&self) -> Result<Vec<Diff>> {
         let mut mods: Vec<Diff> = Vec::new();
@@ -91,6 +195,9 @@ impl OverlayFS {
             if has_prefix(&rel_path, &processed_dirs) {
                 continue; // We already dealt with it
             }
+            if is_excluded(&rel_path) {
+                continue;
+            }
             let meta = fs::symlink_metadata(&path)?;
             let file_type = meta.file_type();
 
@@ -136,7 +243,7 @@ impl OverlayFS {
                 }
             } else {
                 // Deal with files
-                if file_type.is_char_device() && meta.rdev() == 0 {
+                if is_whiteout(&meta) {
                     // Whiteout file!
                     mods.push(Diff::WhiteoutFile(rel_path.clone()));
                 } else if lower_path.is_dir() {
@@ -181,21 +288,48 @@ impl LayerManager for OverlayFS {
             upper: inst.join("layers/diff"),
             work: inst.join("layers/diff.tmp"),
             volatile: false,
+            read_only: false,
         }))
     }
     fn mount(&mut self, to: &Path) -> Result<()> {
-        let base_dirs = [self.lower.clone(), self.base.clone()];
+        // create the directories if they don't exist (work directory may be missing)
+        fs::create_dir_all(&self.work)?;
+        fs::create_dir_all(&self.upper)?;
+        fs::create_dir_all(&self.lower)?;
+        let (base_dirs, upper, work) = if self.read_only {
+            let tmpfs = self.read_only_layer()?;
+            fs::create_dir_all(&tmpfs)?;
+            if !is_mounted(&tmpfs, OsStr::new("tmpfs"))? {
+                mount(
+                    Some("tmpfs"),
+                    tmpfs.as_path(),
+                    Some("tmpfs"),
+                    MsFlags::empty(),
+                    Some("mode=0755"),
+                )?;
+            }
+            fs::create_dir_all(tmpfs.join("upper"))?;
+            fs::create_dir_all(tmpfs.join("work"))?;
+            // the upper layer of the instance is seen, but never written to
+            (
+                vec![self.upper.clone(), self.lower.clone(), self.base.clone()],
+                tmpfs.join("upper"),
+                tmpfs.join("work"),
+            )
+        } else {
+            (
+                vec![self.lower.clone(), self.base.clone()],
+                self.upper.clone(),
+                self.work.clone(),
+            )
+        };
         let mut overlay = Overlay::writable(
             // base_dirs variable contains the base and lower directories
             base_dirs.iter().map(|x| x.as_ref()),
-            self.upper.clone(),
-            self.work.clone(),
+            upper,
+            work,
             to,
         );
-        // create the directories if they don't exist (work directory may be missing)
-        fs::create_dir_all(&self.work)?;
-        fs::create_dir_all(&self.upper)?;
-        fs::create_dir_all(&self.lower)?;
         // check overlay usability
         load_overlayfs_support()?;
         if self.volatile {
@@ -227,12 +361,50 @@ impl LayerManager for OverlayFS {
         Ok(())
     }
 
-    fn commit(&mut self) -> Result<()> {
+    fn rollback_to(&mut self, dest: &Path) -> Result<()> {
+        fs::rename(&self.upper, dest)?;
+        fs::remove_dir_all(&self.work)?;
+        fs::create_dir(&self.upper)?;
+        fs::create_dir(&self.work)?;
+
+        Ok(())
+    }
+
+    fn restore_from(&mut self, src: &Path) -> Result<()> {
+        fs::remove_dir_all(&self.upper)?;
+        fs::remove_dir_all(&self.work)?;
+        fs::create_dir(&self.work)?;
+        if let Err(e) = fs::rename(src, &self.upper) {
+            // leave an empty upper layer so that the instance can still be mounted
+            fs::create_dir(&self.upper)?;
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    fn has_changes(&self) -> Result<bool> {
+        Ok(self
+            .diff()?
+            .iter()
+            .any(|x| !matches!(x, Diff::NewDir(_) | Diff::ModifiedDir(_))))
+    }
+
+    fn commit_with_progress(
+        &mut self,
+        mut progress: Option<&mut dyn CommitProgress>,
+    ) -> Result<CommitSummary> {
         if self.volatile {
             // for safety reasons
             nix::unistd::sync();
         }
+        unshift_ownership(&self.upper)?;
         let mods = self.diff()?;
+        if let Some(progress) = progress.as_deref_mut() {
+            let (files, bytes) = count_files(&self.upper, &self.upper)?;
+            progress.scanned(files, bytes);
+        }
+        let mut summary = CommitSummary::default();
         // FIXME: use drain_filter in the future
         // first pass to execute all the deletion actions
         for i in mods.iter() {
@@ -240,23 +412,46 @@ impl LayerManager for OverlayFS {
                 Diff::WhiteoutFile(_) => overlay_exec_action(i, self)?,
                 _ => continue,
             }
+            summary.whiteouts += 1;
+            if let Some(progress) = progress.as_deref_mut() {
+                progress.merged(i.path(), 0, 0);
+            }
         }
         // second pass for everything else
         for i in mods.iter() {
+            // the files are counted before they are moved away, only if the progress is shown
+            let (files, bytes) = match (progress.is_some(), i) {
+                (true, Diff::File(path) | Diff::Symlink(path) | Diff::OverrideDir(path)) => {
+                    count_files(&self.upper, &self.upper.join(path))?
+                }
+                _ => (0, 0),
+            };
             match i {
                 Diff::WhiteoutFile(_) => continue,
                 _ => overlay_exec_action(i, self)
                     .with_context(|| format!("when processing {:?}", i))?,
             }
+            if !matches!(i, Diff::NewDir(_) | Diff::ModifiedDir(_)) {
+                summary.merged += 1;
+            }
+            if let Some(progress) = progress.as_deref_mut() {
+                progress.merged(i.path(), files, bytes);
+            }
         }
         // clear all the remnant items in the upper layer
         self.rollback()?;
 
-        Ok(())
+        Ok(summary)
     }
 
     fn unmount(&mut self, target: &Path) -> Result<()> {
         umount2(target, MntFlags::MNT_DETACH)?;
+        // the changes made in the read-only mode are discarded
+        let tmpfs = self.read_only_layer()?;
+        if is_mounted(&tmpfs, OsStr::new("tmpfs"))? {
+            umount2(&tmpfs, MntFlags::MNT_DETACH)?;
+            fs::remove_dir(&tmpfs)?;
+        }
 
         Ok(())
     }
@@ -280,6 +475,28 @@ impl LayerManager for OverlayFS {
 
         Ok(())
     }
+
+    fn set_read_only(&mut self, read_only: bool) -> Result<()> {
+        self.read_only = read_only;
+
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> Result<bool> {
+        is_mounted(&self.read_only_layer()?, OsStr::new("tmpfs"))
+    }
+
+    fn clear_machine_id(&mut self) -> Result<()> {
+        let machine_id = self.upper.join(MACHINE_ID_PATH);
+        if let Some(parent) = machine_id.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // an empty machine-id makes systemd generate a transient one at boot
+        fs::write(&machine_id, b"")?;
+        fs::set_permissions(&machine_id, fs::Permissions::from_mode(0o444))?;
+
+        Ok(())
+    }
 }
 
 /// is_mounted: check if a path is a mountpoint with corresponding fs_type
@@ -302,6 +519,11 @@ pub(crate) fn get_overlayfs_manager(inst_name: &str) -> Result<Box<dyn LayerMana
     OverlayFS::from_inst_dir(common::CIEL_DIST_DIR, common::CIEL_INST_DIR, inst_name)
 }
 
+/// Return if the filesystem of the instance is mounted in the read-only mode
+pub(crate) fn is_read_only(inst_name: &str) -> Result<bool> {
+    get_overlayfs_manager(inst_name)?.is_read_only()
+}
+
 /// Check if path have all specified prefixes (with order)
 #[inline]
 fn has_prefix(path: &Path, prefixes: &[PathBuf]) -> bool {
@@ -338,6 +560,44 @@ pub fn test_overlay_usability() -> Result<()> {
     Err(anyhow!("No overlayfs support detected"))
 }
 
+/// Shift the ownership of the files in the upper layer back to the users of the host, if
+/// systemd-nspawn shifted it to the UID range of the container (when the idmapped mounts are
+/// not available with `private-users`), so that the base system is not shifted by committing.
+/// The root directory is shifted too, and its owner is the first UID of the range
+fn unshift_ownership(upper: &Path) -> Result<()> {
+    let base = fs::symlink_metadata(upper)?.uid();
+    if base == 0 {
+        return Ok(());
+    }
+    let unshift = |id: u32| {
+        id.checked_sub(base)
+            .filter(|x| *x < common::CONTAINER_UID_RANGE)
+            .unwrap_or(id)
+    };
+    // the root directory comes last, so that an interrupted run is picked up again
+    for entry in walkdir::WalkDir::new(upper).contents_first(true) {
+        let entry = entry?;
+        let metadata = entry.path().symlink_metadata()?;
+        let (uid, gid) = (unshift(metadata.uid()), unshift(metadata.gid()));
+        if (uid, gid) == (metadata.uid(), metadata.gid()) {
+            continue;
+        }
+        fchownat(
+            None,
+            entry.path(),
+            Some(Uid::from_raw(uid)),
+            Some(Gid::from_raw(gid)),
+            FchownatFlags::NoFollowSymlink,
+        )?;
+        // changing the owner clears the setuid and the setgid bits
+        if !metadata.file_type().is_symlink() && metadata.mode() & 0o6000 != 0 {
+            fs::set_permissions(entry.path(), fs::Permissions::from_mode(metadata.mode()))?;
+        }
+    }
+
+    Ok(())
+}
+
 /// Set permission of to according to from
 #[inline]
 fn sync_permission(from: &Path, to: &Path) -> Result<()> {
@@ -414,3 +674,208 @@ fn overlay_exec_action(action: &Diff, overlay: &OverlayFS) -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_clear_machine_id() {
+    let root = tempfile::tempdir().unwrap();
+    let dist = root.path().join("dist");
+    let inst_dir = root.path().join("instances");
+    fs::create_dir_all(dist.join("etc")).unwrap();
+    fs::write(
+        dist.join(MACHINE_ID_PATH),
+        b"0123456789abcdef0123456789abcdef\n",
+    )
+    .unwrap();
+    let mut man = OverlayFS::from_inst_dir(&dist, &inst_dir, &PathBuf::from("test")).unwrap();
+    fs::create_dir_all(inst_dir.join("test/layers/diff.tmp")).unwrap();
+    man.clear_machine_id().unwrap();
+    let upper = inst_dir.join("test/layers/diff");
+    assert_eq!(fs::read(upper.join(MACHINE_ID_PATH)).unwrap(), b"");
+    // a transient machine-id written into the upper layer must not reach the base
+    fs::write(
+        upper.join(MACHINE_ID_PATH),
+        b"fedcba9876543210fedcba9876543210\n",
+    )
+    .unwrap();
+    fs::write(upper.join("etc/hostname"), b"ciel\n").unwrap();
+    man.commit().unwrap();
+    assert_eq!(
+        fs::read(dist.join(MACHINE_ID_PATH)).unwrap(),
+        b"0123456789abcdef0123456789abcdef\n"
+    );
+    assert_eq!(fs::read(dist.join("etc/hostname")).unwrap(), b"ciel\n");
+    assert!(!upper.join(MACHINE_ID_PATH).exists());
+}
+
+#[test]
+fn test_commit_skips_apt_archives() {
+    let root = tempfile::tempdir().unwrap();
+    let dist = root.path().join("dist");
+    let inst_dir = root.path().join("instances");
+    fs::create_dir_all(dist.join(APT_ARCHIVES_PATH)).unwrap();
+    let mut man = OverlayFS::from_inst_dir(&dist, &inst_dir, &PathBuf::from("test")).unwrap();
+    fs::create_dir_all(inst_dir.join("test/layers/diff.tmp")).unwrap();
+    let upper = inst_dir.join("test/layers/diff");
+    fs::create_dir_all(upper.join(APT_ARCHIVES_PATH).join("partial")).unwrap();
+    fs::write(
+        upper.join(APT_ARCHIVES_PATH).join("ciel_1.0_amd64.deb"),
+        b"",
+    )
+    .unwrap();
+    fs::create_dir_all(upper.join("etc")).unwrap();
+    fs::write(upper.join("etc/hostname"), b"ciel\n").unwrap();
+    man.commit().unwrap();
+    assert!(!dist
+        .join(APT_ARCHIVES_PATH)
+        .join("ciel_1.0_amd64.deb")
+        .exists());
+    assert!(!dist.join(APT_ARCHIVES_PATH).join("partial").exists());
+    assert_eq!(fs::read(dist.join("etc/hostname")).unwrap(), b"ciel\n");
+}
+
+#[test]
+#[ignore = "changing the owner requires root, run it with `cargo test -- --ignored` as root"]
+fn test_commit_unshifts_ownership() {
+    assert!(
+        nix::unistd::geteuid().is_root(),
+        "changing the owner requires root"
+    );
+    let root = tempfile::tempdir().unwrap();
+    let dist = root.path().join("dist");
+    let inst_dir = root.path().join("instances");
+    fs::create_dir_all(dist.join("usr/bin")).unwrap();
+    let mut man = OverlayFS::from_inst_dir(&dist, &inst_dir, &PathBuf::from("test")).unwrap();
+    fs::create_dir_all(inst_dir.join("test/layers/diff.tmp")).unwrap();
+    // as left by systemd-nspawn, which shifted the UIDs to the range starting from 524288
+    let upper = inst_dir.join("test/layers/diff");
+    fs::create_dir_all(upper.join("usr/bin")).unwrap();
+    fs::create_dir_all(upper.join("home")).unwrap();
+    fs::write(upper.join("usr/bin/ping"), b"").unwrap();
+    fs::write(upper.join("home/notes"), b"").unwrap();
+    let chown = |path: &Path, id: u32| {
+        fchownat(
+            None,
+            path,
+            Some(Uid::from_raw(id)),
+            Some(Gid::from_raw(id)),
+            FchownatFlags::NoFollowSymlink,
+        )
+        .unwrap()
+    };
+    for path in ["usr/bin/ping", "usr/bin", "usr", "home", ""] {
+        chown(&upper.join(path), 524288);
+    }
+    chown(&upper.join("home/notes"), 524288 + 1000);
+    fs::set_permissions(
+        upper.join("usr/bin/ping"),
+        fs::Permissions::from_mode(0o4755),
+    )
+    .unwrap();
+    man.commit().unwrap();
+    // created by root in the instance, owned by root of the host
+    let metadata = fs::symlink_metadata(dist.join("usr/bin/ping")).unwrap();
+    assert_eq!((metadata.uid(), metadata.gid()), (0, 0));
+    assert_eq!(metadata.mode() & 0o7777, 0o4755);
+    let metadata = fs::symlink_metadata(dist.join("home/notes")).unwrap();
+    assert_eq!((metadata.uid(), metadata.gid()), (1000, 1000));
+}
+
+#[test]
+fn test_commit_progress() {
+    #[derive(Default)]
+    struct Recorder {
+        scanned: (u64, u64),
+        merged: Vec<(PathBuf, u64, u64)>,
+    }
+    impl CommitProgress for Recorder {
+        fn scanned(&mut self, files: u64, bytes: u64) {
+            self.scanned = (files, bytes);
+        }
+        fn merged(&mut self, path: &Path, files: u64, bytes: u64) {
+            self.merged.push((path.to_owned(), files, bytes));
+        }
+    }
+
+    let root = tempfile::tempdir().unwrap();
+    let dist = root.path().join("dist");
+    let inst_dir = root.path().join("instances");
+    fs::create_dir_all(dist.join("etc")).unwrap();
+    let mut man = OverlayFS::from_inst_dir(&dist, &inst_dir, &PathBuf::from("test")).unwrap();
+    fs::create_dir_all(inst_dir.join("test/layers/diff.tmp")).unwrap();
+    let upper = inst_dir.join("test/layers/diff");
+    fs::create_dir_all(upper.join("etc/ciel")).unwrap();
+    fs::create_dir_all(upper.join(APT_ARCHIVES_PATH)).unwrap();
+    fs::write(upper.join("etc/hostname"), b"ciel\n").unwrap();
+    fs::write(upper.join("etc/ciel/a"), b"abc").unwrap();
+    std::os::unix::fs::symlink("hostname", upper.join("etc/name")).unwrap();
+    // not merged, so not counted either
+    fs::write(
+        upper.join(APT_ARCHIVES_PATH).join("ciel_1.0_amd64.deb"),
+        b"deb",
+    )
+    .unwrap();
+    let mut recorder = Recorder::default();
+    let summary = man.commit_with_progress(Some(&mut recorder)).unwrap();
+    assert_eq!(
+        summary,
+        CommitSummary {
+            merged: 3,
+            whiteouts: 0
+        }
+    );
+    assert_eq!(recorder.scanned, (3, 8 + "hostname".len() as u64));
+    let files = recorder.merged.iter().map(|x| x.1).sum::<u64>();
+    let bytes = recorder.merged.iter().map(|x| x.2).sum::<u64>();
+    assert_eq!((files, bytes), recorder.scanned);
+    assert!(recorder
+        .merged
+        .contains(&(PathBuf::from("etc/ciel/a"), 1, 3)));
+    assert_eq!(fs::read(dist.join("etc/ciel/a")).unwrap(), b"abc");
+}
+
+#[test]
+fn test_rollback_to_and_restore() {
+    let root = tempfile::tempdir().unwrap();
+    let dist = root.path().join("dist");
+    let inst_dir = root.path().join("instances");
+    fs::create_dir_all(&dist).unwrap();
+    let mut man = OverlayFS::from_inst_dir(&dist, &inst_dir, &PathBuf::from("test")).unwrap();
+    let upper = inst_dir.join("test/layers/diff");
+    fs::create_dir_all(inst_dir.join("test/layers/diff.tmp")).unwrap();
+    fs::create_dir_all(upper.join("etc")).unwrap();
+    assert!(!man.has_changes().unwrap());
+    fs::write(upper.join("etc/hostname"), b"ciel\n").unwrap();
+    assert!(man.has_changes().unwrap());
+    let saved = root.path().join("saved");
+    man.rollback_to(&saved).unwrap();
+    assert!(!man.has_changes().unwrap());
+    assert_eq!(fs::read(saved.join("etc/hostname")).unwrap(), b"ciel\n");
+    man.restore_from(&saved).unwrap();
+    assert!(!saved.exists());
+    assert_eq!(fs::read(upper.join("etc/hostname")).unwrap(), b"ciel\n");
+}
+
+#[test]
+fn test_rollback_keeps_config_value() {
+    use crate::config::{apply_config, set_config_value, CielConfig};
+
+    let root = tempfile::tempdir().unwrap();
+    let dist = root.path().join("dist");
+    let inst_dir = root.path().join("instances");
+    fs::create_dir_all(dist.join("etc")).unwrap();
+    let mut config = CielConfig::default();
+    set_config_value(&mut config, "maintainer", "Old <old@aosc.io>").unwrap();
+    crate::actions::apply_config_to_base(&dist, &config).unwrap();
+    let mut man = OverlayFS::from_inst_dir(&dist, &inst_dir, &PathBuf::from("test")).unwrap();
+    let upper = inst_dir.join("test/layers/diff");
+    fs::create_dir_all(inst_dir.join("test/layers/diff.tmp")).unwrap();
+    fs::create_dir_all(&upper).unwrap();
+    // `ciel config set` writes into the mounted instance and the base system
+    set_config_value(&mut config, "maintainer", "New <new@aosc.io>").unwrap();
+    apply_config(&upper, &config, None).unwrap();
+    crate::actions::apply_config_to_base(&dist, &config).unwrap();
+    man.rollback().unwrap();
+    let ab3cfg =
+        fs::read_to_string(dist.join("usr/lib/autobuild3/etc/autobuild/ab3cfg.sh")).unwrap();
+    assert!(ab3cfg.contains("MTER=\"New <new@aosc.io>\""));
+}
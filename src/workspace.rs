@@ -0,0 +1,269 @@
+//! Discovery of the workspace root directory and the paths in the workspace
+
+use anyhow::{anyhow, Result};
+use console::style;
+use std::{
+    ffi::OsStr,
+    fs,
+    io::Write,
+    os::unix::prelude::{MetadataExt, OsStrExt},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::{self, config_location, CielConfig};
+use crate::network::get_arch_name;
+use crate::warn;
+
+/// Environment variable to specify the workspace directory directly
+const CIEL_DIR_ENV: &str = "CIEL_DIR";
+/// Where the location of the workspace is recorded, to find out whether the workspace is moved
+const CIEL_ROOT_FILE: &str = ".ciel/root";
+/// Where the architecture of the loaded OS is recorded
+const CIEL_ARCH_FILE: &str = ".ciel/arch";
+/// Where the branch of the tree chosen by the user is recorded
+const CIEL_BRANCH_FILE: &str = ".ciel/tree-branch";
+/// Where the time of the last successful `update-os` is recorded (in seconds since the epoch)
+const CIEL_LAST_UPDATE_FILE: &str = ".ciel/last-update";
+/// Where the ID of the workspace is stored, the machine names of the instances end with it
+const CIEL_ID_FILE: &str = ".ciel/id";
+
+/// Find the root of the workspace, the `CIEL_DIR` environment variable is used if set,
+/// otherwise the current directory and its parents are searched (like how Git finds `.git`)
+pub fn find_root() -> Result<PathBuf> {
+    discover_root(
+        std::env::var_os(CIEL_DIR_ENV).map(PathBuf::from),
+        &std::env::current_dir()?,
+    )
+}
+
+#[inline]
+fn is_workspace(dir: &Path) -> bool {
+    config_location(dir).is_file()
+}
+
+fn discover_root(ciel_dir: Option<PathBuf>, start: &Path) -> Result<PathBuf> {
+    if let Some(dir) = ciel_dir {
+        if !is_workspace(&dir) {
+            return Err(anyhow!(
+                "{} (from ${}) is not a ciel workspace. Please run `ciel new` there to create one.",
+                dir.display(),
+                CIEL_DIR_ENV
+            ));
+        }
+        return Ok(dir);
+    }
+    let start = start.canonicalize()?;
+    let start_dev = start.metadata()?.dev();
+    for dir in start.ancestors() {
+        // do not cross the filesystem boundary
+        if dir.metadata()?.dev() != start_dev {
+            break;
+        }
+        if is_workspace(dir) {
+            return Ok(dir.to_owned());
+        }
+    }
+
+    Err(anyhow!(
+        "No ciel workspace found in {} or any of its parent directories. Please run `ciel new` to create one, or set ${} to the workspace directory.",
+        start.display(),
+        CIEL_DIR_ENV
+    ))
+}
+
+/// Returns the location of the current workspace when it was last used
+pub fn recorded_root() -> Option<PathBuf> {
+    let data = fs::read(CIEL_ROOT_FILE).ok()?;
+
+    Some(PathBuf::from(OsStr::from_bytes(
+        data.strip_suffix(b"\n").unwrap_or(&data),
+    )))
+}
+
+/// Record the location of the current workspace, the absolute paths of the mounts are
+/// derived from it
+pub fn record_root() -> Result<()> {
+    let root = std::env::current_dir()?;
+    if recorded_root().as_deref() != Some(&root) {
+        let mut data = root.as_os_str().as_bytes().to_owned();
+        data.push(b'\n');
+        fs::write(CIEL_ROOT_FILE, data)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the previous location of the current workspace if it has been moved since last used
+pub fn moved_from() -> Option<PathBuf> {
+    let recorded = recorded_root()?;
+    let current = std::env::current_dir().ok()?;
+    // the workspace may also be reached through a symlink
+    if recorded == current || recorded.canonicalize().ok() == current.canonicalize().ok() {
+        return None;
+    }
+
+    Some(recorded)
+}
+
+fn read_id() -> Option<String> {
+    fs::read_to_string(CIEL_ID_FILE)
+        .ok()
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+}
+
+/// Returns the ID of the current workspace, generated when it is first needed. Unlike the
+/// location, it does not change when the workspace is moved
+pub fn workspace_id() -> Result<String> {
+    if let Some(id) = read_id() {
+        return Ok(id);
+    }
+    let id = format!("{:08x}", rand::random::<u32>());
+    // another ciel may be generating it at the same time, the first one wins
+    match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(CIEL_ID_FILE)
+    {
+        Ok(mut file) => {
+            writeln!(file, "{}", id)?;
+            Ok(id)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            read_id().ok_or_else(|| anyhow!("Unable to read the workspace ID."))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Forget the ID of the current workspace, so that a copy of a workspace gets its own
+pub fn reset_id() -> Result<()> {
+    match fs::remove_file(CIEL_ID_FILE) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Record the architecture of the OS loaded in the current workspace
+pub fn record_arch(arch: &str) -> Result<()> {
+    fs::write(CIEL_ARCH_FILE, format!("{}\n", arch))?;
+
+    Ok(())
+}
+
+/// Returns the architecture of the OS loaded in the current workspace, if it is recorded
+pub fn workspace_arch() -> Option<String> {
+    fs::read_to_string(CIEL_ARCH_FILE)
+        .ok()
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+}
+
+/// Record the branch of the tree chosen by the user (when cloning or switching)
+pub fn record_branch(branch: &str) -> Result<()> {
+    fs::write(CIEL_BRANCH_FILE, format!("{}\n", branch))?;
+
+    Ok(())
+}
+
+/// Returns the branch of the tree chosen by the user, if it is recorded
+pub fn recorded_branch() -> Option<String> {
+    fs::read_to_string(CIEL_BRANCH_FILE)
+        .ok()
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+}
+
+/// Record the time of the last successful update of the OS in the current workspace
+pub fn record_update() -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    fs::write(CIEL_LAST_UPDATE_FILE, format!("{}\n", now))?;
+
+    Ok(())
+}
+
+/// Forget the time of the last update, e.g. when another OS is loaded
+pub fn forget_update() -> Result<()> {
+    match fs::remove_file(CIEL_LAST_UPDATE_FILE) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the time of the last successful update of the OS (in seconds since the epoch),
+/// if the OS has been updated since it was loaded
+pub fn last_update() -> Option<u64> {
+    fs::read_to_string(CIEL_LAST_UPDATE_FILE)
+        .ok()
+        .and_then(|x| x.trim().parse().ok())
+}
+
+/// Returns the path to the output directory, relative to the workspace unless `output-dir` is set.
+/// `branch` is the branch of the tree if the branch-exclusive output directories are used
+pub fn output_dir(config: &CielConfig, branch: Option<&str>) -> PathBuf {
+    output_dir_with_pattern(config, branch, &config.output_dir_pattern)
+}
+
+/// Same as [output_dir], but the specified pattern is used for the branch-exclusive directories
+pub fn output_dir_with_pattern(
+    config: &CielConfig,
+    branch: Option<&str>,
+    pattern: &str,
+) -> PathBuf {
+    let name = match branch {
+        Some(branch) => {
+            let arch =
+                workspace_arch().unwrap_or_else(|| get_arch_name().unwrap_or("unknown").to_owned());
+            config::expand_output_dir_pattern(pattern, branch, &arch).unwrap_or_else(|e| {
+                warn!("Invalid output-dir-pattern ({}), using the default.", e);
+                format!("OUTPUT-{}", branch)
+            })
+        }
+        None => "OUTPUT".to_owned(),
+    };
+
+    match &config.output_dir {
+        Some(dir) => dir.join(name),
+        None => PathBuf::from(name),
+    }
+}
+
+#[test]
+fn test_output_dir() {
+    let mut config = CielConfig::default();
+    assert_eq!(output_dir(&config, None), Path::new("OUTPUT"));
+    assert_eq!(
+        output_dir(&config, Some("stable")),
+        Path::new("OUTPUT-stable")
+    );
+    config.output_dir = Some(PathBuf::from("/srv/ciel"));
+    assert_eq!(output_dir(&config, None), Path::new("/srv/ciel/OUTPUT"));
+    assert_eq!(
+        output_dir_with_pattern(&config, Some("stable"), "debs-{branch}"),
+        Path::new("/srv/ciel/debs-stable")
+    );
+}
+
+#[test]
+fn test_discover_root() {
+    let root = tempfile::tempdir().unwrap();
+    let root = root.path().canonicalize().unwrap();
+    let nested = root.join("TREE/app-devel/foo");
+    std::fs::create_dir_all(&nested).unwrap();
+    assert!(discover_root(None, &nested)
+        .unwrap_err()
+        .to_string()
+        .starts_with("No ciel workspace found"));
+    assert!(discover_root(Some(root.clone()), &nested).is_err());
+
+    let config = config_location(&root);
+    std::fs::create_dir_all(config.parent().unwrap()).unwrap();
+    std::fs::write(&config, "").unwrap();
+    assert_eq!(discover_root(None, &nested).unwrap(), root);
+    assert_eq!(discover_root(None, &root).unwrap(), root);
+    assert_eq!(
+        discover_root(Some(root.clone()), Path::new("/")).unwrap(),
+        root
+    );
+}